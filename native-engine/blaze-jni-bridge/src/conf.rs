@@ -29,7 +29,11 @@ macro_rules! define_conf {
 }
 
 define_conf!(IntConf, BATCH_SIZE);
+define_conf!(IntConf, OUTPUT_CHANNEL_CAPACITY);
+define_conf!(IntConf, OUTPUT_SEND_TIMEOUT_MS);
+define_conf!(LongConf, EXPORT_IN_FLIGHT_MAX_MEM_SIZE);
 define_conf!(DoubleConf, MEMORY_FRACTION);
+define_conf!(DoubleConf, MEMORY_SPILL_WATERMARK);
 define_conf!(BooleanConf, SMJ_INEQUALITY_JOIN_ENABLE);
 define_conf!(BooleanConf, CASE_CONVERT_FUNCTIONS_ENABLE);
 define_conf!(BooleanConf, INPUT_BATCH_STATISTICS_ENABLE);
@@ -44,12 +48,38 @@ define_conf!(StringConf, SPARK_IO_COMPRESSION_CODEC);
 define_conf!(IntConf, TOKIO_WORKER_THREADS_PER_CPU);
 define_conf!(IntConf, SPARK_TASK_CPUS);
 define_conf!(StringConf, SPILL_COMPRESSION_CODEC);
+define_conf!(IntConf, SPILL_DIRECT_TO_DISK_THRESHOLD);
+define_conf!(BooleanConf, SPILL_COLUMN_ENCODING_ENABLE);
+define_conf!(IntConf, SPILL_RESIDENT_THRESHOLD);
+define_conf!(BooleanConf, SPILL_ENCRYPTION_ENABLE);
+define_conf!(LongConf, SPILL_PLACEMENT_SEED);
+define_conf!(LongConf, SPILL_MAX_DISK_BYTES);
+define_conf!(BooleanConf, SHUFFLE_STABLE_ORDER_ENABLE);
+define_conf!(StringConf, SHUFFLE_SPILL_FORMAT);
+define_conf!(IntConf, SHUFFLE_NULL_KEYS_HASH_SEED);
+define_conf!(IntConf, SHUFFLE_NULL_KEYS_PARTITION);
+define_conf!(BooleanConf, SHUFFLE_SEGMENT_TRAILER_ENABLE);
+define_conf!(IntConf, SHUFFLE_SUB_BATCH_MEM_SIZE);
+define_conf!(DoubleConf, SHUFFLE_SKEW_WARNING_RATIO);
+define_conf!(IntConf, SHUFFLE_MAX_BUFFERED_BATCHES);
+define_conf!(BooleanConf, SHUFFLE_BATCH_STATS_ENABLE);
+define_conf!(StringConf, SHUFFLE_WRITER);
+define_conf!(IntConf, SHUFFLE_BYPASS_MERGE_THRESHOLD);
+define_conf!(BooleanConf, SHUFFLE_INDEX_BITMAP_ENABLE);
+define_conf!(BooleanConf, SHUFFLE_INDEX_SPARSE_ENABLE);
+define_conf!(StringConf, SHUFFLE_PARTITION_ID_ASSIGNMENT);
+define_conf!(BooleanConf, SHUFFLE_MERGE_VALIDATION_ENABLE);
+define_conf!(LongConf, SHUFFLE_MAX_MESSAGE_SIZE);
+define_conf!(BooleanConf, SHUFFLE_SYNC_ON_CLOSE_ENABLE);
+define_conf!(IntConf, SORT_MAX_MERGE_FANIN);
 define_conf!(BooleanConf, SMJ_FALLBACK_ENABLE);
 define_conf!(IntConf, SMJ_FALLBACK_ROWS_THRESHOLD);
 define_conf!(IntConf, SMJ_FALLBACK_MEM_SIZE_THRESHOLD);
+define_conf!(IntConf, SMJ_GROUP_SPILL_MEM_SIZE_THRESHOLD);
 define_conf!(IntConf, SUGGESTED_BATCH_MEM_SIZE);
 define_conf!(IntConf, SUGGESTED_BATCH_MEM_SIZE_KWAY_MERGE);
 define_conf!(BooleanConf, ORC_FORCE_POSITIONAL_EVOLUTION);
+define_conf!(StringConf, DEBUG_EXEC_TAGS);
 define_conf!(IntConf, UDAF_FALLBACK_NUM_UDAFS_TRIGGER_SORT_AGG);
 
 pub trait BooleanConf {
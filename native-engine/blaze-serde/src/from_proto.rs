@@ -65,6 +65,7 @@ use datafusion_ext_plans::{
     agg_exec::AggExec,
     broadcast_join_build_hash_map_exec::BroadcastJoinBuildHashMapExec,
     broadcast_join_exec::BroadcastJoinExec,
+    coalesce_exec::CoalesceExec,
     debug_exec::DebugExec,
     empty_partitions_exec::EmptyPartitionsExec,
     expand_exec::ExpandExec,
@@ -72,7 +73,7 @@ use datafusion_ext_plans::{
     filter_exec::FilterExec,
     generate::{create_generator, create_udtf_generator},
     generate_exec::GenerateExec,
-    ipc_reader_exec::IpcReaderExec,
+    ipc_reader_exec::{IpcReadFormat, IpcReaderExec},
     ipc_writer_exec::IpcWriterExec,
     limit_exec::LimitExec,
     orc_exec::OrcExec,
@@ -81,7 +82,7 @@ use datafusion_ext_plans::{
     project_exec::ProjectExec,
     rename_columns_exec::RenameColumnsExec,
     rss_shuffle_writer_exec::RssShuffleWriterExec,
-    shuffle::Partitioning,
+    shuffle::{HashAlgorithm, Partitioning},
     shuffle_writer_exec::ShuffleWriterExec,
     sort_exec::SortExec,
     sort_merge_join_exec::SortMergeJoinExec,
@@ -97,8 +98,9 @@ use crate::{
     error::PlanSerDeError,
     from_proto_binary_op, proto_error, protobuf,
     protobuf::{
-        physical_expr_node::ExprType, physical_plan_node::PhysicalPlanType,
-        physical_repartition::RepartitionType, GenerateFunction, PhysicalRepartition, SortExecNode,
+        physical_expr_node::ExprType, physical_hash_algorithm::Algorithm,
+        physical_plan_node::PhysicalPlanType, physical_repartition::RepartitionType,
+        GenerateFunction, PhysicalRepartition, SortExecNode,
     },
     Schema,
 };
@@ -298,15 +300,27 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
             }
             PhysicalPlanType::IpcReader(ipc_reader) => {
                 let schema = Arc::new(convert_required!(ipc_reader.schema)?);
-                Ok(Arc::new(IpcReaderExec::new(
-                    ipc_reader.num_partitions as usize,
-                    ipc_reader.ipc_provider_resource_id.clone(),
-                    schema,
-                )))
+                let format = match protobuf::IpcReadFormat::try_from(ipc_reader.format)
+                    .expect("invalid IpcReadFormat")
+                {
+                    protobuf::IpcReadFormat::BlazeIpc => IpcReadFormat::Blaze,
+                    protobuf::IpcReadFormat::ArrowIpc => IpcReadFormat::Arrow,
+                };
+                Ok(Arc::new(
+                    IpcReaderExec::new(
+                        ipc_reader.num_partitions as usize,
+                        ipc_reader.ipc_provider_resource_id.clone(),
+                        schema,
+                    )
+                    .with_format(format),
+                ))
             }
             PhysicalPlanType::Debug(debug) => {
                 let input: Arc<dyn ExecutionPlan> = convert_box_required!(debug.input)?;
-                Ok(Arc::new(DebugExec::new(input, debug.debug_id.clone())))
+                Ok(Arc::new(
+                    DebugExec::new(input, debug.debug_id.clone())
+                        .with_sample_rows(debug.sample_rows as usize),
+                ))
             }
             PhysicalPlanType::Sort(sort) => {
                 let input: Arc<dyn ExecutionPlan> = convert_box_required!(sort.input)?;
@@ -509,7 +523,15 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
             }
             PhysicalPlanType::CoalesceBatches(coalesce_batches) => {
                 let input: Arc<dyn ExecutionPlan> = convert_box_required!(coalesce_batches.input)?;
-                Ok(Arc::new(LimitExec::new(input, coalesce_batches.batch_size)))
+                let mut coalesce = CoalesceExec::new(input);
+                if coalesce_batches.batch_size > 0 {
+                    coalesce = coalesce.with_batch_size(coalesce_batches.batch_size as usize);
+                }
+                if coalesce_batches.batch_mem_size > 0 {
+                    coalesce =
+                        coalesce.with_batch_mem_size(coalesce_batches.batch_mem_size as usize);
+                }
+                Ok(Arc::new(coalesce))
             }
             PhysicalPlanType::Expand(expand) => {
                 let schema = Arc::new(convert_required!(expand.schema)?);
@@ -1125,9 +1147,24 @@ pub fn parse_protobuf_partitioning(
                     .iter()
                     .map(|e| try_parse_physical_expr(e, &input.schema()))
                     .collect::<Result<Vec<Arc<dyn PhysicalExpr>>, _>>()?;
+                let hash_algorithm = match hash_part
+                    .hash_algorithm
+                    .as_ref()
+                    .and_then(|a| a.algorithm.as_ref())
+                {
+                    None => HashAlgorithm::default(),
+                    Some(Algorithm::Murmur3(murmur3)) => {
+                        HashAlgorithm::Murmur3 { seed: murmur3.seed }
+                    }
+                    Some(Algorithm::HiveHash(_)) => HashAlgorithm::HiveHash,
+                    Some(Algorithm::Xxhash64(xxhash64)) => HashAlgorithm::XxHash64 {
+                        seed: xxhash64.seed,
+                    },
+                };
                 Ok(Some(Partitioning::HashPartitioning(
                     expr,
                     hash_part.partition_count.try_into().unwrap(),
+                    hash_algorithm,
                 )))
             }
 
@@ -1181,6 +1218,17 @@ pub fn parse_protobuf_partitioning(
                     )))
                 }
             }
+
+            RepartitionType::PartitionIdRepartition(partition_id_part) => {
+                let expr = try_parse_physical_expr_required(
+                    &partition_id_part.partition_id_expr,
+                    &input.schema(),
+                )?;
+                Ok(Some(Partitioning::PartitionIdPartitioning(
+                    expr,
+                    partition_id_part.partition_count.try_into().unwrap(),
+                )))
+            }
         }
     })
 }
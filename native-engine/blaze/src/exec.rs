@@ -28,7 +28,7 @@ use datafusion::{
     },
     prelude::{SessionConfig, SessionContext},
 };
-use datafusion_ext_plans::memmgr::MemManager;
+use datafusion_ext_plans::memmgr::{MemManager, MemManagerConfig};
 use jni::{
     objects::{JClass, JObject},
     JNIEnv,
@@ -73,9 +73,14 @@ pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_callNative(
                 let max_memory = executor_memory_overhead as usize;
                 let memory_fraction = conf::MEMORY_FRACTION.value()?;
                 let batch_size = conf::BATCH_SIZE.value()? as usize;
-                MemManager::init((max_memory as f64 * memory_fraction) as usize);
+                let mem_manager_config =
+                    MemManagerConfig::new((max_memory as f64 * memory_fraction) as usize)
+                        .with_spill_watermark(conf::MEMORY_SPILL_WATERMARK.value()?);
+                MemManager::init(mem_manager_config.clone());
 
-                let session_config = SessionConfig::new().with_batch_size(batch_size);
+                let session_config = SessionConfig::new()
+                    .with_batch_size(batch_size)
+                    .with_extension(Arc::new(mem_manager_config));
                 let runtime_config =
                     RuntimeConfig::new().with_disk_manager(DiskManagerConfig::Disabled);
                 let runtime = Arc::new(RuntimeEnv::new(runtime_config)?);
@@ -123,7 +128,7 @@ pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_finalizeNative(
 #[no_mangle]
 pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_onExit(_: JNIEnv, _: JClass) {
     log::info!("exiting native environment");
-    if MemManager::initialized() {
-        MemManager::get().dump_status();
+    if let Ok(mm) = MemManager::get() {
+        mm.dump_status();
     }
 }
@@ -48,7 +48,7 @@ pub fn update_spark_metric_node(
     Ok(())
 }
 
-fn update_metrics(metric_node: JObject, metric_values: &[(&str, i64)]) -> Result<()> {
+pub fn update_metrics(metric_node: JObject, metric_values: &[(&str, i64)]) -> Result<()> {
     for &(name, value) in metric_values {
         let jname = jni_new_string!(&name)?;
         jni_call!(SparkMetricNode(metric_node).add(jname.as_obj(), value) -> ())?;
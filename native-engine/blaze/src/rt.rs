@@ -39,10 +39,14 @@ use datafusion::{
         displayable, empty::EmptyExec, metrics::ExecutionPlanMetricsSet, ExecutionPlan,
     },
 };
-use datafusion_ext_commons::{df_execution_err, downcast_any};
+use datafusion_ext_commons::{arrow::array_size::BatchSize, df_execution_err, downcast_any};
 use datafusion_ext_plans::{
-    common::execution_context::{cancel_all_tasks, ExecutionContext},
+    common::{
+        execution_context::{cancel_all_tasks, ExecutionContext},
+        in_flight_limiter::{InFlightLimiter, InFlightPermit},
+    },
     ipc_writer_exec::IpcWriterExec,
+    memmgr::MemManager,
     parquet_sink_exec::ParquetSinkExec,
     shuffle_writer_exec::ShuffleWriterExec,
 };
@@ -54,14 +58,15 @@ use tokio::{runtime::Runtime, task::JoinHandle};
 use crate::{
     handle_unwinded_scope,
     logging::{THREAD_PARTITION_ID, THREAD_STAGE_ID},
-    metrics::update_spark_metric_node,
+    metrics::{update_metrics, update_spark_metric_node},
 };
 
 pub struct NativeExecutionRuntime {
     exec_ctx: Arc<ExecutionContext>,
     native_wrapper: GlobalRef,
     plan: Arc<dyn ExecutionPlan>,
-    batch_receiver: Receiver<Result<Option<RecordBatch>>>,
+    batch_receiver: Receiver<Result<Option<(RecordBatch, InFlightPermit)>>>,
+    in_flight_limiter: Arc<InFlightLimiter>,
     tokio_runtime: Runtime,
     join_handle: JoinHandle<()>,
 }
@@ -118,6 +123,7 @@ impl NativeExecutionRuntime {
                 );
                 THREAD_STAGE_ID.set(stage_id);
                 THREAD_PARTITION_ID.set(partition_id);
+                MemManager::set_thread_task_id(Some((stage_id, partition_id)));
             });
         if num_worker_threads > 0 {
             tokio_runtime_builder.worker_threads(num_worker_threads as usize);
@@ -125,8 +131,11 @@ impl NativeExecutionRuntime {
         let tokio_runtime = tokio_runtime_builder.build()?;
 
         // spawn batch producer
+        // exported batches hold in-flight permits until imported by the JVM
         let (batch_sender, batch_receiver) = std::sync::mpsc::sync_channel(1);
         let err_sender = batch_sender.clone();
+        let in_flight_limiter = InFlightLimiter::try_new()?;
+        let in_flight_limiter_cloned = in_flight_limiter.clone();
         let execution_plan_cloned = execution_plan.clone();
         let exec_ctx_cloned = exec_ctx.clone();
         let native_wrapper_cloned = native_wrapper.clone();
@@ -166,8 +175,11 @@ impl NativeExecutionRuntime {
                 .transpose()
                 .or_else(|err| df_execution_err!("{err}"))?
             {
+                let permit = in_flight_limiter_cloned
+                    .acquire(batch.get_batch_mem_size())
+                    .await?;
                 batch_sender
-                    .send(Ok(Some(batch)))
+                    .send(Ok(Some((batch, permit))))
                     .or_else(|err| df_execution_err!("send batch error: {err}"))?;
             }
             batch_sender
@@ -214,6 +226,7 @@ impl NativeExecutionRuntime {
             exec_ctx: exec_ctx.clone(),
             native_wrapper: native_wrapper.clone(),
             plan: execution_plan.clone(),
+            in_flight_limiter,
             tokio_runtime,
             batch_receiver,
             join_handle,
@@ -228,12 +241,13 @@ impl NativeExecutionRuntime {
                 .recv()
                 .or_else(|err| df_execution_err!("receive batch error: {err}"))??
             {
-                Some(batch) => {
+                Some((batch, permit)) => {
                     let struct_array = StructArray::from(batch);
                     let ffi_array = FFI_ArrowArray::new(&struct_array.to_data());
                     jni_call!(BlazeCallNativeWrapper(self.native_wrapper.as_obj())
                         .importBatch(&ffi_array as *const FFI_ArrowArray as i64) -> ()
                     )?;
+                    drop(permit);
                     Ok(true)
                 }
                 None => Ok(false),
@@ -272,6 +286,13 @@ impl NativeExecutionRuntime {
             BlazeCallNativeWrapper(self.native_wrapper.as_obj()).getMetrics() -> JObject
         )?;
         update_spark_metric_node(metrics.as_obj(), self.plan.clone())?;
+        if !metrics.as_obj().is_null() {
+            let peak_mem_size = self.in_flight_limiter.peak_mem_size() as i64;
+            update_metrics(
+                metrics.as_obj(),
+                &[("export_in_flight_peak_mem_size", peak_mem_size)],
+            )?;
+        }
         Ok(())
     }
 }
@@ -26,7 +26,6 @@ pub struct LoserTree<T> {
     values: UncheckedIndex<Vec<T>>,
 }
 
-#[allow(clippy::len_without_is_empty)]
 impl<T: ComparableForLoserTree> LoserTree<T> {
     pub fn new(values: Vec<T>) -> Self {
         let mut tree = unsafe {
@@ -54,17 +53,42 @@ impl<T: ComparableForLoserTree> LoserTree<T> {
         self.values.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// returns the smallest value, panics if the tree is empty
     pub fn peek(&self) -> &T {
-        &self.values[0]
+        assert!(!self.is_empty(), "peeking an empty loser tree");
+        &self.values[self.losers[0]]
     }
 
+    /// returns the smallest value for updating, panics if the tree is empty
     pub fn peek_mut(&mut self) -> LoserTreePeekMut<T> {
+        assert!(!self.is_empty(), "peeking an empty loser tree");
         LoserTreePeekMut {
             tree: self,
             dirty: false,
         }
     }
 
+    /// consumes the tree and repeatedly yields items from the smallest value.
+    /// `advance` moves the smallest value forward and returns the yielded
+    /// item, or None if it is exhausted. exhausted values must be ordered
+    /// after all others, so the iteration ends when the smallest one is
+    /// exhausted.
+    pub fn into_sorted_iter<O>(
+        mut self,
+        mut advance: impl FnMut(&mut T) -> Option<O>,
+    ) -> impl Iterator<Item = O> {
+        std::iter::from_fn(move || {
+            if self.is_empty() {
+                return None;
+            }
+            advance(&mut *self.peek_mut())
+        })
+    }
+
     fn init_tree(&mut self) {
         self.losers.resize(self.values.len(), usize::MAX);
         for i in 0..self.values.len() {
@@ -97,6 +121,20 @@ impl<T: ComparableForLoserTree> LoserTree<T> {
         }
         self.losers[0] = winner;
     }
+
+    /// checks that the winner is not greater than any loser on its path, which
+    /// means it is still the smallest value without adjusting.
+    fn is_winner_valid(&self) -> bool {
+        let winner = self.losers[0];
+        let mut cmp_node = (self.values.len() + winner) / 2;
+        while cmp_node != 0 {
+            if self.values[self.losers[cmp_node]].lt(&self.values[winner]) {
+                return false;
+            }
+            cmp_node /= 2;
+        }
+        true
+    }
 }
 
 /// A PeekMut structure to the loser tree, used to get smallest value and auto
@@ -113,6 +151,17 @@ impl<T: ComparableForLoserTree> LoserTreePeekMut<'_, T> {
             self.dirty = false;
         }
     }
+
+    /// skips adjusting the tree after the smallest value is updated. the
+    /// caller must ensure the updated value is not greater than before, e.g.
+    /// a cursor advanced to another row with the same key.
+    pub fn keep_winner(&mut self) {
+        debug_assert!(
+            self.tree.is_winner_valid(),
+            "keep_winner() with a greater value"
+        );
+        self.dirty = false;
+    }
 }
 
 impl<T: ComparableForLoserTree> Deref for LoserTreePeekMut<'_, T> {
@@ -140,11 +189,203 @@ impl<T: ComparableForLoserTree> Drop for LoserTreePeekMut<'_, T> {
 
 #[cfg(test)]
 mod test {
+    use std::{cmp::Reverse, collections::BinaryHeap};
+
     use itertools::Itertools;
-    use rand::Rng;
+    use rand::{Rng, SeedableRng};
 
     use crate::algorithm::loser_tree::{ComparableForLoserTree, LoserTree};
 
+    struct Cursor {
+        row_idx: usize,
+        values: Vec<u64>,
+    }
+
+    impl ComparableForLoserTree for Cursor {
+        fn lt(&self, other: &Self) -> bool {
+            match (
+                self.values.get(self.row_idx),
+                other.values.get(other.row_idx),
+            ) {
+                (Some(v1), Some(v2)) => v1 < v2,
+                (None, _) => false,
+                (_, None) => true,
+            }
+        }
+    }
+
+    fn new_tree(nodes: Vec<Vec<u64>>) -> LoserTree<Cursor> {
+        LoserTree::new(
+            nodes
+                .into_iter()
+                .map(|values| Cursor { row_idx: 0, values })
+                .collect(),
+        )
+    }
+
+    fn advance(cursor: &mut Cursor) -> Option<u64> {
+        let v = cursor.values.get(cursor.row_idx).cloned()?;
+        cursor.row_idx += 1;
+        Some(v)
+    }
+
+    fn random_sorted_nodes(
+        r: &mut impl Rng,
+        num_nodes: usize,
+        max_node_len: usize,
+        max_value: u64,
+    ) -> Vec<Vec<u64>> {
+        (0..num_nodes)
+            .map(|_| {
+                let node_len = r.gen_range(0..=max_node_len);
+                (0..node_len)
+                    .map(|_| r.gen_range(0..max_value))
+                    .sorted_unstable()
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// merges all nodes, keeping the winner if the key is unchanged
+    fn merge_keeping_winner(loser_tree: &mut LoserTree<Cursor>) -> Vec<u64> {
+        let mut merged = vec![];
+        loop {
+            let mut min = loser_tree.peek_mut();
+            let Some(v) = advance(&mut min) else {
+                break;
+            };
+            merged.push(v);
+            if min.values.get(min.row_idx) == Some(&v) {
+                min.keep_winner();
+            }
+        }
+        merged
+    }
+
+    #[test]
+    fn test_empty_tree() {
+        let loser_tree = new_tree(vec![]);
+        assert_eq!(loser_tree.len(), 0);
+        assert!(loser_tree.is_empty());
+        assert_eq!(loser_tree.into_sorted_iter(advance).count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "peeking an empty loser tree")]
+    fn test_peek_empty_tree() {
+        new_tree(vec![]).peek();
+    }
+
+    #[test]
+    fn test_single_value_tree() {
+        let mut loser_tree = new_tree(vec![vec![1, 2, 3]]);
+        assert_eq!(loser_tree.len(), 1);
+        assert!(!loser_tree.is_empty());
+        assert_eq!(loser_tree.peek().values[loser_tree.peek().row_idx], 1);
+
+        advance(&mut *loser_tree.peek_mut());
+        assert_eq!(loser_tree.peek().values[loser_tree.peek().row_idx], 2);
+        assert_eq!(
+            loser_tree.into_sorted_iter(advance).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+
+        // single exhausted value
+        let loser_tree = new_tree(vec![vec![]]);
+        assert_eq!(loser_tree.into_sorted_iter(advance).count(), 0);
+    }
+
+    #[test]
+    fn test_peek_returns_min() {
+        let mut loser_tree = new_tree(vec![vec![5, 6], vec![1, 9], vec![3], vec![]]);
+        let mut actual = vec![];
+        while let Some(&v) = loser_tree.peek().values.get(loser_tree.peek().row_idx) {
+            actual.push(v);
+            advance(&mut *loser_tree.peek_mut());
+        }
+        assert_eq!(actual, vec![1, 3, 5, 6, 9]);
+    }
+
+    #[test]
+    fn test_keep_winner() {
+        let mut loser_tree = new_tree(vec![vec![1, 1, 1, 4], vec![2, 2], vec![3, 5], vec![]]);
+        assert_eq!(
+            merge_keeping_winner(&mut loser_tree),
+            vec![1, 1, 1, 2, 2, 3, 4, 5]
+        );
+
+        // keep_winner() without actual updating
+        let mut loser_tree = new_tree(vec![vec![3], vec![1], vec![2]]);
+        loser_tree.peek_mut().keep_winner();
+        assert_eq!(loser_tree.peek().values, vec![1]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "keep_winner() with a greater value")]
+    fn test_keep_winner_with_changed_key() {
+        let mut loser_tree = new_tree(vec![vec![1, 9], vec![2]]);
+        let mut min = loser_tree.peek_mut();
+        min.row_idx += 1;
+        min.keep_winner();
+    }
+
+    #[test]
+    fn fuzztest_keep_winner() {
+        let mut r = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..10 {
+            // small value range so that both equal and changed keys are common
+            let num_nodes = r.gen_range(1..=99);
+            let nodes = random_sorted_nodes(&mut r, num_nodes, 999, 100);
+            let expected = nodes
+                .iter()
+                .flatten()
+                .cloned()
+                .sorted_unstable()
+                .collect_vec();
+
+            let mut loser_tree = new_tree(nodes);
+            assert_eq!(merge_keeping_winner(&mut loser_tree), expected);
+            for cursor in loser_tree.values() {
+                assert_eq!(cursor.row_idx, cursor.values.len());
+            }
+        }
+    }
+
+    #[test]
+    fn fuzztest_random_updates() {
+        impl ComparableForLoserTree for u64 {
+            fn lt(&self, other: &Self) -> bool {
+                self < other
+            }
+        }
+
+        // the winner can be updated to any key, and the tree should always
+        // yield the global minimum like a reference heap
+        let mut r = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let num_values = r.gen_range(1..=99);
+            let max_key = r.gen_range(1..=1000);
+            let values = (0..num_values)
+                .map(|_| r.gen_range(0..max_key))
+                .collect::<Vec<u64>>();
+            let mut expected: BinaryHeap<_> = values.iter().map(|&v| Reverse(v)).collect();
+            let mut loser_tree = LoserTree::new(values);
+
+            for _ in 0..1000 {
+                assert_eq!(*loser_tree.peek(), expected.peek().unwrap().0);
+                let new_key = r.gen_range(0..max_key);
+                *loser_tree.peek_mut() = new_key;
+                expected.pop();
+                expected.push(Reverse(new_key));
+            }
+            assert_eq!(
+                loser_tree.values().iter().cloned().sorted().collect_vec(),
+                expected.into_iter().map(|k| k.0).sorted().collect_vec(),
+            );
+        }
+    }
+
     #[test]
     fn fuzztest() {
         for _ in 0..10 {
@@ -168,22 +409,6 @@ mod test {
                 .collect_vec();
 
             // actual
-            struct Cursor {
-                row_idx: usize,
-                values: Vec<u64>,
-            }
-            impl ComparableForLoserTree for Cursor {
-                fn lt(&self, other: &Self) -> bool {
-                    match (
-                        self.values.get(self.row_idx),
-                        other.values.get(other.row_idx),
-                    ) {
-                        (Some(v1), Some(v2)) => v1 < v2,
-                        (None, _) => false,
-                        (_, None) => true,
-                    }
-                }
-            }
             let mut loser_tree = LoserTree::new(
                 nodes
                     .into_iter()
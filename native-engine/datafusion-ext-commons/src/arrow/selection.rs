@@ -12,21 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::Arc;
+use std::{ops::Range, sync::Arc};
 
 use arrow::{
     array::{
         downcast_primitive, Array, ArrayRef, ArrowPrimitiveType, BinaryArray, BooleanBufferBuilder,
-        BufferBuilder, GenericByteArray, PrimitiveArray, StringArray,
+        BufferBuilder, GenericByteArray, PrimitiveArray, StringArray, UInt32Array,
     },
     buffer::{MutableBuffer, NullBuffer, OffsetBuffer},
+    compute::concat_batches,
     datatypes::{ArrowNativeType, ByteArrayType},
     record_batch::{RecordBatch, RecordBatchOptions},
 };
-use arrow_schema::DataType;
-use datafusion::common::Result;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use datafusion::{
+    common::Result,
+    physical_plan::metrics::{Count, Time},
+};
 
-use crate::{downcast_any, prefetch_read_data};
+use crate::{df_execution_err, downcast_any, prefetch_read_data};
 
 pub fn take_batch<T: ArrowPrimitiveType>(
     batch: RecordBatch,
@@ -59,15 +63,121 @@ fn take_cols_internal<T: ArrowPrimitiveType>(
 
 pub type ArrayInterleaver = Box<dyn Fn(&[(usize, usize)]) -> Result<ArrayRef> + Send>;
 pub type BatchInterleaver = Box<dyn Fn(&[(usize, usize)]) -> Result<RecordBatch> + Send>;
+pub type BatchRangesInterleaver =
+    Box<dyn Fn(&[(usize, Range<usize>)]) -> Result<RecordBatch> + Send>;
+
+/// checks that all batches can be interleaved column by column and returns
+/// the batches with a common schema. batches must have the same number of
+/// columns and the same column types, while a column is nullable in the
+/// common schema if it is nullable in any batch.
+fn unify_batch_schemas(batches: &[RecordBatch]) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let Some(first_batch) = batches.first() else {
+        return df_execution_err!("cannot interleave: no input batches");
+    };
+    let first_schema = first_batch.schema();
+    let mut nullables = first_schema
+        .fields()
+        .iter()
+        .map(|field| field.is_nullable())
+        .collect::<Vec<_>>();
+
+    for (batch_idx, batch) in batches.iter().enumerate().skip(1) {
+        let schema = batch.schema_ref();
+        if schema.fields().len() != first_schema.fields().len() {
+            return df_execution_err!(
+                "cannot interleave: batch {batch_idx} has {} columns, expected {}",
+                schema.fields().len(),
+                first_schema.fields().len(),
+            );
+        }
+        for (col_idx, (field, first_field)) in schema
+            .fields()
+            .iter()
+            .zip(first_schema.fields())
+            .enumerate()
+        {
+            if field.data_type() != first_field.data_type() {
+                return df_execution_err!(
+                    "cannot interleave: column {col_idx} ({}) of batch {batch_idx} has type {}, \
+                     expected {}",
+                    first_field.name(),
+                    field.data_type(),
+                    first_field.data_type(),
+                );
+            }
+            nullables[col_idx] |= field.is_nullable();
+        }
+    }
+
+    let schema = if nullables
+        .iter()
+        .zip(first_schema.fields())
+        .all(|(&nullable, field)| nullable == field.is_nullable())
+    {
+        first_schema
+    } else {
+        let fields = first_schema
+            .fields()
+            .iter()
+            .zip(nullables)
+            .map(|(field, nullable)| Field::clone(field).with_nullable(nullable))
+            .collect::<Vec<_>>();
+        Arc::new(Schema::new_with_metadata(
+            fields,
+            first_schema.metadata().clone(),
+        ))
+    };
+    let batches = batches
+        .iter()
+        .map(|batch| {
+            if batch.schema_ref() == &schema {
+                return Ok(batch.clone());
+            }
+            Ok(RecordBatch::try_new_with_options(
+                schema.clone(),
+                batch.columns().to_vec(),
+                &RecordBatchOptions::new().with_row_count(Some(batch.num_rows())),
+            )?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((schema, batches))
+}
+
+/// metrics recorded by an interleaver on every call
+#[derive(Clone, Debug, Default)]
+pub struct InterleaveMetrics {
+    pub output_rows: Count,
+    pub elapsed_compute: Time,
+}
+
+/// creates a batch interleaver recording output rows and elapsed time into
+/// the given metrics. without metrics it is the same as
+/// [`create_batch_interleaver`].
+pub fn create_batch_interleaver_with_metrics(
+    batches: &[RecordBatch],
+    with_prefetching: bool,
+    metrics: Option<InterleaveMetrics>,
+) -> Result<BatchInterleaver> {
+    let batch_interleaver = create_batch_interleaver(batches, with_prefetching)?;
+    let Some(metrics) = metrics else {
+        return Ok(batch_interleaver);
+    };
+    Ok(Box::new(move |indices| {
+        let _timer = metrics.elapsed_compute.timer();
+        let batch = batch_interleaver(indices)?;
+        metrics.output_rows.add(indices.len());
+        Ok(batch)
+    }))
+}
 
 #[inline]
 pub fn create_batch_interleaver(
     batches: &[RecordBatch],
     with_prefetching: bool,
 ) -> Result<BatchInterleaver> {
-    let batch_schema = batches[0].schema();
+    let (batch_schema, batches) = unify_batch_schemas(batches)?;
     let mut col_arrays = vec![vec![]; batches[0].num_columns()];
-    for batch in batches {
+    for batch in &batches {
         for (col_idx, col) in batch.columns().iter().enumerate() {
             col_arrays[col_idx].push(col.clone());
         }
@@ -77,6 +187,21 @@ pub fn create_batch_interleaver(
         .map(|arrays| create_array_interleaver(arrays, with_prefetching))
         .collect::<Result<Vec<_>>>()?;
     Ok(Box::new(move |indices| {
+        // fast path: all indices reference the same batch
+        if let Some(batch) = single_source_batch(&batches, indices) {
+            let start_row_idx = indices[0].1;
+            if indices
+                .iter()
+                .enumerate()
+                .all(|(i, &(_, row_idx))| row_idx == start_row_idx + i)
+            {
+                return Ok(batch.slice(start_row_idx, indices.len()));
+            }
+            let row_indices =
+                UInt32Array::from_iter_values(indices.iter().map(|&(_, row_idx)| row_idx as u32));
+            return take_batch(batch.clone(), row_indices);
+        }
+
         let cols = col_interleavers
             .iter()
             .map(|col_interleaver| col_interleaver(indices))
@@ -90,6 +215,90 @@ pub fn create_batch_interleaver(
     }))
 }
 
+/// how a ranges interleaver builds its output from runs of rows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangesInterleaveStrategy {
+    /// concat if all runs are whole batches or runs are long enough on
+    /// average, otherwise interleave
+    Auto { min_avg_run_len: usize },
+    /// always slice runs and concat them
+    Concat,
+    /// always interleave element-wise
+    Interleave,
+}
+
+impl Default for RangesInterleaveStrategy {
+    fn default() -> Self {
+        Self::Auto {
+            min_avg_run_len: 16,
+        }
+    }
+}
+
+/// creates an interleaver taking rows by runs of (batch_idx, row_range), so
+/// that callers need not materialize one index per row. long runs are sliced
+/// and concatenated, short runs fall back to element-wise interleaving.
+pub fn create_batch_ranges_interleaver(
+    batches: &[RecordBatch],
+    with_prefetching: bool,
+) -> Result<BatchRangesInterleaver> {
+    create_batch_ranges_interleaver_with_strategy(
+        batches,
+        with_prefetching,
+        RangesInterleaveStrategy::default(),
+    )
+}
+
+pub fn create_batch_ranges_interleaver_with_strategy(
+    batches: &[RecordBatch],
+    with_prefetching: bool,
+    strategy: RangesInterleaveStrategy,
+) -> Result<BatchRangesInterleaver> {
+    let (batch_schema, batches) = unify_batch_schemas(batches)?;
+    let batch_interleaver = create_batch_interleaver(&batches, with_prefetching)?;
+    Ok(Box::new(move |ranges| {
+        if let [(batch_idx, range)] = ranges {
+            return Ok(batches[*batch_idx].slice(range.start, range.len()));
+        }
+
+        let use_concat = match strategy {
+            RangesInterleaveStrategy::Concat => true,
+            RangesInterleaveStrategy::Interleave => false,
+            RangesInterleaveStrategy::Auto { min_avg_run_len } => {
+                let num_rows = ranges.iter().map(|(_, range)| range.len()).sum::<usize>();
+                let all_whole_batches = ranges.iter().all(|(batch_idx, range)| {
+                    range.start == 0 && range.end == batches[*batch_idx].num_rows()
+                });
+                all_whole_batches || num_rows >= ranges.len() * min_avg_run_len
+            }
+        };
+        if use_concat {
+            let slices = ranges
+                .iter()
+                .map(|(batch_idx, range)| batches[*batch_idx].slice(range.start, range.len()))
+                .collect::<Vec<_>>();
+            return Ok(concat_batches(&batch_schema, &slices)?);
+        }
+
+        let indices = ranges
+            .iter()
+            .flat_map(|(batch_idx, range)| range.clone().map(|row_idx| (*batch_idx, row_idx)))
+            .collect::<Vec<_>>();
+        batch_interleaver(&indices)
+    }))
+}
+
+fn single_source_batch<'a>(
+    batches: &'a [RecordBatch],
+    indices: &[(usize, usize)],
+) -> Option<&'a RecordBatch> {
+    let &(batch_idx, _) = indices.first()?;
+    if indices.iter().all(|&(idx, _)| idx == batch_idx) {
+        return Some(&batches[batch_idx]);
+    }
+    None
+}
+
 #[inline]
 pub fn create_array_interleaver(
     values: &[ArrayRef],
@@ -272,3 +481,292 @@ pub fn create_array_interleaver(
         Ok(arrow::compute::interleave(&value_refs, indices)?)
     }))
 }
+
+#[cfg(test)]
+mod test {
+    use std::{ops::Range, sync::Arc};
+
+    use arrow::{
+        array::{Array, ArrayRef, Int32Array, Int64Array, StringArray},
+        compute::interleave_record_batch,
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    };
+    use datafusion::common::Result;
+    use rand::{Rng, SeedableRng};
+
+    use crate::arrow::selection::{
+        create_batch_interleaver, create_batch_interleaver_with_metrics,
+        create_batch_ranges_interleaver, create_batch_ranges_interleaver_with_strategy,
+        InterleaveMetrics, RangesInterleaveStrategy,
+    };
+
+    fn build_batch(rng: &mut impl Rng, num_rows: usize) -> RecordBatch {
+        let ints: ArrayRef = Arc::new(Int32Array::from_iter(
+            (0..num_rows).map(|_| Some(rng.gen_range(0..1000)).filter(|v| v % 7 != 0)),
+        ));
+        let strs: ArrayRef = Arc::new(StringArray::from_iter(
+            (0..num_rows).map(|_| Some(format!("s{}", rng.gen_range(0..1000)))),
+        ));
+        RecordBatch::try_from_iter(vec![("i", ints), ("s", strs)]).unwrap()
+    }
+
+    fn random_ranges(
+        rng: &mut impl Rng,
+        batches: &[RecordBatch],
+        num_ranges: usize,
+        max_run_len: usize,
+    ) -> Vec<(usize, Range<usize>)> {
+        (0..num_ranges)
+            .map(|_| {
+                let batch_idx = rng.gen_range(0..batches.len());
+                let num_rows = batches[batch_idx].num_rows();
+                let start = rng.gen_range(0..num_rows);
+                let len = rng.gen_range(0..=max_run_len.min(num_rows - start));
+                (batch_idx, start..start + len)
+            })
+            .collect()
+    }
+
+    fn expand_ranges(ranges: &[(usize, Range<usize>)]) -> Vec<(usize, usize)> {
+        ranges
+            .iter()
+            .flat_map(|(batch_idx, range)| range.clone().map(|row_idx| (*batch_idx, row_idx)))
+            .collect()
+    }
+
+    #[test]
+    fn test_single_batch_fast_path() -> Result<()> {
+        // use a fixed seed to make the test predictable.
+        let mut r = rand::rngs::StdRng::seed_from_u64(37);
+
+        for _ in 0..100 {
+            let batches = (0..r.gen_range(1..5))
+                .map(|_| {
+                    let num_rows = r.gen_range(1..100);
+                    build_batch(&mut r, num_rows)
+                })
+                .collect::<Vec<_>>();
+            let interleaver = create_batch_interleaver(&batches, false)?;
+            let batch_refs = batches.iter().collect::<Vec<_>>();
+
+            let batch_idx = r.gen_range(0..batches.len());
+            let num_rows = batches[batch_idx].num_rows();
+            let start = r.gen_range(0..num_rows);
+            let len = r.gen_range(0..=num_rows - start);
+            let cases = [
+                // random indices from a single batch
+                (0..r.gen_range(1..200))
+                    .map(|_| (batch_idx, r.gen_range(0..num_rows)))
+                    .collect::<Vec<_>>(),
+                // identity permutation
+                (0..num_rows).map(|i| (batch_idx, i)).collect(),
+                // contiguous range
+                (start..start + len).map(|i| (batch_idx, i)).collect(),
+                // indices from multiple batches
+                (0..r.gen_range(1..200))
+                    .map(|_| {
+                        let batch_idx = r.gen_range(0..batches.len());
+                        (batch_idx, r.gen_range(0..batches[batch_idx].num_rows()))
+                    })
+                    .collect(),
+            ];
+            for indices in cases {
+                let expected = interleave_record_batch(&batch_refs, &indices)?;
+                assert_eq!(interleaver(&indices)?, expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleave_metrics() -> Result<()> {
+        // use a fixed seed to make the test predictable.
+        let mut r = rand::rngs::StdRng::seed_from_u64(37);
+        let batches = (0..3).map(|_| build_batch(&mut r, 100)).collect::<Vec<_>>();
+        let metrics = InterleaveMetrics::default();
+        let interleaver =
+            create_batch_interleaver_with_metrics(&batches, false, Some(metrics.clone()))?;
+
+        let mut expected_rows = 0;
+        for _ in 0..10 {
+            let indices = (0..r.gen_range(1..200))
+                .map(|_| (r.gen_range(0..batches.len()), r.gen_range(0..100)))
+                .collect::<Vec<_>>();
+            assert_eq!(interleaver(&indices)?.num_rows(), indices.len());
+            expected_rows += indices.len();
+        }
+        assert_eq!(metrics.output_rows.value(), expected_rows);
+        assert!(metrics.elapsed_compute.value() > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleave_ranges() -> Result<()> {
+        // use a fixed seed to make the test predictable.
+        let mut r = rand::rngs::StdRng::seed_from_u64(37);
+
+        for _ in 0..100 {
+            let batches = (0..r.gen_range(1..5))
+                .map(|_| {
+                    let num_rows = r.gen_range(1..200);
+                    build_batch(&mut r, num_rows)
+                })
+                .collect::<Vec<_>>();
+            let interleaver = create_batch_ranges_interleaver(&batches, false)?;
+            let batch_refs = batches.iter().collect::<Vec<_>>();
+
+            for max_run_len in [1, 4, 64, 200] {
+                let num_ranges = r.gen_range(1..20);
+                let ranges = random_ranges(&mut r, &batches, num_ranges, max_run_len);
+                let expected = interleave_record_batch(&batch_refs, &expand_ranges(&ranges))?;
+                assert_eq!(interleaver(&ranges)?, expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleaver_reused_across_partitions() -> Result<()> {
+        // use a fixed seed to make the test predictable.
+        let mut r = rand::rngs::StdRng::seed_from_u64(37);
+
+        let batches = (0..4)
+            .map(|_| {
+                let num_rows = r.gen_range(1..200);
+                build_batch(&mut r, num_rows)
+            })
+            .collect::<Vec<_>>();
+        let batch_refs = batches.iter().collect::<Vec<_>>();
+
+        // split all rows into partitions like shuffle repartitioning does, and
+        // interleave each partition slice with one shared interleaver
+        let num_partitions = 8;
+        let mut partition_indices = vec![vec![]; num_partitions];
+        for (batch_idx, batch) in batches.iter().enumerate() {
+            for row_idx in 0..batch.num_rows() {
+                partition_indices[r.gen_range(0..num_partitions)].push((batch_idx, row_idx));
+            }
+        }
+        let interleaver = create_batch_interleaver(&batches, true)?;
+        for indices in partition_indices
+            .iter()
+            .filter(|indices| !indices.is_empty())
+        {
+            let expected = interleave_record_batch(&batch_refs, indices)?;
+            assert_eq!(interleaver(indices)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleave_incompatible_schemas() -> Result<()> {
+        let ints: ArrayRef = Arc::new(Int32Array::from(vec![1, 2]));
+        let longs: ArrayRef = Arc::new(Int64Array::from(vec![1, 2]));
+        let strs: ArrayRef = Arc::new(StringArray::from(vec!["a", "b"]));
+        let batch = RecordBatch::try_from_iter(vec![("i", ints.clone()), ("s", strs.clone())])?;
+        let fewer_columns_batch = RecordBatch::try_from_iter(vec![("i", ints.clone())])?;
+        let mismatched_type_batch = RecordBatch::try_from_iter(vec![("i", longs), ("s", strs)])?;
+
+        for (batches, expected_err) in [
+            (
+                vec![batch.clone(), fewer_columns_batch],
+                "cannot interleave: batch 1 has 1 columns, expected 2",
+            ),
+            (
+                vec![batch.clone(), mismatched_type_batch],
+                "cannot interleave: column 0 (i) of batch 1 has type Int64, expected Int32",
+            ),
+            (vec![], "cannot interleave: no input batches"),
+        ] {
+            let err = match create_batch_interleaver(&batches, false) {
+                Ok(_) => panic!("expect an error for incompatible batches"),
+                Err(err) => err.to_string(),
+            };
+            assert!(err.contains(expected_err), "unexpected error: {err}");
+
+            let err = match create_batch_ranges_interleaver(&batches, false) {
+                Ok(_) => panic!("expect an error for incompatible batches"),
+                Err(err) => err.to_string(),
+            };
+            assert!(err.contains(expected_err), "unexpected error: {err}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_interleave_with_nullability_variation() -> Result<()> {
+        let non_nullable_batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("i", DataType::Int32, false)])),
+            vec![Arc::new(Int32Array::from(vec![1, 2]))],
+        )?;
+        let nullable_batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("i", DataType::Int32, true)])),
+            vec![Arc::new(Int32Array::from(vec![None, Some(3)]))],
+        )?;
+        let batches = vec![non_nullable_batch, nullable_batch];
+        let expected_schema = batches[1].schema();
+
+        let interleaver = create_batch_interleaver(&batches, false)?;
+        let interleaved = interleaver(&[(1, 0), (0, 1), (1, 1)])?;
+        assert_eq!(interleaved.schema(), expected_schema);
+        assert_eq!(
+            interleaved.column(0).as_ref(),
+            &Int32Array::from(vec![None, Some(2), Some(3)]) as &dyn Array,
+        );
+
+        // fast path from a single batch also uses the common schema
+        let interleaved = interleaver(&[(0, 0), (0, 1)])?;
+        assert_eq!(interleaved.schema(), expected_schema);
+
+        let ranges_interleaver = create_batch_ranges_interleaver(&batches, false)?;
+        let interleaved = ranges_interleaver(&[(0, 0..2), (1, 0..2)])?;
+        assert_eq!(interleaved.schema(), expected_schema);
+        assert_eq!(interleaved.column(0).null_count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ranges_interleave_strategies() -> Result<()> {
+        // use a fixed seed to make the test predictable.
+        let mut r = rand::rngs::StdRng::seed_from_u64(37);
+        let strategies = [
+            RangesInterleaveStrategy::default(),
+            RangesInterleaveStrategy::Concat,
+            RangesInterleaveStrategy::Interleave,
+        ];
+
+        for _ in 0..100 {
+            let batches = (0..r.gen_range(1..5))
+                .map(|_| {
+                    let num_rows = r.gen_range(1..200);
+                    build_batch(&mut r, num_rows)
+                })
+                .collect::<Vec<_>>();
+            let interleavers = strategies
+                .iter()
+                .map(|&strategy| {
+                    create_batch_ranges_interleaver_with_strategy(&batches, false, strategy)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let batch_refs = batches.iter().collect::<Vec<_>>();
+
+            // whole batches and random subsets
+            let whole_batch_ranges = (0..r.gen_range(1..8))
+                .map(|_| {
+                    let batch_idx = r.gen_range(0..batches.len());
+                    (batch_idx, 0..batches[batch_idx].num_rows())
+                })
+                .collect::<Vec<_>>();
+            let subset_ranges = random_ranges(&mut r, &batches, r.gen_range(1..20), 16);
+
+            for ranges in [whole_batch_ranges, subset_ranges] {
+                let expected = interleave_record_batch(&batch_refs, &expand_ranges(&ranges))?;
+                for interleaver in &interleavers {
+                    assert_eq!(interleaver(&ranges)?, expected);
+                }
+            }
+        }
+        Ok(())
+    }
+}
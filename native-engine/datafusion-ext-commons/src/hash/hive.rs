@@ -0,0 +1,88 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// hive hash of strings and binaries, same as HiveHasher.hashUnsafeBytes
+#[inline]
+pub fn hive_hash_bytes<T: AsRef<[u8]>>(data: T) -> i32 {
+    data.as_ref().iter().fold(0i32, |h, &b| {
+        h.wrapping_mul(31).wrapping_add(b as i8 as i32)
+    })
+}
+
+#[inline]
+pub fn hive_hash_long(value: i64) -> i32 {
+    (((value as u64) >> 32) as i64 ^ value) as i32
+}
+
+/// hive hash of a decimal, same as java BigDecimal.hashCode() of the value
+/// normalized by hive (trailing zeros stripped, no negative scale)
+pub fn hive_hash_decimal(unscaled: i128, scale: i8) -> i32 {
+    if unscaled == 0 {
+        return 0;
+    }
+    let (mut unscaled, mut scale) = (unscaled, scale as i32);
+    while scale > 0 && unscaled % 10 == 0 {
+        unscaled /= 10;
+        scale -= 1;
+    }
+
+    // BigInteger.hashCode(): hash of big-endian 32-bit words of the magnitude
+    let magnitude = unscaled.unsigned_abs();
+    let mag_hash = (0..4)
+        .rev()
+        .map(|i| (magnitude >> (i * 32)) as u32)
+        .skip_while(|&word| word == 0)
+        .fold(0i32, |h, word| h.wrapping_mul(31).wrapping_add(word as i32));
+    let int_hash = if unscaled < 0 {
+        mag_hash.wrapping_neg()
+    } else {
+        mag_hash
+    };
+    int_hash.wrapping_mul(31).wrapping_add(scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // expected values are taken from spark's HashExpressionsSuite
+    #[test]
+    fn test_hive_hash() {
+        assert_eq!(hive_hash_bytes("apache spark"), 1142704523);
+        assert_eq!(hive_hash_bytes("abc"), 96354);
+        assert_eq!(hive_hash_bytes(""), 0);
+        assert_eq!(hive_hash_long(1), 1);
+        assert_eq!(hive_hash_long(-1), 0);
+        assert_eq!(hive_hash_long(i64::MAX), i32::MIN);
+        assert_eq!(hive_hash_long(i64::MIN), i32::MIN);
+    }
+
+    #[test]
+    fn test_hive_hash_decimal() {
+        assert_eq!(hive_hash_decimal(18, 0), 558);
+        assert_eq!(hive_hash_decimal(-18, 0), -558);
+        assert_eq!(hive_hash_decimal(-18 * 10i128.pow(12), 12), -558);
+        assert_eq!(hive_hash_decimal(18446744073709001000, 0), -17070057);
+        assert_eq!(hive_hash_decimal(-18446744073709001000, 0), 17070057);
+        assert_eq!(
+            hive_hash_decimal(18446744073709001000 * 10i128.pow(12), 12),
+            -17070057
+        );
+        assert_eq!(hive_hash_decimal(92233720368547758070000, 4), 2147482656);
+        assert_eq!(hive_hash_decimal(-922337203685477580700000, 5), -2147482656);
+        assert_eq!(hive_hash_decimal(0, 34), 0);
+        assert_eq!(hive_hash_decimal(12345612, 2), 382713974);
+        assert_eq!(hive_hash_decimal(1234561234567890, 10), 1871500252);
+    }
+}
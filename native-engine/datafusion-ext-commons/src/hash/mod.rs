@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod hive;
 pub mod mur;
 pub mod xxhash;
 
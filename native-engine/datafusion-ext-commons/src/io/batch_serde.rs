@@ -20,17 +20,54 @@ use std::{
 
 use arrow::{
     array::*,
-    buffer::{Buffer, MutableBuffer},
+    buffer::{Buffer, MutableBuffer, NullBuffer},
     datatypes::*,
 };
 use datafusion::common::Result;
+use itertools::Itertools;
 use unchecked_index::unchecked_index;
 
 use crate::{
-    df_unimplemented_err,
-    io::{read_bytes_slice, read_len, write_len},
+    df_execution_err, df_unimplemented_err,
+    io::{read_bytes_slice, read_len, read_u8, write_len, write_u8},
 };
 
+/// Encodings applied to columns when writing batches with
+/// [`write_batch_with_encodings`]. the chosen encoding is recorded before each
+/// column so that the reader is able to reverse it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnEncoding {
+    Plain = 0,
+    DeltaRle = 1,
+}
+
+impl ColumnEncoding {
+    fn try_from_u8(v: u8) -> Result<Self> {
+        match v {
+            0 => Ok(ColumnEncoding::Plain),
+            1 => Ok(ColumnEncoding::DeltaRle),
+            other => df_execution_err!("unknown column encoding: {other}"),
+        }
+    }
+
+    /// chooses encoding of a column with a simple heuristic: integer columns
+    /// without nulls whose deltas form long runs (like sorted ids) are written
+    /// with delta-rle, all other columns are written in plain format.
+    pub fn choose(array: &dyn Array) -> Self {
+        const MIN_AVG_RUN_LEN: usize = 4;
+
+        let num_runs = match array.data_type() {
+            DataType::Int32 => count_delta_runs(as_primitive_array::<Int32Type>(array)),
+            DataType::Int64 => count_delta_runs(as_primitive_array::<Int64Type>(array)),
+            _ => None,
+        };
+        match num_runs {
+            Some(num_runs) if num_runs * MIN_AVG_RUN_LEN <= array.len() => Self::DeltaRle,
+            _ => Self::Plain,
+        }
+    }
+}
+
 pub fn write_batch(num_rows: usize, cols: &[ArrayRef], mut output: impl Write) -> Result<()> {
     // write number of columns and rows
     write_len(num_rows, &mut output)?;
@@ -55,6 +92,102 @@ pub fn read_batch(mut input: impl Read, schema: &SchemaRef) -> Result<(usize, Ve
     Ok((num_rows, cols))
 }
 
+pub fn write_batch_with_encodings(
+    num_rows: usize,
+    cols: &[ArrayRef],
+    mut output: impl Write,
+) -> Result<()> {
+    // write number of columns and rows
+    write_len(num_rows, &mut output)?;
+
+    // write columns, each column is prefixed with its encoding
+    for col in cols {
+        let encoding = ColumnEncoding::choose(col);
+        write_u8(encoding as u8, &mut output)?;
+        match encoding {
+            ColumnEncoding::Plain => write_array(col, &mut output)?,
+            ColumnEncoding::DeltaRle => write_delta_rle_array(col, &mut output)?,
+        }
+    }
+    Ok(())
+}
+
+pub fn read_batch_with_encodings(
+    mut input: impl Read,
+    schema: &SchemaRef,
+) -> Result<(usize, Vec<ArrayRef>)> {
+    // read number of columns and rows
+    let num_rows = read_len(&mut input)?;
+
+    // read columns
+    let cols = schema
+        .fields()
+        .into_iter()
+        .map(|field| {
+            let encoding = ColumnEncoding::try_from_u8(read_u8(&mut input)?)?;
+            match encoding {
+                ColumnEncoding::Plain => read_array(&mut input, &field.data_type(), num_rows),
+                ColumnEncoding::DeltaRle => {
+                    read_delta_rle_array(&mut input, &field.data_type(), num_rows)
+                }
+            }
+        })
+        .collect::<Result<_>>()?;
+    Ok((num_rows, cols))
+}
+
+pub fn write_batch_with_column_lens(
+    num_rows: usize,
+    cols: &[ArrayRef],
+    mut output: impl Write,
+) -> Result<()> {
+    // write number of rows
+    write_len(num_rows, &mut output)?;
+
+    // write columns, each column is prefixed with its length
+    let mut col_data = vec![];
+    for col in cols {
+        col_data.clear();
+        write_array(col, &mut col_data)?;
+        write_len(col_data.len(), &mut output)?;
+        output.write_all(&col_data)?;
+    }
+    Ok(())
+}
+
+pub fn read_batch_projected(
+    mut input: impl Read,
+    schema: &SchemaRef,
+    projection: &[usize],
+) -> Result<(usize, Vec<ArrayRef>)> {
+    // read number of rows
+    let num_rows = read_len(&mut input)?;
+
+    // read projected columns, skip the others without decoding
+    let num_read_cols = projection.iter().max().map(|&i| i + 1).unwrap_or(0);
+    if num_read_cols > schema.fields().len() {
+        return df_execution_err!(
+            "projection index out of bounds: {} >= {}",
+            num_read_cols - 1,
+            schema.fields().len(),
+        );
+    }
+    let mut cols = vec![None; num_read_cols];
+    for (col_idx, field) in schema.fields().iter().take(num_read_cols).enumerate() {
+        let col_len = read_len(&mut input)?;
+        let mut col_input = (&mut input).take(col_len as u64);
+        if projection.contains(&col_idx) {
+            cols[col_idx] = Some(read_array(&mut col_input, field.data_type(), num_rows)?);
+        }
+        std::io::copy(&mut col_input, &mut std::io::sink())?;
+    }
+    let cols = projection
+        .iter()
+        .map(|&i| cols[i].clone().expect("projected column not read"))
+        .collect();
+    Ok((num_rows, cols))
+}
+
 pub fn write_array<W: Write>(array: &dyn Array, output: &mut W) -> Result<()> {
     macro_rules! write_primitive {
         ($ty:ident) => {{
@@ -79,7 +212,9 @@ pub fn write_array<W: Write>(array: &dyn Array, output: &mut W) -> Result<()> {
         DataType::Float64 => write_primitive!(Float64),
         DataType::Decimal128(..) => write_primitive!(Decimal128),
         DataType::Utf8 => write_bytes_array(as_string_array(array), output)?,
+        DataType::LargeUtf8 => write_bytes_array(as_largestring_array(array), output)?,
         DataType::Binary => write_bytes_array(as_generic_binary_array::<i32>(array), output)?,
+        DataType::LargeBinary => write_bytes_array(as_generic_binary_array::<i64>(array), output)?,
         DataType::Date32 => write_primitive!(Date32),
         DataType::Date64 => write_primitive!(Date64),
         DataType::Timestamp(TimeUnit::Second, _) => write_primitive!(TimestampSecond),
@@ -87,6 +222,10 @@ pub fn write_array<W: Write>(array: &dyn Array, output: &mut W) -> Result<()> {
         DataType::Timestamp(TimeUnit::Microsecond, _) => write_primitive!(TimestampMicrosecond),
         DataType::Timestamp(TimeUnit::Nanosecond, _) => write_primitive!(TimestampNanosecond),
         DataType::List(_field) => write_list_array(as_list_array(array), output)?,
+        DataType::LargeList(_field) => write_list_array(as_large_list_array(array), output)?,
+        DataType::FixedSizeList(..) => {
+            write_fixed_size_list_array(as_fixed_size_list_array(array), output)?
+        }
         DataType::Map(..) => write_map_array(as_map_array(array), output)?,
         DataType::Struct(_) => write_struct_array(as_struct_array(array), output)?,
         other => df_unimplemented_err!("unsupported data type: {other}")?,
@@ -128,9 +267,21 @@ pub fn read_array<R: Read>(
         DataType::Timestamp(TimeUnit::Millisecond, _) => read_primitive!(TimestampMillisecond),
         DataType::Timestamp(TimeUnit::Microsecond, _) => read_primitive!(TimestampMicrosecond),
         DataType::Timestamp(TimeUnit::Nanosecond, _) => read_primitive!(TimestampNanosecond),
-        DataType::Utf8 => read_bytes_array(num_rows, input, DataType::Utf8)?,
-        DataType::Binary => read_bytes_array(num_rows, input, DataType::Binary)?,
-        DataType::List(list_field) => read_list_array(num_rows, input, list_field)?,
+        DataType::Utf8 => read_bytes_array::<_, i32>(num_rows, input, DataType::Utf8)?,
+        DataType::LargeUtf8 => read_bytes_array::<_, i64>(num_rows, input, DataType::LargeUtf8)?,
+        DataType::Binary => read_bytes_array::<_, i32>(num_rows, input, DataType::Binary)?,
+        DataType::LargeBinary => {
+            read_bytes_array::<_, i64>(num_rows, input, DataType::LargeBinary)?
+        }
+        DataType::List(list_field) => {
+            read_list_array::<_, i32>(num_rows, input, list_field, data_type.clone())?
+        }
+        DataType::LargeList(list_field) => {
+            read_list_array::<_, i64>(num_rows, input, list_field, data_type.clone())?
+        }
+        DataType::FixedSizeList(list_field, list_size) => {
+            read_fixed_size_list_array(num_rows, input, list_field, *list_size)?
+        }
         DataType::Map(map_field, is_sorted) => {
             read_map_array(num_rows, input, map_field, *is_sorted)?
         }
@@ -160,6 +311,19 @@ fn write_bits_buffer<W: Write>(
     Ok(())
 }
 
+// write null buffer of the logical range only, the null buffer is omitted if
+// there are no nulls in the range, which is common in slices
+fn write_null_buffer<W: Write>(nulls: Option<&NullBuffer>, output: &mut W) -> Result<()> {
+    match nulls.filter(|nulls| nulls.null_count() > 0) {
+        Some(nulls) => {
+            write_len(1, output)?;
+            write_bits_buffer(nulls.buffer(), nulls.offset(), nulls.len(), output)?;
+        }
+        None => write_len(0, output)?,
+    }
+    Ok(())
+}
+
 fn read_bits_buffer<R: Read>(input: &mut R, bits_len: usize) -> Result<Buffer> {
     let buf = read_bytes_slice(input, (bits_len + 7) / 8)?;
     Ok(Buffer::from_vec(buf.into()))
@@ -169,21 +333,8 @@ fn write_primitive_array<W: Write, PT: ArrowPrimitiveType>(
     array: &PrimitiveArray<PT>,
     output: &mut W,
 ) -> Result<()> {
-    let offset = array.offset();
-    let len = array.len();
-    let array_data = array.to_data();
-    if let Some(null_buffer) = array_data.nulls() {
-        write_len(1, output)?;
-        write_bits_buffer(
-            null_buffer.buffer(),
-            null_buffer.offset(),
-            null_buffer.len(),
-            output,
-        )?;
-    } else {
-        write_len(0, output)?;
-    }
-    write_primitive_raw_array(&array_data.buffer::<PT::Native>(0)[offset..][..len], output)?;
+    write_null_buffer(array.nulls(), output)?;
+    write_primitive_raw_array(array.values(), output)?;
     Ok(())
 }
 
@@ -215,36 +366,30 @@ fn read_primitive_array<R: Read, PT: ArrowPrimitiveType>(
     Ok(make_array(array_data))
 }
 
-fn write_list_array<W: Write>(array: &ListArray, output: &mut W) -> Result<()> {
-    if let Some(null_buffer) = array.to_data().nulls() {
-        write_len(1, output)?;
-        write_bits_buffer(
-            null_buffer.buffer(),
-            null_buffer.offset(),
-            null_buffer.len(),
-            output,
-        )?;
-    } else {
-        write_len(0, output)?;
-    }
+fn write_list_array<W: Write, O: OffsetSizeTrait>(
+    array: &GenericListArray<O>,
+    output: &mut W,
+) -> Result<()> {
+    write_null_buffer(array.nulls(), output)?;
 
     let value_offsets = array.value_offsets();
     for (beg, end) in value_offsets.iter().zip(&value_offsets[1..]) {
-        let len = end - beg;
-        write_len(len as usize, output)?;
+        let len = end.as_usize() - beg.as_usize();
+        write_len(len, output)?;
     }
     let values = array.values().slice(
-        value_offsets[0] as usize,
-        value_offsets[array.len()] as usize - value_offsets[0] as usize,
+        value_offsets[0].as_usize(),
+        value_offsets[array.len()].as_usize() - value_offsets[0].as_usize(),
     );
     write_array(&values, output)?;
     Ok(())
 }
 
-fn read_list_array<R: Read>(
+fn read_list_array<R: Read, O: OffsetSizeTrait>(
     num_rows: usize,
     input: &mut R,
     list_field: &FieldRef,
+    data_type: DataType,
 ) -> Result<ArrayRef> {
     let has_null_buffer = read_len(input)? == 1;
     let null_buffer: Option<Buffer> = if has_null_buffer {
@@ -253,13 +398,16 @@ fn read_list_array<R: Read>(
         None
     };
 
-    let mut cur_offset = 0;
-    let mut offsets_buffer = MutableBuffer::new((num_rows + 1) * 4);
-    offsets_buffer.push(0u32);
+    let mut cur_offset = 0usize;
+    let mut offsets_buffer = MutableBuffer::new((num_rows + 1) * size_of::<O>());
+    offsets_buffer.push(O::zero());
     for _ in 0..num_rows {
         let len = read_len(input)?;
         let offset = cur_offset + len;
-        offsets_buffer.push(offset as u32);
+        match O::from_usize(offset) {
+            Some(offset) => offsets_buffer.push(offset),
+            None => return df_execution_err!("list offset overflow: {offset}"),
+        }
         cur_offset = offset;
     }
     let offsets_buffer: Buffer = offsets_buffer.into();
@@ -267,7 +415,7 @@ fn read_list_array<R: Read>(
     let values = read_array(input, list_field.data_type(), values_len)?;
 
     let array_data = ArrayData::try_new(
-        DataType::List(list_field.clone()),
+        data_type,
         num_rows,
         null_buffer,
         0,
@@ -277,19 +425,46 @@ fn read_list_array<R: Read>(
     Ok(make_array(array_data))
 }
 
-fn write_map_array<W: Write>(array: &MapArray, output: &mut W) -> Result<()> {
-    let array_data = array.to_data();
-    if let Some(null_buffer) = array_data.nulls() {
-        write_len(1, output)?;
-        write_bits_buffer(
-            null_buffer.buffer(),
-            null_buffer.offset(),
-            null_buffer.len(),
-            output,
-        )?;
+fn write_fixed_size_list_array<W: Write>(array: &FixedSizeListArray, output: &mut W) -> Result<()> {
+    write_null_buffer(array.nulls(), output)?;
+
+    let list_size = array.value_length() as usize;
+    let values = array
+        .values()
+        .slice(array.value_offset(0) as usize, array.len() * list_size);
+    write_array(&values, output)?;
+    Ok(())
+}
+
+fn read_fixed_size_list_array<R: Read>(
+    num_rows: usize,
+    input: &mut R,
+    list_field: &FieldRef,
+    list_size: i32,
+) -> Result<ArrayRef> {
+    let has_null_buffer = read_len(input)? == 1;
+    let null_buffer: Option<Buffer> = if has_null_buffer {
+        Some(read_bits_buffer(input, num_rows)?)
     } else {
-        write_len(0, output)?;
-    }
+        None
+    };
+
+    let values_len = num_rows * list_size as usize;
+    let values = read_array(input, list_field.data_type(), values_len)?;
+
+    let array_data = ArrayData::try_new(
+        DataType::FixedSizeList(list_field.clone(), list_size),
+        num_rows,
+        null_buffer,
+        0,
+        vec![],
+        vec![values.into_data()],
+    )?;
+    Ok(make_array(array_data))
+}
+
+fn write_map_array<W: Write>(array: &MapArray, output: &mut W) -> Result<()> {
+    write_null_buffer(array.nulls(), output)?;
 
     let first_offset = array.value_offsets().first().cloned().unwrap_or_default();
     let mut cur_offset = first_offset;
@@ -367,18 +542,7 @@ fn read_map_array<R: Read>(
 }
 
 fn write_struct_array<W: Write>(array: &StructArray, output: &mut W) -> Result<()> {
-    let array_data = array.to_data();
-    if let Some(null_buffer) = array_data.nulls() {
-        write_len(1, output)?;
-        write_bits_buffer(
-            null_buffer.buffer(),
-            null_buffer.offset(),
-            null_buffer.len(),
-            output,
-        )?;
-    } else {
-        write_len(0, output)?;
-    }
+    write_null_buffer(array.nulls(), output)?;
     for column in array.columns() {
         write_array(&column, output)?;
     }
@@ -410,24 +574,9 @@ fn read_struct_array<R: Read>(num_rows: usize, input: &mut R, fields: &Fields) -
 }
 
 fn write_boolean_array<W: Write>(array: &BooleanArray, output: &mut W) -> Result<()> {
-    let array_data = array.to_data();
-    if let Some(null_buffer) = array_data.nulls() {
-        write_len(1, output)?;
-        write_bits_buffer(
-            null_buffer.buffer(),
-            null_buffer.offset(),
-            null_buffer.len(),
-            output,
-        )?;
-    } else {
-        write_len(0, output)?;
-    }
-    write_bits_buffer(
-        &array_data.buffers()[0],
-        array.offset(),
-        array.len(),
-        output,
-    )?;
+    let values = array.values();
+    write_null_buffer(array.nulls(), output)?;
+    write_bits_buffer(values.inner(), values.offset(), values.len(), output)?;
     Ok(())
 }
 
@@ -455,21 +604,11 @@ fn read_boolean_array<R: Read>(num_rows: usize, input: &mut R) -> Result<ArrayRe
     Ok(make_array(array_data))
 }
 
-fn write_bytes_array<T: ByteArrayType<Offset = i32>, W: Write>(
+fn write_bytes_array<T: ByteArrayType, W: Write>(
     array: &GenericByteArray<T>,
     output: &mut W,
 ) -> Result<()> {
-    if let Some(null_buffer) = array.to_data().nulls() {
-        write_len(1, output)?;
-        write_bits_buffer(
-            null_buffer.buffer(),
-            null_buffer.offset(),
-            null_buffer.len(),
-            output,
-        )?;
-    } else {
-        write_len(0, output)?;
-    }
+    write_null_buffer(array.nulls(), output)?;
 
     // transform offsets to lengths for better compression
     let first_offset = array.value_offsets().first().cloned().unwrap_or_default();
@@ -481,11 +620,11 @@ fn write_bytes_array<T: ByteArrayType<Offset = i32>, W: Write>(
         lens.push(len);
     }
     write_primitive_raw_array(&lens, output)?;
-    output.write_all(&array.value_data()[first_offset as usize..cur_offset as usize])?;
+    output.write_all(&array.value_data()[first_offset.as_usize()..cur_offset.as_usize()])?;
     Ok(())
 }
 
-fn read_bytes_array<R: Read>(
+fn read_bytes_array<R: Read, O: OffsetSizeTrait>(
     num_rows: usize,
     input: &mut R,
     data_type: DataType,
@@ -497,18 +636,18 @@ fn read_bytes_array<R: Read>(
         None
     };
 
-    let lens = read_primitive_raw_array::<i32, R>(input, num_rows)?;
-    let mut cur_offset = 0;
-    let mut offsets_buffer = MutableBuffer::new((num_rows + 1) * 4);
-    offsets_buffer.push(0u32);
+    let lens = read_primitive_raw_array::<O, R>(input, num_rows)?;
+    let mut cur_offset = O::zero();
+    let mut offsets_buffer = MutableBuffer::new((num_rows + 1) * size_of::<O>());
+    offsets_buffer.push(O::zero());
     for len in lens {
         let offset = cur_offset + len;
         cur_offset = offset;
-        offsets_buffer.push(offset as u32);
+        offsets_buffer.push(offset);
     }
     let offsets_buffer: Buffer = offsets_buffer.into();
 
-    let data_len = cur_offset as usize;
+    let data_len = cur_offset.as_usize();
     let data_buffer = Buffer::from_vec(read_bytes_slice(input, data_len)?.into());
     let array_data = ArrayData::try_new(
         data_type,
@@ -571,18 +710,133 @@ fn transpose_raw_bytes(src: &[u8], dest: &mut [u8], num_items: usize, num_item_b
     }
 }
 
+fn count_delta_runs<PT: ArrowPrimitiveType>(array: &PrimitiveArray<PT>) -> Option<usize>
+where
+    PT::Native: Into<i64>,
+{
+    if array.null_count() > 0 || array.len() < 2 {
+        return None;
+    }
+    Some(delta_iter(array.values()).dedup().count())
+}
+
+fn delta_iter<T: Copy + Into<i64>>(values: &[T]) -> impl Iterator<Item = i64> + '_ {
+    values.windows(2).map(|w| {
+        let (prev, cur): (i64, i64) = (w[0].into(), w[1].into());
+        cur.wrapping_sub(prev)
+    })
+}
+
+fn write_delta_rle_array<W: Write>(array: &dyn Array, output: &mut W) -> Result<()> {
+    match array.data_type() {
+        DataType::Int32 => {
+            write_delta_rle_primitive_array(as_primitive_array::<Int32Type>(array), output)
+        }
+        DataType::Int64 => {
+            write_delta_rle_primitive_array(as_primitive_array::<Int64Type>(array), output)
+        }
+        other => df_unimplemented_err!("delta-rle encoding unsupported data type: {other}"),
+    }
+}
+
+fn read_delta_rle_array<R: Read>(
+    input: &mut R,
+    data_type: &DataType,
+    num_rows: usize,
+) -> Result<ArrayRef> {
+    match data_type {
+        DataType::Int32 => read_delta_rle_primitive_array::<_, Int32Type>(input, num_rows),
+        DataType::Int64 => read_delta_rle_primitive_array::<_, Int64Type>(input, num_rows),
+        other => df_unimplemented_err!("delta-rle encoding unsupported data type: {other}"),
+    }
+}
+
+// delta-rle format: first value, then runs of (delta, run_len), all values
+// are zigzag-encoded varints.
+fn write_delta_rle_primitive_array<W: Write, PT: ArrowPrimitiveType>(
+    array: &PrimitiveArray<PT>,
+    output: &mut W,
+) -> Result<()>
+where
+    PT::Native: Into<i64>,
+{
+    if array.null_count() > 0 {
+        return df_execution_err!("delta-rle encoding does not support nulls");
+    }
+    let values = array.values();
+    if values.is_empty() {
+        return Ok(());
+    }
+    write_len(zigzag_encode(values[0].into()), output)?;
+
+    for (run_len, delta) in delta_iter(values).dedup_with_count() {
+        write_len(zigzag_encode(delta), output)?;
+        write_len(run_len, output)?;
+    }
+    Ok(())
+}
+
+fn read_delta_rle_primitive_array<R: Read, PT: ArrowPrimitiveType>(
+    input: &mut R,
+    num_rows: usize,
+) -> Result<ArrayRef>
+where
+    PT::Native: TryFrom<i64>,
+{
+    let mut values = Vec::with_capacity(num_rows);
+    if num_rows > 0 {
+        let mut cur_value = zigzag_decode(read_len(input)?);
+        values.push(cur_value);
+        while values.len() < num_rows {
+            let delta = zigzag_decode(read_len(input)?);
+            let run_len = read_len(input)?;
+            if run_len == 0 || run_len > num_rows - values.len() {
+                return df_execution_err!(
+                    "spill corrupted: delta-rle run length {run_len} exceeds remaining {} rows",
+                    num_rows - values.len()
+                );
+            }
+            for _ in 0..run_len {
+                cur_value = cur_value.wrapping_add(delta);
+                values.push(cur_value);
+            }
+        }
+    }
+    let values = values
+        .into_iter()
+        .map(|v| match PT::Native::try_from(v) {
+            Ok(v) => Ok(v),
+            Err(_) => df_execution_err!("delta-rle decoding error: value {v} out of range"),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Arc::new(PrimitiveArray::<PT>::from_iter_values(values)))
+}
+
+fn zigzag_encode(v: i64) -> usize {
+    ((v << 1) ^ (v >> 63)) as u64 as usize
+}
+
+fn zigzag_decode(v: usize) -> i64 {
+    let v = v as u64;
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
 #[cfg(test)]
 mod test {
     use std::{io::Cursor, sync::Arc};
 
-    use arrow::{array::*, datatypes::*, record_batch::RecordBatch};
+    use arrow::{array::*, buffer::OffsetBuffer, datatypes::*, record_batch::RecordBatch};
     use datafusion::assert_batches_eq;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use crate::io::{
         batch_serde::{
-            read_batch, read_primitive_raw_array, write_batch, write_primitive_raw_array,
+            read_batch, read_batch_with_encodings, read_delta_rle_array, read_primitive_raw_array,
+            write_batch, write_batch_with_encodings, write_delta_rle_array,
+            write_primitive_raw_array, zigzag_encode, ColumnEncoding,
         },
-        recover_named_batch,
+        read_one_batch, read_one_batch_projected, recover_named_batch, write_len, write_one_batch,
+        write_one_batch_with_column_lens,
     };
 
     #[test]
@@ -711,6 +965,149 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_write_and_read_batch_for_large_and_fixed_size_types() {
+        let large_utf8: ArrayRef = Arc::new(LargeStringArray::from(vec![
+            Some("hello"),
+            None,
+            Some(""),
+            Some("你好🍹"),
+        ]));
+        let large_binary: ArrayRef = Arc::new(LargeBinaryArray::from(vec![
+            Some(&b"\x00\x01"[..]),
+            Some(&b""[..]),
+            None,
+            Some(&b"\xff"[..]),
+        ]));
+        let fixed_size_list_data = vec![
+            Some(vec![Some(1.0), Some(2.0)]),
+            None,
+            Some(vec![None, Some(4.0)]),
+            Some(vec![Some(5.0), None]),
+        ];
+        let fixed_size_list: ArrayRef = Arc::new(FixedSizeListArray::from_iter_primitive::<
+            Float32Type,
+            _,
+            _,
+        >(fixed_size_list_data, 2));
+        let large_list_data = vec![
+            Some(vec![Some(0), None]),
+            Some(vec![]),
+            None,
+            Some(vec![Some(3), Some(4), Some(5)]),
+        ];
+        let large_list: ArrayRef = Arc::new(
+            LargeListArray::from_iter_primitive::<Int64Type, _, _>(large_list_data),
+        );
+        // nested: large list of fixed size lists of nullable strings
+        let nested_values = FixedSizeListArray::new(
+            Arc::new(Field::new("item", DataType::LargeUtf8, true)),
+            2,
+            Arc::new(LargeStringArray::from(vec![
+                Some("a"),
+                None,
+                None,
+                None,
+                Some("c"),
+                Some("d"),
+            ])),
+            Some(vec![true, false, true].into()),
+        );
+        let nested: ArrayRef = Arc::new(LargeListArray::new(
+            Arc::new(Field::new("item", nested_values.data_type().clone(), true)),
+            OffsetBuffer::new(vec![0i64, 2, 2, 2, 3].into()),
+            Arc::new(nested_values),
+            Some(vec![true, true, false, true].into()),
+        ));
+        let batch = RecordBatch::try_from_iter_with_nullable(vec![
+            ("large_utf8", large_utf8, true),
+            ("large_binary", large_binary, true),
+            ("fixed_size_list", fixed_size_list, true),
+            ("large_list", large_list, true),
+            ("nested", nested, true),
+        ])
+        .unwrap();
+
+        let all_null_cols = batch
+            .columns()
+            .iter()
+            .map(|col| new_null_array(col.data_type(), 3))
+            .collect::<Vec<_>>();
+        let all_null_batch = RecordBatch::try_new(batch.schema(), all_null_cols).unwrap();
+
+        for batch in [
+            batch.clone(),
+            batch.slice(1, 2),
+            batch.slice(0, 0),
+            all_null_batch,
+        ] {
+            let mut buf = vec![];
+            write_batch(batch.num_rows(), batch.columns(), &mut buf).unwrap();
+            let mut cursor = Cursor::new(buf);
+            let (decoded_num_rows, decoded_cols) =
+                read_batch(&mut cursor, &batch.schema()).unwrap();
+            assert_eq!(
+                recover_named_batch(decoded_num_rows, &decoded_cols, batch.schema()).unwrap(),
+                batch
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_sliced_batch_size() {
+        let build_batch = |range: std::ops::Range<i32>| {
+            let int_array: ArrayRef = Arc::new(Int32Array::from_iter(
+                range.clone().map(|i| Some(i).filter(|i| i % 3 != 0)),
+            ));
+            let str_array: ArrayRef = Arc::new(StringArray::from_iter(
+                range
+                    .clone()
+                    .map(|i| Some(format!("s{i}")).filter(|_| i % 5 != 0)),
+            ));
+            let bool_array: ArrayRef = Arc::new(BooleanArray::from_iter(
+                range.clone().map(|i| Some(i % 2 == 0)),
+            ));
+            let list_array: ArrayRef = Arc::new(ListArray::from_iter_primitive::<Int32Type, _, _>(
+                range.map(|i| Some(vec![Some(i), None])),
+            ));
+            RecordBatch::try_from_iter(vec![
+                ("int", int_array),
+                ("str", str_array),
+                ("bool", bool_array),
+                ("list", list_array),
+            ])
+            .unwrap()
+        };
+        let write = |batch: &RecordBatch| {
+            let mut buf = vec![];
+            write_batch(batch.num_rows(), batch.columns(), &mut buf).unwrap();
+            buf
+        };
+
+        // slices of a large batch are serialized exactly like a fresh batch
+        let large_batch = build_batch(0..1000000);
+        for offset in [0, 1, 500003, 999990] {
+            let sliced = large_batch.slice(offset, 10);
+            let fresh = build_batch(offset as i32..offset as i32 + 10);
+            let sliced_buf = write(&sliced);
+            let fresh_buf = write(&fresh);
+            assert_eq!(sliced_buf.len(), fresh_buf.len());
+            assert_eq!(sliced_buf, fresh_buf);
+
+            let (decoded_num_rows, decoded_cols) =
+                read_batch(Cursor::new(sliced_buf), &sliced.schema()).unwrap();
+            assert_eq!(
+                recover_named_batch(decoded_num_rows, &decoded_cols, sliced.schema()).unwrap(),
+                fresh
+            );
+        }
+
+        // null buffer is omitted for slices without nulls
+        let no_nulls = build_batch(1..3);
+        let with_nulls = large_batch.slice(1, 2);
+        assert_eq!(write(&no_nulls), write(&with_nulls));
+    }
+
     #[test]
     fn test_write_and_read_batch_for_map() {
         let map_array: ArrayRef = Arc::new(
@@ -796,4 +1193,151 @@ mod test {
             sliced
         );
     }
+
+    #[test]
+    fn test_write_and_read_batch_with_encodings() {
+        let ids: ArrayRef = Arc::new(Int64Array::from_iter_values(1000000..1010000));
+        let values: ArrayRef = Arc::new(Int32Array::from_iter_values(
+            (0..10000).map(|i| (i * 7919 % 10007) as i32),
+        ));
+        let strs: ArrayRef = Arc::new(StringArray::from_iter_values(
+            (0..10000).map(|i| format!("s{i}")),
+        ));
+        assert_eq!(ColumnEncoding::choose(&ids), ColumnEncoding::DeltaRle);
+        assert_eq!(ColumnEncoding::choose(&values), ColumnEncoding::Plain);
+        assert_eq!(ColumnEncoding::choose(&strs), ColumnEncoding::Plain);
+
+        let batch = RecordBatch::try_from_iter_with_nullable(vec![
+            ("id", ids, false),
+            ("value", values, false),
+            ("str", strs, false),
+        ])
+        .unwrap();
+
+        let mut plain_buf = vec![];
+        write_batch(batch.num_rows(), batch.columns(), &mut plain_buf).unwrap();
+        let mut encoded_buf = vec![];
+        write_batch_with_encodings(batch.num_rows(), batch.columns(), &mut encoded_buf).unwrap();
+        assert!(encoded_buf.len() < plain_buf.len());
+
+        let mut cursor = Cursor::new(encoded_buf);
+        let (decoded_num_rows, decoded_cols) =
+            read_batch_with_encodings(&mut cursor, &batch.schema()).unwrap();
+        assert_eq!(
+            recover_named_batch(decoded_num_rows, &decoded_cols, batch.schema()).unwrap(),
+            batch
+        );
+    }
+
+    #[test]
+    fn test_delta_rle_errors() {
+        let nullable = Int32Array::from(vec![Some(1), None, Some(3)]);
+        assert!(write_delta_rle_array(&nullable, &mut Vec::<u8>::new()).is_err());
+
+        // first value 0, then a run of 100 deltas of 1 in a 10-rows column
+        let mut buf = vec![];
+        write_len(zigzag_encode(0), &mut buf).unwrap();
+        write_len(zigzag_encode(1), &mut buf).unwrap();
+        write_len(100, &mut buf).unwrap();
+        let err = read_delta_rle_array(&mut Cursor::new(&buf), &DataType::Int32, 10).unwrap_err();
+        assert!(err.to_string().contains("spill corrupted"), "{err}");
+    }
+
+    #[test]
+    fn test_read_projected_batch() {
+        let ints: ArrayRef = Arc::new(Int32Array::from_iter(
+            (0..100).map(|i| Some(i).filter(|i| i % 3 != 0)),
+        ));
+        let strs: ArrayRef = Arc::new(StringArray::from_iter_values(
+            (0..100).map(|i| format!("s{i}")),
+        ));
+        let c1: ArrayRef = Arc::new(BooleanArray::from_iter((0..100).map(|i| Some(i % 2 == 0))));
+        let c2: ArrayRef = Arc::new(Int64Array::from_iter_values(0..100));
+        let structs: ArrayRef =
+            Arc::new(StructArray::try_from(vec![("c1", c1), ("c2", c2)]).unwrap());
+        let batch = RecordBatch::try_from_iter(vec![
+            ("ints", ints),
+            ("strs", strs.clone()),
+            ("structs", structs),
+            ("strs2", strs),
+        ])
+        .unwrap();
+        let schema = batch.schema();
+
+        let mut buf = vec![];
+        for _ in 0..2 {
+            write_one_batch_with_column_lens(batch.num_rows(), batch.columns(), &mut buf).unwrap();
+        }
+
+        // use a fixed seed to make the test predictable.
+        let mut rng = StdRng::seed_from_u64(37);
+        for _ in 0..100 {
+            let projection = (0..rng.random_range(0..=6))
+                .map(|_| rng.random_range(0..batch.num_columns()))
+                .collect::<Vec<_>>();
+            let projected_schema = Arc::new(schema.project(&projection).unwrap());
+            let expected = batch.project(&projection).unwrap();
+
+            // all batches in the stream are readable after skipping columns
+            let mut cursor = Cursor::new(&buf);
+            for _ in 0..2 {
+                let (decoded_num_rows, decoded_cols) =
+                    read_one_batch_projected(&mut cursor, &schema, &projection)
+                        .unwrap()
+                        .unwrap();
+                assert_eq!(
+                    recover_named_batch(decoded_num_rows, &decoded_cols, projected_schema.clone())
+                        .unwrap(),
+                    expected
+                );
+            }
+            assert!(read_one_batch_projected(&mut cursor, &schema, &projection)
+                .unwrap()
+                .is_none());
+        }
+
+        // out-of-bounds projection
+        let mut cursor = Cursor::new(&buf);
+        assert!(read_one_batch_projected(&mut cursor, &schema, &[4]).is_err());
+    }
+
+    #[test]
+    fn test_wide_batch_size_excludes_schema() {
+        // field names and types are never written, wide batches only carry
+        // their column data
+        let num_cols = 300;
+        let num_rows = 100;
+        let cols = (0..num_cols)
+            .map(|i| Arc::new(Int32Array::from_iter_values(i..i + num_rows)) as ArrayRef)
+            .collect::<Vec<_>>();
+
+        let mut buf = vec![];
+        write_one_batch(num_rows as usize, &cols, &mut buf).unwrap();
+        let one_batch_len = buf.len();
+        write_one_batch(num_rows as usize, &cols, &mut buf).unwrap();
+        assert_eq!(buf.len(), one_batch_len * 2);
+
+        let data_len = (num_cols * num_rows) as usize * size_of::<i32>();
+        assert!(one_batch_len < data_len + num_cols as usize * 8);
+
+        let schema = Arc::new(Schema::new(
+            (0..num_cols)
+                .map(|i| {
+                    Field::new(
+                        format!("a_very_long_column_name_{i:0100}"),
+                        DataType::Int32,
+                        false,
+                    )
+                })
+                .collect::<Vec<_>>(),
+        ));
+        let mut cursor = Cursor::new(buf);
+        for _ in 0..2 {
+            let (decoded_num_rows, decoded_cols) =
+                read_one_batch(&mut cursor, &schema).unwrap().unwrap();
+            assert_eq!(decoded_num_rows, num_rows as usize);
+            assert_eq!(decoded_cols, cols);
+        }
+        assert!(read_one_batch(&mut cursor, &schema).unwrap().is_none());
+    }
 }
@@ -12,21 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::{Read, Write};
+use std::io::{Read, Take, Write};
 
 use arrow::{
     array::{Array, ArrayRef, RecordBatchOptions},
     datatypes::SchemaRef,
     record_batch::RecordBatch,
 };
-pub use batch_serde::{read_array, write_array};
+pub use batch_serde::{read_array, write_array, ColumnEncoding};
 use datafusion::common::Result;
 pub use scalar_serde::{read_scalar, write_scalar};
+pub use segment_trailer::{
+    inspect_shuffle_files, SegmentHasher, SegmentStats, SegmentTrailer, SEGMENT_TRAILER_LEN,
+};
+pub use shuffle_index::{
+    decode_shuffle_index, encode_non_empty_bitmap, encode_sparse_index, ShuffleIndex,
+    ShuffleIndexFormat,
+};
 
 use crate::arrow::cast::cast;
 
 mod batch_serde;
 mod scalar_serde;
+mod segment_trailer;
+mod shuffle_index;
 
 pub fn write_raw_slice<T: Sized + Copy>(
     values: &[T],
@@ -52,19 +61,84 @@ pub fn read_raw_slice<T: Sized + Copy>(
     input.read_exact(raw_slice)
 }
 
-pub fn write_one_batch(num_rows: usize, cols: &[ArrayRef], mut output: impl Write) -> Result<()> {
+pub fn write_one_batch(num_rows: usize, cols: &[ArrayRef], output: impl Write) -> Result<()> {
+    write_one_batch_impl(num_rows, cols, output, |data| {
+        batch_serde::write_batch(num_rows, cols, data)
+    })
+}
+
+pub fn read_one_batch(
+    input: impl Read,
+    schema: &SchemaRef,
+) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+    read_one_batch_impl(input, |input| batch_serde::read_batch(input, schema))
+}
+
+/// same as [`write_one_batch`], but each column is written with an encoding
+/// chosen by [`ColumnEncoding::choose`]. must be read with
+/// [`read_one_batch_with_encodings`].
+pub fn write_one_batch_with_encodings(
+    num_rows: usize,
+    cols: &[ArrayRef],
+    output: impl Write,
+) -> Result<()> {
+    write_one_batch_impl(num_rows, cols, output, |data| {
+        batch_serde::write_batch_with_encodings(num_rows, cols, data)
+    })
+}
+
+pub fn read_one_batch_with_encodings(
+    input: impl Read,
+    schema: &SchemaRef,
+) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+    read_one_batch_impl(input, |input| {
+        batch_serde::read_batch_with_encodings(input, schema)
+    })
+}
+
+/// same as [`write_one_batch`], but each column is prefixed with its length,
+/// so that unneeded columns can be skipped by [`read_one_batch_projected`].
+pub fn write_one_batch_with_column_lens(
+    num_rows: usize,
+    cols: &[ArrayRef],
+    output: impl Write,
+) -> Result<()> {
+    write_one_batch_impl(num_rows, cols, output, |data| {
+        batch_serde::write_batch_with_column_lens(num_rows, cols, data)
+    })
+}
+
+/// reads a batch written by [`write_one_batch_with_column_lens`], only columns
+/// in projection are decoded and returned. the returned columns match
+/// `schema.project(projection)`.
+pub fn read_one_batch_projected(
+    input: impl Read,
+    schema: &SchemaRef,
+    projection: &[usize],
+) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+    read_one_batch_impl(input, |input| {
+        batch_serde::read_batch_projected(input, schema, projection)
+    })
+}
+
+fn write_one_batch_impl(
+    num_rows: usize,
+    cols: &[ArrayRef],
+    mut output: impl Write,
+    write_batch: impl FnOnce(&mut Vec<u8>) -> Result<()>,
+) -> Result<()> {
     assert!(cols.iter().all(|col| col.len() == num_rows));
 
     let mut batch_data = vec![];
-    batch_serde::write_batch(num_rows, cols, &mut batch_data)?;
+    write_batch(&mut batch_data)?;
     write_len(batch_data.len(), &mut output)?;
     output.write_all(&batch_data)?;
     Ok(())
 }
 
-pub fn read_one_batch(
-    mut input: impl Read,
-    schema: &SchemaRef,
+fn read_one_batch_impl<R: Read>(
+    mut input: R,
+    read_batch: impl FnOnce(&mut Take<R>) -> Result<(usize, Vec<ArrayRef>)>,
 ) -> Result<Option<(usize, Vec<ArrayRef>)>> {
     let batch_data_len = match read_len(&mut input) {
         Ok(len) => len,
@@ -76,7 +150,7 @@ pub fn read_one_batch(
         }
     };
     let mut input = input.take(batch_data_len as u64);
-    let (num_rows, cols) = batch_serde::read_batch(&mut input, schema)?;
+    let (num_rows, cols) = read_batch(&mut input)?;
 
     // consume trailing bytes
     std::io::copy(&mut input, &mut std::io::sink())?;
@@ -113,16 +187,19 @@ pub fn write_len<W: Write>(mut len: usize, output: &mut W) -> std::io::Result<()
 }
 
 pub fn read_len<R: Read>(input: &mut R) -> std::io::Result<usize> {
+    let overflowed = || std::io::Error::new(std::io::ErrorKind::InvalidData, "length overflowed");
     let mut len = 0usize;
-    let mut factor = 1;
+    let mut factor = 1usize;
     loop {
         let v = read_u8(input)?;
+        len = ((v % 128) as usize)
+            .checked_mul(factor)
+            .and_then(|v| len.checked_add(v))
+            .ok_or_else(overflowed)?;
         if v < 128 {
-            len += (v as usize) * factor;
             break;
         }
-        len += (v - 128) as usize * factor;
-        factor *= 128;
+        factor = factor.checked_mul(128).ok_or_else(overflowed)?;
     }
     Ok(len)
 }
@@ -0,0 +1,208 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io::Write, path::Path};
+
+use datafusion::common::Result;
+use itertools::Itertools;
+
+use crate::{
+    df_execution_err, hash::xxhash::spark_compatible_xxhash64_hash, io::decode_shuffle_index,
+};
+
+const TRAILER_MAGIC: &[u8; 4] = b"BLZT";
+const TRAILER_VERSION: u8 = 1;
+const HASH_CHUNK_SIZE: usize = 65536;
+
+pub const SEGMENT_TRAILER_LEN: usize = 4 + 1 + 8 * 5;
+
+/// trailer appended to the end of a spill/shuffle segment, describing the
+/// segment so that it can be inspected without knowing which build wrote it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SegmentTrailer {
+    pub num_batches: u64,
+    pub num_rows: u64,
+    pub uncompressed_size: u64,
+    /// number of bytes from the segment start to the trailer
+    pub payload_len: u64,
+    /// hash of payload bytes computed with [`SegmentHasher`]
+    pub payload_hash: u64,
+}
+
+impl SegmentTrailer {
+    pub fn to_bytes(&self) -> [u8; SEGMENT_TRAILER_LEN] {
+        let mut bytes = [0u8; SEGMENT_TRAILER_LEN];
+        bytes[0..4].copy_from_slice(TRAILER_MAGIC);
+        bytes[4] = TRAILER_VERSION;
+        for (i, v) in [
+            self.num_batches,
+            self.num_rows,
+            self.uncompressed_size,
+            self.payload_len,
+            self.payload_hash,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            bytes[5 + i * 8..][..8].copy_from_slice(&v.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// parses trailer bytes, returns None if bytes are not a known trailer
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != SEGMENT_TRAILER_LEN
+            || &bytes[0..4] != TRAILER_MAGIC
+            || bytes[4] != TRAILER_VERSION
+        {
+            return None;
+        }
+        let read_u64 =
+            |i: usize| u64::from_le_bytes(bytes[5 + i * 8..][..8].try_into().expect("8 bytes"));
+        Some(Self {
+            num_batches: read_u64(0),
+            num_rows: read_u64(1),
+            uncompressed_size: read_u64(2),
+            payload_len: read_u64(3),
+            payload_hash: read_u64(4),
+        })
+    }
+}
+
+/// xxhash64 of segment payload, computed over fixed-size chunks so that it
+/// can be updated incrementally while writing.
+#[derive(Default)]
+pub struct SegmentHasher {
+    hash: u64,
+    buf: Vec<u8>,
+}
+
+impl SegmentHasher {
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // fast path: hash full chunks without buffering
+            if self.buf.is_empty() && data.len() >= HASH_CHUNK_SIZE {
+                self.hash_chunk(&data[..HASH_CHUNK_SIZE]);
+                data = &data[HASH_CHUNK_SIZE..];
+                continue;
+            }
+            let len = (HASH_CHUNK_SIZE - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.buf.len() == HASH_CHUNK_SIZE {
+                self.hash_buf();
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> u64 {
+        if !self.buf.is_empty() {
+            self.hash_buf();
+        }
+        self.hash
+    }
+
+    fn hash_buf(&mut self) {
+        self.hash = spark_compatible_xxhash64_hash(&self.buf, self.hash as i64) as u64;
+        self.buf.clear();
+    }
+
+    fn hash_chunk(&mut self, chunk: &[u8]) {
+        self.hash = spark_compatible_xxhash64_hash(chunk, self.hash as i64) as u64;
+    }
+}
+
+/// stats of a partition segment collected from its trailers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SegmentStats {
+    pub offset: u64,
+    pub len: u64,
+    pub num_trailers: usize,
+    pub num_batches: u64,
+    pub num_rows: u64,
+    pub uncompressed_size: u64,
+    pub num_checksum_errors: usize,
+    /// number of bytes at the segment start not covered by any trailer
+    pub untrailed_len: u64,
+}
+
+/// walks a shuffle data/index file pair and prints stats of each partition
+/// segment, useful for inspecting files written by unknown builds.
+pub fn inspect_shuffle_files(
+    data_file: impl AsRef<Path>,
+    index_file: impl AsRef<Path>,
+    mut output: impl Write,
+) -> Result<Vec<SegmentStats>> {
+    let data = std::fs::read(data_file)?;
+    let offsets = decode_shuffle_index(&std::fs::read(index_file)?)?.offsets;
+
+    let mut all_stats = vec![];
+    for (partition_id, (&beg, &end)) in offsets.iter().tuple_windows().enumerate() {
+        if beg > end || end > data.len() as u64 {
+            return df_execution_err!(
+                "invalid segment range of partition {partition_id}: {beg}..{end}"
+            );
+        }
+        let stats = inspect_segment(beg, &data[beg as usize..end as usize]);
+        writeln!(
+            output,
+            "partition {partition_id}: offset={}, len={}, trailers={}, batches={}, rows={}, \
+             uncompressed_size={}, checksum_errors={}, untrailed_len={}",
+            stats.offset,
+            stats.len,
+            stats.num_trailers,
+            stats.num_batches,
+            stats.num_rows,
+            stats.uncompressed_size,
+            stats.num_checksum_errors,
+            stats.untrailed_len,
+        )?;
+        all_stats.push(stats);
+    }
+    Ok(all_stats)
+}
+
+fn inspect_segment(offset: u64, segment: &[u8]) -> SegmentStats {
+    let mut stats = SegmentStats {
+        offset,
+        len: segment.len() as u64,
+        ..Default::default()
+    };
+
+    // a segment may consist of several trailed sub-segments (e.g. merged from
+    // multiple spills), walk them backwards from the segment end
+    let mut end = segment.len();
+    while end >= SEGMENT_TRAILER_LEN {
+        let trailer_start = end - SEGMENT_TRAILER_LEN;
+        let Some(trailer) = SegmentTrailer::from_bytes(&segment[trailer_start..end]) else {
+            break;
+        };
+        if trailer.payload_len > trailer_start as u64 {
+            break;
+        }
+        let payload_start = trailer_start - trailer.payload_len as usize;
+        let mut hasher = SegmentHasher::default();
+        hasher.update(&segment[payload_start..trailer_start]);
+        if hasher.finish() != trailer.payload_hash {
+            stats.num_checksum_errors += 1;
+        }
+        stats.num_trailers += 1;
+        stats.num_batches += trailer.num_batches;
+        stats.num_rows += trailer.num_rows;
+        stats.uncompressed_size += trailer.uncompressed_size;
+        end = payload_start;
+    }
+    stats.untrailed_len = end as u64;
+    stats
+}
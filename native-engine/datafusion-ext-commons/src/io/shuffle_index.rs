@@ -0,0 +1,304 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use datafusion::common::Result;
+
+use crate::df_execution_err;
+
+// an index file is a list of i64 offsets, optionally followed by a bitmap of
+// non-empty partitions and a version byte. the last byte of a plain index
+// file is the highest byte of the data file size, which is always zero, so it
+// never collides with the version.
+const NON_EMPTY_BITMAP_VERSION: u8 = 1;
+
+// a sparse index file is a list of i64 (partition_id, offset) pairs of
+// non-empty partitions, followed by (num_partitions, data size) and a version
+// byte.
+const SPARSE_VERSION: u8 = 2;
+
+// partition ids are 24 bits in spark sort shuffle, which bounds memory of
+// decoding a corrupted sparse index
+const MAX_NUM_PARTITIONS: usize = 1 << 24;
+
+/// format of written shuffle index files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShuffleIndexFormat {
+    /// offsets of all partitions, readable by spark
+    #[default]
+    Dense,
+    /// offsets followed by the bitmap of non-empty partitions
+    DenseWithBitmap,
+    /// offsets of non-empty partitions only
+    Sparse,
+}
+
+/// decoded shuffle index file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShuffleIndex {
+    pub offsets: Vec<u64>,
+    /// one bit per partition, set if the partition is non-empty. None if the
+    /// index file is written without bitmap.
+    pub non_empty_bitmap: Option<Vec<u8>>,
+}
+
+impl ShuffleIndex {
+    pub fn num_partitions(&self) -> usize {
+        self.offsets.len().saturating_sub(1)
+    }
+
+    /// returns whether the partition has data, without comparing offsets if
+    /// the bitmap is available
+    pub fn is_non_empty(&self, partition_id: usize) -> bool {
+        match &self.non_empty_bitmap {
+            Some(bitmap) => bitmap[partition_id / 8] & (1 << (partition_id % 8)) != 0,
+            None => self.offsets[partition_id] < self.offsets[partition_id + 1],
+        }
+    }
+}
+
+/// encodes the bitmap of non-empty partitions with the version byte, to be
+/// appended after offsets in an index file
+pub fn encode_non_empty_bitmap(offsets: &[u64]) -> Vec<u8> {
+    let num_partitions = offsets.len().saturating_sub(1);
+    let mut bitmap = vec![0u8; num_partitions.div_ceil(8) + 1];
+    for (partition_id, w) in offsets.windows(2).enumerate() {
+        if w[0] < w[1] {
+            bitmap[partition_id / 8] |= 1 << (partition_id % 8);
+        }
+    }
+    bitmap[num_partitions.div_ceil(8)] = NON_EMPTY_BITMAP_VERSION;
+    bitmap
+}
+
+/// encodes offsets as a sparse index file, in which empty partitions take no
+/// space
+pub fn encode_sparse_index(offsets: &[u64]) -> Vec<u8> {
+    let num_partitions = offsets.len().saturating_sub(1);
+    let mut index_data = vec![];
+    let mut push_pair = |partition_id: usize, offset: u64| {
+        index_data.extend_from_slice(&(partition_id as i64).to_le_bytes());
+        index_data.extend_from_slice(&(offset as i64).to_le_bytes());
+    };
+    for (partition_id, w) in offsets.windows(2).enumerate() {
+        if w[0] < w[1] {
+            push_pair(partition_id, w[0]);
+        }
+    }
+    push_pair(num_partitions, offsets.last().cloned().unwrap_or_default());
+    index_data.push(SPARSE_VERSION);
+    index_data
+}
+
+fn decode_sparse_index(index_data: &[u8]) -> Result<ShuffleIndex> {
+    let pairs_data = &index_data[..index_data.len() - 1];
+    if pairs_data.is_empty() || !pairs_data.len().is_multiple_of(16) {
+        return df_execution_err!("invalid sparse shuffle index length: {}", index_data.len());
+    }
+    let pairs = pairs_data
+        .chunks_exact(16)
+        .map(|chunk| {
+            let partition_id = i64::from_le_bytes(chunk[..8].try_into().expect("8 bytes"));
+            let offset = i64::from_le_bytes(chunk[8..].try_into().expect("8 bytes"));
+            (partition_id, offset)
+        })
+        .collect::<Vec<_>>();
+
+    // the last pair is (num_partitions, data size), check it before allocating
+    let num_partitions = pairs[pairs.len() - 1].0;
+    if !(0..=MAX_NUM_PARTITIONS as i64).contains(&num_partitions) {
+        return df_execution_err!("invalid sparse shuffle index partitions: {num_partitions}");
+    }
+    let num_partitions = num_partitions as usize;
+
+    // empty partitions have the same offset as the next non-empty partition
+    let mut offsets: Vec<u64> = Vec::with_capacity(num_partitions + 1);
+    for &(partition_id, offset) in &pairs {
+        if partition_id < offsets.len() as i64 || partition_id > num_partitions as i64 {
+            return df_execution_err!("invalid sparse shuffle index partition: {partition_id}");
+        }
+        let prev_offset = offsets.last().cloned().unwrap_or_default();
+        if offset < 0 || (offset as u64) < prev_offset {
+            return df_execution_err!(
+                "invalid sparse shuffle index offset of partition {partition_id}: {offset}"
+            );
+        }
+        offsets.resize(partition_id as usize + 1, offset as u64);
+    }
+    Ok(ShuffleIndex {
+        offsets,
+        non_empty_bitmap: None,
+    })
+}
+
+/// decodes an index file written in any [`ShuffleIndexFormat`]
+pub fn decode_shuffle_index(index_data: &[u8]) -> Result<ShuffleIndex> {
+    let decode_offsets = |data: &[u8]| {
+        data.chunks_exact(8)
+            .map(|chunk| i64::from_le_bytes(chunk.try_into().expect("8 bytes")) as u64)
+            .collect::<Vec<_>>()
+    };
+
+    if index_data.len().is_multiple_of(8) && index_data.last() != Some(&NON_EMPTY_BITMAP_VERSION) {
+        return Ok(ShuffleIndex {
+            offsets: decode_offsets(index_data),
+            non_empty_bitmap: None,
+        });
+    }
+
+    // find the number of partitions from the length, which is
+    // 8 * (n + 1) + ceil(n / 8) + 1
+    let Some(&version) = index_data.last() else {
+        return df_execution_err!("empty shuffle index");
+    };
+    if version == SPARSE_VERSION {
+        return decode_sparse_index(index_data);
+    }
+    if version != NON_EMPTY_BITMAP_VERSION {
+        return df_execution_err!("unsupported shuffle index version: {version}");
+    }
+    let Some(num_partitions) =
+        (0..=index_data.len() / 8).find(|&n| 8 * (n + 1) + n.div_ceil(8) + 1 == index_data.len())
+    else {
+        return df_execution_err!("invalid shuffle index length: {}", index_data.len());
+    };
+    let bitmap_start = 8 * (num_partitions + 1);
+    Ok(ShuffleIndex {
+        offsets: decode_offsets(&index_data[..bitmap_start]),
+        non_empty_bitmap: Some(index_data[bitmap_start..index_data.len() - 1].to_vec()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use datafusion::common::Result;
+
+    use crate::io::{decode_shuffle_index, encode_non_empty_bitmap, encode_sparse_index};
+
+    fn encode_offsets(offsets: &[u64]) -> Vec<u8> {
+        offsets
+            .iter()
+            .flat_map(|&offset| (offset as i64).to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_sparse_non_empty_bitmap() -> Result<()> {
+        // 100 partitions, only a few of them are non-empty
+        let non_empty = [0, 7, 8, 63, 99];
+        let mut offsets = vec![0u64];
+        for partition_id in 0..100 {
+            let len = non_empty.contains(&partition_id) as u64 * 10;
+            offsets.push(offsets.last().unwrap() + len);
+        }
+
+        let mut index_data = encode_offsets(&offsets);
+        index_data.extend(encode_non_empty_bitmap(&offsets));
+        assert_eq!(index_data.len(), 8 * 101 + 13 + 1);
+
+        let index = decode_shuffle_index(&index_data)?;
+        assert_eq!(index.offsets, offsets);
+        assert_eq!(index.num_partitions(), 100);
+        let bitmap = index.non_empty_bitmap.as_ref().expect("bitmap");
+        assert_eq!(bitmap.len(), 13);
+        for partition_id in 0..100 {
+            assert_eq!(
+                index.is_non_empty(partition_id),
+                non_empty.contains(&partition_id),
+                "partition {partition_id}",
+            );
+        }
+
+        // index without bitmap is still readable
+        let index = decode_shuffle_index(&encode_offsets(&offsets))?;
+        assert_eq!(index.offsets, offsets);
+        assert!(index.non_empty_bitmap.is_none());
+        assert!(index.is_non_empty(63));
+        assert!(!index.is_non_empty(64));
+
+        // lengths with a multiple of 8 are not mistaken for plain offsets
+        for num_partitions in 0..200 {
+            let offsets = (0..=num_partitions as u64).collect::<Vec<_>>();
+            let mut index_data = encode_offsets(&offsets);
+            index_data.extend(encode_non_empty_bitmap(&offsets));
+            let index = decode_shuffle_index(&index_data)?;
+            assert_eq!(index.offsets, offsets);
+            assert!(index.non_empty_bitmap.is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sparse_index() -> Result<()> {
+        // 10000 partitions, only a few of them are non-empty
+        let non_empty = [0, 1, 4097, 9999];
+        let mut offsets = vec![0u64];
+        for partition_id in 0..10000 {
+            let len = non_empty.contains(&partition_id) as u64 * 10;
+            offsets.push(offsets.last().unwrap() + len);
+        }
+
+        let dense_data = encode_offsets(&offsets);
+        let sparse_data = encode_sparse_index(&offsets);
+        assert_eq!(sparse_data.len(), 16 * 5 + 1);
+        assert!(sparse_data.len() * 100 < dense_data.len());
+
+        // sparse index resolves the same ranges
+        let index = decode_shuffle_index(&sparse_data)?;
+        assert_eq!(index.num_partitions(), 10000);
+        assert_eq!(index.offsets, decode_shuffle_index(&dense_data)?.offsets);
+        for partition_id in 0..10000 {
+            assert_eq!(
+                index.is_non_empty(partition_id),
+                non_empty.contains(&partition_id),
+                "partition {partition_id}",
+            );
+        }
+
+        // all partitions empty, or no partitions at all
+        for offsets in [vec![0u64; 5], vec![0u64]] {
+            let index = decode_shuffle_index(&encode_sparse_index(&offsets))?;
+            assert_eq!(index.offsets, offsets);
+        }
+        assert!(decode_shuffle_index(&sparse_data[8..]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_sparse_index() {
+        let encode_pairs = |pairs: &[(i64, i64)]| {
+            let mut index_data = pairs
+                .iter()
+                .flat_map(|&(partition_id, offset)| [partition_id, offset])
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>();
+            index_data.push(2); // sparse version
+            index_data
+        };
+        assert!(decode_shuffle_index(&encode_pairs(&[(0, 0), (3, 10)])).is_ok());
+
+        for (pairs, err) in [
+            (vec![(i64::MAX, 10)], "partitions"),
+            (vec![(-1, 10)], "partitions"),
+            (vec![(-1, 0), (3, 10)], "partition: -1"),
+            (vec![(5, 0), (3, 10)], "partition: 5"),
+            (vec![(1, 0), (1, 5), (3, 10)], "partition: 1"),
+            (vec![(0, 0), (1, 20), (3, 10)], "offset of partition 3"),
+            (vec![(0, -5), (3, 10)], "offset of partition 0"),
+            (vec![(0, 0), (1, 5), (2, 8), (1, 10)], "partition: 2"),
+        ] {
+            let e = decode_shuffle_index(&encode_pairs(&pairs)).unwrap_err();
+            assert!(e.to_string().contains(err), "{pairs:?}: {e}");
+        }
+    }
+}
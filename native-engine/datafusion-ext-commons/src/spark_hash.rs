@@ -17,12 +17,23 @@
 use arrow::{
     array::*,
     datatypes::{
-        ArrowDictionaryKeyType, ArrowNativeType, DataType, Int16Type, Int32Type, Int64Type,
-        Int8Type, TimeUnit,
+        ArrowDictionaryKeyType, ArrowNativeType, DataType, Date32Type, Decimal128Type, Int16Type,
+        Int32Type, Int64Type, Int8Type, TimeUnit,
+    },
+};
+use datafusion::common::Result;
+
+use crate::{
+    df_execution_err,
+    hash::{
+        hive::{hive_hash_bytes, hive_hash_decimal, hive_hash_long},
+        mur::spark_compatible_murmur3_hash,
+        xxhash::spark_compatible_xxhash64_hash,
     },
 };
 
-use crate::hash::{mur::spark_compatible_murmur3_hash, xxhash::spark_compatible_xxhash64_hash};
+// same as spark's Decimal.MAX_LONG_DIGITS
+const MAX_LONG_DIGITS: u8 = 18;
 
 pub fn create_murmur3_hashes(len: usize, arrays: &[ArrayRef], seed: i32) -> Vec<i32> {
     create_hashes(len, arrays, seed, |data: &[u8], seed: i32| {
@@ -36,6 +47,57 @@ pub fn create_xxhash64_hashes(len: usize, arrays: &[ArrayRef], seed: i64) -> Vec
     })
 }
 
+/// Creates hive hash values for every row, same as spark's HiveHash used by
+/// hive bucketing. columns are combined as `31 * hash + column_hash` and null
+/// values are hashed to 0.
+pub fn create_hive_hashes(len: usize, arrays: &[ArrayRef]) -> Result<Vec<i32>> {
+    let mut hashes = vec![0i32; len];
+    for array in arrays {
+        let col_hashes = hive_hash_array(array)?;
+        for (hash, col_hash) in hashes.iter_mut().zip(col_hashes) {
+            *hash = hash.wrapping_mul(31).wrapping_add(col_hash);
+        }
+    }
+    Ok(hashes)
+}
+
+fn hive_hash_array(array: &ArrayRef) -> Result<Vec<i32>> {
+    macro_rules! hash_values {
+        ($array:expr, $h:expr) => {{
+            let array = $array;
+            (0..array.len())
+                .map(|i| {
+                    if array.is_valid(i) {
+                        $h(array.value(i))
+                    } else {
+                        0
+                    }
+                })
+                .collect()
+        }};
+    }
+
+    Ok(match array.data_type() {
+        DataType::Null => vec![0; array.len()],
+        DataType::Boolean => hash_values!(array.as_boolean(), |v: bool| v as i32),
+        DataType::Int8 => hash_values!(array.as_primitive::<Int8Type>(), |v: i8| v as i32),
+        DataType::Int16 => hash_values!(array.as_primitive::<Int16Type>(), |v: i16| v as i32),
+        DataType::Int32 => hash_values!(array.as_primitive::<Int32Type>(), |v: i32| v),
+        DataType::Date32 => hash_values!(array.as_primitive::<Date32Type>(), |v: i32| v),
+        DataType::Int64 => hash_values!(array.as_primitive::<Int64Type>(), hive_hash_long),
+        DataType::Utf8 => hash_values!(array.as_string::<i32>(), hive_hash_bytes),
+        DataType::LargeUtf8 => hash_values!(array.as_string::<i64>(), hive_hash_bytes),
+        DataType::Binary => hash_values!(array.as_binary::<i32>(), hive_hash_bytes),
+        DataType::LargeBinary => hash_values!(array.as_binary::<i64>(), hive_hash_bytes),
+        &DataType::Decimal128(_, scale) => {
+            hash_values!(array.as_primitive::<Decimal128Type>(), |v: i128| {
+                hive_hash_decimal(v, scale)
+            })
+        }
+        other => return df_execution_err!("unsupported data type in hive hash: {other}"),
+    })
+}
+
 /// Creates hash values for every row, based on the values in the
 /// columns.
 ///
@@ -51,7 +113,8 @@ pub fn create_hashes<T: num::PrimInt>(
     if arrays.is_empty() {
         return vec![seed; len];
     }
-    let mut hash_buffer = vec![T::zero(); len];
+    // spark skips null values, so rows with null keys keep the seed
+    let mut hash_buffer = vec![seed; len];
 
     // hash first column
     hash_array(&arrays[0], &mut hash_buffer, seed, true, h);
@@ -126,22 +189,26 @@ fn hash_array<T: num::PrimInt>(
     }
 
     macro_rules! hash_array_decimal {
-        ($array_type:ident, $column:ident, $hashes:ident, $h:expr) => {
+        ($array_type:ident, $column:ident, $precision:expr, $hashes:ident, $h:expr) => {
             let array = $column.as_any().downcast_ref::<$array_type>().unwrap();
 
             if array.null_count() == 0 {
                 for (i, hash) in $hashes.iter_mut().enumerate() {
-                    *hash = $h(
-                        array.value(i).to_le_bytes().as_ref(),
+                    *hash = hash_decimal(
+                        &array.value(i).to_be_bytes(),
+                        $precision,
                         initial_seed_or!(*hash),
+                        $h,
                     );
                 }
             } else {
                 for (i, hash) in $hashes.iter_mut().enumerate() {
                     if !array.is_null(i) {
-                        *hash = $h(
-                            array.value(i).to_le_bytes().as_ref(),
+                        *hash = hash_decimal(
+                            &array.value(i).to_be_bytes(),
+                            $precision,
                             initial_seed_or!(*hash),
+                            $h,
                         );
                     }
                 }
@@ -223,8 +290,11 @@ fn hash_array<T: num::PrimInt>(
         DataType::LargeUtf8 => {
             hash_array!(LargeStringArray, array, hashes_buffer, h);
         }
-        DataType::Decimal128(..) => {
-            hash_array_decimal!(Decimal128Array, array, hashes_buffer, h);
+        &DataType::Decimal128(precision, _) => {
+            hash_array_decimal!(Decimal128Array, array, precision, hashes_buffer, h);
+        }
+        &DataType::Decimal256(precision, _) => {
+            hash_array_decimal!(Decimal256Array, array, precision, hashes_buffer, h);
         }
         DataType::Dictionary(index_type, _) => match index_type.as_ref() {
             DataType::Int8 => create_hashes_dictionary::<Int8Type, _>(
@@ -257,6 +327,13 @@ fn hash_array<T: num::PrimInt>(
             ),
             other => panic!("Unsupported dictionary type in hasher hashing: {other}"),
         },
+        DataType::Struct(_) if array.null_count() == 0 => {
+            // fields are hashed in order, same as hashing separated columns
+            let struct_array = array.as_any().downcast_ref::<StructArray>().unwrap();
+            for (i, col) in struct_array.columns().iter().enumerate() {
+                hash_array(col, hashes_buffer, initial_seed, is_initial && i == 0, h);
+            }
+        }
         _ => {
             for idx in 0..array.len() {
                 hash_one(array, idx, &mut hashes_buffer[idx], h);
@@ -315,9 +392,10 @@ fn hash_one<T: num::PrimInt>(
     }
 
     macro_rules! hash_one_decimal {
-        ($array_type:ident, $column:ident, $hash:ident, $idx:ident, $h:expr) => {
+        ($array_type:ident, $column:ident, $precision:expr, $hash:ident, $idx:ident, $h:expr) => {
             let array = $column.as_any().downcast_ref::<$array_type>().unwrap();
-            *$hash = $h(array.value($idx as usize).to_le_bytes().as_ref(), *$hash);
+            let be_bytes = array.value($idx as usize).to_be_bytes();
+            *$hash = hash_decimal(&be_bytes, $precision, *$hash, $h);
         };
     }
 
@@ -351,13 +429,13 @@ fn hash_one<T: num::PrimInt>(
             DataType::Float64 => {
                 hash_one_primitive!(Float64Array, col, f64, hash, idx, h);
             }
-            DataType::Timestamp(TimeUnit::Second, None) => {
+            DataType::Timestamp(TimeUnit::Second, _) => {
                 hash_one_primitive!(TimestampSecondArray, col, i64, hash, idx, h);
             }
-            DataType::Timestamp(TimeUnit::Millisecond, None) => {
+            DataType::Timestamp(TimeUnit::Millisecond, _) => {
                 hash_one_primitive!(TimestampMillisecondArray, col, i64, hash, idx, h);
             }
-            DataType::Timestamp(TimeUnit::Microsecond, None) => {
+            DataType::Timestamp(TimeUnit::Microsecond, _) => {
                 hash_one_primitive!(TimestampMicrosecondArray, col, i64, hash, idx, h);
             }
             DataType::Timestamp(TimeUnit::Nanosecond, _) => {
@@ -381,8 +459,11 @@ fn hash_one<T: num::PrimInt>(
             DataType::LargeUtf8 => {
                 hash_one_binary!(LargeStringArray, col, hash, idx, h);
             }
-            DataType::Decimal128(..) => {
-                hash_one_decimal!(Decimal128Array, col, hash, idx, h);
+            &DataType::Decimal128(precision, _) => {
+                hash_one_decimal!(Decimal128Array, col, precision, hash, idx, h);
+            }
+            &DataType::Decimal256(precision, _) => {
+                hash_one_decimal!(Decimal256Array, col, precision, hash, idx, h);
             }
             DataType::List(..) => {
                 let list_array = col.as_any().downcast_ref::<ListArray>().unwrap();
@@ -412,6 +493,35 @@ fn hash_one<T: num::PrimInt>(
     }
 }
 
+/// Hashes a decimal the same as spark: decimals of precision <= 18 are hashed
+/// as their unscaled long values, others as the bytes of their unscaled
+/// BigInteger values. unscaled values are already normalized to the scale of
+/// the data type, so values with trailing zeros need no extra handling.
+#[inline]
+fn hash_decimal<T: num::PrimInt>(
+    unscaled_be_bytes: &[u8],
+    precision: u8,
+    seed: T,
+    h: impl Fn(&[u8], T) -> T,
+) -> T {
+    if precision <= MAX_LONG_DIGITS {
+        let long_be_bytes = &unscaled_be_bytes[unscaled_be_bytes.len() - 8..];
+        let unscaled = i64::from_be_bytes(long_be_bytes.try_into().expect("8 bytes"));
+        return h(&unscaled.to_le_bytes(), seed);
+    }
+
+    // strip redundant sign bytes, same as java's BigInteger.toByteArray()
+    let mut start = 0;
+    while start + 1 < unscaled_be_bytes.len() {
+        let (byte, next_sign) = (unscaled_be_bytes[start], unscaled_be_bytes[start + 1] >> 7);
+        if (byte, next_sign) != (0x00, 0) && (byte, next_sign) != (0xff, 1) {
+            break;
+        }
+        start += 1;
+    }
+    h(&unscaled_be_bytes[start..], seed)
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
@@ -421,8 +531,8 @@ mod tests {
             make_array, Array, ArrayData, ArrayRef, Int32Array, Int64Array, Int8Array, MapArray,
             StringArray, StructArray, UInt32Array,
         },
-        buffer::Buffer,
-        datatypes::{DataType, Field, ToByteSlice},
+        buffer::{Buffer, NullBuffer},
+        datatypes::{i256, DataType, Field, Fields, ToByteSlice},
     };
 
     use super::*;
@@ -542,6 +652,182 @@ mod tests {
         assert_eq!(hashes, expected);
     }
 
+    #[test]
+    fn test_struct() {
+        let a = Arc::new(Int32Array::from(vec![
+            Some(1),
+            Some(2),
+            Some(0),
+            None,
+            Some(-1),
+        ]));
+        let b = Arc::new(StringArray::from(vec![
+            Some("a"),
+            None,
+            Some("x"),
+            Some("bc"),
+            Some("hello"),
+        ]));
+        let fields = Fields::from(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+        ]);
+        let nulls = NullBuffer::from(vec![true, true, false, true, true]);
+        let struct_array = Arc::new(StructArray::new(
+            fields.clone(),
+            vec![a.clone(), b.clone()],
+            Some(nulls),
+        )) as ArrayRef;
+
+        // generated with Murmur3Hash(Seq(struct), 42).eval() in Spark
+        let hashes = create_murmur3_hashes(5, &[struct_array.clone()], 42);
+        assert_eq!(
+            hashes,
+            vec![-936062819, 1765031574, 42, 1735422606, -1075704493]
+        );
+
+        // struct fields are chained with following columns
+        let c = Arc::new(Int32Array::from(vec![10, 20, 30, 40, 50])) as ArrayRef;
+        let hashes = create_murmur3_hashes(5, &[struct_array, c], 42);
+        assert_eq!(
+            hashes,
+            vec![75324779, 2014605499, 1796998381, -821488332, 1557163960]
+        );
+
+        // struct without nulls is hashed the same as its fields
+        let struct_array = Arc::new(StructArray::new(fields, vec![a.clone(), b.clone()], None));
+        assert_eq!(
+            create_murmur3_hashes(5, &[struct_array.clone()], 42),
+            create_murmur3_hashes(5, &[a.clone(), b.clone()], 42),
+        );
+        assert_eq!(
+            create_xxhash64_hashes(5, &[struct_array], 42),
+            create_xxhash64_hashes(5, &[a, b], 42),
+        );
+    }
+
+    #[test]
+    fn test_list_array() {
+        let list_array = Arc::new(ListArray::from_iter_primitive::<Int32Type, _, _>(vec![
+            Some(vec![Some(1), Some(2)]),
+            Some(vec![]),
+            None,
+            Some(vec![Some(3), None, Some(4)]),
+        ])) as ArrayRef;
+
+        // generated with Murmur3Hash(Seq(array), 42).eval() in Spark
+        let hashes = create_murmur3_hashes(4, &[list_array], 42);
+        assert_eq!(hashes, vec![-222940379, 42, 42, -1530663635]);
+    }
+
+    #[test]
+    fn test_decimal() -> Result<()> {
+        // decimals of precision <= 18 are hashed as unscaled longs, the same
+        // as Murmur3Hash(Seq(Literal(1L)), 42).eval() in test_i64
+        let small = Arc::new(
+            Decimal128Array::from(vec![Some(1), Some(0), Some(-1), None])
+                .with_precision_and_scale(10, 2)?,
+        ) as ArrayRef;
+        let longs = Arc::new(Int64Array::from(vec![Some(1), Some(0), Some(-1), None])) as ArrayRef;
+        let hashes = create_murmur3_hashes(4, &[small.clone()], 42);
+        let expected: Vec<i32> = [0x99f0149d_u32, 0x9c67b85d, 0xc8008529, 42]
+            .into_iter()
+            .map(|v| v as i32)
+            .collect();
+        assert_eq!(hashes, expected);
+        assert_eq!(
+            create_xxhash64_hashes(4, &[small], 42),
+            create_xxhash64_hashes(4, &[longs], 42),
+        );
+
+        // larger decimals are hashed as bytes of BigInteger.toByteArray(),
+        // computed by spark's Murmur3_x86_32.hashUnsafeBytes(), e.g. 1.50 is
+        // hashed as [0x00, 0x96]
+        let large = Arc::new(
+            Decimal128Array::from(vec![Some(150), Some(-150), Some(100), Some(0), None])
+                .with_precision_and_scale(38, 2)?,
+        ) as ArrayRef;
+        let hashes = create_murmur3_hashes(5, &[large.clone()], 42);
+        assert_eq!(
+            hashes,
+            vec![-339019914, -690534446, -295670906, -783713497, 42]
+        );
+
+        let large_scaled = Arc::new(
+            Decimal128Array::from(vec![
+                15000000000,
+                -15000000000,
+                10i128.pow(37),
+                -(10i128.pow(37)),
+            ])
+            .with_precision_and_scale(38, 10)?,
+        ) as ArrayRef;
+        let hashes = create_murmur3_hashes(4, &[large_scaled], 42);
+        assert_eq!(hashes, vec![-994477761, 1831729871, 216387744, 1461205389]);
+
+        // decimal256 is hashed the same as decimal128 of the same precision
+        let large256 = Arc::new(
+            Decimal256Array::from(vec![
+                Some(i256::from_i128(150)),
+                Some(i256::from_i128(-150)),
+                Some(i256::from_i128(100)),
+                Some(i256::ZERO),
+                None,
+            ])
+            .with_precision_and_scale(38, 2)?,
+        ) as ArrayRef;
+        assert_eq!(
+            create_murmur3_hashes(5, &[large256.clone()], 42),
+            create_murmur3_hashes(5, &[large.clone()], 42),
+        );
+        assert_eq!(
+            create_xxhash64_hashes(5, &[large256], 42),
+            create_xxhash64_hashes(5, &[large], 42),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_hive_hashes() -> Result<()> {
+        let a = Arc::new(Int32Array::from(vec![Some(1), None, Some(2), Some(-1)])) as ArrayRef;
+        let b = Arc::new(StringArray::from(vec![
+            Some("a"),
+            Some("a"),
+            None,
+            Some("abc"),
+        ]));
+        let c = Arc::new(
+            Decimal128Array::from(vec![Some(150), None, Some(10000), Some(-1800)])
+                .with_precision_and_scale(10, 2)?,
+        );
+        let d = Arc::new(Date32Array::from(vec![
+            Some(19000),
+            Some(0),
+            None,
+            Some(-1),
+        ]));
+        let e = Arc::new(Int64Array::from(vec![
+            Some(1),
+            Some(-1),
+            Some(i64::MAX),
+            None,
+        ]));
+
+        // computed by hive's spec: hash = 31 * hash + column_hash
+        let hashes = create_hive_hashes(4, &[a.clone(), b, c])?;
+        assert_eq!(hashes, vec![4434, 3007, 5022, 2985455]);
+        let hashes = create_hive_hashes(4, &[d, e])?;
+        assert_eq!(hashes, vec![589001, 0, i32::MIN, -31]);
+
+        // single column is hashed to the column hash
+        let hashes = create_hive_hashes(4, &[a])?;
+        assert_eq!(hashes, vec![1, 0, 2, -1]);
+
+        let unsupported = Arc::new(Float64Array::from(vec![1.0])) as ArrayRef;
+        assert!(create_hive_hashes(1, &[unsupported]).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_map_array() {
         // Construct key and values
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::{
+    collections::HashMap,
     fmt::{Debug, Formatter},
     io::Cursor,
     sync::Arc,
@@ -32,7 +33,7 @@ use datafusion::{
     common::{cast::as_binary_array, Result},
     physical_expr::PhysicalExprRef,
 };
-use datafusion_ext_commons::{downcast_any, suggested_batch_mem_size};
+use datafusion_ext_commons::{df_execution_err, downcast_any, suggested_batch_mem_size};
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 
@@ -42,7 +43,8 @@ use crate::{
         agg::{Agg, IdxSelection},
         agg_hash_map::AggHashMapKey,
         spark_udaf_wrapper::{AccUDAFBufferRowsColumn, SparkUDAFMemTracker, SparkUDAFWrapper},
-        AggExecMode, AggExpr, AggMode, GroupingExpr, AGG_BUF_COLUMN_NAME,
+        AggExecMode, AggExpr, AggMode, GroupingExpr, AGG_BUF_COLUMN_NAME, AGG_BUF_VERSION,
+        AGG_BUF_VERSION_KEY,
     },
     common::{
         cached_exprs_evaluator::CachedExprsEvaluator,
@@ -113,6 +115,14 @@ impl AggContext {
         let need_final_merge = aggs.iter().any(|agg| agg.mode == AggMode::Final);
         assert!(!(need_final_merge && aggs.iter().any(|agg| agg.mode != AggMode::Final)));
 
+        // merged states must be serialized in the same layout
+        if need_partial_merge {
+            match input_schema.fields().last() {
+                Some(field) => check_agg_buf_version(field)?,
+                None => return df_execution_err!("agg: missing agg buffer column"),
+            }
+        }
+
         let need_partial_update_aggs: Vec<(usize, Arc<dyn Agg>)> = aggs
             .iter()
             .enumerate()
@@ -136,7 +146,11 @@ impl AggContext {
                 ));
             }
         } else {
-            agg_fields.push(Field::new(AGG_BUF_COLUMN_NAME, DataType::Binary, false));
+            agg_fields.push(
+                Field::new(AGG_BUF_COLUMN_NAME, DataType::Binary, false).with_metadata(
+                    HashMap::from([(AGG_BUF_VERSION_KEY.to_string(), AGG_BUF_VERSION.to_string())]),
+                ),
+            );
         }
         let agg_schema = Arc::new(Schema::new(agg_fields));
         let output_schema = Arc::new(Schema::new(
@@ -456,7 +470,7 @@ impl AggContext {
         exec_ctx
             .baseline_metrics()
             .record_output(output_batch.num_rows());
-        sender.send(output_batch).await;
+        sender.send(output_batch).await?;
         return Ok(());
     }
 
@@ -475,3 +489,21 @@ impl AggContext {
             .get_or_try_init(|| SparkUDAFMemTracker::try_new())
     }
 }
+
+/// checks the agg buffer field is written in the current state layout. fields
+/// without a version tag (e.g. schemas passed from the JVM side) are accepted.
+pub fn check_agg_buf_version(field: &Field) -> Result<()> {
+    if field.data_type() != &DataType::Binary {
+        return df_execution_err!(
+            "agg: expect binary agg buffer column, found {}: {}",
+            field.name(),
+            field.data_type(),
+        );
+    }
+    match field.metadata().get(AGG_BUF_VERSION_KEY) {
+        Some(version) if version != AGG_BUF_VERSION => df_execution_err!(
+            "agg: incompatible agg buffer version: {version}, expect {AGG_BUF_VERSION}"
+        ),
+        _ => Ok(()),
+    }
+}
@@ -193,7 +193,7 @@ impl AggTable {
                     .record_output(batch.num_rows());
                 self.output_time
                     .exclude_timer_async(sender.send(batch))
-                    .await;
+                    .await?;
 
                 // free memory of the output batch
                 // this is not precise because the used memory is accounted by records and
@@ -291,7 +291,7 @@ impl AggTable {
                 self.exec_ctx
                     .baseline_metrics()
                     .record_output(batch.num_rows());
-                sender.send(batch).await;
+                sender.send(batch).await?;
             }
             acc_table.resize(0);
         }
@@ -35,6 +35,12 @@ use datafusion::physical_expr::PhysicalExpr;
 
 pub const AGG_BUF_COLUMN_NAME: &str = "#9223372036854775807";
 
+/// metadata key of the agg buffer field, tagging the layout of serialized
+/// accumulator states. bump the version when any state layout changes, so
+/// that final aggs reject states written by incompatible versions.
+pub const AGG_BUF_VERSION_KEY: &str = "blaze.agg_buf.version";
+pub const AGG_BUF_VERSION: &str = "1";
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AggExecMode {
     HashAgg,
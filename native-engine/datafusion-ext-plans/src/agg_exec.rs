@@ -212,7 +212,7 @@ fn execute_agg_with_grouping_hash(
 ) -> Result<SendableRecordBatchStream> {
     // create tables
     let tables = Arc::new(AggTable::try_new(agg_ctx.clone(), exec_ctx.clone())?);
-    MemManager::register_consumer(tables.clone(), true);
+    MemManager::register_consumer(tables.clone(), true)?;
 
     // start processing input batches
     let mut coalesced = exec_ctx.coalesce_with_default_batch_size(input_stream);
@@ -313,7 +313,7 @@ fn execute_agg_no_grouping(
                 &RecordBatchOptions::new().with_row_count(Some(1)),
             )?;
             exec_ctx.baseline_metrics().record_output(1);
-            sender.send(batch).await;
+            sender.send(batch).await?;
             log::info!("aggregate exec (no grouping) outputting one record");
             Ok(())
         }))
@@ -351,7 +351,7 @@ fn execute_agg_sorted(
                     staging_keys.clear();
                     staging_acc_table.resize(0);
                     exec_ctx.baseline_metrics().record_output(num_rows);
-                    sender.send((batch)).await;
+                    sender.send((batch)).await?;
                 }};
             }
 
@@ -408,20 +408,28 @@ fn execute_agg_sorted(
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{collections::HashMap, sync::Arc};
 
     use arrow::{
-        array::Int32Array,
-        datatypes::{DataType, Field, Schema},
+        array::{Array, AsArray, Int32Array, Int64Array},
+        compute::concat_batches,
+        datatypes::{DataType, Field, Float64Type, Int32Type, Int64Type, Schema, SchemaRef},
         record_batch::RecordBatch,
     };
+    use bytes::Bytes;
     use datafusion::{
         assert_batches_sorted_eq,
         common::{Result, ScalarValue},
         physical_expr::{expressions as phys_expr, expressions::Column},
-        physical_plan::{common, memory::MemoryExec, ExecutionPlan},
+        physical_plan::{
+            common,
+            memory::MemoryExec,
+            metrics::{ExecutionPlanMetricsSet, Time},
+            ExecutionPlan,
+        },
         prelude::SessionContext,
     };
+    use itertools::Itertools;
 
     use crate::{
         agg::{
@@ -429,10 +437,16 @@ mod test {
             AggExecMode::HashAgg,
             AggExpr, AggFunction,
             AggMode::{Final, Partial},
-            GroupingExpr,
+            GroupingExpr, AGG_BUF_VERSION, AGG_BUF_VERSION_KEY,
         },
         agg_exec::AggExec,
+        common::execution_context::ExecutionContext,
         memmgr::MemManager,
+        shuffle::{
+            buffered_data::read_segment,
+            sort_repartitioner::{read_index_file, SortShuffleRepartitioner},
+            HashAlgorithm, Partitioning, ShuffleRepartitioner,
+        },
     };
 
     fn build_table_i32(
@@ -676,6 +690,209 @@ mod test {
         assert_batches_sorted_eq!(expected, &batches);
         Ok(())
     }
+
+    fn two_phase_aggs(schema: &SchemaRef) -> Result<(Vec<AggExpr>, Vec<AggExpr>)> {
+        let partial_aggs = [
+            ("sum", AggFunction::Sum, DataType::Int64),
+            ("cnt", AggFunction::Count, DataType::Int64),
+            ("avg", AggFunction::Avg, DataType::Float64),
+        ]
+        .into_iter()
+        .map(|(field_name, agg_function, data_type)| {
+            Ok(AggExpr {
+                field_name: field_name.to_string(),
+                mode: Partial,
+                agg: create_agg(
+                    agg_function,
+                    &[phys_expr::col("val", schema)?],
+                    schema,
+                    data_type,
+                )?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+        let final_aggs = partial_aggs
+            .iter()
+            .cloned()
+            .map(|mut agg| {
+                agg.agg = agg
+                    .agg
+                    .with_new_exprs(vec![Arc::new(phys_expr::Literal::new(ScalarValue::Null))])?;
+                agg.mode = Final;
+                Ok(agg)
+            })
+            .collect::<Result<_>>()?;
+        Ok((partial_aggs, final_aggs))
+    }
+
+    fn final_agg_exec(final_aggs: Vec<AggExpr>, input: Arc<dyn ExecutionPlan>) -> Result<AggExec> {
+        AggExec::try_new(
+            HashAgg,
+            vec![GroupingExpr {
+                field_name: "key".to_string(),
+                expr: Arc::new(Column::new("key", 0)),
+            }],
+            final_aggs,
+            false,
+            input,
+        )
+    }
+
+    /// collects (sum, count, avg) of each group
+    fn collect_results(batches: &[RecordBatch]) -> HashMap<i32, (Option<i64>, i64, Option<f64>)> {
+        let mut results = HashMap::new();
+        for batch in batches {
+            let key_col = batch.column(0).as_primitive::<Int32Type>();
+            let sum_col = batch.column(1).as_primitive::<Int64Type>();
+            let cnt_col = batch.column(2).as_primitive::<Int64Type>();
+            let avg_col = batch.column(3).as_primitive::<Float64Type>();
+            for i in 0..batch.num_rows() {
+                let result = (
+                    sum_col.is_valid(i).then(|| sum_col.value(i)),
+                    cnt_col.value(i),
+                    avg_col.is_valid(i).then(|| avg_col.value(i)),
+                );
+                assert!(results.insert(key_col.value(i), result).is_none());
+            }
+        }
+        results
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_two_phase_agg_through_shuffle() -> Result<()> {
+        MemManager::init(1000000);
+        let session_ctx = SessionContext::new();
+        let task_ctx = session_ctx.task_ctx();
+
+        let batches = (0..4)
+            .map(|batch_id| {
+                let keys = Int32Array::from_iter_values((0..1000).map(|i| (i * 7 + batch_id) % 97));
+                let vals = (0..1000)
+                    .map(|i| Some(i as i64 * 3 - batch_id as i64).filter(|_| i % 11 != 0))
+                    .collect::<Int64Array>();
+                RecordBatch::try_from_iter_with_nullable(vec![
+                    ("key", Arc::new(keys) as _, false),
+                    ("val", Arc::new(vals) as _, true),
+                ])
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let schema = batches[0].schema();
+        let (partial_aggs, final_aggs) = two_phase_aggs(&schema)?;
+        let partial_agg = |input: Arc<dyn ExecutionPlan>| {
+            AggExec::try_new(
+                HashAgg,
+                vec![GroupingExpr {
+                    field_name: "key".to_string(),
+                    expr: phys_expr::col("key", &schema)?,
+                }],
+                partial_aggs.clone(),
+                false,
+                input,
+            )
+        };
+
+        // expected results, aggregated without shuffling
+        let input = Arc::new(MemoryExec::try_new(
+            &[batches.clone()],
+            schema.clone(),
+            None,
+        )?);
+        let single_phase = final_agg_exec(final_aggs.clone(), Arc::new(partial_agg(input)?))?;
+        let expected =
+            collect_results(&common::collect(single_phase.execute(0, task_ctx.clone())?).await?);
+
+        // partial states are tagged with the layout version
+        let input = Arc::new(MemoryExec::try_new(
+            &[batches[0..2].to_vec(), batches[2..4].to_vec()],
+            schema.clone(),
+            None,
+        )?);
+        let partial_agg = Arc::new(partial_agg(input)?);
+        let partial_schema = partial_agg.schema();
+        let agg_buf_field = partial_schema.fields().last().unwrap();
+        assert_eq!(
+            agg_buf_field.metadata().get(AGG_BUF_VERSION_KEY),
+            Some(&AGG_BUF_VERSION.to_string()),
+        );
+
+        // shuffle partial states of each map partition by key
+        let num_reduce_partitions = 3;
+        let output_dir = tempfile::tempdir()?;
+        let mut reduce_partitions = vec![vec![]; num_reduce_partitions];
+        for map_partition in 0..2 {
+            let metrics = ExecutionPlanMetricsSet::new();
+            let exec_ctx = ExecutionContext::new(
+                task_ctx.clone(),
+                map_partition,
+                partial_schema.clone(),
+                &metrics,
+            );
+            let data_file = output_dir.path().join(format!("data.{map_partition}"));
+            let index_file = output_dir.path().join(format!("index.{map_partition}"));
+            let repartitioner = Arc::new(SortShuffleRepartitioner::new(
+                exec_ctx,
+                data_file.to_string_lossy().to_string(),
+                index_file.to_string_lossy().to_string(),
+                Partitioning::HashPartitioning(
+                    vec![Arc::new(Column::new("key", 0))],
+                    num_reduce_partitions,
+                    HashAlgorithm::default(),
+                ),
+                Time::new(),
+            ));
+            MemManager::register_consumer(repartitioner.clone(), true)?;
+            let partial_output = partial_agg.execute(map_partition, task_ctx.clone())?;
+            for batch in common::collect(partial_output).await? {
+                repartitioner.insert_batch(batch).await?;
+            }
+            repartitioner.shuffle_write().await?;
+
+            let data = std::fs::read(&data_file)?;
+            let offsets = read_index_file(&index_file.to_string_lossy())?;
+            for (reduce_partition, (&beg, &end)) in offsets.iter().tuple_windows().enumerate() {
+                let segment = Bytes::from(data[beg as usize..end as usize].to_vec());
+                reduce_partitions[reduce_partition].extend(read_segment(segment, &partial_schema)?);
+            }
+        }
+
+        // merge shuffled states in final mode
+        let mut results = HashMap::new();
+        for reduce_batches in reduce_partitions {
+            let input = Arc::new(MemoryExec::try_new(
+                &[reduce_batches],
+                partial_schema.clone(),
+                None,
+            )?);
+            let final_agg = final_agg_exec(final_aggs.clone(), input)?;
+            let output = common::collect(final_agg.execute(0, task_ctx.clone())?).await?;
+            for (key, result) in collect_results(&output) {
+                assert!(
+                    results.insert(key, result).is_none(),
+                    "key={key} in multiple partitions"
+                );
+            }
+        }
+        assert_eq!(results.len(), 97);
+        assert_eq!(results, expected);
+
+        // states of another layout version are rejected
+        let output = common::collect(partial_agg.execute(0, task_ctx.clone())?).await?;
+        let batch = concat_batches(&partial_schema, &output)?;
+        let mut fields = partial_schema.fields().to_vec();
+        let agg_buf_field = fields.pop().unwrap();
+        fields.push(Arc::new(agg_buf_field.as_ref().clone().with_metadata(
+            HashMap::from([(AGG_BUF_VERSION_KEY.to_string(), "0".to_string())]),
+        )));
+        let mismatched_schema = Arc::new(Schema::new(fields));
+        let batch = RecordBatch::try_new(mismatched_schema.clone(), batch.columns().to_vec())?;
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![batch]],
+            mismatched_schema,
+            None,
+        )?);
+        assert!(final_agg_exec(final_aggs, input).is_err());
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -683,24 +900,26 @@ mod fuzztest {
     use std::{collections::HashMap, sync::Arc};
 
     use arrow::{
-        array::{Array, ArrayRef, AsArray, Float64Builder, Int64Builder},
+        array::{Array, ArrayRef, AsArray, Float64Builder, Int32Array, Int64Array, Int64Builder},
         compute::concat_batches,
-        datatypes::{DataType, Float64Type, Int64Type},
+        datatypes::{DataType, Float64Type, Int32Type, Int64Type},
         record_batch::RecordBatch,
     };
     use datafusion::{
-        common::Result,
-        physical_expr::expressions as phys_expr,
+        common::{Result, ScalarValue},
+        physical_expr::{expressions as phys_expr, expressions::Column},
         physical_plan::memory::MemoryExec,
         prelude::{SessionConfig, SessionContext},
     };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use crate::{
         agg::{
+            agg::create_agg,
             count::AggCount,
             sum::AggSum,
             AggExecMode::HashAgg,
-            AggExpr,
+            AggExpr, AggFunction,
             AggMode::{Final, Partial},
             GroupingExpr,
         },
@@ -838,4 +1057,153 @@ mod fuzztest {
         }
         Ok(())
     }
+    /// collects (sum, count, min, max, avg) of each group
+    fn collect_agg_results(
+        batches: &[RecordBatch],
+    ) -> HashMap<Option<i32>, (Option<i64>, i64, Option<i64>, Option<i64>, Option<f64>)> {
+        let mut results = HashMap::new();
+        for batch in batches {
+            let key_col = batch.column(0).as_primitive::<Int32Type>();
+            let sum_col = batch.column(1).as_primitive::<Int64Type>();
+            let cnt_col = batch.column(2).as_primitive::<Int64Type>();
+            let min_col = batch.column(3).as_primitive::<Int64Type>();
+            let max_col = batch.column(4).as_primitive::<Int64Type>();
+            let avg_col = batch.column(5).as_primitive::<Float64Type>();
+            for i in 0..batch.num_rows() {
+                let result = (
+                    sum_col.is_valid(i).then(|| sum_col.value(i)),
+                    cnt_col.value(i),
+                    min_col.is_valid(i).then(|| min_col.value(i)),
+                    max_col.is_valid(i).then(|| max_col.value(i)),
+                    avg_col.is_valid(i).then(|| avg_col.value(i)),
+                );
+                let key = key_col.is_valid(i).then(|| key_col.value(i));
+                assert!(
+                    results.insert(key, result).is_none(),
+                    "duplicated group: {key:?}"
+                );
+            }
+        }
+        results
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn fuzztest_with_null_groups() -> Result<()> {
+        MemManager::init(1000); // small memory config to trigger spill
+        let session_ctx =
+            SessionContext::new_with_config(SessionConfig::new().with_batch_size(1000));
+        let task_ctx = session_ctx.task_ctx();
+        let mut rng = StdRng::seed_from_u64(37);
+
+        let mut batches = vec![];
+        for _batch_id in 0..50 {
+            let keys: ArrayRef = Arc::new(
+                (0..2000)
+                    .map(|_| {
+                        Some(rng.random_range(0..5000)).filter(|_| rng.random_range(0..20) > 0)
+                    })
+                    .collect::<Int32Array>(),
+            );
+            let vals: ArrayRef = Arc::new(
+                (0..2000)
+                    .map(|_| Some(rng.random_range(-1000000..1000000)).filter(|_| rng.random()))
+                    .collect::<Int64Array>(),
+            );
+            batches.push(RecordBatch::try_from_iter_with_nullable(vec![
+                ("key", keys, true),
+                ("val", vals, true),
+            ])?);
+        }
+        let schema = batches[0].schema();
+
+        let aggs = [
+            ("sum", AggFunction::Sum, DataType::Int64),
+            ("cnt", AggFunction::Count, DataType::Int64),
+            ("min", AggFunction::Min, DataType::Int64),
+            ("max", AggFunction::Max, DataType::Int64),
+            ("avg", AggFunction::Avg, DataType::Float64),
+        ]
+        .into_iter()
+        .map(|(field_name, agg_function, data_type)| {
+            Ok(AggExpr {
+                field_name: field_name.to_string(),
+                mode: Partial,
+                agg: create_agg(
+                    agg_function,
+                    &[phys_expr::col("val", &schema)?],
+                    &schema,
+                    data_type,
+                )?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+        let input = Arc::new(MemoryExec::try_new(
+            &[batches.clone()],
+            schema.clone(),
+            None,
+        )?);
+        let partial_agg = Arc::new(AggExec::try_new(
+            HashAgg,
+            vec![GroupingExpr {
+                field_name: "key".to_string(),
+                expr: phys_expr::col("key", &schema)?,
+            }],
+            aggs.clone(),
+            false,
+            input,
+        )?);
+        let final_agg = Arc::new(AggExec::try_new(
+            HashAgg,
+            vec![GroupingExpr {
+                field_name: "key".to_string(),
+                expr: Arc::new(Column::new("key", 0)),
+            }],
+            aggs.into_iter()
+                .map(|mut agg| {
+                    agg.agg = agg
+                        .agg
+                        .with_new_exprs(vec![Arc::new(phys_expr::Literal::new(
+                            ScalarValue::Null,
+                        ))])?;
+                    agg.mode = Final;
+                    Ok(agg)
+                })
+                .collect::<Result<_>>()?,
+            false,
+            partial_agg,
+        )?);
+        let output = datafusion::physical_plan::collect(final_agg, task_ctx).await?;
+        let results = collect_agg_results(&output);
+
+        let df_ctx = SessionContext::new();
+        df_ctx.register_batch("t", concat_batches(&schema, &batches)?)?;
+        let expected_output = df_ctx
+            .sql("SELECT key, sum(val), count(val), min(val), max(val), avg(val) FROM t GROUP BY key")
+            .await?
+            .collect()
+            .await?;
+        let expected_results = collect_agg_results(&expected_output);
+
+        assert!(expected_results.contains_key(&None));
+        assert_eq!(results.len(), expected_results.len());
+        for (key, expected) in expected_results {
+            let result = results[&key];
+            assert_eq!(
+                (result.0, result.1, result.2, result.3),
+                (expected.0, expected.1, expected.2, expected.3),
+                "key={key:?}"
+            );
+            let avg_matched = match (result.4, expected.4) {
+                (Some(avg), Some(expected_avg)) => (avg - expected_avg).abs() < 1e-6,
+                (avg, expected_avg) => avg == expected_avg,
+            };
+            assert!(
+                avg_matched,
+                "key={key:?}, avg: {:?} vs {:?}",
+                result.4, expected.4
+            );
+        }
+        Ok(())
+    }
 }
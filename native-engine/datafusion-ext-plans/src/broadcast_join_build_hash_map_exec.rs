@@ -44,8 +44,8 @@ use once_cell::sync::OnceCell;
 
 use crate::{
     common::{
-        execution_context::ExecutionContext, stream_exec::create_record_batch_stream_exec,
-        timer_helper::TimerHelper,
+        execution_context::ExecutionContext, spillable_row_buffer::SpillableRowBuffer,
+        stream_exec::create_record_batch_stream_exec, timer_helper::TimerHelper,
     },
     joins::join_hash_map::{join_hash_map_schema, JoinHashMap},
     sort_exec::create_default_ascending_sort_exec,
@@ -155,7 +155,7 @@ pub fn execute_build_hash_map(
                 .unwrap_or(i32::MAX) as usize;
 
             let data_schema = input.schema();
-            let mut staging_batches: Vec<RecordBatch> = vec![];
+            let staging = SpillableRowBuffer::try_new(exec_ctx.clone(), data_schema.clone())?;
             let mut staging_num_rows = 0;
             let mut stating_mem_size = 0;
             let mut fallback_to_sorted = false;
@@ -165,7 +165,7 @@ pub fn execute_build_hash_map(
                 .await
                 .transpose()?
             {
-                staging_batches.push(batch.clone());
+                staging.push(batch.clone()).await?;
                 if smj_fallback_enabled {
                     staging_num_rows += batch.num_rows();
                     stating_mem_size += batch.get_batch_mem_size();
@@ -180,12 +180,19 @@ pub fn execute_build_hash_map(
                 }
             }
 
+            // staging data is spilled, the hash map does not fit in memory
+            if smj_fallback_enabled && staging.num_spills().await > 0 {
+                fallback_to_sorted = true;
+            }
+            let staging_batches = staging.scan().await;
+            drop(staging);
+
             // no fallbacks - generate one hashmap batch
             if !fallback_to_sorted {
-                let data_batch =
-                    coalesce_batches_unchecked(data_schema, &std::mem::take(&mut staging_batches));
+                let staging_batches = staging_batches.collect::<Result<Vec<_>>>()?;
+                let data_batch = coalesce_batches_unchecked(data_schema, &staging_batches);
                 let hash_map = JoinHashMap::create_from_data_batch(data_batch, &keys)?;
-                sender.send(hash_map.into_hash_map_batch()?).await;
+                sender.send(hash_map.into_hash_map_batch()?).await?;
                 exec_ctx
                     .baseline_metrics()
                     .elapsed_compute()
@@ -197,8 +204,7 @@ pub fn execute_build_hash_map(
             // sort all input data
             let input: SendableRecordBatchStream = Box::pin(RecordBatchStreamAdapter::new(
                 data_schema,
-                futures::stream::iter(staging_batches.into_iter().map(|batch| Ok(batch)))
-                    .chain(input),
+                futures::stream::iter(staging_batches).chain(input),
             ));
             let input_exec = create_record_batch_stream_exec(input, exec_ctx.partition_id())?;
             let sort_exec = create_default_ascending_sort_exec(
@@ -223,7 +229,7 @@ pub fn execute_build_hash_map(
                         .chain(Some(null_table_data_column))
                         .collect(),
                 )?;
-                sender.send(sorted_hash_map_batch).await;
+                sender.send(sorted_hash_map_batch).await?;
             }
             exec_ctx
                 .baseline_metrics()
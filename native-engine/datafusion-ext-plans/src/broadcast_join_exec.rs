@@ -28,6 +28,7 @@ use arrow::{
 };
 use arrow_schema::Schema;
 use async_trait::async_trait;
+use bytesize::ByteSize;
 use datafusion::{
     common::{JoinSide, Result, Statistics},
     execution::context::TaskContext,
@@ -72,6 +73,7 @@ use crate::{
         join_utils::{JoinType, JoinType::*},
         JoinParams, JoinProjection,
     },
+    memmgr::{MemConsumer, MemConsumerInfo, MemManager},
     sort_exec::create_default_ascending_sort_exec,
     sort_merge_join_exec::SortMergeJoinExec,
 };
@@ -444,7 +446,7 @@ async fn execute_join_with_smj_fallback(
 
     // send all outputs
     while let Some(batch) = join_output.next().await.transpose()? {
-        sender.send(batch).await;
+        sender.send(batch).await?;
     }
 
     // elapsed_compute = sort time + merge time
@@ -511,9 +513,10 @@ async fn execute_join(
             build_time.clone(),
         )?
     };
+    let cached_build_hash_map_id = cached_build_hash_map_id.filter(|_| is_built);
     let built_collected = collect_join_hash_map(
         Box::pin(built_input.peekable()),
-        cached_build_hash_map_id.filter(|_| is_built),
+        cached_build_hash_map_id.clone(),
         &map_keys,
         build_time,
     )
@@ -521,6 +524,9 @@ async fn execute_join(
 
     match built_collected {
         CollectJoinHashMapResult::Map(map) => {
+            let _build_side_mem =
+                BuildSideMemConsumer::try_register(&map, cached_build_hash_map_id.as_deref())
+                    .await?;
             let join_with_map = execute_join_with_map(
                 probed_plan,
                 map,
@@ -554,6 +560,77 @@ async fn execute_join(
     Ok(())
 }
 
+/// accounts memory of the broadcast build side, which cannot be spilled.
+/// a cached hash map is accounted only once, the consumer is shared by all
+/// tasks using it and deregistered when the last of them finishes.
+struct BuildSideMemConsumer {
+    mem_consumer_info: Option<Weak<MemConsumerInfo>>,
+}
+
+impl BuildSideMemConsumer {
+    async fn try_register(map: &JoinHashMap, cached_id: Option<&str>) -> Result<Arc<Self>> {
+        type Slots = tokio::sync::Mutex<HashMap<String, Weak<BuildSideMemConsumer>>>;
+        static CACHED_BUILD_SIDE_MEM: OnceCell<Slots> = OnceCell::new();
+
+        let Some(cached_id) = cached_id else {
+            return Self::try_register_new(map).await;
+        };
+        let mut cached_build_side_mem = CACHED_BUILD_SIDE_MEM
+            .get_or_init(|| Slots::default())
+            .lock()
+            .await;
+        cached_build_side_mem.retain(|_, v| v.strong_count() > 0);
+        if let Some(consumer) = cached_build_side_mem.get(cached_id).and_then(Weak::upgrade) {
+            return Ok(consumer);
+        }
+        let consumer = Self::try_register_new(map).await?;
+        cached_build_side_mem.insert(cached_id.to_string(), Arc::downgrade(&consumer));
+        Ok(consumer)
+    }
+
+    async fn try_register_new(map: &JoinHashMap) -> Result<Arc<Self>> {
+        let mm = MemManager::get()?;
+        let mem_size = map.mem_size();
+        if mm.mem_unspillable() + mem_size > mm.total() {
+            return df_execution_err!(
+                "broadcast build side exceeds memory ({}), {}",
+                ByteSize(mem_size as u64),
+                mm.status_string(),
+            );
+        }
+
+        let consumer = Arc::new(Self {
+            mem_consumer_info: None,
+        });
+        MemManager::register_consumer(consumer.clone(), false)?;
+        consumer.update_mem_used(mem_size).await?;
+        Ok(consumer)
+    }
+}
+
+#[async_trait]
+impl MemConsumer for BuildSideMemConsumer {
+    fn name(&self) -> &str {
+        "BroadcastJoinBuildSide"
+    }
+
+    fn set_consumer_info(&mut self, consumer_info: Weak<MemConsumerInfo>) {
+        self.mem_consumer_info = Some(consumer_info);
+    }
+
+    fn get_consumer_info(&self) -> &Weak<MemConsumerInfo> {
+        self.mem_consumer_info
+            .as_ref()
+            .expect("consumer info not set")
+    }
+}
+
+impl Drop for BuildSideMemConsumer {
+    fn drop(&mut self) {
+        MemManager::deregister_consumer(self);
+    }
+}
+
 enum CollectJoinHashMapResult {
     Map(Arc<JoinHashMap>),
     SortedStream(Pin<Box<Peekable<SendableRecordBatchStream>>>),
@@ -663,3 +740,45 @@ async fn get_cached_join_hash_map<Fut: Future<Output = Result<CollectJoinHashMap
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::array::{Int32Array, RecordBatch};
+    use datafusion::{common::Result, physical_expr::expressions::Column};
+
+    use crate::{
+        broadcast_join_exec::BuildSideMemConsumer, joins::join_hash_map::JoinHashMap,
+        memmgr::MemManager,
+    };
+
+    #[tokio::test]
+    async fn test_cached_build_side_mem_accounted_once() -> Result<()> {
+        MemManager::with_isolated_manager(100000000, async {
+            let keys = Arc::new(Int32Array::from_iter_values(0..1000));
+            let data_batch = RecordBatch::try_from_iter(vec![("a", keys as _)])?;
+            let map =
+                JoinHashMap::create_from_data_batch(data_batch, &[Arc::new(Column::new("a", 0))])?;
+            let mem_size = map.mem_size();
+
+            // tasks using the same cached map share one consumer
+            let cached1 = BuildSideMemConsumer::try_register(&map, Some("cached")).await?;
+            let cached2 = BuildSideMemConsumer::try_register(&map, Some("cached")).await?;
+            assert!(Arc::ptr_eq(&cached1, &cached2));
+            assert_eq!(MemManager::get()?.mem_unspillable(), mem_size);
+
+            let uncached = BuildSideMemConsumer::try_register(&map, None).await?;
+            assert_eq!(MemManager::get()?.mem_unspillable(), mem_size * 2);
+            drop(uncached);
+
+            // released when the last user finishes
+            drop(cached1);
+            assert_eq!(MemManager::get()?.mem_unspillable(), mem_size);
+            drop(cached2);
+            assert_eq!(MemManager::get()?.mem_unspillable(), 0);
+            Ok(())
+        })
+        .await
+    }
+}
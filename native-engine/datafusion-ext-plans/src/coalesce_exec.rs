@@ -0,0 +1,469 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    any::Any,
+    fmt::{Debug, Formatter},
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc, Weak,
+    },
+};
+
+use arrow::{datatypes::SchemaRef, record_batch::RecordBatch};
+use async_trait::async_trait;
+use datafusion::{
+    common::Result,
+    execution::context::TaskContext,
+    physical_expr::EquivalenceProperties,
+    physical_plan::{
+        metrics::{Count, ExecutionPlanMetricsSet, MetricsSet},
+        DisplayAs, DisplayFormatType, ExecutionMode, ExecutionPlan, ExecutionPlanProperties,
+        PlanProperties, SendableRecordBatchStream, Statistics,
+    },
+};
+use datafusion_ext_commons::{
+    arrow::{array_size::BatchSize, coalesce::coalesce_batches_unchecked},
+    batch_size, suggested_batch_mem_size,
+};
+use futures::StreamExt;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+
+use crate::{
+    common::execution_context::{ExecutionContext, WrappedRecordBatchSender},
+    memmgr::{MemConsumer, MemConsumerInfo, MemManager},
+};
+
+/// coalesces small batches until either the row count or the memory size
+/// target is reached. oversized input batches are split. buffered batches are
+/// registered to the mem manager and flushed early under memory pressure.
+#[derive(Debug)]
+pub struct CoalesceExec {
+    input: Arc<dyn ExecutionPlan>,
+    batch_size: usize,
+    batch_mem_size: usize,
+    metrics: ExecutionPlanMetricsSet,
+    props: OnceCell<PlanProperties>,
+}
+
+impl CoalesceExec {
+    pub fn new(input: Arc<dyn ExecutionPlan>) -> Self {
+        Self {
+            input,
+            batch_size: batch_size(),
+            batch_mem_size: suggested_batch_mem_size(),
+            metrics: ExecutionPlanMetricsSet::new(),
+            props: OnceCell::new(),
+        }
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn with_batch_mem_size(mut self, batch_mem_size: usize) -> Self {
+        self.batch_mem_size = batch_mem_size.max(1);
+        self
+    }
+}
+
+impl DisplayAs for CoalesceExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "CoalesceExec(batch_size={}, batch_mem_size={})",
+            self.batch_size, self.batch_mem_size
+        )
+    }
+}
+
+impl ExecutionPlan for CoalesceExec {
+    fn name(&self) -> &str {
+        "CoalesceExec"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        self.props.get_or_init(|| {
+            PlanProperties::new(
+                EquivalenceProperties::new(self.schema()),
+                self.input.output_partitioning().clone(),
+                ExecutionMode::Bounded,
+            )
+        })
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![&self.input]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(Arc::new(
+            Self::new(children[0].clone())
+                .with_batch_size(self.batch_size)
+                .with_batch_mem_size(self.batch_mem_size),
+        ))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let exec_ctx = ExecutionContext::new(context, partition, self.schema(), &self.metrics);
+        let mut input = exec_ctx.execute_with_input_stats(&self.input)?;
+        let batch_size = self.batch_size;
+        let batch_mem_size = self.batch_mem_size;
+
+        Ok(exec_ctx
+            .clone()
+            .output_with_sender("Coalesce", move |sender| async move {
+                let coalescer =
+                    Arc::new(Coalescer::new(exec_ctx, sender, batch_size, batch_mem_size));
+                MemManager::register_consumer(coalescer.clone(), true)?;
+
+                while let Some(batch) = input.next().await.transpose()? {
+                    coalescer.insert_batch(batch).await?;
+                }
+                coalescer.flush().await?;
+                Ok(())
+            }))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn statistics(&self) -> Result<Statistics> {
+        self.input.statistics()
+    }
+}
+
+struct Coalescer {
+    exec_ctx: Arc<ExecutionContext>,
+    sender: Arc<WrappedRecordBatchSender>,
+    mem_consumer_info: Option<Weak<MemConsumerInfo>>,
+    batch_size: usize,
+    batch_mem_size: usize,
+    staging: Mutex<Staging>,
+    flush_requested: AtomicBool,
+    mem_flush_count: Count,
+}
+
+#[derive(Default)]
+struct Staging {
+    batches: Vec<RecordBatch>,
+    num_rows: usize,
+    mem_size: usize,
+}
+
+impl Coalescer {
+    fn new(
+        exec_ctx: Arc<ExecutionContext>,
+        sender: Arc<WrappedRecordBatchSender>,
+        batch_size: usize,
+        batch_mem_size: usize,
+    ) -> Self {
+        let mem_flush_count = exec_ctx.register_counter_metric("mem_flush_count");
+        Self {
+            exec_ctx,
+            sender,
+            mem_consumer_info: None,
+            batch_size,
+            batch_mem_size,
+            staging: Mutex::default(),
+            flush_requested: AtomicBool::new(false),
+            mem_flush_count,
+        }
+    }
+
+    async fn insert_batch(&self, batch: RecordBatch) -> Result<()> {
+        for (slice, slice_mem_size) in self.split_batch(batch) {
+            self.flush_if_requested().await?;
+
+            // flush first if the slice does not fit in staging batches
+            let fits = {
+                let staging = self.staging.lock();
+                staging.num_rows + slice.num_rows() <= self.batch_size
+                    && staging.mem_size + slice_mem_size <= self.batch_mem_size
+            };
+            if !fits {
+                self.flush().await?;
+            }
+
+            let (num_rows, mem_size) = {
+                let mut staging = self.staging.lock();
+                staging.num_rows += slice.num_rows();
+                staging.mem_size += slice_mem_size;
+                staging.batches.push(slice);
+                (staging.num_rows, staging.mem_size)
+            };
+            if num_rows >= self.batch_size || mem_size >= self.batch_mem_size {
+                self.flush().await?;
+            } else {
+                self.update_mem_used(mem_size).await?;
+                self.flush_if_requested().await?;
+            }
+        }
+        Ok(())
+    }
+
+    // flushes staged batches if requested by spill(), only called by the
+    // producer so that output batches are sent in order
+    async fn flush_if_requested(&self) -> Result<()> {
+        if self.flush_requested.swap(false, SeqCst) {
+            self.mem_flush_count.add(1);
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    // splits a batch into slices within both row count and memory size
+    // targets. memory size of a slice is estimated proportionally to its rows
+    // because slices share buffers with the original batch.
+    fn split_batch(&self, batch: RecordBatch) -> Vec<(RecordBatch, usize)> {
+        let num_rows = batch.num_rows();
+        if num_rows == 0 {
+            return vec![];
+        }
+        let mem_size = batch.get_batch_mem_size();
+        let num_slices = num_rows
+            .div_ceil(self.batch_size)
+            .max(mem_size.div_ceil(self.batch_mem_size))
+            .min(num_rows);
+        let slice_rows = num_rows.div_ceil(num_slices);
+
+        (0..num_rows)
+            .step_by(slice_rows)
+            .map(|offset| {
+                let len = slice_rows.min(num_rows - offset);
+                (batch.slice(offset, len), mem_size * len / num_rows)
+            })
+            .collect()
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let staging = std::mem::take(&mut *self.staging.lock());
+        if staging.batches.is_empty() {
+            return Ok(());
+        }
+        let coalesced = coalesce_batches_unchecked(self.exec_ctx.output_schema(), &staging.batches);
+        drop(staging);
+        self.update_mem_used(0).await?;
+        self.sender.send(coalesced).await
+    }
+}
+
+#[async_trait]
+impl MemConsumer for Coalescer {
+    fn name(&self) -> &str {
+        "Coalescer"
+    }
+
+    fn set_consumer_info(&mut self, consumer_info: Weak<MemConsumerInfo>) {
+        self.mem_consumer_info = Some(consumer_info);
+    }
+
+    fn get_consumer_info(&self) -> &Weak<MemConsumerInfo> {
+        self.mem_consumer_info
+            .as_ref()
+            .expect("consumer info not set")
+    }
+
+    // staged batches are sent out instead of being spilled to disk. spill()
+    // may be called from other tasks and must not wait on the output channel,
+    // so it only requests the producer to flush on its next insert
+    async fn spill(&self) -> Result<()> {
+        self.flush_requested.store(true, SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for Coalescer {
+    fn drop(&mut self) {
+        MemManager::deregister_consumer(self);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{ArrayRef, AsArray, BinaryArray, Int32Array},
+        compute::concat_batches,
+        datatypes::Int32Type,
+        record_batch::RecordBatch,
+    };
+    use datafusion::{
+        common::Result,
+        physical_plan::{
+            common, memory::MemoryExec, metrics::ExecutionPlanMetricsSet, ExecutionPlan,
+        },
+        prelude::SessionContext,
+    };
+    use futures::FutureExt;
+
+    use crate::{
+        coalesce_exec::{CoalesceExec, Coalescer},
+        common::execution_context::{ExecutionContext, WrappedRecordBatchSender},
+        memmgr::{MemConsumer, MemManager},
+    };
+
+    fn build_batch(range: std::ops::Range<i32>, value_len: usize) -> Result<RecordBatch> {
+        let a = Int32Array::from_iter_values(range.clone());
+        let b = BinaryArray::from_iter_values(range.map(|i| vec![i as u8; value_len]));
+        Ok(RecordBatch::try_from_iter([
+            ("a", Arc::new(a) as ArrayRef),
+            ("b", Arc::new(b) as ArrayRef),
+        ])?)
+    }
+
+    fn values(batches: &[RecordBatch]) -> Result<Vec<i32>> {
+        let batch = concat_batches(&batches[0].schema(), batches)?;
+        Ok(batch
+            .column(0)
+            .as_primitive::<Int32Type>()
+            .values()
+            .to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_small_batches() -> Result<()> {
+        MemManager::init(1000000);
+        let batches = (0..100)
+            .map(|i| build_batch(i * 10..i * 10 + 10, 4))
+            .collect::<Result<Vec<_>>>()?;
+        let input = Arc::new(MemoryExec::try_new(
+            &[batches.clone()],
+            batches[0].schema(),
+            None,
+        )?);
+        let coalesce = Arc::new(
+            CoalesceExec::new(input)
+                .with_batch_size(300)
+                .with_batch_mem_size(1 << 30),
+        );
+
+        let output = coalesce.execute(0, SessionContext::new().task_ctx())?;
+        let output_batches = common::collect(output).await?;
+        let num_rows = output_batches
+            .iter()
+            .map(|b| b.num_rows())
+            .collect::<Vec<_>>();
+        assert_eq!(num_rows, vec![300, 300, 300, 100]);
+        assert_eq!(values(&output_batches)?, (0..1000).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_wide_batches() -> Result<()> {
+        MemManager::init(1000000);
+
+        // batches of 100 rows, about 10KB per row
+        let batches = (0..5)
+            .map(|i| build_batch(i * 100..i * 100 + 100, 10240))
+            .collect::<Result<Vec<_>>>()?;
+        let batch_mem_size = 256 << 10;
+        let max_rows = batch_mem_size / 10240;
+
+        let metrics = ExecutionPlanMetricsSet::new();
+        let exec_ctx = ExecutionContext::new(
+            SessionContext::new().task_ctx(),
+            0,
+            batches[0].schema(),
+            &metrics,
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1000);
+        let sender = WrappedRecordBatchSender::new(exec_ctx.clone(), tx);
+        let coalescer = Arc::new(Coalescer::new(exec_ctx, sender, 10000, batch_mem_size));
+        MemManager::register_consumer(coalescer.clone(), true)?;
+
+        // buffered memory is bounded by the memory size target
+        for batch in &batches {
+            coalescer.insert_batch(batch.clone()).await?;
+            assert!(coalescer.consumer_info().mem_used() <= batch_mem_size);
+        }
+        coalescer.flush().await?;
+        assert_eq!(coalescer.consumer_info().mem_used(), 0);
+
+        let mut output_batches = vec![];
+        while let Ok(batch) = rx.try_recv() {
+            output_batches.push(batch?);
+        }
+        assert!(output_batches
+            .iter()
+            .all(|b| b.num_rows() > 0 && b.num_rows() <= max_rows));
+        assert_eq!(values(&output_batches)?, (0..500).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush_under_memory_pressure() -> Result<()> {
+        MemManager::init(1000000);
+        let batches = (0..3)
+            .map(|i| build_batch(i * 10..i * 10 + 10, 4))
+            .collect::<Result<Vec<_>>>()?;
+
+        let metrics = ExecutionPlanMetricsSet::new();
+        let exec_ctx = ExecutionContext::new(
+            SessionContext::new().task_ctx(),
+            0,
+            batches[0].schema(),
+            &metrics,
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let sender = WrappedRecordBatchSender::new(exec_ctx.clone(), tx.clone());
+        let coalescer = Arc::new(Coalescer::new(exec_ctx, sender, 10000, 1 << 30));
+        MemManager::register_consumer(coalescer.clone(), true)?;
+
+        for batch in &batches {
+            coalescer.insert_batch(batch.clone()).await?;
+        }
+        let mem_used = coalescer.consumer_info().mem_used();
+        assert!(mem_used > 0);
+        assert!(rx.try_recv().is_err());
+
+        // spilling never waits on the full output channel, it only requests
+        // the producer to flush
+        tx.try_send(Ok(batches[0].clone())).expect("channel full");
+        let spilled = coalescer.force_spill().now_or_never();
+        assert!(matches!(spilled, Some(Ok(()))));
+        assert_eq!(coalescer.consumer_info().mem_used(), mem_used);
+        assert_eq!(coalescer.mem_flush_count.value(), 0);
+        rx.try_recv().expect("existing batch")?;
+
+        // staged batches are flushed on the next insert, before the inserted
+        // batch is staged
+        coalescer.insert_batch(build_batch(30..40, 4)?).await?;
+        assert_eq!(coalescer.mem_flush_count.value(), 1);
+        let flushed = rx.try_recv().expect("flushed batch")?;
+        assert_eq!(values(&[flushed])?, (0..30).collect::<Vec<_>>());
+        assert!(rx.try_recv().is_err());
+        assert!(coalescer.consumer_info().mem_used() > 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,361 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use arrow::{
+    array::{ArrayRef, RecordBatch},
+    datatypes::SchemaRef,
+    row::{Row, RowConverter, Rows, SortField},
+};
+use datafusion::{
+    common::Result, physical_expr::PhysicalSortExpr, physical_plan::SendableRecordBatchStream,
+};
+use datafusion_ext_commons::{
+    algorithm::loser_tree::{ComparableForLoserTree, LoserTree},
+    arrow::selection::create_batch_interleaver,
+};
+use futures::StreamExt;
+
+use crate::common::execution_context::{ExecutionContext, WrappedRecordBatchSender};
+
+/// merges streams sorted by `sort_exprs` into one sorted stream, with at most
+/// `batch_size` rows in each output batch. rows with equal keys are output in
+/// the order of input streams. all streams must have the output schema of
+/// `exec_ctx`.
+pub fn merge_sorted_streams(
+    exec_ctx: &Arc<ExecutionContext>,
+    streams: Vec<SendableRecordBatchStream>,
+    sort_exprs: &[PhysicalSortExpr],
+    batch_size: usize,
+) -> Result<SendableRecordBatchStream> {
+    let schema = exec_ctx.output_schema();
+    let sort_fields = sort_exprs
+        .iter()
+        .map(|sort_expr| {
+            Ok(SortField::new_with_options(
+                sort_expr.expr.data_type(&schema)?,
+                sort_expr.options,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let merger = SortedStreamsMerger {
+        schema,
+        sort_exprs: sort_exprs.to_vec(),
+        row_converter: RowConverter::new(sort_fields)?,
+        staged_batches: vec![],
+        indices: vec![],
+        batch_size: batch_size.max(1),
+    };
+    let output = exec_ctx.output_with_sender("MergeSortedStreams", move |sender| {
+        merger.merge(streams, sender)
+    });
+    Ok(output)
+}
+
+struct SortedStreamsMerger {
+    schema: SchemaRef,
+    sort_exprs: Vec<PhysicalSortExpr>,
+    row_converter: RowConverter,
+    staged_batches: Vec<RecordBatch>,
+    indices: Vec<(usize, usize)>,
+    batch_size: usize,
+}
+
+impl SortedStreamsMerger {
+    async fn merge(
+        mut self,
+        streams: Vec<SendableRecordBatchStream>,
+        sender: Arc<WrappedRecordBatchSender>,
+    ) -> Result<()> {
+        let mut cursors = vec![];
+        for (stream_idx, stream) in streams.into_iter().enumerate() {
+            let mut cursor = MergeCursor {
+                stream_idx,
+                stream,
+                batch: RecordBatch::new_empty(self.schema.clone()),
+                rows: self.row_converter.empty_rows(0, 0),
+                batch_idx: 0,
+                row_idx: 0,
+                finished: false,
+            };
+            self.load_next_batch(&mut cursor).await?;
+            cursors.push(cursor);
+        }
+        let mut cursors = LoserTree::new(cursors);
+
+        while !cursors.is_empty() && !cursors.peek().finished {
+            let mut min_cursor = cursors.peek_mut();
+            self.indices
+                .push((min_cursor.batch_idx, min_cursor.row_idx));
+            min_cursor.row_idx += 1;
+            if min_cursor.row_idx == min_cursor.rows.num_rows() {
+                self.load_next_batch(&mut min_cursor).await?;
+            }
+            drop(min_cursor);
+
+            if self.indices.len() >= self.batch_size {
+                self.flush(&mut cursors, &sender).await?;
+            }
+        }
+        self.flush(&mut cursors, &sender).await
+    }
+
+    /// moves the cursor to the next non-empty batch of its stream, or marks it
+    /// finished if the stream ends
+    async fn load_next_batch(&mut self, cursor: &mut MergeCursor) -> Result<()> {
+        while let Some(batch) = cursor.stream.next().await.transpose()? {
+            if batch.num_rows() == 0 {
+                continue;
+            }
+            let key_cols = self
+                .sort_exprs
+                .iter()
+                .map(|sort_expr| {
+                    sort_expr
+                        .expr
+                        .evaluate(&batch)?
+                        .into_array(batch.num_rows())
+                })
+                .collect::<Result<Vec<ArrayRef>>>()?;
+            cursor.rows = self.row_converter.convert_columns(&key_cols)?;
+            cursor.row_idx = 0;
+            cursor.batch_idx = self.staged_batches.len();
+            self.staged_batches.push(batch.clone());
+            cursor.batch = batch;
+            return Ok(());
+        }
+        cursor.finished = true;
+        Ok(())
+    }
+
+    async fn flush(
+        &mut self,
+        cursors: &mut LoserTree<MergeCursor>,
+        sender: &WrappedRecordBatchSender,
+    ) -> Result<()> {
+        if self.indices.is_empty() {
+            return Ok(());
+        }
+        let batch_interleaver = create_batch_interleaver(&self.staged_batches, false)?;
+        let output_batch = batch_interleaver(&self.indices)?;
+        self.indices.clear();
+
+        // only current batches of unfinished cursors are still referenced
+        self.staged_batches.clear();
+        for cursor in cursors.values_mut() {
+            if !cursor.finished {
+                cursor.batch_idx = self.staged_batches.len();
+                self.staged_batches.push(cursor.batch.clone());
+            }
+        }
+        sender.send(output_batch).await
+    }
+}
+
+struct MergeCursor {
+    stream_idx: usize,
+    stream: SendableRecordBatchStream,
+    batch: RecordBatch,
+    rows: Rows,
+    batch_idx: usize,
+    row_idx: usize,
+    finished: bool,
+}
+
+impl MergeCursor {
+    fn cur_row(&self) -> Row<'_> {
+        self.rows.row(self.row_idx)
+    }
+}
+
+impl ComparableForLoserTree for MergeCursor {
+    #[inline(always)]
+    fn lt(&self, other: &Self) -> bool {
+        if self.finished {
+            return false;
+        }
+        if other.finished {
+            return true;
+        }
+        (self.cur_row(), self.stream_idx) < (other.cur_row(), other.stream_idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{ArrayRef, AsArray, Int32Array, RecordBatch},
+        compute::{concat, concat_batches, sort, SortOptions},
+        datatypes::{DataType, Field, Int32Type, Schema, SchemaRef},
+    };
+    use datafusion::{
+        common::Result,
+        physical_expr::{expressions::Column, PhysicalSortExpr},
+        physical_plan::{
+            common::collect, metrics::ExecutionPlanMetricsSet, stream::RecordBatchStreamAdapter,
+            SendableRecordBatchStream,
+        },
+        prelude::SessionContext,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::common::{batch_merge::merge_sorted_streams, execution_context::ExecutionContext};
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("key", DataType::Int32, true),
+            Field::new("stream_idx", DataType::Int32, false),
+        ]))
+    }
+
+    /// builds a stream of batches of random sizes from sorted keys
+    fn build_stream(
+        rng: &mut StdRng,
+        stream_idx: usize,
+        keys: Vec<Option<i32>>,
+    ) -> SendableRecordBatchStream {
+        let mut batches = vec![];
+        let mut keys = &keys[..];
+        while !keys.is_empty() {
+            let num_rows = rng.random_range(0..=keys.len().min(10));
+            let columns: Vec<ArrayRef> = vec![
+                Arc::new(Int32Array::from(keys[..num_rows].to_vec())),
+                Arc::new(Int32Array::from(vec![stream_idx as i32; num_rows])),
+            ];
+            batches.push(RecordBatch::try_new(schema(), columns).unwrap());
+            keys = &keys[num_rows..];
+        }
+        Box::pin(RecordBatchStreamAdapter::new(
+            schema(),
+            futures::stream::iter(batches.into_iter().map(Ok)),
+        ))
+    }
+
+    async fn merge(
+        streams: Vec<SendableRecordBatchStream>,
+        options: SortOptions,
+        batch_size: usize,
+    ) -> Result<RecordBatch> {
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema(),
+            &ExecutionPlanMetricsSet::new(),
+        );
+        let sort_exprs = vec![PhysicalSortExpr {
+            expr: Arc::new(Column::new("key", 0)),
+            options,
+        }];
+        let output = merge_sorted_streams(&exec_ctx, streams, &sort_exprs, batch_size)?;
+        let output_batches = collect(output).await?;
+        assert!(output_batches
+            .iter()
+            .all(|batch| batch.num_rows() <= batch_size));
+        Ok(concat_batches(&schema(), &output_batches)?)
+    }
+
+    #[tokio::test]
+    async fn test_merge_sorted_streams() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(37);
+        for _ in 0..20 {
+            // some streams are empty or end much earlier than others
+            let num_streams = rng.random_range(1..10);
+            let stream_keys = (0..num_streams)
+                .map(|_| {
+                    let num_rows = rng.random_range(0..100);
+                    let mut keys = (0..num_rows)
+                        .map(|_| rng.random_range(0..50))
+                        .collect::<Vec<_>>();
+                    keys.sort();
+                    keys
+                })
+                .collect::<Vec<_>>();
+
+            // rows with equal keys are taken in the order of streams
+            let mut expected = stream_keys
+                .iter()
+                .enumerate()
+                .flat_map(|(stream_idx, keys)| {
+                    keys.iter().map(move |&key| (key, stream_idx as i32))
+                })
+                .collect::<Vec<_>>();
+            expected.sort_by_key(|&(key, _)| key);
+
+            let streams = stream_keys
+                .into_iter()
+                .enumerate()
+                .map(|(stream_idx, keys)| {
+                    build_stream(&mut rng, stream_idx, keys.into_iter().map(Some).collect())
+                })
+                .collect::<Vec<_>>();
+            let batch_size = rng.random_range(1..20);
+            let merged = merge(streams, SortOptions::default(), batch_size).await?;
+            let keys = merged.column(0).as_primitive::<Int32Type>().values();
+            let stream_idxs = merged.column(1).as_primitive::<Int32Type>().values();
+            let actual = keys
+                .iter()
+                .cloned()
+                .zip(stream_idxs.iter().cloned())
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected);
+        }
+
+        // no input streams
+        let merged = merge(vec![], SortOptions::default(), 10).await?;
+        assert_eq!(merged.num_rows(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_sorted_streams_with_sort_options() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(37);
+        for descending in [false, true] {
+            for nulls_first in [false, true] {
+                let options = SortOptions {
+                    descending,
+                    nulls_first,
+                };
+                let stream_keys = (0..5)
+                    .map(|_| {
+                        let keys = Int32Array::from(
+                            (0..rng.random_range(0..50))
+                                .map(|_| Some(rng.random_range(0..20)).filter(|v| v % 5 != 0))
+                                .collect::<Vec<_>>(),
+                        );
+                        sort(&keys, Some(options)).unwrap()
+                    })
+                    .collect::<Vec<_>>();
+                let key_refs = stream_keys.iter().map(|k| k.as_ref()).collect::<Vec<_>>();
+                let all_keys = concat(&key_refs)?;
+                let expected = sort(&all_keys, Some(options))?;
+
+                let streams = stream_keys
+                    .iter()
+                    .enumerate()
+                    .map(|(stream_idx, keys)| {
+                        let keys = keys.as_primitive::<Int32Type>().iter().collect();
+                        build_stream(&mut rng, stream_idx, keys)
+                    })
+                    .collect::<Vec<_>>();
+                let merged = merge(streams, options, 7).await?;
+                assert_eq!(merged.column(0), &expected, "options: {options:?}");
+            }
+        }
+        Ok(())
+    }
+}
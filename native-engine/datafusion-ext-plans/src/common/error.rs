@@ -0,0 +1,205 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::{Display, Formatter};
+
+use bytesize::ByteSize;
+use datafusion::common::DataFusionError;
+
+/// error codes embedded in error messages as `[CODE]`, they are stable so
+/// that the jvm side can map them to spark exceptions
+const ERROR_CODES: [&str; 6] = [
+    "BLAZE_OUT_OF_MEMORY",
+    "BLAZE_SPILL_DISK_EXHAUSTED",
+    "BLAZE_SPILL_IO",
+    "BLAZE_SHUFFLE_IO",
+    "BLAZE_SHUFFLE_CORRUPTION",
+    "BLAZE_CANCELLED",
+];
+
+/// errors of spilling, shuffle io and memory management which callers need to
+/// tell apart, see [`Self::code`]
+#[derive(Debug)]
+pub enum BlazeError {
+    /// memory cannot be reserved even after spilling other consumers
+    OutOfMemory {
+        consumer: String,
+        requested: usize,
+        available: usize,
+    },
+    /// spills use more disk than allowed
+    SpillDiskExhausted {
+        consumer: String,
+        used: u64,
+        max: u64,
+    },
+    /// spill file cannot be created or written, path is none for temp files
+    SpillIo {
+        path: Option<String>,
+        source: std::io::Error,
+    },
+    /// shuffle output file cannot be written
+    ShuffleIo {
+        path: String,
+        source: std::io::Error,
+    },
+    /// shuffle data or spill is truncated or cannot be decoded, the message
+    /// describes where
+    ShuffleCorruption {
+        partition: Option<usize>,
+        message: String,
+    },
+    /// task completed or killed
+    Cancelled,
+}
+
+impl BlazeError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            BlazeError::OutOfMemory { .. } => ERROR_CODES[0],
+            BlazeError::SpillDiskExhausted { .. } => ERROR_CODES[1],
+            BlazeError::SpillIo { .. } => ERROR_CODES[2],
+            BlazeError::ShuffleIo { .. } => ERROR_CODES[3],
+            BlazeError::ShuffleCorruption { .. } => ERROR_CODES[4],
+            BlazeError::Cancelled => ERROR_CODES[5],
+        }
+    }
+}
+
+impl Display for BlazeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] ", self.code())?;
+        match self {
+            BlazeError::OutOfMemory {
+                consumer,
+                requested,
+                available,
+            } => write!(
+                f,
+                "{consumer} cannot reserve {}, available: {}",
+                ByteSize(*requested as u64),
+                ByteSize(*available as u64),
+            ),
+            BlazeError::SpillDiskExhausted {
+                consumer,
+                used,
+                max,
+            } => write!(
+                f,
+                "{consumer} spills use {} of disk, exceeding max spill disk usage {} \
+                    (spark.blaze.spill.maxDiskBytes)",
+                ByteSize(*used),
+                ByteSize(*max),
+            ),
+            BlazeError::SpillIo { path, source } => match path {
+                Some(path) => write!(f, "spill io error on {path}: {source}"),
+                None => write!(f, "spill io error on temp file: {source}"),
+            },
+            BlazeError::ShuffleIo { path, source } => {
+                write!(f, "shuffle io error on {path}: {source}")
+            }
+            BlazeError::ShuffleCorruption { message, .. } => write!(f, "{message}"),
+            BlazeError::Cancelled => write!(f, "shuffle write cancelled: task completed/killed"),
+        }
+    }
+}
+
+impl std::error::Error for BlazeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlazeError::SpillIo { source, .. } | BlazeError::ShuffleIo { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// memory and disk exhaustion are ResourcesExhausted as in datafusion, other
+/// errors are kept as external errors and can be downcast
+impl From<BlazeError> for DataFusionError {
+    fn from(err: BlazeError) -> Self {
+        match err {
+            BlazeError::OutOfMemory { .. } | BlazeError::SpillDiskExhausted { .. } => {
+                DataFusionError::ResourcesExhausted(err.to_string())
+            }
+            err => DataFusionError::External(Box::new(err)),
+        }
+    }
+}
+
+/// returns the first error code in the error message, which may have been
+/// wrapped by other errors or passed through the jvm
+pub fn error_code(message: &str) -> Option<&'static str> {
+    ERROR_CODES
+        .into_iter()
+        .filter_map(|code| Some((message.find(&format!("[{code}]"))?, code)))
+        .min()
+        .map(|(_, code)| code)
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error;
+
+    use datafusion::common::DataFusionError;
+
+    use crate::common::error::{error_code, BlazeError};
+
+    #[test]
+    fn test_error_codes() {
+        let err = BlazeError::OutOfMemory {
+            consumer: "SortShuffleRepartitioner".to_string(),
+            requested: 1 << 20,
+            available: 0,
+        };
+        assert_eq!(
+            err.to_string(),
+            "[BLAZE_OUT_OF_MEMORY] SortShuffleRepartitioner cannot reserve 1.0 MiB, available: 0 B"
+        );
+        let err = DataFusionError::from(err);
+        assert!(matches!(err, DataFusionError::ResourcesExhausted(_)));
+        assert_eq!(error_code(&err.to_string()), Some("BLAZE_OUT_OF_MEMORY"));
+
+        let err = BlazeError::SpillIo {
+            path: Some("/tmp/spill".to_string()),
+            source: std::io::Error::other("disk full"),
+        };
+        assert_eq!(
+            err.source().map(|e| e.to_string()).as_deref(),
+            Some("disk full")
+        );
+        let err = DataFusionError::from(err);
+        assert_eq!(error_code(&err.to_string()), Some("BLAZE_SPILL_IO"));
+        let DataFusionError::External(err) = err else {
+            panic!("expect an external error");
+        };
+        assert!(matches!(
+            err.downcast_ref::<BlazeError>(),
+            Some(BlazeError::SpillIo { .. })
+        ));
+
+        // the outermost code is reported for nested errors
+        let message = format!(
+            "{}: {}",
+            BlazeError::Cancelled,
+            BlazeError::ShuffleCorruption {
+                partition: Some(3),
+                message: "truncated".to_string(),
+            }
+        );
+        assert_eq!(error_code(&message), Some("BLAZE_CANCELLED"));
+        assert_eq!(error_code("shuffle write error"), None);
+    }
+}
@@ -13,21 +13,27 @@
 // limitations under the License.
 
 use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    cell::RefCell,
     future::Future,
     panic::AssertUnwindSafe,
     pin::Pin,
     sync::{Arc, Weak},
     task::{ready, Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use arrow::{array::RecordBatch, datatypes::SchemaRef};
-use blaze_jni_bridge::{conf, conf::BooleanConf, is_task_running};
+use arrow::{array::RecordBatch, compute::concat_batches, datatypes::SchemaRef};
+use blaze_jni_bridge::{
+    conf,
+    conf::{BooleanConf, IntConf},
+    is_jni_bridge_inited, is_task_running,
+};
 use datafusion::{
     common::Result,
     execution::{RecordBatchStream, SendableRecordBatchStream, TaskContext},
     physical_plan::{
-        metrics::{BaselineMetrics, Count, ExecutionPlanMetricsSet, MetricBuilder, Time},
+        metrics::{BaselineMetrics, Count, ExecutionPlanMetricsSet, Gauge, MetricBuilder, Time},
         stream::{RecordBatchReceiverStream, RecordBatchStreamAdapter},
         ExecutionPlan,
     },
@@ -40,7 +46,10 @@ use futures::{Stream, StreamExt};
 use futures_util::FutureExt;
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
-use tokio::sync::mpsc::Sender;
+use tokio::{
+    runtime::{Handle, Runtime},
+    sync::mpsc::Sender,
+};
 
 use crate::{
     common::{column_pruning::ExecuteWithColumnPruning, timer_helper::TimerHelper},
@@ -122,6 +131,10 @@ impl ExecutionContext {
             .counter(name.to_owned(), self.partition_id)
     }
 
+    pub fn register_gauge_metric(&self, name: &str) -> Gauge {
+        MetricBuilder::new(self.execution_plan_metrics()).gauge(name.to_owned(), self.partition_id)
+    }
+
     pub fn coalesce_with_default_batch_size(
         self: &Arc<Self>,
         input: SendableRecordBatchStream,
@@ -309,36 +322,286 @@ impl ExecutionContext {
         })
     }
 
+    /// spawns the output() producer and returns a stream of batches it sends.
+    /// the producer must propagate errors returned by sender.send(), which
+    /// happens when the returned stream is dropped. the spawned task is also
+    /// aborted on dropping the stream.
     pub fn output_with_sender<Fut: Future<Output = Result<()>> + Send>(
         self: &Arc<Self>,
         desc: &'static str,
         output: impl FnOnce(Arc<WrappedRecordBatchSender>) -> Fut + Send + 'static,
     ) -> SendableRecordBatchStream {
-        let mut stream_builder = RecordBatchReceiverStream::builder(self.output_schema(), 1);
+        self.output_with_sender_builder(desc).build(output)
+    }
+
+    /// same as [`Self::output_with_sender`], with configurable channel capacity
+    /// and coalescing of small batches.
+    pub fn output_with_sender_builder(self: &Arc<Self>, desc: &'static str) -> OutputWithSender {
+        OutputWithSender {
+            exec_ctx: self.clone(),
+            desc,
+            capacity: output_channel_capacity(),
+            coalesce_rows: 0,
+            baseline_metrics: None,
+            record_output_wait_time: false,
+            spawn_policy: SpawnPolicy::default(),
+            send_timeout: output_send_timeout(),
+        }
+    }
+}
+
+thread_local! {
+    static PANIC_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+/// installs a panic hook capturing backtrace of the panicking thread, so that
+/// output_with_sender can report where the producer panicked. backtraces are
+/// only captured when enabled by RUST_BACKTRACE/RUST_LIB_BACKTRACE.
+fn install_panic_backtrace_hook() {
+    static INSTALLED: OnceCell<()> = OnceCell::new();
+    INSTALLED.get_or_init(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANIC_BACKTRACE.with(|backtrace| {
+                *backtrace.borrow_mut() = Some(Backtrace::capture());
+            });
+            default_hook(info);
+        }));
+    });
+}
+
+fn take_panic_backtrace() -> Option<Backtrace> {
+    PANIC_BACKTRACE
+        .with(|backtrace| backtrace.borrow_mut().take())
+        .filter(|backtrace| backtrace.status() == BacktraceStatus::Captured)
+}
+
+fn output_send_timeout() -> Option<Duration> {
+    static SEND_TIMEOUT: OnceCell<Option<Duration>> = OnceCell::new();
+    *SEND_TIMEOUT.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            let timeout_ms = conf::OUTPUT_SEND_TIMEOUT_MS.value().unwrap_or(0);
+            (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms as u64))
+        } else {
+            None // for testing
+        }
+    })
+}
+
+fn output_channel_capacity() -> usize {
+    static CAPACITY: OnceCell<usize> = OnceCell::new();
+    *CAPACITY.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::OUTPUT_CHANNEL_CAPACITY.value().unwrap_or(1).max(1) as usize
+        } else {
+            1 // for testing
+        }
+    })
+}
+
+pub struct OutputWithSender {
+    exec_ctx: Arc<ExecutionContext>,
+    desc: &'static str,
+    capacity: usize,
+    coalesce_rows: usize,
+    baseline_metrics: Option<BaselineMetrics>,
+    record_output_wait_time: bool,
+    spawn_policy: SpawnPolicy,
+    send_timeout: Option<Duration>,
+}
+
+/// where the producer of output_with_sender runs, the output stream is always
+/// bridged on the current runtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SpawnPolicy {
+    /// runs on the current tokio runtime
+    #[default]
+    Async,
+
+    /// runs on a tokio blocking thread, used for producers doing heavy
+    /// synchronous IO or computation, which should not starve runtime workers
+    Blocking,
+
+    /// runs on a lazily created dedicated runtime with the given number of
+    /// worker threads. the runtime is shared by all producers and its size
+    /// is decided by the first producer using it. threads of the dedicated
+    /// runtime are not bound to any spark task context.
+    DedicatedPool(usize),
+}
+
+fn dedicated_runtime(num_worker_threads: usize) -> &'static Runtime {
+    static RUNTIME: OnceCell<Runtime> = OnceCell::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(num_worker_threads.max(1))
+            .thread_name("blaze-output-producer")
+            .enable_all()
+            .build()
+            .expect("error creating dedicated runtime for output producers")
+    })
+}
+
+impl OutputWithSender {
+    /// number of batches buffered in the output channel, defaults to
+    /// spark.blaze.outputChannel.capacity
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// concatenates consecutive small batches until they have at least
+    /// `coalesce_rows` rows before sending, 0 for disabling coalescing
+    pub fn with_coalescing(mut self, coalesce_rows: usize) -> Self {
+        self.coalesce_rows = coalesce_rows;
+        self
+    }
+
+    /// records producer time (excluding time blocked on sending) as
+    /// elapsed_compute, counts output rows and batches, and marks the metrics
+    /// done when the producer completes. the producer must not call
+    /// sender.exclude_time() with this option.
+    pub fn with_baseline_metrics(mut self, baseline_metrics: &BaselineMetrics) -> Self {
+        self.baseline_metrics = Some(baseline_metrics.clone());
+        self
+    }
+
+    /// records time blocked on sending as output_wait_time, which reflects
+    /// slowness of downstream consumers.
+    pub fn with_output_wait_time(mut self) -> Self {
+        self.record_output_wait_time = true;
+        self
+    }
+
+    /// runs the producer according to the spawn policy, defaults to
+    /// [`SpawnPolicy::Async`]
+    pub fn with_spawn_policy(mut self, spawn_policy: SpawnPolicy) -> Self {
+        self.spawn_policy = spawn_policy;
+        self
+    }
+
+    /// fails the producer with "output stalled" if a batch cannot be sent
+    /// within `send_timeout`, so that a stalled consumer does not keep the
+    /// producer alive forever. None for waiting forever, defaults to
+    /// spark.blaze.outputChannel.sendTimeoutMs
+    pub fn with_send_timeout(mut self, send_timeout: Option<Duration>) -> Self {
+        self.send_timeout = send_timeout;
+        self
+    }
+
+    pub fn build<Fut: Future<Output = Result<()>> + Send>(
+        self,
+        output: impl FnOnce(Arc<WrappedRecordBatchSender>) -> Fut + Send + 'static,
+    ) -> SendableRecordBatchStream {
+        let desc = self.desc;
+        let partition_id = self.exec_ctx.partition_id();
+        install_panic_backtrace_hook();
+
+        let mut stream_builder =
+            RecordBatchReceiverStream::builder(self.exec_ctx.output_schema(), self.capacity);
         let err_sender = stream_builder.tx().clone();
-        let wrapped_sender =
-            WrappedRecordBatchSender::new(self.clone(), stream_builder.tx().clone());
+        let wrapped_sender = WrappedRecordBatchSender::new_with_coalescing(
+            self.exec_ctx.clone(),
+            stream_builder.tx().clone(),
+            self.coalesce_rows,
+        );
+        let output_wait_time = self
+            .record_output_wait_time
+            .then(|| self.exec_ctx.register_timer_metric("output_wait_time"));
+        let baseline_metrics = self.baseline_metrics;
+        let spawn_policy = self.spawn_policy;
+        let send_timeout = self.send_timeout;
+        if let Some(send_timeout) = send_timeout {
+            wrapped_sender.send_timeout.get_or_init(|| send_timeout);
+        }
+        if let Some(baseline_metrics) = &baseline_metrics {
+            wrapped_sender.exclude_time(baseline_metrics.elapsed_compute());
+            let output_batches = self.exec_ctx.register_counter_metric("output_batches");
+            wrapped_sender.output_metrics.get_or_init(|| OutputMetrics {
+                baseline_metrics: baseline_metrics.clone(),
+                output_batches,
+            });
+        }
+        if let Some(output_wait_time) = output_wait_time {
+            wrapped_sender
+                .output_wait_time
+                .get_or_init(|| output_wait_time);
+        }
 
         stream_builder.spawn(async move {
-            let result = AssertUnwindSafe(async move {
-                if let Err(err) = output(wrapped_sender).await {
+            let producer = AssertUnwindSafe(async move {
+                let closed_sender = wrapped_sender.sender.clone();
+                let staged_sender = wrapped_sender.clone();
+                let compute_timer = baseline_metrics
+                    .as_ref()
+                    .map(|baseline_metrics| baseline_metrics.elapsed_compute().timer());
+                let output_result = match output(wrapped_sender).await {
+                    Ok(()) => staged_sender.flush_staging().await,
+                    Err(err) => Err(err),
+                };
+                drop(compute_timer);
+                if output_result.is_ok() {
+                    if let Some(baseline_metrics) = &baseline_metrics {
+                        baseline_metrics.done();
+                    }
+                }
+                if let Err(err) = output_result {
+                    if closed_sender.is_closed() {
+                        // receiver is dropped, stop producing silently
+                        log::info!("output_with_sender[{desc}]: stopped as receiver dropped");
+                        return;
+                    }
                     panic!("output_with_sender[{desc}]: output() returns error: {err}");
                 }
             })
             .catch_unwind()
-            .await
-            .map(|_| Ok(()))
-            .unwrap_or_else(|err| {
-                let panic_message =
-                    panic_message::get_panic_message(&err).unwrap_or("unknown error");
-                df_execution_err!("{panic_message}")
+            .map(move |result| {
+                // backtrace must be taken in the panicking thread
+                result.or_else(|err| {
+                    let panic_message =
+                        panic_message::get_panic_message(&err).unwrap_or("unknown error");
+                    let backtrace = take_panic_backtrace()
+                        .map(|backtrace| format!("\nbacktrace:\n{backtrace}"))
+                        .unwrap_or_default();
+                    df_execution_err!(
+                        "panic in {desc} (partition {partition_id}): {panic_message}{backtrace}"
+                    )
+                })
             });
 
+            let result = match spawn_policy {
+                SpawnPolicy::Async => producer.await,
+                SpawnPolicy::Blocking => {
+                    let handle = Handle::current();
+                    tokio::task::spawn_blocking(move || handle.block_on(producer))
+                        .await
+                        .or_else(|err| df_execution_err!("{desc} producer join error: {err}"))
+                        .and_then(|result| result)
+                }
+                SpawnPolicy::DedicatedPool(num_worker_threads) => {
+                    dedicated_runtime(num_worker_threads)
+                        .spawn(producer)
+                        .await
+                        .or_else(|err| df_execution_err!("{desc} producer join error: {err}"))
+                        .and_then(|result| result)
+                }
+            };
+
             if let Err(err) = result {
-                err_sender
-                    .send(df_execution_err!("{err}"))
-                    .await
-                    .unwrap_or_default();
+                let send_err = err_sender.send(df_execution_err!("{err}"));
+                let send_result = match send_timeout {
+                    Some(send_timeout) => tokio::time::timeout(send_timeout, send_err).await,
+                    None => Ok(send_err.await),
+                };
+                let Ok(send_result) = send_result else {
+                    // consumer is stalled, it gets the error on polling again
+                    log::warn!("output_with_sender[{desc}]: consumer stalled, error: {err}");
+                    return Err(err);
+                };
+                if send_result.is_err() {
+                    // receiver is dropped, nobody is waiting for the error
+                    log::warn!("output_with_sender[{desc}]: receiver dropped, error: {err}");
+                    return Ok(());
+                }
 
                 // panic current spawn
                 let task_running = is_task_running();
@@ -398,14 +661,43 @@ pub struct WrappedRecordBatchSender {
     exec_ctx: Arc<ExecutionContext>,
     sender: Sender<Result<RecordBatch>>,
     exclude_time: OnceCell<Time>,
+    coalesce_rows: usize,
+    staging: Mutex<StagingBatches>,
+    output_metrics: OnceCell<OutputMetrics>,
+    output_wait_time: OnceCell<Time>,
+    send_timeout: OnceCell<Duration>,
+}
+
+struct OutputMetrics {
+    baseline_metrics: BaselineMetrics,
+    output_batches: Count,
+}
+
+#[derive(Default)]
+struct StagingBatches {
+    batches: Vec<RecordBatch>,
+    num_rows: usize,
 }
 
 impl WrappedRecordBatchSender {
     pub fn new(exec_ctx: Arc<ExecutionContext>, sender: Sender<Result<RecordBatch>>) -> Arc<Self> {
+        Self::new_with_coalescing(exec_ctx, sender, 0)
+    }
+
+    pub fn new_with_coalescing(
+        exec_ctx: Arc<ExecutionContext>,
+        sender: Sender<Result<RecordBatch>>,
+        coalesce_rows: usize,
+    ) -> Arc<Self> {
         let wrapped = Arc::new(Self {
             exec_ctx,
             sender,
             exclude_time: OnceCell::new(),
+            coalesce_rows,
+            staging: Mutex::default(),
+            output_metrics: OnceCell::new(),
+            output_wait_time: OnceCell::new(),
+            send_timeout: OnceCell::new(),
         });
         let mut working_senders = working_senders().lock();
         working_senders.push(Arc::downgrade(&wrapped));
@@ -420,26 +712,96 @@ impl WrappedRecordBatchSender {
         self.exclude_time.get_or_init(|| exclude_time.clone());
     }
 
-    pub async fn send(&self, batch: RecordBatch) {
+    /// sends a batch to the output stream, returns an error if the receiver
+    /// has been dropped. callers must propagate the error to stop producing.
+    pub async fn send(&self, batch: RecordBatch) -> Result<()> {
         if batch.num_rows() == 0 {
-            return;
+            return Ok(());
+        }
+        if self.coalesce_rows == 0 {
+            return self.send_batch(batch).await;
         }
-        let exclude_time = self.exclude_time.get().cloned();
-        let send_time = exclude_time.as_ref().map(|_| Instant::now());
-        self.sender
-            .send(Ok(batch))
+
+        // stage small batches until there are enough rows
+        let staged_batches = {
+            let mut staging = self.staging.lock();
+            staging.num_rows += batch.num_rows();
+            staging.batches.push(batch);
+            if staging.num_rows < self.coalesce_rows {
+                return Ok(());
+            }
+            std::mem::take(&mut *staging).batches
+        };
+        self.send_batch(coalesce_staged_batches(staged_batches)?)
             .await
-            .unwrap_or_else(|err| panic!("output_with_sender: send error: {err}"));
+    }
 
-        send_time.inspect(|send_time| {
-            exclude_time
-                .as_ref()
-                .unwrap()
-                .sub_duration(send_time.elapsed());
-        });
+    /// sends all staged batches, called after the producer finishes.
+    async fn flush_staging(&self) -> Result<()> {
+        let staged_batches = std::mem::take(&mut *self.staging.lock()).batches;
+        if staged_batches.is_empty() {
+            return Ok(());
+        }
+        self.send_batch(coalesce_staged_batches(staged_batches)?)
+            .await
+    }
+
+    async fn send_batch(&self, batch: RecordBatch) -> Result<()> {
+        let num_rows = batch.num_rows();
+        let send_time = Instant::now();
+        let send_result = match self.send_timeout.get() {
+            Some(&send_timeout) => {
+                match tokio::time::timeout(send_timeout, self.sender.send(Ok(batch))).await {
+                    Ok(send_result) => send_result,
+                    Err(_) => return df_execution_err!("output stalled"),
+                }
+            }
+            None => self.sender.send(Ok(batch)).await,
+        };
+        if send_result.is_err() {
+            return df_execution_err!("output_with_sender: receiver dropped");
+        }
+        let send_elapsed = send_time.elapsed();
+
+        if let Some(exclude_time) = self.exclude_time.get() {
+            exclude_time.sub_duration(send_elapsed);
+        }
+        if let Some(output_wait_time) = self.output_wait_time.get() {
+            output_wait_time.add_duration(send_elapsed);
+        }
+        if let Some(output_metrics) = self.output_metrics.get() {
+            output_metrics.baseline_metrics.record_output(num_rows);
+            output_metrics.output_batches.add(1);
+        }
+        Ok(())
     }
 }
 
+/// concatenates staged batches in order, batches may only differ in schema
+/// metadata, the schema of the first batch is used for the output.
+fn coalesce_staged_batches(mut batches: Vec<RecordBatch>) -> Result<RecordBatch> {
+    if batches.len() == 1 {
+        return Ok(batches.pop().unwrap());
+    }
+    let schema = batches[0].schema();
+    for batch in &batches[1..] {
+        let batch_schema = batch.schema();
+        let compatible = batch_schema.fields().len() == schema.fields().len()
+            && batch_schema
+                .fields()
+                .iter()
+                .zip(schema.fields())
+                .all(|(f1, f2)| f1.data_type().equals_datatype(f2.data_type()));
+        if !compatible {
+            return df_execution_err!(
+                "output_with_sender: cannot coalesce batches with incompatible schemas: \
+                 {schema:?} vs {batch_schema:?}"
+            );
+        }
+    }
+    Ok(concat_batches(&schema, &batches)?)
+}
+
 pub fn cancel_all_tasks(task_ctx: &Arc<TaskContext>) {
     let mut working_senders = working_senders().lock();
     *working_senders = std::mem::take(&mut *working_senders)
@@ -457,3 +819,396 @@ pub fn cancel_all_tasks(task_ctx: &Arc<TaskContext>) {
         })
         .collect();
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        backtrace::{Backtrace, BacktraceStatus},
+        collections::HashMap,
+        panic::AssertUnwindSafe,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use arrow::{
+        array::{AsArray, Int32Array, Int64Array, RecordBatch},
+        datatypes::{DataType, Field, Int32Type, Schema},
+    };
+    use datafusion::{
+        common::Result,
+        physical_plan::metrics::{ExecutionPlanMetricsSet, MetricValue},
+        prelude::SessionContext,
+    };
+    use datafusion_ext_commons::df_execution_err;
+    use futures::{FutureExt, StreamExt, TryStreamExt};
+
+    use crate::common::{
+        error::{error_code, BlazeError},
+        execution_context::{coalesce_staged_batches, ExecutionContext, SpawnPolicy},
+    };
+
+    // sets the flag when the producer terminates
+    struct SetOnDrop(Arc<AtomicBool>);
+
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) {
+            self.0.store(true, SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_producer_stops_when_stream_dropped() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(vec![1]))])?;
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema,
+            &ExecutionPlanMetricsSet::new(),
+        );
+
+        let num_sent = Arc::new(AtomicUsize::new(0));
+        let producer_terminated = Arc::new(AtomicBool::new(false));
+        let num_sent_cloned = num_sent.clone();
+        let producer_terminated_cloned = producer_terminated.clone();
+        let mut stream = exec_ctx.output_with_sender("Test", move |sender| async move {
+            let _guard = SetOnDrop(producer_terminated_cloned);
+            for _ in 0..1000000 {
+                sender.send(batch.clone()).await?;
+                num_sent_cloned.fetch_add(1, SeqCst);
+            }
+            Ok(())
+        });
+
+        // consume one batch and drop the stream
+        assert!(stream.next().await.transpose()?.is_some());
+        drop(stream);
+
+        for _ in 0..10000 {
+            if producer_terminated.load(SeqCst) {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(producer_terminated.load(SeqCst));
+        assert!(num_sent.load(SeqCst) < 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_with_coalescing() -> Result<()> {
+        let field = Field::new("a", DataType::Int32, false);
+        let schema_with_metadata = |value: &str| {
+            Arc::new(Schema::new_with_metadata(
+                vec![field.clone()],
+                HashMap::from([("k".to_string(), value.to_string())]),
+            ))
+        };
+        let schema1 = schema_with_metadata("1");
+        let schema2 = schema_with_metadata("2");
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema1.clone(),
+            &ExecutionPlanMetricsSet::new(),
+        );
+
+        // batches with metadata-only schema differences are coalesced in order
+        let stream = exec_ctx
+            .output_with_sender_builder("Test")
+            .with_capacity(4)
+            .with_coalescing(7)
+            .build(move |sender| async move {
+                for i in 0..10 {
+                    let schema = if i % 2 == 0 { &schema1 } else { &schema2 };
+                    let values = Int32Array::from_iter_values(i * 3..i * 3 + 3);
+                    let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(values)])?;
+                    sender.send(batch).await?;
+                }
+                Ok(())
+            });
+        let batches: Vec<RecordBatch> = stream.try_collect().await?;
+        let num_rows = batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>();
+        assert_eq!(num_rows, vec![9, 9, 9, 3]);
+
+        let values = batches
+            .iter()
+            .flat_map(|b| b.column(0).as_primitive::<Int32Type>().values().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(values, (0..30).collect::<Vec<_>>());
+
+        // batches with different data types cannot be coalesced
+        let batch1 = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)])),
+            vec![Arc::new(Int32Array::from(vec![1]))],
+        )?;
+        let batch2 = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)])),
+            vec![Arc::new(Int64Array::from(vec![1]))],
+        )?;
+        assert!(coalesce_staged_batches(vec![batch1, batch2]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_producer_panic_reports_context() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            3,
+            schema,
+            &ExecutionPlanMetricsSet::new(),
+        );
+        fn explode() -> Result<()> {
+            panic!("producer exploded")
+        }
+        let mut stream =
+            exec_ctx.output_with_sender("PanickingOp", |_sender| async move { explode() });
+
+        // the error is delivered through the stream, the panicked task may also
+        // be propagated to the consumer
+        let message = match AssertUnwindSafe(stream.next()).catch_unwind().await {
+            Ok(Some(Err(err))) => err.to_string(),
+            Err(panic) => panic_message::get_panic_message(&panic)
+                .unwrap_or_default()
+                .to_string(),
+            Ok(_) => panic!("expect an error from panicked producer"),
+        };
+        assert!(
+            message.contains("panic in PanickingOp (partition 3): producer exploded"),
+            "unexpected error: {message}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_producer_panic_reports_label() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema,
+            &ExecutionPlanMetricsSet::new(),
+        );
+        fn explode() -> Result<()> {
+            panic!("agg exploded")
+        }
+        let mut stream = exec_ctx
+            .output_with_sender_builder("HashAgg")
+            .with_spawn_policy(SpawnPolicy::Blocking)
+            .build(|_sender| async move { explode() });
+
+        let message = match AssertUnwindSafe(stream.next()).catch_unwind().await {
+            Ok(Some(Err(err))) => err.to_string(),
+            Err(panic) => panic_message::get_panic_message(&panic)
+                .unwrap_or_default()
+                .to_string(),
+            Ok(_) => panic!("expect an error from panicked producer"),
+        };
+        assert!(message.contains("HashAgg"), "unexpected error: {message}");
+        assert!(
+            message.contains("agg exploded"),
+            "unexpected error: {message}"
+        );
+
+        // backtrace is attached only if enabled by RUST_BACKTRACE
+        let backtrace_enabled = Backtrace::capture().status() == BacktraceStatus::Captured;
+        assert_eq!(
+            message.contains("backtrace:"),
+            backtrace_enabled,
+            "unexpected error: {message}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_producer_error_codes() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let session_ctx = SessionContext::new();
+        let errors = vec![
+            BlazeError::OutOfMemory {
+                consumer: "SortShuffleRepartitioner".to_string(),
+                requested: 1 << 20,
+                available: 0,
+            },
+            BlazeError::SpillIo {
+                path: None,
+                source: std::io::Error::other("no space left on device"),
+            },
+            BlazeError::ShuffleCorruption {
+                partition: Some(1),
+                message: "truncated shuffle spill".to_string(),
+            },
+            BlazeError::Cancelled,
+        ];
+        for err in errors {
+            let code = err.code();
+            let exec_ctx = ExecutionContext::new(
+                session_ctx.task_ctx(),
+                0,
+                schema.clone(),
+                &ExecutionPlanMetricsSet::new(),
+            );
+            let mut stream = exec_ctx
+                .output_with_sender("FailingOp", move |_sender| async move { Err(err.into()) });
+
+            // codes survive the errors being stringified by the producer
+            let message = match AssertUnwindSafe(stream.next()).catch_unwind().await {
+                Ok(Some(Err(err))) => err.to_string(),
+                Err(panic) => panic_message::get_panic_message(&panic)
+                    .unwrap_or_default()
+                    .to_string(),
+                Ok(_) => panic!("expect an error from failed producer"),
+            };
+            assert_eq!(error_code(&message), Some(code), "{message}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_output_with_baseline_metrics() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+        )?;
+        let session_ctx = SessionContext::new();
+        let metrics = ExecutionPlanMetricsSet::new();
+        let exec_ctx = ExecutionContext::new(session_ctx.task_ctx(), 0, schema, &metrics);
+
+        let mut stream = exec_ctx
+            .output_with_sender_builder("Test")
+            .with_baseline_metrics(exec_ctx.baseline_metrics())
+            .with_output_wait_time()
+            .build(move |sender| async move {
+                for _ in 0..5 {
+                    sender.send(batch.clone()).await?;
+                }
+                Ok(())
+            });
+
+        // slow consumer, producer is blocked on sending
+        while let Some(batch) = stream.next().await.transpose()? {
+            assert_eq!(batch.num_rows(), 3);
+            tokio::task::yield_now().await;
+        }
+
+        let metrics = metrics.clone_inner();
+        let metric_value = |name: &str| metrics.sum_by_name(name).unwrap().as_usize();
+        assert_eq!(metric_value("output_rows"), 15);
+        assert_eq!(metric_value("output_batches"), 5);
+        assert!(metric_value("output_wait_time") > 0);
+        assert!(metrics.iter().any(|metric| matches!(
+            metric.value(),
+            MetricValue::EndTimestamp(end_time) if end_time.value().is_some()
+        )));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_blocking_producer_does_not_delay_other_streams() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(vec![1]))])?;
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema,
+            &ExecutionPlanMetricsSet::new(),
+        );
+
+        for spawn_policy in [SpawnPolicy::Blocking, SpawnPolicy::DedicatedPool(1)] {
+            // the slow producer blocks its thread until released
+            let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+            let slow_batch = batch.clone();
+            let slow_stream = exec_ctx
+                .output_with_sender_builder("Slow")
+                .with_spawn_policy(spawn_policy)
+                .build(move |sender| async move {
+                    // only bounds the test if the runtime is blocked
+                    if release_rx.recv_timeout(Duration::from_secs(60)).is_err() {
+                        return df_execution_err!("slow producer not released");
+                    }
+                    sender.send(slow_batch).await?;
+                    Ok(())
+                });
+            tokio::task::yield_now().await; // let the slow producer start
+
+            // the fast stream finishes while the slow producer is still blocked
+            let fast_batch = batch.clone();
+            let mut fast_stream = exec_ctx.output_with_sender("Fast", move |sender| async move {
+                sender.send(fast_batch).await?;
+                Ok(())
+            });
+            assert!(fast_stream.next().await.transpose()?.is_some());
+            assert!(fast_stream.next().await.transpose()?.is_none());
+            release_tx.send(()).unwrap_or_else(|_| {
+                panic!("fast stream is delayed by slow producer with {spawn_policy:?}")
+            });
+
+            let slow_batches: Vec<RecordBatch> = slow_stream.try_collect().await?;
+            assert_eq!(slow_batches.len(), 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_timeout_on_stalled_consumer() -> Result<()> {
+        tokio::time::pause();
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(vec![1]))])?;
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema,
+            &ExecutionPlanMetricsSet::new(),
+        );
+
+        let num_sent = Arc::new(AtomicUsize::new(0));
+        let producer_terminated = Arc::new(AtomicBool::new(false));
+        let num_sent_cloned = num_sent.clone();
+        let producer_terminated_cloned = producer_terminated.clone();
+        let stream = exec_ctx
+            .output_with_sender_builder("Test")
+            .with_capacity(1)
+            .with_send_timeout(Some(Duration::from_millis(100)))
+            .build(move |sender| async move {
+                let _guard = SetOnDrop(producer_terminated_cloned);
+                for _ in 0..10 {
+                    sender.send(batch.clone()).await?;
+                    num_sent_cloned.fetch_add(1, SeqCst);
+                }
+                Ok(())
+            });
+
+        // do not read from the stream, the producer fails after the timeout
+        let start_time = tokio::time::Instant::now();
+        for _ in 0..100 {
+            if producer_terminated.load(SeqCst) {
+                break;
+            }
+            tokio::time::advance(Duration::from_millis(10)).await;
+        }
+        assert!(producer_terminated.load(SeqCst));
+        assert!(start_time.elapsed() >= Duration::from_millis(100));
+        assert!(num_sent.load(SeqCst) < 10);
+
+        let err = stream
+            .try_collect::<Vec<_>>()
+            .await
+            .expect_err("stalled output should fail");
+        assert!(err.to_string().contains("output stalled"), "{err}");
+        Ok(())
+    }
+}
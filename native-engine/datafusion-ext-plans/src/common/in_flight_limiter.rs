@@ -0,0 +1,188 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering::SeqCst},
+    Arc, Weak,
+};
+
+use async_trait::async_trait;
+use blaze_jni_bridge::{
+    conf::{LongConf, EXPORT_IN_FLIGHT_MAX_MEM_SIZE},
+    is_jni_bridge_inited,
+};
+use datafusion::common::Result;
+use once_cell::sync::OnceCell;
+use tokio::sync::Notify;
+
+use crate::memmgr::{MemConsumer, MemConsumerInfo, MemManager};
+
+/// limits memory of batches exported to the JVM but not yet consumed. the
+/// in-flight bytes are accounted as an unspillable consumer, and producers
+/// are blocked when they exceed the cap until the JVM consumes some batches.
+pub struct InFlightLimiter {
+    mem_consumer_info: Option<Weak<MemConsumerInfo>>,
+    state: Arc<InFlightState>,
+    max_mem_size: usize,
+}
+
+#[derive(Default)]
+struct InFlightState {
+    mem_size: AtomicUsize,
+    peak_mem_size: AtomicUsize,
+    released: Notify,
+}
+
+/// holds in-flight bytes of an exported batch, released on dropping
+pub struct InFlightPermit {
+    state: Arc<InFlightState>,
+    consumer_info: Weak<MemConsumerInfo>,
+    mem_size: usize,
+}
+
+impl InFlightLimiter {
+    /// creates a limiter with the cap of spark.blaze.export.inFlight.maxMemSize
+    pub fn try_new() -> Result<Arc<Self>> {
+        Self::try_new_with_max_mem_size(export_in_flight_max_mem_size())
+    }
+
+    /// creates a limiter with the given cap, 0 for no limit
+    pub fn try_new_with_max_mem_size(max_mem_size: usize) -> Result<Arc<Self>> {
+        let limiter = Arc::new(Self {
+            mem_consumer_info: None,
+            state: Arc::default(),
+            max_mem_size,
+        });
+        MemManager::register_consumer(limiter.clone(), false)?;
+        Ok(limiter)
+    }
+
+    /// waits until a batch of `mem_size` bytes can be exported. a batch is
+    /// always allowed when nothing is in flight, even if it exceeds the cap.
+    pub async fn acquire(&self, mem_size: usize) -> Result<InFlightPermit> {
+        loop {
+            let in_flight = self.state.mem_size.load(SeqCst);
+            if self.max_mem_size == 0 || in_flight == 0 || in_flight + mem_size <= self.max_mem_size
+            {
+                break;
+            }
+            self.state.released.notified().await;
+        }
+        let in_flight = self.state.mem_size.fetch_add(mem_size, SeqCst) + mem_size;
+        self.state.peak_mem_size.fetch_max(in_flight, SeqCst);
+        self.update_mem_used_with_diff(mem_size as isize).await?;
+        Ok(InFlightPermit {
+            state: self.state.clone(),
+            consumer_info: self.get_consumer_info().clone(),
+            mem_size,
+        })
+    }
+
+    pub fn in_flight_mem_size(&self) -> usize {
+        self.state.mem_size.load(SeqCst)
+    }
+
+    /// high-water mark of in-flight bytes
+    pub fn peak_mem_size(&self) -> usize {
+        self.state.peak_mem_size.load(SeqCst)
+    }
+}
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        self.state.mem_size.fetch_sub(self.mem_size, SeqCst);
+        if let Some(consumer_info) = self.consumer_info.upgrade() {
+            consumer_info.release_mem_used(self.mem_size);
+        }
+        self.state.released.notify_one();
+    }
+}
+
+#[async_trait]
+impl MemConsumer for InFlightLimiter {
+    fn name(&self) -> &str {
+        "InFlightLimiter"
+    }
+
+    fn set_consumer_info(&mut self, consumer_info: Weak<MemConsumerInfo>) {
+        self.mem_consumer_info = Some(consumer_info);
+    }
+
+    fn get_consumer_info(&self) -> &Weak<MemConsumerInfo> {
+        self.mem_consumer_info
+            .as_ref()
+            .expect("consumer info not set")
+    }
+}
+
+impl Drop for InFlightLimiter {
+    fn drop(&mut self) {
+        MemManager::deregister_consumer(self);
+    }
+}
+
+fn export_in_flight_max_mem_size() -> usize {
+    static MAX_MEM_SIZE: OnceCell<usize> = OnceCell::new();
+    *MAX_MEM_SIZE.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            EXPORT_IN_FLIGHT_MAX_MEM_SIZE.value().unwrap_or(0).max(0) as usize
+        } else {
+            0 // for testing
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use datafusion::common::Result;
+
+    use crate::{
+        common::in_flight_limiter::InFlightLimiter,
+        memmgr::{MemConsumer, MemManager},
+    };
+
+    #[tokio::test]
+    async fn test_block_until_released() -> Result<()> {
+        MemManager::init(1000000);
+        let limiter = InFlightLimiter::try_new_with_max_mem_size(100)?;
+
+        // oversized batch is allowed when nothing is in flight
+        let permit1 = limiter.acquire(150).await?;
+        assert_eq!(limiter.in_flight_mem_size(), 150);
+        assert_eq!(limiter.consumer_info().mem_used(), 150);
+
+        // blocked until the first batch is consumed
+        tokio::time::pause();
+        let mut acquiring = Box::pin(limiter.acquire(60));
+        let blocked = tokio::time::timeout(Duration::from_millis(100), &mut acquiring).await;
+        assert!(blocked.is_err());
+        drop(permit1);
+        assert_eq!(limiter.consumer_info().mem_used(), 0);
+        let permit2 = acquiring.await?;
+        assert_eq!(limiter.in_flight_mem_size(), 60);
+        assert_eq!(limiter.consumer_info().mem_used(), 60);
+
+        // batches fitting the cap are not blocked
+        let permit3 = limiter.acquire(40).await?;
+        assert_eq!(limiter.in_flight_mem_size(), 100);
+        drop(permit2);
+        drop(permit3);
+        assert_eq!(limiter.in_flight_mem_size(), 0);
+        assert_eq!(limiter.consumer_info().mem_used(), 0);
+        assert_eq!(limiter.peak_mem_size(), 150);
+        Ok(())
+    }
+}
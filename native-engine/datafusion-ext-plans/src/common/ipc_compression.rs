@@ -15,33 +15,105 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::io::{BufReader, Read, Take, Write};
+use std::{
+    io::{BufReader, Read, Take, Write},
+    sync::Arc,
+};
 
-use arrow::{array::ArrayRef, datatypes::SchemaRef};
-use blaze_jni_bridge::{conf, conf::StringConf, is_jni_bridge_inited};
+use arrow::{
+    array::{ArrayRef, AsArray},
+    compute::{cast, max, min},
+    datatypes::{DataType, Int64Type, SchemaRef},
+};
+use blaze_jni_bridge::{
+    conf,
+    conf::{BooleanConf, LongConf, StringConf},
+    is_jni_bridge_inited,
+};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use count_write::CountWrite;
 use datafusion::common::Result;
 use datafusion_ext_commons::{
     df_execution_err,
-    io::{read_one_batch, write_one_batch},
+    io::{read_len, write_len, SegmentHasher, SegmentTrailer, SEGMENT_TRAILER_LEN},
 };
 use once_cell::sync::OnceCell;
 
+use crate::memmgr::spill::{DefaultSpillSerializer, SpillSerializer};
+
 pub const DEFAULT_SHUFFLE_COMPRESSION_TARGET_BUF_SIZE: usize = 4194304;
 const ZSTD_LEVEL: i32 = 1;
 
+// blocks written by older versions start with a plain u32 block length. newer
+// blocks start with a u32 header word with the highest bit set, which carries a
+// format version byte and a flags byte, followed by a varint block length.
+const BLOCK_HEADER_MARK: u32 = 1 << 31;
+const BLOCK_FORMAT_VERSION: u8 = 1;
+const BLOCK_FLAG_ZSTD: u8 = 1;
+const BLOCK_FLAG_TRAILER: u8 = 2;
+const BLOCK_FLAG_STATS: u8 = 4;
+const COLUMN_STATS_LEN: usize = 4 + 8 + 8;
+const BLOCK_HEADER_MAX_LEN: usize = 4 + 10;
+
+// consumers of ipc messages commonly fail on messages of 2GB or more
+const DEFAULT_MAX_MESSAGE_SIZE: usize = i32::MAX as usize;
+
 pub struct IpcCompressionWriter<W: Write> {
     output: W,
     shared_buf: VecBuffer,
     block_writer: IoCompressionWriter<VecBufferWrite>,
     block_empty: bool,
+    segment: Option<SegmentState>,
+    stat_columns: Vec<usize>,
+    block_stats: Vec<ColumnStats>,
+    serializer: Arc<dyn SpillSerializer>,
+    max_message_size: usize,
+    target_buf_size: usize,
 }
 unsafe impl<W: Write> Send for IpcCompressionWriter<W> {}
 
+/// min/max of non-null values of an integer column in a block
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColumnStats {
+    pub column: usize,
+    pub min: i64,
+    pub max: i64,
+}
+
+/// filters blocks by their stats, a block is skipped only if it has stats
+/// of the column and none of them falls into `min..=max`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchStatsPredicate {
+    pub column: usize,
+    pub min: i64,
+    pub max: i64,
+}
+
+impl BatchStatsPredicate {
+    pub fn new(column: usize, min: i64, max: i64) -> Self {
+        Self { column, min, max }
+    }
+
+    fn may_match(&self, block_stats: &[ColumnStats]) -> bool {
+        block_stats
+            .iter()
+            .filter(|stats| stats.column == self.column)
+            .all(|stats| stats.min <= self.max && stats.max >= self.min)
+    }
+}
+
+#[derive(Default)]
+struct SegmentState {
+    trailer: SegmentTrailer,
+    hasher: SegmentHasher,
+}
+
 impl<W: Write> IpcCompressionWriter<W> {
     pub fn new(output: W) -> Self {
         let mut shared_buf = VecBuffer::default();
-        shared_buf.inner_mut().extend_from_slice(&[0u8; 4]);
+        shared_buf
+            .inner_mut()
+            .extend_from_slice(&[0u8; BLOCK_HEADER_MAX_LEN]);
 
         let block_writer = IoCompressionWriter::new_with_configured_codec(shared_buf.writer());
         Self {
@@ -49,9 +121,48 @@ impl<W: Write> IpcCompressionWriter<W> {
             shared_buf,
             block_writer,
             block_empty: true,
+            segment: shuffle_segment_trailer_enabled().then(SegmentState::default),
+            stat_columns: vec![],
+            block_stats: vec![],
+            serializer: Arc::new(DefaultSpillSerializer),
+            max_message_size: shuffle_max_message_size(),
+            target_buf_size: DEFAULT_SHUFFLE_COMPRESSION_TARGET_BUF_SIZE,
         }
     }
 
+    /// serializes batches with the given serializer instead of the default
+    pub fn with_serializer(mut self, serializer: Arc<dyn SpillSerializer>) -> Self {
+        self.serializer = serializer;
+        self
+    }
+
+    /// splits batches larger than `max_message_size` bytes of data, so that
+    /// each serialized batch stays under the limit
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// finishes a compressed block once it reaches about `target_buf_size`
+    /// bytes, smaller blocks use less memory but compress worse
+    pub fn with_target_buf_size(mut self, target_buf_size: usize) -> Self {
+        self.target_buf_size = target_buf_size;
+        self
+    }
+
+    /// writes min/max of the given integer columns into a stats block
+    /// preceding each batch. every batch is written in its own block, so that
+    /// readers can skip it without decoding.
+    pub fn with_stat_columns(mut self, stat_columns: Vec<usize>) -> Self {
+        self.stat_columns = stat_columns;
+        self
+    }
+
+    pub fn with_segment_trailer(mut self, enabled: bool) -> Self {
+        self.segment = enabled.then(SegmentState::default);
+        self
+    }
+
     pub fn set_output(&mut self, output: W) {
         assert!(
             self.block_empty,
@@ -64,11 +175,40 @@ impl<W: Write> IpcCompressionWriter<W> {
         if num_rows == 0 {
             return Ok(());
         }
-        write_one_batch(num_rows, cols, &mut self.block_writer)?;
+
+        // write oversized batches in halves, a single row is never split
+        if num_rows > 1 && batch_data_size(cols)? > self.max_message_size {
+            let slice = |offset, len| {
+                cols.iter()
+                    .map(|col| col.slice(offset, len))
+                    .collect::<Vec<_>>()
+            };
+            let mid = num_rows / 2;
+            self.write_batch(mid, &slice(0, mid))?;
+            return self.write_batch(num_rows - mid, &slice(mid, num_rows - mid));
+        }
+
+        let mut block_writer = CountWrite::from(&mut self.block_writer);
+        self.serializer
+            .write_batch(num_rows, cols, &mut block_writer)?;
         self.block_empty = false;
+        let has_stats = !self.stat_columns.is_empty();
+        if has_stats {
+            self.block_stats = self
+                .stat_columns
+                .iter()
+                .filter_map(|&column| compute_column_stats(column, cols.get(column)?))
+                .collect();
+        }
+
+        if let Some(segment) = &mut self.segment {
+            segment.trailer.num_batches += 1;
+            segment.trailer.num_rows += num_rows as u64;
+            segment.trailer.uncompressed_size += block_writer.count();
+        }
 
         let buf_len = self.shared_buf.inner().len();
-        if buf_len as f64 >= DEFAULT_SHUFFLE_COMPRESSION_TARGET_BUF_SIZE as f64 * 0.9 {
+        if has_stats || buf_len as f64 >= self.target_buf_size as f64 * 0.9 {
             self.finish_current_buf()?;
         }
         Ok(())
@@ -79,16 +219,37 @@ impl<W: Write> IpcCompressionWriter<W> {
             // finish current buf
             self.block_writer.finish_internal()?;
 
-            // write
-            let block_len = self.shared_buf.inner().len() - 4;
-            self.shared_buf.inner_mut()[0..4]
-                .as_mut()
-                .write_u32::<LittleEndian>(block_len as u32)?;
-            self.output.write_all(self.shared_buf.inner())?;
+            // stats block is written before the data block it describes
+            if !self.block_stats.is_empty() {
+                let block_stats = std::mem::take(&mut self.block_stats);
+                let stats_block = encode_stats_block(&block_stats)?;
+                self.output.write_all(&stats_block)?;
+                if let Some(segment) = &mut self.segment {
+                    segment.hasher.update(&stats_block);
+                    segment.trailer.payload_len += stats_block.len() as u64;
+                }
+            }
+
+            // write header into the end of reserved space, so that the whole
+            // block is written at once
+            let block_len = self.shared_buf.inner().len() - BLOCK_HEADER_MAX_LEN;
+            let mut header = Vec::with_capacity(BLOCK_HEADER_MAX_LEN);
+            write_block_header(block_len, block_flags(), &mut header)?;
+            let header_start = BLOCK_HEADER_MAX_LEN - header.len();
+            let buf = self.shared_buf.inner_mut();
+            buf[header_start..BLOCK_HEADER_MAX_LEN].copy_from_slice(&header);
+            self.output.write_all(&buf[header_start..])?;
+
+            if let Some(segment) = &mut self.segment {
+                segment.hasher.update(&buf[header_start..]);
+                segment.trailer.payload_len += (buf.len() - header_start) as u64;
+            }
 
             // open next buf
             self.shared_buf.inner_mut().clear();
-            self.shared_buf.inner_mut().extend_from_slice(&[0u8; 4]);
+            self.shared_buf
+                .inner_mut()
+                .extend_from_slice(&[0u8; BLOCK_HEADER_MAX_LEN]);
             self.block_writer =
                 IoCompressionWriter::new_with_configured_codec(self.shared_buf.writer());
             self.block_empty = true;
@@ -96,6 +257,30 @@ impl<W: Write> IpcCompressionWriter<W> {
         Ok(())
     }
 
+    /// finishes current buf and ends the segment, writing a trailer block if
+    /// segment trailers are enabled
+    pub fn finish_segment(&mut self) -> Result<()> {
+        self.finish_current_buf()?;
+
+        if let Some(SegmentState {
+            mut trailer,
+            mut hasher,
+        }) = self.segment.take()
+        {
+            if trailer.num_batches > 0 {
+                let mut header = Vec::with_capacity(BLOCK_HEADER_MAX_LEN);
+                write_block_header(SEGMENT_TRAILER_LEN, BLOCK_FLAG_TRAILER, &mut header)?;
+                hasher.update(&header);
+                trailer.payload_len += header.len() as u64;
+                trailer.payload_hash = hasher.finish();
+                self.output.write_all(&header)?;
+                self.output.write_all(&trailer.to_bytes())?;
+            }
+            self.segment = Some(SegmentState::default());
+        }
+        Ok(())
+    }
+
     pub fn inner(&self) -> &W {
         &self.output
     }
@@ -107,6 +292,9 @@ impl<W: Write> IpcCompressionWriter<W> {
 
 pub struct IpcCompressionReader<R: Read + 'static> {
     input: InputState<R>,
+    predicate: Option<BatchStatsPredicate>,
+    num_skipped_blocks: usize,
+    serializer: Arc<dyn SpillSerializer>,
 }
 unsafe impl<R: Read> Send for IpcCompressionReader<R> {}
 
@@ -122,30 +310,48 @@ impl<R: Read> IpcCompressionReader<R> {
     pub fn new(input: R) -> Self {
         Self {
             input: InputState::BlockStart(input),
+            predicate: None,
+            num_skipped_blocks: 0,
+            serializer: Arc::new(DefaultSpillSerializer),
         }
     }
 
+    /// deserializes batches with the serializer they are written with
+    pub fn with_serializer(mut self, serializer: Arc<dyn SpillSerializer>) -> Self {
+        self.serializer = serializer;
+        self
+    }
+
+    /// skips blocks whose stats do not match the predicate. blocks without
+    /// stats are always read.
+    pub fn with_predicate(mut self, predicate: BatchStatsPredicate) -> Self {
+        self.predicate = Some(predicate);
+        self
+    }
+
+    /// number of data blocks skipped by the predicate
+    pub fn num_skipped_blocks(&self) -> usize {
+        self.num_skipped_blocks
+    }
+
     pub fn read_batch(&mut self, schema: &SchemaRef) -> Result<Option<(usize, Vec<ArrayRef>)>> {
         struct Reader<'a, R: Read + 'static>(&'a mut IpcCompressionReader<R>);
         impl<'a, R: Read> Read for Reader<'a, R> {
             fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
                 match std::mem::take(&mut self.0.input) {
                     InputState::BlockStart(mut input) => {
-                        let block_len = match input.read_u32::<LittleEndian>() {
-                            Ok(block_len) => block_len,
-                            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
-                                return Ok(0);
-                            }
-                            Err(err) => {
-                                return Err(err);
-                            }
+                        let (block_len, codec) = match read_block_header_filtered(
+                            &mut input,
+                            self.0.predicate.as_ref(),
+                            &mut self.0.num_skipped_blocks,
+                        )? {
+                            Some(header) => header,
+                            None => return Ok(0),
                         };
                         let taken = input.take(block_len as u64);
 
-                        self.0.input = InputState::BlockContent(IoCompressionReader::try_new(
-                            io_compression_codec(),
-                            taken,
-                        )?);
+                        self.0.input =
+                            InputState::BlockContent(IoCompressionReader::try_new(codec, taken)?);
                         self.read(buf)
                     }
                     InputState::BlockContent(mut block_reader) => match block_reader.read(buf) {
@@ -164,7 +370,8 @@ impl<R: Read> IpcCompressionReader<R> {
                 }
             }
         }
-        read_one_batch(&mut Reader(self), schema)
+        let serializer = self.serializer.clone();
+        serializer.read_batch(&mut Reader(self), schema)
     }
 }
 
@@ -255,6 +462,179 @@ impl<R: Read> Read for IoCompressionReader<R> {
     }
 }
 
+fn block_flags() -> u8 {
+    match io_compression_codec() {
+        "zstd" => BLOCK_FLAG_ZSTD,
+        _ => 0,
+    }
+}
+
+fn write_block_header<W: Write>(
+    block_len: usize,
+    flags: u8,
+    output: &mut W,
+) -> std::io::Result<()> {
+    let header = BLOCK_HEADER_MARK | ((BLOCK_FORMAT_VERSION as u32) << 8) | flags as u32;
+    output.write_u32::<LittleEndian>(header)?;
+    write_len(block_len, output)
+}
+
+enum BlockKind {
+    Data(&'static str),
+    Trailer,
+    Stats,
+}
+
+/// reads block header, returns block length and codec, or None if reaching
+/// the end of input. segment trailer and stats blocks are skipped.
+#[cfg(test)]
+fn read_block_header<R: Read>(input: &mut R) -> std::io::Result<Option<(usize, &'static str)>> {
+    read_block_header_filtered(input, None, &mut 0)
+}
+
+/// reads block header like `read_block_header`, data blocks whose stats do
+/// not match the predicate are also skipped
+fn read_block_header_filtered<R: Read>(
+    input: &mut R,
+    predicate: Option<&BatchStatsPredicate>,
+    num_skipped_blocks: &mut usize,
+) -> std::io::Result<Option<(usize, &'static str)>> {
+    let mut skip_next_block = false;
+    loop {
+        match read_block_header_impl(input)? {
+            Some((block_len, BlockKind::Data(codec))) if !skip_next_block => {
+                return Ok(Some((block_len, codec)));
+            }
+            Some((block_len, BlockKind::Data(_))) => {
+                skip_block(input, block_len, "data")?;
+                *num_skipped_blocks += 1;
+                skip_next_block = false;
+            }
+            Some((block_len, BlockKind::Stats)) => {
+                let block_stats = decode_stats_block(input, block_len)?;
+                skip_next_block = predicate.is_some_and(|p| !p.may_match(&block_stats));
+            }
+            Some((block_len, BlockKind::Trailer)) => {
+                skip_block(input, block_len, "segment trailer")?;
+            }
+            None => return Ok(None),
+        }
+    }
+}
+
+fn skip_block<R: Read>(input: &mut R, block_len: usize, kind: &str) -> std::io::Result<()> {
+    let mut block = input.by_ref().take(block_len as u64);
+    let skipped = std::io::copy(&mut block, &mut std::io::sink())?;
+    if skipped != block_len as u64 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("truncated {kind} block"),
+        ));
+    }
+    Ok(())
+}
+
+/// reads block header, returns block length and kind, or None if reaching
+/// the end of input
+fn read_block_header_impl<R: Read>(input: &mut R) -> std::io::Result<Option<(usize, BlockKind)>> {
+    let invalid_data = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+    let header = match input.read_u32::<LittleEndian>() {
+        Ok(header) => header,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    // legacy block, the header is the block length
+    if header & BLOCK_HEADER_MARK == 0 {
+        return Ok(Some((
+            header as usize,
+            BlockKind::Data(io_compression_codec()),
+        )));
+    }
+
+    let version = (header >> 8) as u8;
+    let flags = header as u8;
+    if version != BLOCK_FORMAT_VERSION || header & !BLOCK_HEADER_MARK > 0xffff {
+        return Err(invalid_data(format!(
+            "unsupported ipc block format: {header:#010x}"
+        )));
+    }
+    if flags & !(BLOCK_FLAG_ZSTD | BLOCK_FLAG_TRAILER | BLOCK_FLAG_STATS) != 0 {
+        return Err(invalid_data(format!(
+            "unsupported ipc block flags: {flags:#04x}"
+        )));
+    }
+    let block_len = read_len(input)?;
+    if flags & BLOCK_FLAG_TRAILER != 0 {
+        return Ok(Some((block_len, BlockKind::Trailer)));
+    }
+    if flags & BLOCK_FLAG_STATS != 0 {
+        return Ok(Some((block_len, BlockKind::Stats)));
+    }
+    let codec = match flags & BLOCK_FLAG_ZSTD {
+        0 => "lz4",
+        _ => "zstd",
+    };
+    Ok(Some((block_len, BlockKind::Data(codec))))
+}
+
+// only integer-like columns are supported, returns None for other types or
+// all-null columns
+fn compute_column_stats(column: usize, array: &ArrayRef) -> Option<ColumnStats> {
+    let supported = match array.data_type() {
+        DataType::UInt64 => false, // may overflow i64
+        DataType::Date32 | DataType::Date64 => true,
+        data_type => data_type.is_integer(),
+    };
+    if !supported {
+        return None;
+    }
+    let array = cast(array, &DataType::Int64).ok()?;
+    let array = array.as_primitive::<Int64Type>();
+    Some(ColumnStats {
+        column,
+        min: min(array)?,
+        max: max(array)?,
+    })
+}
+
+// stats block: header, number of columns (u32), and (column, min, max) of
+// each column
+fn encode_stats_block(block_stats: &[ColumnStats]) -> std::io::Result<Vec<u8>> {
+    let block_len = 4 + block_stats.len() * COLUMN_STATS_LEN;
+    let mut block = Vec::with_capacity(BLOCK_HEADER_MAX_LEN + block_len);
+    write_block_header(block_len, BLOCK_FLAG_STATS, &mut block)?;
+    block.write_u32::<LittleEndian>(block_stats.len() as u32)?;
+    for stats in block_stats {
+        block.write_u32::<LittleEndian>(stats.column as u32)?;
+        block.write_i64::<LittleEndian>(stats.min)?;
+        block.write_i64::<LittleEndian>(stats.max)?;
+    }
+    Ok(block)
+}
+
+fn decode_stats_block<R: Read>(
+    input: &mut R,
+    block_len: usize,
+) -> std::io::Result<Vec<ColumnStats>> {
+    let num_columns = input.read_u32::<LittleEndian>()? as usize;
+    if Some(block_len) != num_columns.checked_mul(COLUMN_STATS_LEN).map(|len| len + 4) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid stats block: len={block_len}, num_columns={num_columns}"),
+        ));
+    }
+    (0..num_columns)
+        .map(|_| {
+            Ok(ColumnStats {
+                column: input.read_u32::<LittleEndian>()? as usize,
+                min: input.read_i64::<LittleEndian>()?,
+                max: input.read_i64::<LittleEndian>()?,
+            })
+        })
+        .collect()
+}
+
 fn io_compression_codec() -> &'static str {
     static CODEC: OnceCell<String> = OnceCell::new();
     CODEC
@@ -269,6 +649,45 @@ fn io_compression_codec() -> &'static str {
         .as_str()
 }
 
+/// returns bytes of data referenced by the (possibly sliced) columns, which
+/// is close to their serialized size
+fn batch_data_size(cols: &[ArrayRef]) -> Result<usize> {
+    let mut data_size = 0;
+    for col in cols {
+        data_size += col.to_data().get_slice_memory_size()?;
+    }
+    Ok(data_size)
+}
+
+fn shuffle_max_message_size() -> usize {
+    static MAX_MESSAGE_SIZE: OnceCell<usize> = OnceCell::new();
+    *MAX_MESSAGE_SIZE.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_MAX_MESSAGE_SIZE
+                .value()
+                .ok()
+                .and_then(|max_message_size| usize::try_from(max_message_size).ok())
+                .filter(|&max_message_size| max_message_size > 0)
+                .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+        } else {
+            DEFAULT_MAX_MESSAGE_SIZE // for testing
+        }
+    })
+}
+
+fn shuffle_segment_trailer_enabled() -> bool {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    *ENABLED.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_SEGMENT_TRAILER_ENABLE
+                .value()
+                .unwrap_or(false)
+        } else {
+            false // for testing
+        }
+    })
+}
+
 #[derive(Default)]
 struct VecBuffer {
     vec: Box<Vec<u8>>,
@@ -311,9 +730,12 @@ mod tests {
     use std::{error::Error, io::Cursor, sync::Arc};
 
     use arrow::{
-        array::StringArray,
+        array::{Int32Array, StringArray},
         datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
     };
+    use datafusion_ext_commons::io::{inspect_shuffle_files, write_one_batch};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use super::*;
 
@@ -340,4 +762,269 @@ mod tests {
         assert!(reader.read_batch(&schema)?.is_none());
         Ok(())
     }
+
+    #[test]
+    fn test_split_oversized_batch() -> Result<(), Box<dyn Error>> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Utf8, false),
+        ]));
+        let ids: ArrayRef = Arc::new(Int32Array::from_iter_values(0..100));
+        let values: ArrayRef = Arc::new(StringArray::from_iter_values(
+            (0..100).map(|i| format!("{i:050}")),
+        ));
+        let small: ArrayRef = Arc::new(StringArray::from(vec!["small"]));
+
+        // the large batch is split into several batches, followed by the
+        // small batch in its own segment
+        let mut writer = IpcCompressionWriter::new(vec![]).with_max_message_size(1000);
+        writer.write_batch(100, &[ids.clone(), values.clone()])?;
+        writer.finish_current_buf()?;
+        let offset = writer.inner().len();
+        let small_ids: ArrayRef = Arc::new(Int32Array::from(vec![100]));
+        writer.write_batch(1, &[small_ids, small.clone()])?;
+        writer.finish_current_buf()?;
+        let buf = writer.inner().clone();
+
+        let mut reader = IpcCompressionReader::new(Cursor::new(buf[..offset].to_vec()));
+        let mut batches = vec![];
+        while let Some((num_rows, cols)) = reader.read_batch(&schema)? {
+            assert!(batch_data_size(&cols)? <= 1000);
+            batches.push(RecordBatch::try_new(schema.clone(), cols)?);
+            assert_eq!(batches.last().unwrap().num_rows(), num_rows);
+        }
+        assert!(batches.len() > 1);
+        let merged = arrow::compute::concat_batches(&schema, &batches)?;
+        assert_eq!(merged.columns(), &[ids, values]);
+
+        let mut reader = IpcCompressionReader::new(Cursor::new(buf[offset..].to_vec()));
+        let (num_rows, cols) = reader.read_batch(&schema)?.unwrap();
+        assert_eq!(num_rows, 1);
+        assert_eq!(cols[1], small);
+        assert!(reader.read_batch(&schema)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_header() -> Result<(), Box<dyn Error>> {
+        for (block_len, expected_header_len) in [
+            (0, 5),
+            (127, 5),
+            (128, 6),
+            (1 << 31, 9),
+            (usize::MAX, BLOCK_HEADER_MAX_LEN),
+        ] {
+            for flags in [0, BLOCK_FLAG_ZSTD] {
+                let mut buf = vec![];
+                write_block_header(block_len, flags, &mut buf)?;
+                assert_eq!(buf.len(), expected_header_len);
+
+                let expected_codec = if flags == 0 { "lz4" } else { "zstd" };
+                let mut cursor = Cursor::new(buf);
+                let header = read_block_header(&mut cursor)?;
+                assert_eq!(header, Some((block_len, expected_codec)));
+                assert!(read_block_header(&mut cursor)?.is_none());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_legacy_blocks() -> Result<(), Box<dyn Error>> {
+        let test_array1: ArrayRef = Arc::new(StringArray::from(vec![Some("hello"), Some("world")]));
+        let test_array2: ArrayRef = Arc::new(StringArray::from(vec![Some("foo"), Some("bar")]));
+        let schema = Arc::new(Schema::new(vec![Field::new("", DataType::Utf8, false)]));
+
+        // legacy block: u32 block length + compressed data
+        let mut block = vec![];
+        let mut block_writer = IoCompressionWriter::try_new("lz4", &mut block)?;
+        write_one_batch(2, &[test_array1.clone()], &mut block_writer)?;
+        block_writer.finish()?;
+        let mut buf = vec![];
+        buf.write_u32::<LittleEndian>(block.len() as u32)?;
+        buf.extend_from_slice(&block);
+
+        // followed by a versioned block
+        let mut writer = IpcCompressionWriter::new(&mut buf);
+        writer.write_batch(2, &[test_array2.clone()])?;
+        writer.finish_current_buf()?;
+
+        let mut reader = IpcCompressionReader::new(Cursor::new(buf));
+        let (num_rows1, arrays1) = reader.read_batch(&schema)?.unwrap();
+        assert_eq!(num_rows1, 2);
+        assert_eq!(arrays1, &[test_array1]);
+        let (num_rows2, arrays2) = reader.read_batch(&schema)?.unwrap();
+        assert_eq!(num_rows2, 2);
+        assert_eq!(arrays2, &[test_array2]);
+        assert!(reader.read_batch(&schema)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_segment_trailer() -> Result<(), Box<dyn Error>> {
+        let test_array1: ArrayRef = Arc::new(StringArray::from(vec![Some("hello"), Some("world")]));
+        let test_array2: ArrayRef = Arc::new(StringArray::from(vec![Some("foo"), Some("bar")]));
+        let schema = Arc::new(Schema::new(vec![Field::new("", DataType::Utf8, false)]));
+
+        // partition 0: two batches, partition 1: empty, partition 2: one batch
+        let mut data = vec![];
+        let mut offsets = vec![0u64];
+        let mut writer = IpcCompressionWriter::new(&mut data).with_segment_trailer(true);
+        writer.write_batch(2, &[test_array1.clone()])?;
+        writer.write_batch(2, &[test_array2.clone()])?;
+        writer.finish_segment()?;
+        offsets.push(writer.inner().len() as u64);
+        writer.finish_segment()?;
+        offsets.push(writer.inner().len() as u64);
+        writer.write_batch(2, &[test_array1.clone()])?;
+        writer.finish_segment()?;
+        offsets.push(writer.inner().len() as u64);
+
+        // trailers are skipped by readers
+        let mut reader = IpcCompressionReader::new(Cursor::new(data.clone()));
+        for expected in [&test_array1, &test_array2, &test_array1] {
+            let (num_rows, arrays) = reader.read_batch(&schema)?.unwrap();
+            assert_eq!(num_rows, 2);
+            assert_eq!(arrays, &[expected.clone()]);
+        }
+        assert!(reader.read_batch(&schema)?.is_none());
+
+        let dir = tempfile::tempdir()?;
+        let data_file = dir.path().join("data");
+        let index_file = dir.path().join("index");
+        std::fs::write(&data_file, &data)?;
+        std::fs::write(
+            &index_file,
+            offsets
+                .iter()
+                .flat_map(|&offset| (offset as i64).to_le_bytes())
+                .collect::<Vec<_>>(),
+        )?;
+
+        let mut output = vec![];
+        let stats = inspect_shuffle_files(&data_file, &index_file, &mut output)?;
+        assert_eq!(stats.len(), 3);
+        assert_eq!(String::from_utf8(output)?.lines().count(), 3);
+        for (stats, expected_batches) in stats.iter().zip([2, 0, 1]) {
+            assert_eq!(stats.num_trailers, (expected_batches > 0) as usize);
+            assert_eq!(stats.num_batches, expected_batches);
+            assert_eq!(stats.num_rows, expected_batches * 2);
+            assert_eq!(stats.num_checksum_errors, 0);
+            assert_eq!(stats.untrailed_len, 0);
+        }
+        assert!(stats[0].uncompressed_size > 0);
+
+        // corrupted payload is detected
+        let corrupted_pos = offsets[1] as usize - SEGMENT_TRAILER_LEN - 8;
+        data[corrupted_pos] ^= 0xff;
+        std::fs::write(&data_file, &data)?;
+        let stats = inspect_shuffle_files(&data_file, &index_file, std::io::sink())?;
+        assert_eq!(stats[0].num_checksum_errors, 1);
+        assert_eq!(stats[2].num_checksum_errors, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_stats() -> Result<(), Box<dyn Error>> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("", DataType::Int32, true),
+            Field::new("", DataType::Utf8, false),
+        ]));
+        let batches: Vec<Vec<ArrayRef>> = [
+            Int32Array::from_iter_values(0..10),
+            Int32Array::from_iter_values(10..20),
+            Int32Array::from(vec![None, Some(25), Some(20)]),
+            Int32Array::from(vec![None, None]),
+        ]
+        .into_iter()
+        .map(|keys| {
+            let values = StringArray::from_iter_values((0..keys.len()).map(|i| format!("{i}")));
+            vec![Arc::new(keys) as ArrayRef, Arc::new(values)]
+        })
+        .collect();
+
+        let mut data = vec![];
+        let mut writer = IpcCompressionWriter::new(&mut data)
+            .with_segment_trailer(true)
+            .with_stat_columns(vec![0, 1]);
+        for cols in &batches {
+            writer.write_batch(cols[0].len(), cols)?;
+        }
+        writer.finish_segment()?;
+        let data_len = data.len() as u64;
+
+        let read_all =
+            |predicate: Option<BatchStatsPredicate>| -> Result<(Vec<Vec<ArrayRef>>, usize)> {
+                let mut reader = IpcCompressionReader::new(Cursor::new(data.clone()));
+                if let Some(predicate) = predicate {
+                    reader = reader.with_predicate(predicate);
+                }
+                let mut read_batches = vec![];
+                while let Some((_, cols)) = reader.read_batch(&schema)? {
+                    read_batches.push(cols);
+                }
+                Ok((read_batches, reader.num_skipped_blocks()))
+            };
+
+        // stats blocks are ignored without predicates
+        assert_eq!(read_all(None)?, (batches.clone(), 0));
+
+        // blocks without matching keys are skipped, blocks without stats
+        // (all-null keys or non-integer columns) are always read
+        let (read_batches, num_skipped) = read_all(Some(BatchStatsPredicate::new(0, 12, 15)))?;
+        assert_eq!(read_batches, vec![batches[1].clone(), batches[3].clone()]);
+        assert_eq!(num_skipped, 2);
+        let (read_batches, num_skipped) = read_all(Some(BatchStatsPredicate::new(0, 25, 30)))?;
+        assert_eq!(read_batches, vec![batches[2].clone(), batches[3].clone()]);
+        assert_eq!(num_skipped, 2);
+        assert_eq!(
+            read_all(Some(BatchStatsPredicate::new(1, 100, 100)))?,
+            (batches.clone(), 0)
+        );
+
+        // stats blocks are covered by the segment trailer
+        let dir = tempfile::tempdir()?;
+        let data_file = dir.path().join("data");
+        let index_file = dir.path().join("index");
+        std::fs::write(&data_file, &data)?;
+        std::fs::write(
+            &index_file,
+            [0, data_len as i64]
+                .iter()
+                .flat_map(|offset| offset.to_le_bytes())
+                .collect::<Vec<_>>(),
+        )?;
+        let stats = inspect_shuffle_files(&data_file, &index_file, std::io::sink())?;
+        assert_eq!(stats[0].num_batches, 4);
+        assert_eq!(stats[0].num_checksum_errors, 0);
+        assert_eq!(stats[0].untrailed_len, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_random_bytes() {
+        // use a fixed seed to make the test predictable.
+        let mut rng = StdRng::seed_from_u64(37);
+        let schema = Arc::new(Schema::new(vec![Field::new("", DataType::Utf8, false)]));
+
+        for i in 0..10000 {
+            let mut buf = vec![];
+            if i % 2 == 0 {
+                // exercise versioned header parsing more often
+                let flags = rng.random_range(0..=BLOCK_FLAG_ZSTD);
+                write_block_header(rng.random_range(0..1024), flags, &mut buf).unwrap();
+            }
+            let len = rng.random_range(0..256);
+            buf.extend((0..len).map(|_| rng.random::<u8>()));
+
+            // must return errors instead of panicking
+            let mut reader = IpcCompressionReader::new(Cursor::new(buf));
+            for _ in 0..16 {
+                match reader.read_batch(&schema) {
+                    Ok(Some(_)) => {}
+                    Ok(None) | Err(_) => break,
+                }
+            }
+        }
+    }
 }
@@ -12,11 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod batch_merge;
 pub mod cached_exprs_evaluator;
 pub mod column_pruning;
+pub mod error;
 pub mod execution_context;
+pub mod in_flight_limiter;
 pub mod ipc_compression;
 pub mod offsetted;
+pub mod spillable_row_buffer;
 pub mod stream_exec;
 pub mod timer_helper;
 
@@ -91,16 +91,12 @@ pub struct OffsettedMergeIterator<'a, O, T> {
     cur_partition_id: usize,
     cur_offset: O,
     merged_offsets: Vec<O>,
+    num_adjustments: usize,
     _phantom: PhantomData<&'a ()>,
 }
 
 impl<'a, O: PrimInt + 'a, T: 'a> OffsettedMergeIterator<'a, O, T> {
     pub fn new(num_partitions: usize, offsets: Vec<Offsetted<O, T>>) -> Self {
-        assert!(
-            !offsets.is_empty(),
-            "OffsettedSpillsMergeIterator got no spills"
-        );
-
         let cursors = RadixQueue::new(
             offsets
                 .into_iter()
@@ -108,19 +104,24 @@ impl<'a, O: PrimInt + 'a, T: 'a> OffsettedMergeIterator<'a, O, T> {
                 .collect(),
             num_partitions,
         );
-        let cur_partition_id = cursors.peek().cur;
-
-        Self {
+        let mut new = Self {
             num_partitions,
             cursors,
-            cur_partition_id,
+            cur_partition_id: 0,
             cur_offset: O::zero(),
             merged_offsets: Default::default(),
+            num_adjustments: 0,
             _phantom: Default::default(),
-        }
+        };
+        new.cur_partition_id = new.peek_next_partition_id();
+        new
     }
 
     pub fn peek_next_partition_id(&self) -> usize {
+        // no cursors, all partitions are empty
+        if self.cursors.len() == 0 {
+            return self.num_partitions;
+        }
         self.cursors.peek().cur
     }
 
@@ -128,6 +129,12 @@ impl<'a, O: PrimInt + 'a, T: 'a> OffsettedMergeIterator<'a, O, T> {
         &self.merged_offsets
     }
 
+    /// number of times the min cursor is forwarded and adjusted in the queue,
+    /// which is once per merged non-empty segment
+    pub fn num_adjustments(&self) -> usize {
+        self.num_adjustments
+    }
+
     pub fn next_partition_chunk<'z>(
         &'z mut self,
     ) -> Option<(usize, OffsettedMergePartitionChunkIterator<'a, 'z, O, T>)> {
@@ -147,6 +154,13 @@ impl<'a, O: PrimInt + 'a, T: 'a> Iterator for OffsettedMergeIterator<'a, O, T> {
     type Item = (usize, &'a mut T, Range<O>);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.cursors.len() == 0 {
+            self.cur_partition_id = self.num_partitions;
+            self.merged_offsets
+                .resize(self.num_partitions + 1, self.cur_offset);
+            return None;
+        }
+
         let mut min_cursor = self.cursors.peek_mut();
         self.cur_partition_id = min_cursor.cur;
         self.merged_offsets
@@ -166,6 +180,7 @@ impl<'a, O: PrimInt + 'a, T: 'a> Iterator for OffsettedMergeIterator<'a, O, T> {
         self.cur_offset = self.cur_offset + range.end - range.start;
         min_cursor.cur += 1;
         min_cursor.skip_empty_partitions();
+        self.num_adjustments += 1;
 
         // return current reader
         Some((self.cur_partition_id, data, range))
@@ -190,3 +205,48 @@ impl<'a, O: PrimInt + 'a, T: 'a> Iterator for OffsettedMergePartitionChunkIterat
 
 pub type OffsettedMergePartitionChunkIteratorBypassLifetimeCheck<O, T> =
     OffsettedMergePartitionChunkIterator<'static, 'static, O, T>;
+
+#[cfg(test)]
+mod test {
+    use crate::common::offsetted::{Offsetted, OffsettedMergeIterator};
+
+    #[test]
+    fn test_merge_empty_inputs() {
+        let num_partitions = 4;
+
+        // no cursors at all
+        let mut merge_iter = OffsettedMergeIterator::<u64, ()>::new(num_partitions, vec![]);
+        assert!(merge_iter.next_partition_chunk().is_none());
+        assert!(merge_iter.next().is_none());
+        assert_eq!(merge_iter.merged_offsets(), &[0; 5]);
+
+        // every input is empty
+        let mut merge_iter = OffsettedMergeIterator::new(
+            num_partitions,
+            vec![
+                Offsetted::new(vec![0u64; 5], ()),
+                Offsetted::new(vec![7u64; 5], ()),
+            ],
+        );
+        assert!(merge_iter.next().is_none());
+        assert_eq!(merge_iter.merged_offsets(), &[0; 5]);
+    }
+
+    #[test]
+    fn test_merge_offsets() {
+        let mut merge_iter = OffsettedMergeIterator::new(
+            3,
+            vec![
+                Offsetted::new(vec![0u64, 2, 2, 5], "a"),
+                Offsetted::new(vec![0u64, 1, 1, 1], "b"),
+            ],
+        );
+        let mut merged = vec![];
+        while let Some((partition_id, data, range)) = merge_iter.next() {
+            merged.push((partition_id, *data, range.start, range.end));
+        }
+        merged.sort_unstable();
+        assert_eq!(merged, vec![(0, "a", 0, 2), (0, "b", 0, 1), (2, "a", 2, 5)]);
+        assert_eq!(merge_iter.merged_offsets(), &[0, 3, 3, 6]);
+    }
+}
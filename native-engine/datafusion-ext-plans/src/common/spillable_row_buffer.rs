@@ -0,0 +1,305 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Weak},
+};
+
+use arrow::{
+    array::{RecordBatch, RecordBatchOptions},
+    datatypes::SchemaRef,
+};
+use async_trait::async_trait;
+use datafusion::{common::Result, error::DataFusionError};
+use datafusion_ext_commons::arrow::array_size::BatchSize;
+use futures::lock::Mutex;
+
+use crate::{
+    common::execution_context::ExecutionContext,
+    memmgr::{
+        spill::{
+            read_spill_batch, try_new_disk_spill, write_spill_batch, Spill, SpillCompressedReader,
+        },
+        MemConsumer, MemConsumerInfo, MemManager,
+    },
+};
+
+/// buffers record batches in memory while under the memory budget. batches
+/// are spilled in insertion order when the memory manager asks, and all rows
+/// can be scanned multiple times in their original order.
+pub struct SpillableRowBuffer {
+    exec_ctx: Arc<ExecutionContext>,
+    schema: SchemaRef,
+    mem_consumer_info: Option<Weak<MemConsumerInfo>>,
+    data: Mutex<BufferedRows>,
+}
+
+#[derive(Default)]
+struct BufferedRows {
+    batches: Vec<RecordBatch>,
+    spills: Vec<Arc<dyn Spill>>,
+    num_rows: usize,
+    mem_used: usize,
+}
+
+impl SpillableRowBuffer {
+    pub fn try_new(exec_ctx: Arc<ExecutionContext>, schema: SchemaRef) -> Result<Arc<Self>> {
+        let buffer = Arc::new(Self {
+            exec_ctx,
+            schema,
+            mem_consumer_info: None,
+            data: Mutex::default(),
+        });
+        MemManager::register_consumer(buffer.clone(), true)?;
+        Ok(buffer)
+    }
+
+    pub async fn push(&self, batch: RecordBatch) -> Result<()> {
+        if batch.num_rows() == 0 {
+            return Ok(());
+        }
+        let mem_used = {
+            let mut data = self.data.lock().await;
+            data.num_rows += batch.num_rows();
+            data.mem_used += batch.get_batch_mem_size();
+            data.batches.push(batch);
+            data.mem_used
+        };
+        self.update_mem_used(mem_used).await
+    }
+
+    pub async fn num_rows(&self) -> usize {
+        self.data.lock().await.num_rows
+    }
+
+    pub async fn num_spills(&self) -> usize {
+        self.data.lock().await.spills.len()
+    }
+
+    /// returns memory used by batches not spilled yet
+    pub async fn mem_used(&self) -> usize {
+        self.data.lock().await.mem_used
+    }
+
+    /// scans all rows pushed so far in insertion order, spilled rows first.
+    /// in-memory batches are kept alive by the scan even if they get spilled
+    /// during scanning.
+    pub async fn scan(&self) -> SpillableRowBufferScan {
+        let data = self.data.lock().await;
+        SpillableRowBufferScan {
+            schema: self.schema.clone(),
+            spill_reader: None,
+            spill: None,
+            spills: data.spills.iter().cloned().collect(),
+            batches: data.batches.iter().cloned().collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl MemConsumer for SpillableRowBuffer {
+    fn name(&self) -> &str {
+        "SpillableRowBuffer"
+    }
+
+    fn set_consumer_info(&mut self, consumer_info: Weak<MemConsumerInfo>) {
+        self.mem_consumer_info = Some(consumer_info);
+    }
+
+    fn get_consumer_info(&self) -> &Weak<MemConsumerInfo> {
+        self.mem_consumer_info
+            .as_ref()
+            .expect("consumer info not set")
+    }
+
+    async fn spill(&self) -> Result<()> {
+        let mut data = self.data.lock().await;
+        let batches = std::mem::take(&mut data.batches);
+        if !batches.is_empty() {
+            let spill_metrics = self.exec_ctx.spill_metrics().clone();
+            let spill = tokio::task::spawn_blocking(move || {
+                // on-heap spills are consumed by reading, use disk spills which
+                // can be scanned multiple times
+                let mut spill = try_new_disk_spill(&spill_metrics)?;
+                let mut writer = spill.get_compressed_writer();
+                for batch in &batches {
+                    write_spill_batch(batch.num_rows(), batch.columns(), &mut writer)?;
+                }
+                writer.finish()?;
+                spill.complete()?;
+                Ok::<_, DataFusionError>(spill)
+            })
+            .await
+            .expect("tokio error")?;
+            data.spills.push(Arc::from(spill));
+        }
+        data.mem_used = 0;
+        drop(data);
+        self.update_mem_used(0).await
+    }
+}
+
+impl Drop for SpillableRowBuffer {
+    fn drop(&mut self) {
+        MemManager::deregister_consumer(self);
+    }
+}
+
+pub struct SpillableRowBufferScan {
+    schema: SchemaRef,
+    // the reader borrows the current spill, it must be dropped first
+    spill_reader: Option<SpillCompressedReader<'static>>,
+    spill: Option<Arc<dyn Spill>>,
+    spills: VecDeque<Arc<dyn Spill>>,
+    batches: VecDeque<RecordBatch>,
+}
+
+impl SpillableRowBufferScan {
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>> {
+        loop {
+            if let Some(spill_reader) = &mut self.spill_reader {
+                if let Some((num_rows, cols)) = read_spill_batch(spill_reader, &self.schema)? {
+                    return Ok(Some(RecordBatch::try_new_with_options(
+                        self.schema.clone(),
+                        cols,
+                        &RecordBatchOptions::new().with_row_count(Some(num_rows)),
+                    )?));
+                }
+                self.spill_reader = None;
+                self.spill = None;
+            }
+            let Some(spill) = self.spills.pop_front() else {
+                break;
+            };
+            let spill_reader: SpillCompressedReader<'static> = unsafe {
+                // safety: self.spill keeps the spill alive
+                std::mem::transmute(spill.get_compressed_reader())
+            };
+            self.spill_reader = Some(spill_reader);
+            self.spill = Some(spill);
+        }
+        Ok(self.batches.pop_front())
+    }
+}
+
+impl Iterator for SpillableRowBufferScan {
+    type Item = Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_batch().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{AsArray, Int32Array, RecordBatch},
+        datatypes::{DataType, Field, Int32Type, Schema},
+    };
+    use datafusion::{
+        common::Result, physical_plan::metrics::ExecutionPlanMetricsSet, prelude::SessionContext,
+    };
+
+    use crate::{
+        common::{execution_context::ExecutionContext, spillable_row_buffer::SpillableRowBuffer},
+        memmgr::{MemConsumer, MemManager},
+    };
+
+    fn scanned_values(batches: Vec<RecordBatch>) -> Vec<i32> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                batch
+                    .column(0)
+                    .as_primitive::<Int32Type>()
+                    .values()
+                    .to_vec()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_spill_and_rescan() -> Result<()> {
+        MemManager::init(1000000);
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema.clone(),
+            &ExecutionPlanMetricsSet::new(),
+        );
+        let buffer = SpillableRowBuffer::try_new(exec_ctx, schema.clone())?;
+        let batch = |range: std::ops::Range<i32>| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int32Array::from_iter_values(range))],
+            )
+        };
+
+        // in-memory rows only
+        buffer.push(batch(0..10)?).await?;
+        buffer.push(batch(10..15)?).await?;
+        assert!(buffer.consumer_info().mem_used() > 0);
+        let scanned = buffer.scan().await.collect::<Result<Vec<_>>>()?;
+        assert_eq!(scanned_values(scanned), (0..15).collect::<Vec<_>>());
+
+        // spilled rows are scanned before in-memory rows
+        buffer.spill().await?;
+        assert_eq!(buffer.consumer_info().mem_used(), 0);
+        buffer.push(batch(15..30)?).await?;
+        buffer.spill().await?;
+        buffer.push(batch(30..31)?).await?;
+        assert_eq!(buffer.num_spills().await, 2);
+        assert_eq!(buffer.num_rows().await, 31);
+        for _ in 0..2 {
+            let scanned = buffer.scan().await.collect::<Result<Vec<_>>>()?;
+            assert_eq!(scanned_values(scanned), (0..31).collect::<Vec<_>>());
+        }
+
+        // a running scan is not affected by spilling
+        let mut scan = buffer.scan().await;
+        let first = scan.next().transpose()?.unwrap();
+        buffer.spill().await?;
+        let rest = scan.collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            scanned_values([vec![first], rest].concat()),
+            (0..31).collect::<Vec<_>>(),
+        );
+        assert_eq!(buffer.num_spills().await, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_buffer() -> Result<()> {
+        MemManager::init(1000000);
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema.clone(),
+            &ExecutionPlanMetricsSet::new(),
+        );
+        let buffer = SpillableRowBuffer::try_new(exec_ctx, schema.clone())?;
+        buffer.push(RecordBatch::new_empty(schema)).await?;
+        buffer.spill().await?;
+        assert_eq!(buffer.num_spills().await, 0);
+        assert_eq!(buffer.scan().await.count(), 0);
+        Ok(())
+    }
+}
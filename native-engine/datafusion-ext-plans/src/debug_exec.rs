@@ -14,27 +14,40 @@
 
 use std::{any::Any, fmt::Formatter, sync::Arc};
 
-use arrow::{datatypes::SchemaRef, util::pretty::pretty_format_batches};
+use arrow::{
+    array::{Array, ArrayRef},
+    datatypes::{DataType, SchemaRef},
+    row::{OwnedRow, RowConverter, SortField},
+    util::pretty::pretty_format_batches,
+};
 use async_trait::async_trait;
+use blaze_jni_bridge::{conf, conf::StringConf, is_jni_bridge_inited};
 use datafusion::{
+    common::ScalarValue,
     error::Result,
     execution::context::TaskContext,
     physical_expr::EquivalenceProperties,
     physical_plan::{
-        metrics::{ExecutionPlanMetricsSet, MetricsSet},
+        metrics::{Count, ExecutionPlanMetricsSet, MetricsSet},
         DisplayAs, DisplayFormatType, ExecutionMode, ExecutionPlan, ExecutionPlanProperties,
         PlanProperties, SendableRecordBatchStream, Statistics,
     },
 };
 use futures::StreamExt;
+use itertools::Itertools;
 use once_cell::sync::OnceCell;
 
 use crate::common::execution_context::ExecutionContext;
 
+/// passes batches through unchanged, logging schema, row counts and sample
+/// rows under the debug id, with per-column null counts and min/max.
+/// only DebugExecs whose debug id is listed in `spark.blaze.debugExec.tags`
+/// are instrumented (all of them if no tags are listed).
 #[derive(Debug)]
 pub struct DebugExec {
     input: Arc<dyn ExecutionPlan>,
     debug_id: String,
+    sample_rows: usize,
     metrics: ExecutionPlanMetricsSet,
     props: OnceCell<PlanProperties>,
 }
@@ -44,15 +57,22 @@ impl DebugExec {
         Self {
             input,
             debug_id,
+            sample_rows: 0,
             metrics: ExecutionPlanMetricsSet::new(),
             props: OnceCell::new(),
         }
     }
+
+    /// max number of rows logged in each partition
+    pub fn with_sample_rows(mut self, sample_rows: usize) -> Self {
+        self.sample_rows = sample_rows;
+        self
+    }
 }
 
 impl DisplayAs for DebugExec {
     fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "DebugExec")
+        write!(f, "DebugExec: tag={}", self.debug_id)
     }
 }
 
@@ -86,12 +106,12 @@ impl ExecutionPlan for DebugExec {
 
     fn with_new_children(
         self: Arc<Self>,
-        _children: Vec<Arc<dyn ExecutionPlan>>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
     ) -> Result<Arc<dyn ExecutionPlan>> {
-        Ok(Arc::new(DebugExec::new(
-            self.input.clone(),
-            self.debug_id.clone(),
-        )))
+        Ok(Arc::new(
+            DebugExec::new(children[0].clone(), self.debug_id.clone())
+                .with_sample_rows(self.sample_rows),
+        ))
     }
 
     fn execute(
@@ -99,19 +119,66 @@ impl ExecutionPlan for DebugExec {
         partition: usize,
         context: Arc<TaskContext>,
     ) -> Result<SendableRecordBatchStream> {
+        // not instrumented, pass through the input stream directly
+        if !is_debug_tag_instrumented(&self.debug_id) {
+            return self.input.execute(partition, context);
+        }
+
         let exec_ctx = ExecutionContext::new(context, partition, self.schema(), &self.metrics);
         let debug_id = self.debug_id.clone();
+        let mut remaining_sample_rows = self.sample_rows;
+        let mut column_stats = self
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| {
+                let null_count =
+                    exec_ctx.register_counter_metric(&format!("null_count.{}", field.name()));
+                ColumnStats::new(field.data_type(), null_count)
+            })
+            .collect::<Vec<_>>();
+        log::info!(
+            "DebugExec[{debug_id}](partition={partition}): schema={:?}",
+            self.schema()
+        );
 
         let mut input = exec_ctx.execute(&self.input)?;
         Ok(
             exec_ctx.output_with_sender("Debug", move |sender| async move {
+                let mut num_batches = 0;
+                let mut num_rows = 0;
                 while let Some(batch) = input.next().await.transpose()? {
-                    let table_str = pretty_format_batches(&[batch.clone()])?
-                        .to_string()
-                        .replace('\n', &format!("\n{debug_id} - "));
-                    log::info!("DebugExec(partition={partition}):\n{table_str}");
-                    sender.send(batch).await;
+                    num_batches += 1;
+                    num_rows += batch.num_rows();
+                    log::info!(
+                        "DebugExec[{debug_id}](partition={partition}): batch #{num_batches} with \
+                         {} rows",
+                        batch.num_rows(),
+                    );
+                    for (stats, column) in column_stats.iter_mut().zip(batch.columns()) {
+                        stats.update(column)?;
+                    }
+
+                    let num_sample_rows = remaining_sample_rows.min(batch.num_rows());
+                    if num_sample_rows > 0 {
+                        remaining_sample_rows -= num_sample_rows;
+                        let table_str = pretty_format_batches(&[batch.slice(0, num_sample_rows)])?
+                            .to_string()
+                            .replace('\n', &format!("\n{debug_id} - "));
+                        log::info!("DebugExec[{debug_id}](partition={partition}):\n{table_str}");
+                    }
+                    sender.send(batch).await?;
                 }
+
+                let column_stats_str = column_stats
+                    .iter()
+                    .map(|stats| stats.summary())
+                    .collect::<Result<Vec<_>>>()?
+                    .join(", ");
+                log::info!(
+                    "DebugExec[{debug_id}](partition={partition}): finished with {num_batches} \
+                     batches, {num_rows} rows, column stats: [{column_stats_str}]"
+                );
                 Ok(())
             }),
         )
@@ -125,3 +192,167 @@ impl ExecutionPlan for DebugExec {
         todo!()
     }
 }
+
+/// accumulates null count and min/max of non-null values of a column, min/max
+/// are compared in row format so that any row-encodable type is supported
+struct ColumnStats {
+    null_count: Count,
+    converter: Option<RowConverter>,
+    min: Option<OwnedRow>,
+    max: Option<OwnedRow>,
+}
+
+impl ColumnStats {
+    fn new(data_type: &DataType, null_count: Count) -> Self {
+        Self {
+            null_count,
+            converter: RowConverter::new(vec![SortField::new(data_type.clone())]).ok(),
+            min: None,
+            max: None,
+        }
+    }
+
+    fn update(&mut self, column: &ArrayRef) -> Result<()> {
+        self.null_count.add(column.null_count());
+        let Some(converter) = &mut self.converter else {
+            return Ok(()); // min/max not supported
+        };
+        if column.null_count() == column.len() {
+            return Ok(());
+        }
+
+        let rows = converter.convert_columns(&[column.clone()])?;
+        let valid_rows = (0..column.len())
+            .filter(|&i| column.is_valid(i))
+            .map(|i| rows.row(i));
+        let Some((min, max)) = valid_rows.minmax().into_option() else {
+            return Ok(());
+        };
+        if self.min.as_ref().is_none_or(|cur| min < cur.row()) {
+            self.min = Some(min.owned());
+        }
+        if self.max.as_ref().is_none_or(|cur| max > cur.row()) {
+            self.max = Some(max.owned());
+        }
+        Ok(())
+    }
+
+    fn summary(&self) -> Result<String> {
+        let value_to_string = |value: &Option<OwnedRow>| -> Result<String> {
+            match (value, &self.converter) {
+                (Some(value), Some(converter)) => {
+                    let array = converter.convert_rows([value.row()])?.remove(0);
+                    Ok(ScalarValue::try_from_array(&array, 0)?.to_string())
+                }
+                _ => Ok("N/A".to_string()),
+            }
+        };
+        Ok(format!(
+            "(nulls={}, min={}, max={})",
+            self.null_count.value(),
+            value_to_string(&self.min)?,
+            value_to_string(&self.max)?,
+        ))
+    }
+}
+
+/// returns whether a DebugExec with the given debug id should be instrumented
+fn is_debug_tag_instrumented(debug_id: &str) -> bool {
+    static TAGS: OnceCell<Vec<String>> = OnceCell::new();
+    let tags = TAGS.get_or_init(|| {
+        let tags = if is_jni_bridge_inited() {
+            conf::DEBUG_EXEC_TAGS.value().unwrap_or_default()
+        } else {
+            String::new() // for testing
+        };
+        tags.split(',')
+            .map(|tag| tag.trim().to_owned())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    });
+    tags.is_empty() || tags.iter().any(|tag| tag == debug_id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{ArrayRef, Int32Array, StringArray},
+        datatypes::DataType,
+        record_batch::RecordBatch,
+    };
+    use datafusion::{
+        common::Result,
+        physical_plan::{common, memory::MemoryExec, metrics::Count, ExecutionPlan},
+        prelude::SessionContext,
+    };
+
+    use crate::{
+        debug_exec::{ColumnStats, DebugExec},
+        memmgr::MemManager,
+    };
+
+    fn build_batches() -> Result<Vec<RecordBatch>> {
+        Ok(vec![
+            RecordBatch::try_from_iter([
+                (
+                    "a",
+                    Arc::new(Int32Array::from(vec![Some(3), None, Some(-7)])) as ArrayRef,
+                ),
+                (
+                    "b",
+                    Arc::new(StringArray::from(vec![Some("x"), Some("zz"), None])),
+                ),
+            ])?,
+            RecordBatch::try_from_iter([
+                (
+                    "a",
+                    Arc::new(Int32Array::from(vec![Some(10), Some(0)])) as ArrayRef,
+                ),
+                ("b", Arc::new(StringArray::from(vec![None::<&str>, None]))),
+            ])?,
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_debug_exec_passes_through() -> Result<()> {
+        MemManager::init(10000);
+        let batches = build_batches()?;
+        let input = Arc::new(MemoryExec::try_new(
+            &[batches.clone()],
+            batches[0].schema(),
+            None,
+        )?);
+        let debug_exec = Arc::new(DebugExec::new(input, "test".to_string()).with_sample_rows(2));
+
+        let output = debug_exec.execute(0, SessionContext::new().task_ctx())?;
+        let output_batches = common::collect(output).await?;
+        assert_eq!(output_batches, batches);
+
+        let metrics = debug_exec.metrics().unwrap();
+        let null_count = |name: &str| metrics.sum_by_name(name).map(|v| v.as_usize());
+        assert_eq!(null_count("null_count.a"), Some(1));
+        assert_eq!(null_count("null_count.b"), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_column_stats() -> Result<()> {
+        let batches = build_batches()?;
+        let mut a_stats = ColumnStats::new(&DataType::Int32, Count::new());
+        let mut b_stats = ColumnStats::new(&DataType::Utf8, Count::new());
+        for batch in &batches {
+            a_stats.update(batch.column(0))?;
+            b_stats.update(batch.column(1))?;
+        }
+        assert_eq!(a_stats.summary()?, "(nulls=1, min=-7, max=10)");
+        assert_eq!(b_stats.summary()?, "(nulls=3, min=x, max=zz)");
+
+        // all values are null
+        let mut stats = ColumnStats::new(&DataType::Utf8, Count::new());
+        stats.update(batches[1].column(1))?;
+        assert_eq!(stats.summary()?, "(nulls=2, min=N/A, max=N/A)");
+        Ok(())
+    }
+}
@@ -39,6 +39,7 @@ pub struct ExpandExec {
     schema: SchemaRef,
     projections: Vec<Vec<Arc<dyn PhysicalExpr>>>,
     input: Arc<dyn ExecutionPlan>,
+    coalesce_output: bool,
     metrics: ExecutionPlanMetricsSet,
     props: OnceCell<PlanProperties>,
 }
@@ -67,10 +68,17 @@ impl ExpandExec {
             schema,
             projections,
             input,
+            coalesce_output: false,
             metrics: ExecutionPlanMetricsSet::new(),
             props: OnceCell::new(),
         })
     }
+
+    /// coalesces small per-projection batches toward batch_size
+    pub fn with_coalesce_output(mut self, coalesce_output: bool) -> Self {
+        self.coalesce_output = coalesce_output;
+        self
+    }
 }
 
 impl DisplayAs for ExpandExec {
@@ -114,6 +122,7 @@ impl ExecutionPlan for ExpandExec {
             schema: self.schema(),
             projections: self.projections.clone(),
             input: children[0].clone(),
+            coalesce_output: self.coalesce_output,
             metrics: ExecutionPlanMetricsSet::new(),
             props: OnceCell::new(),
         }))
@@ -126,7 +135,10 @@ impl ExecutionPlan for ExpandExec {
     ) -> Result<SendableRecordBatchStream> {
         let exec_ctx = ExecutionContext::new(context, partition, self.schema(), &self.metrics);
         let input = exec_ctx.execute_with_input_stats(&self.input)?;
-        let output = execute_expand(input, self.projections.clone(), exec_ctx)?;
+        let output = execute_expand(input, self.projections.clone(), exec_ctx.clone())?;
+        if self.coalesce_output {
+            return Ok(exec_ctx.coalesce_with_default_batch_size(output));
+        }
         Ok(output)
     }
 
@@ -174,7 +186,7 @@ fn execute_expand(
                     exec_ctx
                         .baseline_metrics()
                         .record_output(output_batch.num_rows());
-                    sender.send(output_batch).await;
+                    sender.send(output_batch).await?;
                 }
             }
             Ok(())
@@ -186,8 +198,8 @@ mod test {
     use std::sync::Arc;
 
     use arrow::{
-        array::{BooleanArray, Float32Array, Int32Array, StringArray},
-        datatypes::{DataType, Field, Schema},
+        array::{Array, AsArray, BooleanArray, Float32Array, Int32Array, StringArray},
+        datatypes::{DataType, Field, Int64Type, Schema},
         record_batch::RecordBatch,
     };
     use datafusion::{
@@ -493,4 +505,70 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_expand_exec_grouping_id() -> Result<()> {
+        MemManager::init(10000);
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+        ]));
+        let batches = (0..2)
+            .map(|i| {
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(Int32Array::from(vec![Some(i), None, Some(i + 10)])),
+                        Arc::new(StringArray::from(vec![None, Some("x"), Some("y")])),
+                    ],
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let num_input_rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        let input = Arc::new(MemoryExec::try_new(&[batches], schema.clone(), None)?);
+
+        let output_schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+            Field::new("spark_grouping_id", DataType::Int64, false),
+        ]));
+        let null_a = lit(ScalarValue::Int32(None));
+        let null_b = lit(ScalarValue::Utf8(None));
+        let projections = vec![
+            vec![col("a", &schema)?, col("b", &schema)?, lit(0i64)],
+            vec![col("a", &schema)?, null_b.clone(), lit(1i64)],
+            vec![null_a, null_b, lit(3i64)],
+        ];
+        let expand_exec =
+            ExpandExec::try_new(output_schema, projections, input)?.with_coalesce_output(true);
+
+        let session_ctx = SessionContext::new();
+        let task_ctx = session_ctx.task_ctx();
+        let output = expand_exec.execute(0, task_ctx)?;
+        let batches = common::collect(output).await?;
+        assert_eq!(batches.len(), 1); // coalesced
+
+        let num_output_rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(num_output_rows, 3 * num_input_rows);
+
+        let mut grouping_ids = vec![];
+        for batch in &batches {
+            let gids = batch.column(2).as_primitive::<Int64Type>();
+            for row in 0..batch.num_rows() {
+                let gid = gids.value(row);
+                grouping_ids.push(gid);
+                if gid >= 1 {
+                    assert!(batch.column(1).is_null(row));
+                }
+                if gid >= 3 {
+                    assert!(batch.column(0).is_null(row));
+                }
+            }
+        }
+        let expected_ids = [0i64, 0, 0, 1, 1, 1, 3, 3, 3].repeat(2);
+        assert_eq!(grouping_ids, expected_ids);
+        Ok(())
+    }
 }
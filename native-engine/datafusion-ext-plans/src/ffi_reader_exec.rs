@@ -189,7 +189,7 @@ fn read_ffi(
                         .record_output(batch.num_rows());
                     batch
                 };
-                sender.send(batch).await;
+                sender.send(batch).await?;
             }
             Ok(())
         }))
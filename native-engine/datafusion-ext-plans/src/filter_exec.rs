@@ -188,7 +188,7 @@ fn execute_filter(
                 exec_ctx
                     .baseline_metrics()
                     .record_output(filtered_batch.num_rows());
-                sender.send(filtered_batch).await;
+                sender.send(filtered_batch).await?;
             }
             Ok(())
         }))
@@ -51,6 +51,7 @@ impl Generator for ExplodeArray {
         Ok(Box::new(ExplodeArrayGenerateState {
             input_array: input_array.as_list().clone(),
             cur_row_id: 0,
+            cur_offset: 0,
         }))
     }
 
@@ -64,10 +65,19 @@ impl Generator for ExplodeArray {
         let mut sub_lists = vec![];
 
         while row_idx < state.input_array.len() && row_ids.len() < batch_size {
+            // large lists are split into multiple chunks
             let sub_list = state.input_array.value(row_idx);
-            row_ids.resize(row_ids.len() + sub_list.len(), row_idx as i32);
-            pos_ids.extend(0..sub_list.len() as i32);
-            sub_lists.push(sub_list);
+            let start = state.cur_offset;
+            let len = (sub_list.len() - start).min(batch_size - row_ids.len());
+            row_ids.resize(row_ids.len() + len, row_idx as i32);
+            pos_ids.extend(start as i32..(start + len) as i32);
+            sub_lists.push(sub_list.slice(start, len));
+
+            if start + len < sub_list.len() {
+                state.cur_offset = start + len;
+                break;
+            }
+            state.cur_offset = 0;
             row_idx += 1;
         }
         state.cur_row_id = row_idx;
@@ -92,6 +102,7 @@ impl Generator for ExplodeArray {
 struct ExplodeArrayGenerateState {
     pub input_array: ListArray,
     pub cur_row_id: usize,
+    pub cur_offset: usize,
 }
 
 impl GenerateState for ExplodeArrayGenerateState {
@@ -133,6 +144,7 @@ impl Generator for ExplodeMap {
         Ok(Box::new(ExplodeMapGenerateState {
             input_array: input_array.as_map().clone(),
             cur_row_id: 0,
+            cur_offset: 0,
         }))
     }
 
@@ -147,13 +159,20 @@ impl Generator for ExplodeMap {
         let mut sub_val_lists = vec![];
 
         while row_idx < state.input_array.len() && row_ids.len() < batch_size {
+            // large maps are split into multiple chunks
             let sub_struct = state.input_array.value(row_idx);
-            let sub_key_list = sub_struct.column(0);
-            let sub_val_list = sub_struct.column(1);
-            row_ids.resize(row_ids.len() + sub_key_list.len(), row_idx as i32);
-            pos_ids.extend(0..sub_key_list.len() as i32);
-            sub_key_lists.push(sub_key_list.clone());
-            sub_val_lists.push(sub_val_list.clone());
+            let start = state.cur_offset;
+            let len = (sub_struct.len() - start).min(batch_size - row_ids.len());
+            row_ids.resize(row_ids.len() + len, row_idx as i32);
+            pos_ids.extend(start as i32..(start + len) as i32);
+            sub_key_lists.push(sub_struct.column(0).slice(start, len));
+            sub_val_lists.push(sub_struct.column(1).slice(start, len));
+
+            if start + len < sub_struct.len() {
+                state.cur_offset = start + len;
+                break;
+            }
+            state.cur_offset = 0;
             row_idx += 1;
         }
         state.cur_row_id = row_idx;
@@ -179,6 +198,7 @@ impl Generator for ExplodeMap {
 struct ExplodeMapGenerateState {
     pub input_array: MapArray,
     pub cur_row_id: usize,
+    pub cur_offset: usize,
 }
 
 impl GenerateState for ExplodeMapGenerateState {
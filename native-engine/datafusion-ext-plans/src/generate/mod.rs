@@ -52,6 +52,8 @@ pub trait Generator: Debug + Send + Sync {
 
 pub trait GenerateState: Send + Sync {
     fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    /// index of the first input row which is not completely generated
     fn cur_row_id(&self) -> usize;
 }
 
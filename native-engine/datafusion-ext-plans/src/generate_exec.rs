@@ -285,7 +285,7 @@ fn execute_generate(
                     exec_ctx
                         .baseline_metrics()
                         .record_output(output_batch.num_rows());
-                    sender.send(output_batch).await;
+                    sender.send(output_batch).await?;
                 }
             }
 
@@ -331,7 +331,7 @@ fn execute_generate(
                 exec_ctx
                     .baseline_metrics()
                     .record_output(output_batch.num_rows());
-                sender.send(output_batch).await;
+                sender.send(output_batch).await?;
             }
             Ok(())
         }))
@@ -349,6 +349,7 @@ mod test {
         physical_plan::{common, memory::MemoryExec, ExecutionPlan},
         prelude::SessionContext,
     };
+    use datafusion_ext_commons::batch_size;
 
     use crate::{
         generate::{create_generator, GenerateFunc},
@@ -533,4 +534,85 @@ mod test {
         assert_batches_eq!(expected, &batches);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_pos_explode_large_list() -> Result<()> {
+        let session_ctx = SessionContext::new();
+        let task_ctx = session_ctx.task_ctx();
+
+        // a single row is exploded into more rows than batch_size
+        let num_large = batch_size() * 2 + 5;
+        let large = (0..num_large as i32)
+            .map(|i| (i % 7 != 0).then_some(i))
+            .collect::<Vec<_>>();
+        let col_a: ArrayRef = Arc::new(Int32Array::from(vec![1, 2, 3, 4]));
+        let col_b: ArrayRef = Arc::new(ListArray::from_iter_primitive::<Int32Type, _, _>(vec![
+            None,
+            Some(vec![]),
+            Some(large),
+            Some(vec![None, Some(-1)]),
+        ]));
+        let input_batch = RecordBatch::try_from_iter_with_nullable(vec![
+            ("a", col_a, false),
+            ("b", col_b, true),
+        ])?;
+
+        // each generated chunk is no larger than batch_size
+        let generator = create_generator(
+            &input_batch.schema(),
+            GenerateFunc::PosExplode,
+            vec![Arc::new(Column::new("b", 1))],
+        )?;
+        let mut state = generator.eval_start(&input_batch)?;
+        let mut num_chunks = 0;
+        while let Some(generated) = generator.eval_loop(&mut state)? {
+            assert!(generated.row_ids.len() <= batch_size());
+            num_chunks += 1;
+        }
+        assert_eq!(num_chunks, 3);
+
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![input_batch.clone()]],
+            input_batch.schema(),
+            None,
+        )?);
+        let generate = Arc::new(GenerateExec::try_new(
+            input,
+            generator,
+            vec![Column::new("a", 0)],
+            Arc::new(Schema::new(vec![
+                Field::new("pos", DataType::Int32, true),
+                Field::new("b", DataType::Int32, true),
+            ])),
+            true,
+        )?);
+        let output = generate.execute(0, task_ctx)?;
+        let batches = common::collect(output).await?;
+        let output = arrow::compute::concat_batches(&generate.schema(), &batches)?;
+        assert_eq!(output.num_rows(), 2 + num_large + 2);
+
+        let a = output.column(0).as_primitive::<Int32Type>();
+        let pos = output.column(1).as_primitive::<Int32Type>();
+        let b = output.column(2).as_primitive::<Int32Type>();
+
+        // outer rows for null and empty lists
+        assert_eq!((a.value(0), a.value(1)), (1, 2));
+        assert!(pos.is_null(0) && b.is_null(0));
+        assert!(pos.is_null(1) && b.is_null(1));
+
+        // positions continue across chunks, nested nulls are kept
+        for i in 0..num_large {
+            assert_eq!(a.value(2 + i), 3);
+            assert_eq!(pos.value(2 + i), i as i32);
+            assert_eq!(b.is_null(2 + i), i % 7 == 0);
+        }
+        let last = 2 + num_large;
+        assert_eq!((a.value(last), pos.value(last)), (4, 0));
+        assert!(b.is_null(last));
+        assert_eq!(
+            (a.value(last + 1), pos.value(last + 1), b.value(last + 1)),
+            (4, 1, -1)
+        );
+        Ok(())
+    }
 }
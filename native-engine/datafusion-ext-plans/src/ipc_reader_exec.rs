@@ -25,7 +25,8 @@ use std::{
 
 use arrow::{
     array::{Array, ArrayRef, RecordBatch, RecordBatchOptions},
-    datatypes::SchemaRef,
+    datatypes::{DataType, SchemaRef},
+    ipc::reader::StreamReader,
 };
 use async_trait::async_trait;
 use blaze_jni_bridge::{
@@ -55,13 +56,28 @@ use jni::objects::{GlobalRef, JObject};
 use once_cell::sync::OnceCell;
 use parking_lot::Mutex;
 
-use crate::common::{execution_context::ExecutionContext, ipc_compression::IpcCompressionReader};
+use crate::common::{
+    execution_context::ExecutionContext,
+    ipc_compression::{BatchStatsPredicate, IpcCompressionReader},
+};
+
+/// encoding of blocks provided by the jvm
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpcReadFormat {
+    /// compressed blocks written by `IpcCompressionWriter`
+    #[default]
+    Blaze,
+    /// standard arrow ipc stream, one stream per block
+    Arrow,
+}
 
 #[derive(Debug, Clone)]
 pub struct IpcReaderExec {
     pub num_partitions: usize,
     pub ipc_provider_resource_id: String,
     pub schema: SchemaRef,
+    pub format: IpcReadFormat,
+    pub predicate: Option<BatchStatsPredicate>,
     pub metrics: ExecutionPlanMetricsSet,
     props: OnceCell<PlanProperties>,
 }
@@ -75,10 +91,23 @@ impl IpcReaderExec {
             num_partitions,
             ipc_provider_resource_id,
             schema,
+            format: IpcReadFormat::default(),
+            predicate: None,
             metrics: ExecutionPlanMetricsSet::new(),
             props: OnceCell::new(),
         }
     }
+
+    pub fn with_format(mut self, format: IpcReadFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// skips blocks of batches whose stats do not match the predicate
+    pub fn with_predicate(mut self, predicate: Option<BatchStatsPredicate>) -> Self {
+        self.predicate = predicate;
+        self
+    }
 }
 
 impl DisplayAs for IpcReaderExec {
@@ -119,11 +148,15 @@ impl ExecutionPlan for IpcReaderExec {
         self: Arc<Self>,
         _children: Vec<Arc<dyn ExecutionPlan>>,
     ) -> Result<Arc<dyn ExecutionPlan>> {
-        Ok(Arc::new(Self::new(
-            self.num_partitions,
-            self.ipc_provider_resource_id.clone(),
-            self.schema.clone(),
-        )))
+        Ok(Arc::new(
+            Self::new(
+                self.num_partitions,
+                self.ipc_provider_resource_id.clone(),
+                self.schema.clone(),
+            )
+            .with_format(self.format)
+            .with_predicate(self.predicate),
+        ))
     }
 
     fn execute(
@@ -146,7 +179,7 @@ impl ExecutionPlan for IpcReaderExec {
         assert!(!blocks_local.as_obj().is_null());
 
         let blocks = jni_new_global_ref!(blocks_local.as_obj())?;
-        read_ipc(blocks, exec_ctx.clone())
+        read_ipc(blocks, self.format, self.predicate, exec_ctx.clone())
     }
 
     fn metrics(&self) -> Option<MetricsSet> {
@@ -160,9 +193,13 @@ impl ExecutionPlan for IpcReaderExec {
 
 fn read_ipc(
     blocks: GlobalRef,
+    format: IpcReadFormat,
+    predicate: Option<BatchStatsPredicate>,
     exec_ctx: Arc<ExecutionContext>,
 ) -> Result<SendableRecordBatchStream> {
     let size_counter = exec_ctx.register_counter_metric("size");
+    let skipped_blocks_counter = exec_ctx.register_counter_metric("skipped_blocks");
+    let decoded_rows_counter = exec_ctx.register_counter_metric("decoded_rows");
 
     Ok(exec_ctx
         .clone()
@@ -175,6 +212,7 @@ fn read_ipc(
             let staging_cols: Arc<Mutex<Vec<Vec<ArrayRef>>>> = Arc::new(Mutex::new(vec![]));
             let staging_num_rows = AtomicUsize::new(0);
             let staging_mem_size = AtomicUsize::new(0);
+            let mut block_idx = 0;
 
             while let Some(block) = {
                 let blocks = blocks.clone();
@@ -189,19 +227,25 @@ fn read_ipc(
                 .expect("tokio spawn_blocking error")?
             } {
                 // get ipc reader
+                let schema = exec_ctx.output_schema();
+                let block_err = |err: DataFusionError| {
+                    err.context(format!("ipc reader: error reading block {block_idx}"))
+                };
                 let mut reader = tokio::task::spawn_blocking(move || {
-                    if jni_call!(BlazeBlockObject(block.as_obj()).hasFileSegment() -> bool)? {
-                        return get_file_reader(block.as_obj());
-                    }
-                    if jni_call!(BlazeBlockObject(block.as_obj()).hasByteBuffer() -> bool)? {
-                        return get_byte_buffer_reader(block.as_obj());
-                    }
-                    get_channel_reader(block.as_obj())
+                    let input = get_block_reader(block.as_obj())?;
+                    Ok::<_, DataFusionError>(
+                        BlockReader::try_new(format, input, &schema)?.with_predicate(predicate),
+                    )
                 })
                 .await
-                .expect("tokio spawn_blocking error")?;
-
-                while let Some((num_rows, cols)) = reader.read_batch(&exec_ctx.output_schema())? {
+                .expect("tokio spawn_blocking error")
+                .map_err(block_err)?;
+
+                while let Some((num_rows, cols)) = reader
+                    .read_batch(&exec_ctx.output_schema())
+                    .map_err(block_err)?
+                {
+                    decoded_rows_counter.add(num_rows);
                     let (cur_staging_num_rows, cur_staging_mem_size) = {
                         let staging_cols_cloned = staging_cols.clone();
                         let mut staging_cols = staging_cols_cloned.lock();
@@ -233,9 +277,11 @@ fn read_ipc(
                         staging_mem_size.store(0, SeqCst);
                         size_counter.add(batch.get_batch_mem_size());
                         exec_ctx.baseline_metrics().record_output(batch.num_rows());
-                        sender.send(batch).await;
+                        sender.send(batch).await?;
                     }
                 }
+                skipped_blocks_counter.add(reader.num_skipped_blocks());
+                block_idx += 1;
             }
 
             let cur_staging_num_rows = staging_num_rows.load(SeqCst);
@@ -251,20 +297,88 @@ fn read_ipc(
                 )?;
                 size_counter.add(batch.get_batch_mem_size());
                 exec_ctx.baseline_metrics().record_output(batch.num_rows());
-                sender.send(batch).await;
+                sender.send(batch).await?;
             }
             Ok(())
         }))
 }
 
-fn get_channel_reader(block: JObject) -> Result<IpcCompressionReader<Box<dyn Read + Send>>> {
+/// decodes batches from a single block
+enum BlockReader {
+    Blaze(IpcCompressionReader<Box<dyn Read + Send>>),
+    Arrow(StreamReader<Box<dyn Read + Send>>),
+}
+
+impl BlockReader {
+    fn try_new(
+        format: IpcReadFormat,
+        input: Box<dyn Read + Send>,
+        schema: &SchemaRef,
+    ) -> Result<Self> {
+        match format {
+            IpcReadFormat::Blaze => Ok(Self::Blaze(IpcCompressionReader::new(input))),
+            IpcReadFormat::Arrow => {
+                let reader = StreamReader::try_new(input, None)?;
+                let data_types = |schema: &SchemaRef| -> Vec<DataType> {
+                    schema
+                        .fields()
+                        .iter()
+                        .map(|field| field.data_type().clone())
+                        .collect()
+                };
+                let block_data_types = data_types(&reader.schema());
+                let expected_data_types = data_types(schema);
+                if block_data_types != expected_data_types {
+                    df_execution_err!(
+                        "schema not matched: {block_data_types:?} vs {expected_data_types:?}"
+                    )?;
+                }
+                Ok(Self::Arrow(reader))
+            }
+        }
+    }
+
+    fn with_predicate(self, predicate: Option<BatchStatsPredicate>) -> Self {
+        match (self, predicate) {
+            (Self::Blaze(reader), Some(predicate)) => Self::Blaze(reader.with_predicate(predicate)),
+            (reader, _) => reader,
+        }
+    }
+
+    fn num_skipped_blocks(&self) -> usize {
+        match self {
+            Self::Blaze(reader) => reader.num_skipped_blocks(),
+            Self::Arrow(_) => 0,
+        }
+    }
+
+    fn read_batch(&mut self, schema: &SchemaRef) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+        match self {
+            Self::Blaze(reader) => reader.read_batch(schema),
+            Self::Arrow(reader) => Ok(reader
+                .next()
+                .transpose()?
+                .map(|batch| (batch.num_rows(), batch.columns().to_vec()))),
+        }
+    }
+}
+
+fn get_block_reader(block: JObject) -> Result<Box<dyn Read + Send>> {
+    if jni_call!(BlazeBlockObject(block).hasFileSegment() -> bool)? {
+        return get_file_reader(block);
+    }
+    if jni_call!(BlazeBlockObject(block).hasByteBuffer() -> bool)? {
+        return get_byte_buffer_reader(block);
+    }
+    get_channel_reader(block)
+}
+
+fn get_channel_reader(block: JObject) -> Result<Box<dyn Read + Send>> {
     let channel_reader = ReadableByteChannelReader::try_new(block)?;
-    Ok(IpcCompressionReader::new(Box::new(
-        BufReader::with_capacity(65536, channel_reader),
-    )))
+    Ok(Box::new(BufReader::with_capacity(65536, channel_reader)))
 }
 
-fn get_file_reader(block: JObject) -> Result<IpcCompressionReader<Box<dyn Read + Send>>> {
+fn get_file_reader(block: JObject) -> Result<Box<dyn Read + Send>> {
     let path = jni_call!(BlazeBlockObject(block).getFilePath() -> JObject)?;
     let path = jni_get_string!(path.as_obj().into())?;
     let offset = jni_call!(BlazeBlockObject(block).getFileOffset() -> i64)?;
@@ -272,20 +386,21 @@ fn get_file_reader(block: JObject) -> Result<IpcCompressionReader<Box<dyn Read +
     let mut file = File::open(&path)?;
     file.seek(SeekFrom::Start(offset as u64))?;
 
-    Ok(IpcCompressionReader::new(Box::new(
-        BufReader::with_capacity(65536, file.take(length as u64)),
+    Ok(Box::new(BufReader::with_capacity(
+        65536,
+        file.take(length as u64),
     )))
 }
 
-fn get_byte_buffer_reader(block: JObject) -> Result<IpcCompressionReader<Box<dyn Read + Send>>> {
+fn get_byte_buffer_reader(block: JObject) -> Result<Box<dyn Read + Send>> {
     let byte_buffer = jni_call!(BlazeBlockObject(block).getByteBuffer() -> JObject)?;
     if jni_call!(JavaBuffer(byte_buffer.as_obj()).isDirect() -> bool)? {
         let reader = DirectByteBufferReader::try_new(block, byte_buffer.as_obj())?;
-        return Ok(IpcCompressionReader::new(Box::new(reader)));
+        return Ok(Box::new(reader));
     }
     if jni_call!(JavaBuffer(byte_buffer.as_obj()).hasArray() -> bool)? {
         let reader = HeapByteBufferReader::try_new(block, byte_buffer.as_obj())?;
-        return Ok(IpcCompressionReader::new(Box::new(reader)));
+        return Ok(Box::new(reader));
     }
     df_execution_err!("ByteBuffer is not direct and do not have array")
 }
@@ -441,3 +556,87 @@ impl Drop for HeapByteBufferReader {
         let _ = self.block;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, sync::Arc};
+
+    use arrow::{
+        array::{ArrayRef, Int32Array, StringArray},
+        datatypes::{DataType, Field, Schema},
+        ipc::writer::StreamWriter,
+        record_batch::RecordBatch,
+    };
+    use datafusion::common::Result;
+
+    use crate::{
+        common::ipc_compression::IpcCompressionWriter,
+        ipc_reader_exec::{BlockReader, IpcReadFormat},
+    };
+
+    fn build_batch() -> RecordBatch {
+        let col_a: ArrayRef = Arc::new(Int32Array::from(vec![Some(1), None, Some(3)]));
+        let col_b: ArrayRef = Arc::new(StringArray::from(vec![Some("x"), Some("y"), None]));
+        RecordBatch::try_from_iter_with_nullable(vec![("a", col_a, true), ("b", col_b, true)])
+            .unwrap()
+    }
+
+    fn build_arrow_block(batch: &RecordBatch, num_batches: usize) -> Result<Vec<u8>> {
+        let mut block = vec![];
+        let mut writer = StreamWriter::try_new(&mut block, &batch.schema())?;
+        for _ in 0..num_batches {
+            writer.write(batch)?;
+        }
+        writer.finish()?;
+        drop(writer);
+        Ok(block)
+    }
+
+    #[test]
+    fn test_read_blocks() -> Result<()> {
+        let batch = build_batch();
+        let schema = batch.schema();
+
+        let mut blaze_block = vec![];
+        let mut writer = IpcCompressionWriter::new(&mut blaze_block);
+        writer.write_batch(batch.num_rows(), batch.columns())?;
+        writer.finish_segment()?;
+        drop(writer);
+        let arrow_block = build_arrow_block(&batch, 2)?;
+
+        for (format, block, num_batches) in [
+            (IpcReadFormat::Blaze, blaze_block, 1),
+            (IpcReadFormat::Arrow, arrow_block, 2),
+        ] {
+            let mut reader = BlockReader::try_new(format, Box::new(Cursor::new(block)), &schema)?;
+            let mut num_read_batches = 0;
+            while let Some((num_rows, cols)) = reader.read_batch(&schema)? {
+                assert_eq!(num_rows, batch.num_rows());
+                assert_eq!(cols.as_slice(), batch.columns());
+                num_read_batches += 1;
+            }
+            assert_eq!(num_read_batches, num_batches);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_arrow_block_schema_not_matched() -> Result<()> {
+        let batch = build_batch();
+        let arrow_block = build_arrow_block(&batch, 1)?;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, true),
+            Field::new("b", DataType::Utf8, true),
+        ]));
+
+        let err = BlockReader::try_new(
+            IpcReadFormat::Arrow,
+            Box::new(Cursor::new(arrow_block)),
+            &schema,
+        )
+        .err()
+        .expect("schema not matched");
+        assert!(err.to_string().contains("schema not matched"));
+        Ok(())
+    }
+}
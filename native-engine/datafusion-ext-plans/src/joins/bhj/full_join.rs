@@ -140,7 +140,7 @@ impl<const P: JoinerParams> FullJoiner<P> {
             &RecordBatchOptions::new().with_row_count(Some(num_rows)),
         )?;
         self.output_rows.fetch_add(output_batch.num_rows(), Relaxed);
-        self.output_sender.send(output_batch).await;
+        self.output_sender.send(output_batch).await?;
         Ok(())
     }
 
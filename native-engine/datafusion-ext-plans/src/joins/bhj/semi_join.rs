@@ -137,7 +137,7 @@ impl<const P: JoinerParams> SemiJoiner<P> {
     async fn flush(&self, cols: Vec<ArrayRef>) -> Result<()> {
         let output_batch = RecordBatch::try_new(self.join_params.output_schema.clone(), cols)?;
         self.output_rows.fetch_add(output_batch.num_rows(), Relaxed);
-        self.output_sender.send(output_batch).await;
+        self.output_sender.send(output_batch).await?;
         Ok(())
     }
 }
@@ -27,6 +27,7 @@ use arrow::{
 };
 use datafusion::{common::Result, physical_expr::PhysicalExprRef};
 use datafusion_ext_commons::{
+    arrow::array_size::{ArraySize, BatchSize},
     io::{read_len, read_raw_slice, write_len, write_raw_slice},
     prefetch_read_data,
     spark_hash::create_hashes,
@@ -417,6 +418,18 @@ impl JoinHashMap {
     pub fn get_range(&self, map_value: MapValue) -> &[u32] {
         map_value.get_range(self)
     }
+
+    /// returns memory size of data, key columns and hash table
+    pub fn mem_size(&self) -> usize {
+        self.data_batch.get_batch_mem_size()
+            + self
+                .key_columns
+                .iter()
+                .map(|col| col.get_array_mem_size())
+                .sum::<usize>()
+            + self.table.map.len() * size_of::<MapValueGroup>()
+            + self.table.mapped_indices.len() * size_of::<u32>()
+    }
 }
 
 #[inline]
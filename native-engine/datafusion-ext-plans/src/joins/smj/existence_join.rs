@@ -66,7 +66,7 @@ impl ExistenceJoiner {
 
         if output_batch.num_rows() > 0 {
             self.output_rows += output_batch.num_rows();
-            self.output_sender.send(output_batch).await;
+            self.output_sender.send(output_batch).await?;
         }
         Ok(())
     }
@@ -14,22 +14,31 @@
 
 use std::{cmp::Ordering, pin::Pin, sync::Arc};
 
-use arrow::array::{RecordBatch, RecordBatchOptions};
+use arrow::{
+    array::{ArrayRef, RecordBatch, RecordBatchOptions, UInt32Array},
+    row::OwnedRow,
+};
 use async_trait::async_trait;
-use datafusion::common::Result;
-use datafusion_ext_commons::arrow::selection::create_batch_interleaver;
+use datafusion::common::{JoinSide, Result};
+use datafusion_ext_commons::arrow::selection::{create_batch_interleaver, take_cols};
 use itertools::Itertools;
 
 use crate::{
-    common::execution_context::WrappedRecordBatchSender,
+    common::{
+        execution_context::{ExecutionContext, WrappedRecordBatchSender},
+        spillable_row_buffer::SpillableRowBuffer,
+    },
     compare_cursor, cur_forward,
-    joins::{Idx, JoinParams, StreamCursors},
+    joins::{stream_cursor::StreamCursor, Idx, JoinParams, StreamCursors},
+    memmgr::MemConsumer,
     sort_merge_join_exec::Joiner,
 };
 
 pub struct FullJoiner<const L_OUTER: bool, const R_OUTER: bool> {
     join_params: JoinParams,
     output_sender: Arc<WrappedRecordBatchSender>,
+    exec_ctx: Arc<ExecutionContext>,
+    group_spill_threshold: usize,
     lindices: Vec<Idx>,
     rindices: Vec<Idx>,
     output_rows: usize,
@@ -41,10 +50,17 @@ pub type RightOuterJoiner = FullJoiner<false, true>;
 pub type FullOuterJoiner = FullJoiner<true, true>;
 
 impl<const L_OUTER: bool, const R_OUTER: bool> FullJoiner<L_OUTER, R_OUTER> {
-    pub fn new(join_params: JoinParams, output_sender: Arc<WrappedRecordBatchSender>) -> Self {
+    pub fn new(
+        join_params: JoinParams,
+        output_sender: Arc<WrappedRecordBatchSender>,
+        exec_ctx: Arc<ExecutionContext>,
+        group_spill_threshold: usize,
+    ) -> Self {
         Self {
             join_params,
             output_sender,
+            exec_ctx,
+            group_spill_threshold,
             lindices: vec![],
             rindices: vec![],
             output_rows: 0,
@@ -65,19 +81,187 @@ impl<const L_OUTER: bool, const R_OUTER: bool> FullJoiner<L_OUTER, R_OUTER> {
         let rbatch_interleaver = create_batch_interleaver(&curs.1.projected_batches, false)?;
         let lcols = lbatch_interleaver(&lindices)?;
         let rcols = rbatch_interleaver(&rindices)?;
+        self.send_output([lcols.columns(), rcols.columns()].concat(), num_rows)
+            .await
+    }
 
+    async fn send_output(
+        mut self: Pin<&mut Self>,
+        cols: Vec<ArrayRef>,
+        num_rows: usize,
+    ) -> Result<()> {
         let output_batch = RecordBatch::try_new_with_options(
             self.join_params.projection.schema.clone(),
-            [lcols.columns(), rcols.columns()].concat(),
+            cols,
             &RecordBatchOptions::new().with_row_count(Some(num_rows)),
         )?;
 
         if output_batch.num_rows() > 0 {
             self.output_rows += output_batch.num_rows();
-            self.output_sender.send(output_batch).await;
+            self.output_sender.send(output_batch).await?;
         }
         Ok(())
     }
+
+    /// moves rows of a key group from cursor batches into the spilled group,
+    /// the group is spilled once its buffered rows exceed the threshold
+    async fn add_spilled_group_rows(
+        &self,
+        group: &SpilledGroup,
+        cur: &StreamCursor,
+        indices: &[Idx],
+    ) -> Result<()> {
+        let batch_interleaver = create_batch_interleaver(&cur.projected_batches, false)?;
+        for chunk in indices.chunks(self.join_params.batch_size) {
+            group.buffer.push(batch_interleaver(chunk)?).await?;
+        }
+        if group.buffer.mem_used().await > self.group_spill_threshold {
+            group.buffer.spill().await?;
+        }
+        Ok(())
+    }
+
+    /// joins a key group whose rows on `group_side` are too large to be kept
+    /// in cursor batches. `group_indices` and `streamed_indices` are rows of
+    /// the key collected so far on each side. the remaining group rows are
+    /// moved into a spilled group, which is then joined with all streamed
+    /// rows of the key chunk by chunk.
+    async fn join_oversized_group(
+        mut self: Pin<&mut Self>,
+        curs: &mut StreamCursors,
+        group_side: JoinSide,
+        group_indices: &mut Vec<Idx>,
+        streamed_indices: &[Idx],
+    ) -> Result<()> {
+        // pending output refers to cursor batches which are released below
+        self.as_mut().flush(curs).await?;
+        let (group_cur, streamed_cur) = match group_side {
+            JoinSide::Left => (&mut curs.0, &mut curs.1),
+            _ => (&mut curs.1, &mut curs.0),
+        };
+        let group = SpilledGroup {
+            buffer: SpillableRowBuffer::try_new(
+                self.exec_ctx.clone(),
+                group_cur.projected_batch_schema.clone(),
+            )?,
+            key: group_cur.key(group_indices[0]).owned(),
+        };
+
+        // collect remaining group rows without holding all of them in memory
+        loop {
+            let equal = !group_cur.finished && group_cur.cur_key() == group.key.row();
+            if equal {
+                group_indices.push(group_cur.cur_idx);
+                cur_forward!(group_cur);
+            }
+            if !group_indices.is_empty()
+                && (!equal
+                    || group_indices.len() >= self.join_params.batch_size
+                    || group_cur.num_buffered_batches() > 1)
+            {
+                self.add_spilled_group_rows(&group, group_cur, group_indices)
+                    .await?;
+                group_indices.clear();
+                group_cur.clean_out_dated_batches();
+            }
+            if !equal {
+                break;
+            }
+        }
+
+        // streamed rows collected so far are joined in the first chunk
+        let mut streamed_indices = streamed_indices.to_vec();
+        loop {
+            let equal = !streamed_cur.finished && streamed_cur.cur_key() == group.key.row();
+            if equal {
+                streamed_indices.push(streamed_cur.cur_idx);
+                cur_forward!(streamed_cur);
+            }
+            if !streamed_indices.is_empty()
+                && (!equal
+                    || streamed_indices.len() >= self.join_params.batch_size
+                    || streamed_cur.num_buffered_batches() > 1)
+            {
+                let batch_interleaver =
+                    create_batch_interleaver(&streamed_cur.projected_batches, false)?;
+                let streamed_batch = batch_interleaver(&std::mem::take(&mut streamed_indices))?;
+                self.as_mut()
+                    .join_spilled_group(&group, streamed_batch, group_side)
+                    .await?;
+                streamed_cur.clean_out_dated_batches();
+            }
+            if !equal {
+                return Ok(());
+            }
+        }
+    }
+
+    /// outputs cartesian product of a spilled key group and streamed rows,
+    /// the group is scanned once for each chunk of streamed rows
+    async fn join_spilled_group(
+        mut self: Pin<&mut Self>,
+        group: &SpilledGroup,
+        streamed_batch: RecordBatch,
+        group_side: JoinSide,
+    ) -> Result<()> {
+        let batch_size = self.join_params.batch_size;
+        let num_streamed_rows = streamed_batch.num_rows();
+
+        for group_batch in group.buffer.scan().await {
+            let group_batch = group_batch?;
+            let num_rows = group_batch.num_rows();
+            let mut group_indices = vec![];
+            let mut streamed_indices = vec![];
+            for group_idx in 0..num_rows {
+                group_indices.extend(std::iter::repeat(group_idx as u32).take(num_streamed_rows));
+                streamed_indices.extend(0..num_streamed_rows as u32);
+
+                if group_indices.len() >= batch_size || group_idx + 1 == num_rows {
+                    let num_output_rows = group_indices.len();
+                    let group_cols = take_cols(
+                        group_batch.columns(),
+                        UInt32Array::from(std::mem::take(&mut group_indices)),
+                    )?;
+                    let streamed_cols = take_cols(
+                        streamed_batch.columns(),
+                        UInt32Array::from(std::mem::take(&mut streamed_indices)),
+                    )?;
+                    let output_cols = match group_side {
+                        JoinSide::Left => [group_cols, streamed_cols].concat(),
+                        _ => [streamed_cols, group_cols].concat(),
+                    };
+                    self.as_mut()
+                        .send_output(output_cols, num_output_rows)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// rows of a key group which are too large to be kept in cursor batches,
+/// spilled once buffered rows exceed the group spill threshold
+struct SpilledGroup {
+    buffer: Arc<SpillableRowBuffer>,
+    key: OwnedRow,
+}
+
+/// memory size of cursor batches holding rows of a key group, rows are added
+/// in cursor order
+#[derive(Default)]
+struct GroupMemSize {
+    batch_idx: Option<usize>,
+    mem_size: usize,
+}
+
+impl GroupMemSize {
+    fn add(&mut self, cur: &StreamCursor, idx: Idx) {
+        if self.batch_idx != Some(idx.0) {
+            self.batch_idx = Some(idx.0);
+            self.mem_size += cur.projected_batches[idx.0].get_array_memory_size();
+        }
+    }
 }
 
 #[async_trait]
@@ -118,6 +302,10 @@ impl<const L_OUTER: bool, const R_OUTER: bool> Joiner for FullJoiner<L_OUTER, R_
                     equal_rindices.push(curs.1.cur_idx);
                     let l_key_idx = curs.0.cur_idx;
                     let r_key_idx = curs.1.cur_idx;
+                    let mut l_group_mem_size = GroupMemSize::default();
+                    let mut r_group_mem_size = GroupMemSize::default();
+                    l_group_mem_size.add(&curs.0, l_key_idx);
+                    r_group_mem_size.add(&curs.1, r_key_idx);
                     cur_forward!(curs.0);
                     cur_forward!(curs.1);
 
@@ -125,12 +313,14 @@ impl<const L_OUTER: bool, const R_OUTER: bool> Joiner for FullJoiner<L_OUTER, R_
                     let mut has_multi_equal = false;
                     let mut l_equal = true;
                     let mut r_equal = true;
+                    let threshold = self.group_spill_threshold;
                     while l_equal && r_equal {
                         if l_equal {
                             l_equal = !curs.0.finished && curs.0.cur_key() == curs.0.key(l_key_idx);
                             if l_equal {
                                 has_multi_equal = true;
                                 equal_lindices.push(curs.0.cur_idx);
+                                l_group_mem_size.add(&curs.0, curs.0.cur_idx);
                                 cur_forward!(curs.0);
                             }
                         }
@@ -139,9 +329,17 @@ impl<const L_OUTER: bool, const R_OUTER: bool> Joiner for FullJoiner<L_OUTER, R_
                             if r_equal {
                                 has_multi_equal = true;
                                 equal_rindices.push(curs.1.cur_idx);
+                                r_group_mem_size.add(&curs.1, curs.1.cur_idx);
                                 cur_forward!(curs.1);
                             }
                         }
+                        // too large sides are spilled below
+                        if has_multi_equal
+                            && (l_group_mem_size.mem_size > threshold
+                                || r_group_mem_size.mem_size > threshold)
+                        {
+                            break;
+                        }
                     }
 
                     // fast path for one-to-one join
@@ -151,6 +349,30 @@ impl<const L_OUTER: bool, const R_OUTER: bool> Joiner for FullJoiner<L_OUTER, R_
                         continue;
                     }
 
+                    // spill the key group if either side is too large
+                    if l_group_mem_size.mem_size > threshold {
+                        self.as_mut()
+                            .join_oversized_group(
+                                curs,
+                                JoinSide::Left,
+                                &mut equal_lindices,
+                                &equal_rindices,
+                            )
+                            .await?;
+                        continue;
+                    }
+                    if r_group_mem_size.mem_size > threshold {
+                        self.as_mut()
+                            .join_oversized_group(
+                                curs,
+                                JoinSide::Right,
+                                &mut equal_rindices,
+                                &equal_lindices,
+                            )
+                            .await?;
+                        continue;
+                    }
+
                     for (&lidx, &ridx) in equal_lindices.iter().cartesian_product(&equal_rindices) {
                         self.lindices.push(lidx);
                         self.rindices.push(ridx);
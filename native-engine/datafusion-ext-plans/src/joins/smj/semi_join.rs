@@ -99,7 +99,7 @@ impl<const P: JoinerParams> SemiJoiner<P> {
 
         if output_batch.num_rows() > 0 {
             self.output_rows += output_batch.num_rows();
-            self.output_sender.send(output_batch).await;
+            self.output_sender.send(output_batch).await?;
         }
         Ok(())
     }
@@ -19,9 +19,10 @@ mod tests {
     use arrow::{
         self,
         array::*,
-        compute::SortOptions,
+        compute::{concat_batches, SortOptions},
         datatypes::{DataType, Field, Schema, SchemaRef},
         record_batch::RecordBatch,
+        util::display::array_value_to_string,
     };
     use datafusion::{
         assert_batches_sorted_eq,
@@ -31,6 +32,7 @@ mod tests {
         physical_plan::{common, joins::utils::*, memory::MemoryExec, ExecutionPlan},
         prelude::SessionContext,
     };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
     use TestType::*;
 
     use crate::{
@@ -981,4 +983,205 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn join_broadcast_build_side_exceeds_memory() -> Result<()> {
+        for test_type in [BHJLeftProbed, SHJLeftProbed] {
+            let left = build_table(
+                ("a1", &vec![1, 2, 3]),
+                ("b1", &vec![4, 5, 5]),
+                ("c1", &vec![7, 8, 9]),
+            );
+            // build side is larger than total memory of mem manager
+            let num_build_rows = 200000;
+            let right = build_table(
+                ("a2", &(0..num_build_rows).collect()),
+                ("b1", &(0..num_build_rows).collect()),
+                ("c2", &(0..num_build_rows).collect()),
+            );
+            let on: JoinOn = vec![(
+                Arc::new(Column::new_with_schema("b1", &left.schema())?),
+                Arc::new(Column::new_with_schema("b1", &right.schema())?),
+            )];
+
+            let err = join_collect(test_type, left, right, on, Inner)
+                .await
+                .expect_err("build side should exceed memory");
+            assert!(
+                err.to_string()
+                    .contains("broadcast build side exceeds memory"),
+                "unexpected error: {err}"
+            );
+            assert!(err.to_string().contains("mem manager status"));
+        }
+        Ok(())
+    }
+
+    /// returns batches sorted by nullable keys, with an optional skewed key
+    fn build_sorted_batches(
+        rng: &mut StdRng,
+        names: (&str, &str),
+        num_rows: usize,
+        num_keys: i32,
+        skewed_key: Option<(i32, usize)>,
+        batch_rows: usize,
+    ) -> Vec<RecordBatch> {
+        let mut keys = (0..num_rows)
+            .map(|_| (!rng.random_bool(0.1)).then(|| rng.random_range(0..num_keys)))
+            .collect::<Vec<_>>();
+        if let Some((key, count)) = skewed_key {
+            keys.extend(std::iter::repeat(Some(key)).take(count));
+        }
+        keys.sort(); // nulls first
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(names.0, DataType::Int32, true),
+            Field::new(names.1, DataType::Int32, false),
+        ]));
+        keys.chunks(batch_rows)
+            .map(|chunk| {
+                let values = (0..chunk.len()).map(|_| rng.random_range(0..1000));
+                RecordBatch::try_new(
+                    schema.clone(),
+                    vec![
+                        Arc::new(Int32Array::from(chunk.to_vec())),
+                        Arc::new(Int32Array::from_iter_values(values)),
+                    ],
+                )
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn sorted_rows(batches: &[RecordBatch]) -> Result<Vec<String>> {
+        let mut rows = vec![];
+        for batch in batches {
+            for row_idx in 0..batch.num_rows() {
+                let row = batch
+                    .columns()
+                    .iter()
+                    .map(|col| array_value_to_string(col, row_idx))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                rows.push(row.join("|"));
+            }
+        }
+        rows.sort();
+        Ok(rows)
+    }
+
+    /// runs sort merge join and returns sorted output rows and number of spills
+    async fn smj_sorted_rows(
+        left: Vec<RecordBatch>,
+        right: Vec<RecordBatch>,
+        join_type: JoinType,
+        group_spill_threshold: usize,
+    ) -> Result<(Vec<String>, usize)> {
+        MemManager::init(1000000);
+        let session_ctx = SessionContext::new();
+        let task_ctx = session_ctx.task_ctx();
+        let left = build_table_from_batches(left);
+        let right = build_table_from_batches(right);
+        let on: JoinOn = vec![(
+            Arc::new(Column::new_with_schema("k1", &left.schema())?),
+            Arc::new(Column::new_with_schema("k2", &right.schema())?),
+        )];
+        let schema = build_join_schema_for_test(&left.schema(), &right.schema(), join_type)?;
+        let join = Arc::new(
+            SortMergeJoinExec::try_new(
+                schema,
+                left,
+                right,
+                on,
+                join_type,
+                vec![SortOptions::default()],
+            )?
+            .with_group_spill_threshold(group_spill_threshold),
+        );
+        let batches = common::collect(join.execute(0, task_ctx)?).await?;
+        let num_spills = join
+            .metrics()
+            .and_then(|metrics| metrics.sum_by_name("mem_spill_count"))
+            .map(|v| v.as_usize())
+            .unwrap_or(0);
+        Ok((sorted_rows(&batches)?, num_spills))
+    }
+
+    async fn datafusion_sorted_rows(
+        left: Vec<RecordBatch>,
+        right: Vec<RecordBatch>,
+        join_type: JoinType,
+    ) -> Result<Vec<String>> {
+        let ctx = SessionContext::new();
+        ctx.register_batch("l", concat_batches(&left[0].schema(), &left)?)?;
+        ctx.register_batch("r", concat_batches(&right[0].schema(), &right)?)?;
+        let sql = match join_type {
+            Inner => "SELECT l.k1, l.v1, r.k2, r.v2 FROM l JOIN r ON l.k1 = r.k2",
+            Left => "SELECT l.k1, l.v1, r.k2, r.v2 FROM l LEFT JOIN r ON l.k1 = r.k2",
+            LeftSemi => "SELECT l.k1, l.v1 FROM l LEFT SEMI JOIN r ON l.k1 = r.k2",
+            LeftAnti => "SELECT l.k1, l.v1 FROM l LEFT ANTI JOIN r ON l.k1 = r.k2",
+            _ => unreachable!(),
+        };
+        let batches = ctx.sql(sql).await?.collect().await?;
+        sorted_rows(&batches)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn join_smj_fuzz_with_datafusion() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(37);
+        for join_type in [Inner, Left, LeftSemi, LeftAnti] {
+            // usize::MAX never spills, 0 spills every multi-row key group
+            for group_spill_threshold in [usize::MAX, 0] {
+                let left = build_sorted_batches(&mut rng, ("k1", "v1"), 1000, 50, None, 37);
+                let right = build_sorted_batches(&mut rng, ("k2", "v2"), 800, 50, None, 29);
+                let (smj_rows, _) = smj_sorted_rows(
+                    left.clone(),
+                    right.clone(),
+                    join_type,
+                    group_spill_threshold,
+                )
+                .await?;
+                let df_rows = datafusion_sorted_rows(left, right, join_type).await?;
+                assert_eq!(smj_rows, df_rows);
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn join_smj_skewed_key_group_spill() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(37);
+        for join_type in [Inner, Left] {
+            let left = build_sorted_batches(&mut rng, ("k1", "v1"), 300, 20, Some((7, 500)), 64);
+            let right = build_sorted_batches(&mut rng, ("k2", "v2"), 300, 20, Some((7, 400)), 64);
+            let (smj_rows, num_spills) =
+                smj_sorted_rows(left.clone(), right.clone(), join_type, 1024).await?;
+            let df_rows = datafusion_sorted_rows(left, right, join_type).await?;
+            assert!(num_spills > 0, "skewed key group should be spilled");
+            assert_eq!(smj_rows, df_rows);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn join_smj_skewed_right_key_group_spill() -> Result<()> {
+        // all left rows are in one batch within the threshold, so the right
+        // side is the group exceeding it while both sides are collected
+        let mut rng = StdRng::seed_from_u64(37);
+        for join_type in [Inner, Left] {
+            let left = build_sorted_batches(&mut rng, ("k1", "v1"), 300, 20, Some((7, 500)), 1000);
+            let right = build_sorted_batches(&mut rng, ("k2", "v2"), 300, 20, Some((7, 400)), 4);
+            let group_spill_threshold = left[0].get_array_memory_size();
+            let (smj_rows, num_spills) = smj_sorted_rows(
+                left.clone(),
+                right.clone(),
+                join_type,
+                group_spill_threshold,
+            )
+            .await?;
+            let df_rows = datafusion_sorted_rows(left, right, join_type).await?;
+            assert!(num_spills > 0, "skewed key group should be spilled");
+            assert_eq!(smj_rows, df_rows);
+        }
+        Ok(())
+    }
 }
@@ -119,7 +119,7 @@ fn execute_limit(
                     remaining -= batch.num_rows() as u64;
                 }
                 exec_ctx.baseline_metrics().record_output(batch.num_rows());
-                sender.send(batch).await;
+                sender.send(batch).await?;
             }
             Ok(())
         }))
@@ -14,54 +14,189 @@
 
 pub mod metrics;
 pub mod spill;
+mod spill_cipher;
 
 use std::{
-    sync::{Arc, Weak},
+    cell::Cell,
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc, Weak,
+    },
     time::Duration,
 };
+#[cfg(test)]
+use std::{cell::RefCell, future::Future};
 
 use async_trait::async_trait;
 use blaze_jni_bridge::{is_jni_bridge_inited, jni_call_static};
 use bytesize::ByteSize;
-use datafusion::common::Result;
+use datafusion::{common::Result, prelude::SessionConfig};
+use datafusion_ext_commons::df_execution_err;
 use once_cell::sync::OnceCell;
 use parking_lot::{Condvar, Mutex};
 
+use crate::{
+    common::error::BlazeError,
+    memmgr::spill::{spill_placement_seed, SpillPlacement},
+};
+
 static MEM_MANAGER: OnceCell<Arc<MemManager>> = OnceCell::new();
 
+thread_local! {
+    static THREAD_TASK_ID: Cell<Option<MemTaskId>> = const { Cell::new(None) };
+}
+
+#[cfg(test)]
+thread_local! {
+    static ISOLATED_MEM_MANAGER: RefCell<Option<Arc<MemManager>>> = const { RefCell::new(None) };
+}
+
+/// restores the previous mem manager of the thread when dropped, see
+/// [`MemManager::isolate`]
+#[cfg(test)]
+pub struct IsolatedMemManager {
+    prev: Option<Arc<MemManager>>,
+}
+
+#[cfg(test)]
+impl Drop for IsolatedMemManager {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        ISOLATED_MEM_MANAGER.with(|isolated| *isolated.borrow_mut() = prev);
+    }
+}
+
 // never triggers waiting/spilling for consumers which use very little memory
 const MIN_TRIGGER_SIZE: usize = 1 << 24; // 16MB
 
+/// identifies the task of consumers as (stage_id, partition_id), spillable
+/// memory is shared fairly among tasks running in the same executor
+pub type MemTaskId = (usize, usize);
+
+/// configuration of the mem manager, decided once per executor
+#[derive(Clone, Debug)]
+pub struct MemManagerConfig {
+    pub total: usize,
+    /// fraction of managed memory at which spillable consumers start spilling
+    pub spill_watermark: f64,
+    /// directories of disk spills, empty to use the files provided by spark
+    pub spill_dirs: Vec<PathBuf>,
+}
+
+impl MemManagerConfig {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            spill_watermark: 1.0,
+            spill_dirs: vec![],
+        }
+    }
+
+    pub fn with_spill_watermark(mut self, spill_watermark: f64) -> Self {
+        self.spill_watermark = spill_watermark;
+        self
+    }
+
+    pub fn with_spill_dirs(mut self, spill_dirs: Vec<PathBuf>) -> Self {
+        self.spill_dirs = spill_dirs;
+        self
+    }
+
+    /// returns the config stored in the session config, see
+    /// [`SessionConfig::with_extension`]
+    pub fn from_session_config(session_config: &SessionConfig) -> Option<Arc<Self>> {
+        session_config.get_extension::<Self>()
+    }
+}
+
+impl From<usize> for MemManagerConfig {
+    fn from(total: usize) -> Self {
+        Self::new(total)
+    }
+}
+
 pub struct MemManager {
     total: usize,
+    spill_watermark: f64,
+    spill_dirs: Vec<PathBuf>,
     consumers: Mutex<Vec<Arc<MemConsumerInfo>>>,
+    tasks: Mutex<HashMap<MemTaskId, Arc<Mutex<MemTaskStatus>>>>,
     status: Mutex<MemManagerStatus>,
     cv: Condvar,
+    spill_placement: SpillPlacement,
 }
 
 impl MemManager {
-    pub fn init(total: usize) {
-        MEM_MANAGER.get_or_init(|| {
-            log::info!(
-                "mem manager initialized with total memory: {}",
-                ByteSize(total as u64),
-            );
+    fn new(config: MemManagerConfig) -> Arc<Self> {
+        log::info!(
+            "mem manager initialized with total memory: {}, spill watermark: {}",
+            ByteSize(config.total as u64),
+            config.spill_watermark,
+        );
+        Arc::new(MemManager {
+            total: config.total,
+            spill_watermark: config.spill_watermark,
+            spill_dirs: config.spill_dirs,
+            consumers: Mutex::default(),
+            tasks: Mutex::default(),
+            status: Mutex::default(),
+            cv: Condvar::default(),
+            spill_placement: SpillPlacement::new(spill_placement_seed()),
+        })
+    }
 
-            Arc::new(MemManager {
-                total,
-                consumers: Mutex::default(),
-                status: Mutex::default(),
-                cv: Condvar::default(),
-            })
-        });
+    /// initializes the process-global mem manager. it lives until the
+    /// executor exits, later calls are ignored.
+    pub fn init(config: impl Into<MemManagerConfig>) {
+        MEM_MANAGER.get_or_init(|| Self::new(config.into()));
     }
 
     pub fn initialized() -> bool {
-        MEM_MANAGER.get().is_some()
+        Self::get().is_ok()
+    }
+
+    /// returns the mem manager, or an error if it is not initialized
+    pub fn get() -> Result<Arc<MemManager>> {
+        #[cfg(test)]
+        if let Some(mm) = ISOLATED_MEM_MANAGER.with(|mm| mm.borrow().clone()) {
+            return Ok(mm);
+        }
+        match MEM_MANAGER.get() {
+            Some(mm) => Ok(mm.clone()),
+            None => df_execution_err!("mem manager not initialized"),
+        }
+    }
+
+    /// runs `fut` with a mem manager of its own instead of the global one,
+    /// so that consumers of concurrent tests never spill for each other.
+    /// consumers registered within the scope keep using the isolated
+    /// manager. the scope is thread local, `fut` must run on a current
+    /// thread runtime.
+    #[cfg(test)]
+    pub async fn with_isolated_manager<T>(
+        config: impl Into<MemManagerConfig>,
+        fut: impl Future<Output = T>,
+    ) -> T {
+        let _isolated = Self::isolate(config);
+        fut.await
     }
 
-    pub fn get() -> &'static MemManager {
-        MEM_MANAGER.get().expect("mem manager not initialized")
+    /// like [`Self::with_isolated_manager`], but the current thread uses the
+    /// isolated manager until the returned guard is dropped
+    #[cfg(test)]
+    pub fn isolate(config: impl Into<MemManagerConfig>) -> IsolatedMemManager {
+        let mm = Self::new(config.into());
+        let prev = ISOLATED_MEM_MANAGER.with(|isolated| isolated.replace(Some(mm)));
+        IsolatedMemManager { prev }
+    }
+
+    /// tags consumers registered by the current thread with the task, native
+    /// runtimes set it when starting their threads. untagged consumers are
+    /// treated as tasks of their own.
+    pub fn set_thread_task_id(task_id: Option<MemTaskId>) {
+        THREAD_TASK_ID.set(task_id);
     }
 
     pub fn num_consumers(&self) -> usize {
@@ -76,13 +211,45 @@ impl MemManager {
         self.total_used() as f64 / self.total as f64
     }
 
-    pub fn register_consumer(mut consumer: Arc<dyn MemConsumer>, spillable: bool) {
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn spill_placement(&self) -> &SpillPlacement {
+        &self.spill_placement
+    }
+
+    pub fn spill_dirs(&self) -> &[PathBuf] {
+        &self.spill_dirs
+    }
+
+    /// returns memory used by consumers which cannot be spilled
+    pub fn mem_unspillable(&self) -> usize {
+        let mm_status = self.status.lock();
+        mm_status.total_used - mm_status.mem_spillables
+    }
+
+    pub fn register_consumer(mut consumer: Arc<dyn MemConsumer>, spillable: bool) -> Result<()> {
+        let mm = Self::get()?;
+        let task = THREAD_TASK_ID.get().map(|task_id| {
+            // count the consumer before releasing tasks lock, otherwise the
+            // task may be removed by deregistering its last consumer
+            let mut mm_tasks = mm.tasks.lock();
+            let task = mm_tasks.entry(task_id).or_default().clone();
+            task.lock().num_consumers += 1;
+            (task_id, task)
+        });
         let consumer_info = Arc::new(MemConsumerInfo {
             name: consumer.name().to_owned(),
+            mm: mm.clone(),
+            task,
+            consumer: Arc::downgrade(&consumer),
             status: Mutex::new(MemConsumerStatus {
                 mem_used: 0,
                 spillable,
             }),
+            on_first_spill: Mutex::new(None),
+            spill_requested: AtomicBool::new(false),
         });
         log::info!("mem manager registering consumer: {}", consumer.name());
 
@@ -93,69 +260,236 @@ impl MemManager {
             consumer_mut.set_consumer_info(Arc::downgrade(&consumer_info));
         }
 
-        let mm = Self::get();
         let mut mm_consumers = mm.consumers.lock();
         let mut mm_status = mm.status.lock();
-        mm_consumers.push(consumer_info);
         mm_status.num_consumers += 1;
         if spillable {
-            mm_status.num_spillables += 1;
+            consumer_info.update_spillables(&mut mm_status, 1, 0);
         }
+        mm_consumers.push(consumer_info);
+        Ok(())
     }
 
     pub fn deregister_consumer(consumer: &dyn MemConsumer) {
-        let mm = Self::get();
+        let consumer_info = consumer.consumer_info();
+        let mm = &consumer_info.mm;
         let mut mm_consumers = mm.consumers.lock();
         let mut mm_status = mm.status.lock();
 
-        let consumer_info = consumer.consumer_info();
         let consumer_status = consumer_info.status.lock();
 
         // update mm status
         assert!(mm_status.total_used >= consumer_status.mem_used);
         mm_status.num_consumers -= 1;
-        mm_status.update_total_used_with_diff(-(consumer_status.mem_used as isize));
+        mm_status.update_total_used_with_diff(-(consumer_status.mem_used as isize), &mm.cv);
 
         // update mm spillable status
         if consumer_status.spillable {
-            assert!(mm_status.mem_spillables >= consumer_status.mem_used);
-            mm_status.num_spillables -= 1;
-            mm_status.mem_spillables -= consumer_status.mem_used;
+            let mem_used = consumer_status.mem_used as isize;
+            consumer_info.update_spillables(&mut mm_status, -1, -mem_used);
         }
 
         // remove consumer info
-        for i in 0..mm_consumers.len() {
-            if Arc::ptr_eq(&mm_consumers[i], &consumer_info) {
-                log::info!("mem manager deregistered consumer: {}", consumer.name());
-                mm_consumers.swap_remove(i);
-
-                drop(mm_status);
-                drop(mm_consumers);
-                return;
+        let Some(i) = mm_consumers
+            .iter()
+            .position(|c| Arc::ptr_eq(c, &consumer_info))
+        else {
+            unreachable!("deregistering non-registered memory consumer")
+        };
+        log::info!("mem manager deregistered consumer: {}", consumer.name());
+        mm_consumers.swap_remove(i);
+        drop(consumer_status);
+        drop(mm_status);
+        drop(mm_consumers);
+
+        // remove task when its last consumer is deregistered
+        if let Some((task_id, task)) = &consumer_info.task {
+            let mut mm_tasks = mm.tasks.lock();
+            let mut task_status = task.lock();
+            task_status.num_consumers -= 1;
+            if task_status.num_consumers == 0 {
+                mm_tasks.remove(task_id);
             }
         }
-        unreachable!("deregistering non-registered memory consumer")
+    }
+
+    /// returns the fair share of spillable memory of each task, like spark's
+    /// ExecutionMemoryPool does
+    fn task_mem_max(&self) -> usize {
+        let mm_status = *self.status.lock();
+        let mem_unspillable = mm_status.total_used - mm_status.mem_spillables;
+        let total_managed = self
+            .total
+            .saturating_sub(get_mem_jvm_direct_used())
+            .saturating_sub(mem_unspillable);
+        let total_managed = (total_managed as f64 * self.spill_watermark) as usize;
+        total_managed / mm_status.num_spillable_tasks.max(1)
+    }
+
+    /// returns the largest non-empty spillable consumer of the tasks using
+    /// more than `task_mem_max`, excluding the task of `consumer_info`
+    fn select_victim_above_fair_share(
+        &self,
+        consumer_info: &Arc<MemConsumerInfo>,
+        task_mem_max: usize,
+    ) -> Option<Arc<MemConsumerInfo>> {
+        self.consumers
+            .lock()
+            .iter()
+            .filter(|c| !consumer_info.same_task(c))
+            .filter(|c| {
+                let status = *c.status.lock();
+                status.spillable && status.mem_used > 0
+            })
+            .filter(|c| c.task_mem_used() > task_mem_max)
+            .max_by_key(|c| c.mem_used())
+            .cloned()
+    }
+
+    /// grows memory used by the consumer before a large insert. other
+    /// consumers of the same task are spilled inline (largest first), then
+    /// consumers of other tasks are requested to spill on their own tasks
+    /// and the reservation waits for them to release memory.
+    pub async fn reserve(consumer: &dyn MemConsumer, bytes: usize) -> Result<()> {
+        const WAIT_TIME: Duration = Duration::from_millis(10000);
+        const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+        let consumer_info = consumer.consumer_info();
+        let mm = &consumer_info.mm;
+        let mut spilled: Vec<Arc<MemConsumerInfo>> = vec![];
+        let mut wait_deadline = None;
+
+        loop {
+            let available = mm
+                .total
+                .saturating_sub(get_mem_jvm_direct_used())
+                .saturating_sub(mm.total_used());
+            if bytes <= available {
+                break;
+            }
+            let out_of_memory = || -> Result<()> {
+                mm.dump_status();
+                Err(BlazeError::OutOfMemory {
+                    consumer: consumer.name().to_owned(),
+                    requested: bytes,
+                    available,
+                }
+                .into())
+            };
+
+            // waiting for other tasks to spill
+            if let Some(wait_deadline) = wait_deadline {
+                if tokio::time::Instant::now() >= wait_deadline {
+                    log::warn!(
+                        "mem manager: {} timeout waiting for other tasks to spill",
+                        consumer.name(),
+                    );
+                    return out_of_memory();
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+                continue;
+            }
+
+            let spillable_consumers = mm
+                .consumers
+                .lock()
+                .iter()
+                .filter(|c| !Arc::ptr_eq(c, &consumer_info))
+                .filter(|c| !spilled.iter().any(|s| Arc::ptr_eq(s, c)))
+                .filter(|c| {
+                    let status = *c.status.lock();
+                    status.spillable && status.mem_used > 0
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+
+            // spill consumers of the same task inline, largest first
+            let victim = spillable_consumers
+                .iter()
+                .filter(|c| consumer_info.same_task(c))
+                .max_by_key(|c| c.mem_used())
+                .cloned();
+            if let Some(victim) = victim {
+                spilled.push(victim.clone());
+                if let Some(victim_consumer) = victim.consumer.upgrade() {
+                    log::info!(
+                        "mem manager spilling {} (mem_used: {}) for reserving {} in {}",
+                        victim.name,
+                        ByteSize(victim.mem_used() as u64),
+                        ByteSize(bytes as u64),
+                        consumer.name(),
+                    );
+                    victim_consumer.spill().await?;
+                }
+                continue;
+            }
+
+            // request consumers of other tasks to spill until enough memory
+            // would be released, prefer tasks using more than their fair share
+            let task_mem_max = mm.task_mem_max();
+            let mut victims = spillable_consumers;
+            victims.sort_by_key(|c| {
+                std::cmp::Reverse((c.task_mem_used() > task_mem_max, c.mem_used()))
+            });
+            let mut requested = 0;
+            for victim in &victims {
+                if requested >= bytes - available {
+                    break;
+                }
+                log::info!(
+                    "mem manager requesting {} (mem_used: {}) to spill for reserving {} in {}",
+                    victim.name,
+                    ByteSize(victim.mem_used() as u64),
+                    ByteSize(bytes as u64),
+                    consumer.name(),
+                );
+                victim.request_spill();
+                requested += victim.mem_used();
+            }
+            if requested < bytes - available {
+                return out_of_memory();
+            }
+            wait_deadline = Some(tokio::time::Instant::now() + WAIT_TIME);
+        }
+
+        let mut mm_status = mm.status.lock();
+        let mut consumer_status = consumer_info.status.lock();
+        consumer_status.mem_used += bytes;
+        mm_status.update_total_used_with_diff(bytes as isize, &mm.cv);
+        if consumer_status.spillable {
+            consumer_info.update_spillables(&mut mm_status, 0, bytes as isize);
+        }
+        Ok(())
     }
 
     pub fn dump_status(&self) {
-        let mm_status = self.status.lock();
-        log::info!(
-            "mem manager status: total: {}, mem_used: {}, jvm_direct: {}",
+        for line in self.status_string().lines() {
+            log::info!("{line}");
+        }
+    }
+
+    /// formats status of mem manager and all consumers, one line for each
+    pub fn status_string(&self) -> String {
+        let mm_status = *self.status.lock();
+        let mut status = format!(
+            "mem manager status: total: {}, mem_used: {}, jvm_direct: {}, spillable tasks: {}",
             ByteSize(self.total as u64),
             ByteSize(mm_status.total_used as u64),
             ByteSize(get_mem_jvm_direct_used() as u64),
+            mm_status.num_spillable_tasks,
         );
-        drop(mm_status);
 
         for consumer in &*self.consumers.lock() {
             let consumer_status = consumer.status.lock();
-            log::info!(
-                "* consumer: {}, spillable: {}, mem_used: {}",
+            status.push_str(&format!(
+                "\n* consumer: {}, task: {:?}, spillable: {}, mem_used: {}",
                 consumer.name,
+                consumer.task.as_ref().map(|(task_id, _)| task_id),
                 consumer_status.spillable,
                 ByteSize(consumer_status.mem_used as u64),
-            );
+            ));
         }
+        status
     }
 }
 
@@ -165,10 +499,24 @@ struct MemManagerStatus {
     total_used: usize,
     num_spillables: usize,
     mem_spillables: usize,
+    // untagged spillable consumers are counted as tasks of their own
+    num_spillable_tasks: usize,
+}
+
+#[derive(Default, Clone, Copy)]
+struct MemTaskStatus {
+    num_consumers: usize,
+    num_spillables: usize,
+    mem_spillables: usize,
+}
+
+fn add_diff(value: usize, diff: isize) -> usize {
+    assert!(value as isize + diff >= 0);
+    (value as isize + diff) as usize
 }
 
 impl MemManagerStatus {
-    fn update_total_used_with_diff(&mut self, diff_used: isize) -> usize {
+    fn update_total_used_with_diff(&mut self, diff_used: isize, cv: &Condvar) -> usize {
         assert!(self.total_used as isize + diff_used >= 0);
 
         let new_used = (self.total_used as isize + diff_used) as usize;
@@ -176,16 +524,113 @@ impl MemManagerStatus {
 
         // freeing some memory, notifies all waiting growers
         if new_used < old_used {
-            MemManager::get().cv.notify_all();
+            cv.notify_all();
         }
         new_used
     }
 }
 
-#[derive(Debug)]
 pub struct MemConsumerInfo {
     name: String,
+    mm: Arc<MemManager>,
+    task: Option<(MemTaskId, Arc<Mutex<MemTaskStatus>>)>,
+    consumer: Weak<dyn MemConsumer>,
     status: Mutex<MemConsumerStatus>,
+    on_first_spill: Mutex<Option<FirstSpillCallback>>,
+    // set by other tasks, the consumer spills on its next update of mem used
+    spill_requested: AtomicBool,
+}
+
+/// one-shot callback invoked the first time a consumer spills
+pub type FirstSpillCallback = Box<dyn FnOnce() + Send>;
+
+impl MemConsumerInfo {
+    pub fn mem_used(&self) -> usize {
+        self.status.lock().mem_used
+    }
+
+    /// releases `released` bytes of memory used by the consumer. releasing
+    /// never waits or spills, so it is usable where async is unavailable,
+    /// e.g. in drop()
+    pub fn release_mem_used(&self, released: usize) {
+        let mut mm_status = self.mm.status.lock();
+        let mut status = self.status.lock();
+        let old_used = status.mem_used;
+        let new_used = old_used.saturating_sub(released);
+        status.mem_used = new_used;
+        let diff_used = new_used as isize - old_used as isize;
+        mm_status.update_total_used_with_diff(diff_used, &self.mm.cv);
+        if status.spillable {
+            self.update_spillables(&mut mm_status, 0, diff_used);
+        }
+    }
+
+    /// returns spillable memory used by the task of this consumer
+    fn task_mem_used(&self) -> usize {
+        match &self.task {
+            Some((_, task)) => task.lock().mem_spillables,
+            None => {
+                let status = *self.status.lock();
+                if status.spillable {
+                    status.mem_used
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    /// asks the consumer to spill on its own task, spilling consumers of
+    /// other tasks inline would register their on-heap spills to the wrong
+    /// task, which releases them when it finishes
+    fn request_spill(&self) {
+        self.spill_requested.store(true, SeqCst);
+    }
+
+    fn same_task(&self, other: &Arc<MemConsumerInfo>) -> bool {
+        match (&self.task, &other.task) {
+            (Some((task_id, _)), Some((other_task_id, _))) => task_id == other_task_id,
+            _ => std::ptr::eq(self, Arc::as_ptr(other)),
+        }
+    }
+
+    /// updates spillable status of the mem manager and the task of this
+    /// consumer, must be called with the mem manager status locked
+    fn update_spillables(
+        &self,
+        mm_status: &mut MemManagerStatus,
+        num_diff: isize,
+        mem_diff: isize,
+    ) {
+        mm_status.num_spillables = add_diff(mm_status.num_spillables, num_diff);
+        mm_status.mem_spillables = add_diff(mm_status.mem_spillables, mem_diff);
+
+        let Some((_, task)) = &self.task else {
+            mm_status.num_spillable_tasks = add_diff(mm_status.num_spillable_tasks, num_diff);
+            return;
+        };
+        let mut task_status = task.lock();
+        let was_spillable = task_status.num_spillables > 0;
+        task_status.num_spillables = add_diff(task_status.num_spillables, num_diff);
+        task_status.mem_spillables = add_diff(task_status.mem_spillables, mem_diff);
+        match (was_spillable, task_status.num_spillables > 0) {
+            (false, true) => mm_status.num_spillable_tasks += 1,
+            (true, false) => mm_status.num_spillable_tasks -= 1,
+            _ => {}
+        }
+    }
+}
+
+// the mem manager is not printed, it refers back to all its consumers
+impl std::fmt::Debug for MemConsumerInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemConsumerInfo")
+            .field("name", &self.name)
+            .field("task", &self.task.as_ref().map(|(task_id, _)| task_id))
+            .field("consumer", &self.consumer)
+            .field("status", &self.status)
+            .finish()
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -207,7 +652,8 @@ pub trait MemConsumer: Send + Sync {
     }
 
     fn mem_used_percent(&self) -> f64 {
-        let mm = MemManager::get();
+        let consumer_info = self.consumer_info();
+        let mm = &consumer_info.mm;
         let total = mm.total;
         let mm_status = *mm.status.lock();
 
@@ -215,7 +661,7 @@ pub trait MemConsumer: Send + Sync {
         let total_managed = total
             .saturating_sub(get_mem_jvm_direct_used())
             .saturating_sub(mem_unspillable);
-        let mem_used = self.consumer_info().status.lock().mem_used;
+        let mem_used = consumer_info.status.lock().mem_used;
         let consumer_mem_max = total_managed / mm_status.num_spillables.max(1);
         mem_used as f64 / consumer_mem_max as f64
     }
@@ -225,14 +671,12 @@ pub trait MemConsumer: Send + Sync {
         let mut consumer_status = consumer_info.status.lock();
 
         if consumer_status.spillable != spillable {
-            let mut mm_status = MemManager::get().status.lock();
+            let mut mm_status = consumer_info.mm.status.lock();
+            let mem_used = consumer_status.mem_used as isize;
             if spillable {
-                mm_status.num_spillables += 1;
-                mm_status.mem_spillables += consumer_status.mem_used;
+                consumer_info.update_spillables(&mut mm_status, 1, mem_used);
             } else {
-                assert!(mm_status.mem_spillables >= consumer_status.mem_used);
-                mm_status.num_spillables -= 1;
-                mm_status.mem_spillables -= consumer_status.mem_used;
+                consumer_info.update_spillables(&mut mm_status, -1, -mem_used);
             }
         }
         consumer_status.spillable = spillable;
@@ -281,6 +725,22 @@ pub trait MemConsumer: Send + Sync {
         update_consumer_mem_used_with_custom_updater(self, |_| (0, 0), true).await
     }
 
+    /// sets a callback invoked the first time this consumer spills non-empty
+    /// buffers, e.g. for adaptive execution to react to memory pressure
+    fn set_on_first_spill(&self, callback: FirstSpillCallback) {
+        *self.consumer_info().on_first_spill.lock() = Some(callback);
+    }
+
+    /// invokes the first spill callback if not yet invoked, consumers call it
+    /// in spill() before spilling non-empty buffers
+    fn fire_on_first_spill(&self) {
+        let callback = self.consumer_info().on_first_spill.lock().take();
+        if let Some(callback) = callback {
+            log::info!("{} spilling for the first time", self.name());
+            callback();
+        }
+    }
+
     /// spills this consumer and returns used memory after spilling
     async fn spill(&self) -> Result<()> {
         unimplemented!()
@@ -293,15 +753,15 @@ async fn update_consumer_mem_used_with_custom_updater(
     forced: bool,
 ) -> Result<()> {
     let consumer_name = consumer.name();
-    let mm = MemManager::get();
     let consumer_info = consumer.consumer_info();
+    let mm = &consumer_info.mm;
     let total = mm.total;
 
-    #[derive(Clone, Copy, PartialEq)]
     enum Operation {
-        Spill,   // spill this consumer
-        Wait,    // wait other consumers to spill
-        Nothing, // do nothing
+        Spill,                             // spill this consumer
+        SpillVictim(Arc<MemConsumerInfo>), // request a consumer of another task to spill
+        Wait,                              // wait other consumers to spill
+        Nothing,                           // do nothing
     }
 
     let (mem_unspillable, mem_jvm_direct_used);
@@ -319,21 +779,34 @@ async fn update_consumer_mem_used_with_custom_updater(
         );
 
         // update mm status
-        let total_used = mm_status.update_total_used_with_diff(diff_used);
+        let total_used = mm_status.update_total_used_with_diff(diff_used, &mm.cv);
 
         // update mm spillable status
         if consumer_status.spillable {
-            assert!(mm_status.mem_spillables as isize + diff_used >= 0);
-            mm_status.mem_spillables = (mm_status.mem_spillables as isize + diff_used) as usize;
+            consumer_info.update_spillables(&mut mm_status, 0, diff_used);
         }
 
+        // spill requested by another task, spilled here on the own task
+        let spill_requested =
+            consumer_info.spill_requested.swap(false, SeqCst) && spillable && new_used > 0;
+        let forced = forced || spill_requested;
+
         // consumer is empty or unspillable, no need to wait or spill
         if !forced && (old_used == 0 || new_used == 0 || !spillable) {
             return Ok(());
         }
 
+        // get spillable memory of the task, untagged consumers are tasks
+        let (task_used, task_num_spillables) = match &consumer_info.task {
+            Some((_, task)) => {
+                let task_status = task.lock();
+                (task_status.mem_spillables, task_status.num_spillables)
+            }
+            None => (new_used, 1),
+        };
+
         // unlock
-        let num_spillables = mm_status.num_spillables;
+        let num_spillable_tasks = mm_status.num_spillable_tasks;
         let mem_spillables = mm_status.mem_spillables;
         drop(consumer_status);
         drop(mm_status);
@@ -347,17 +820,25 @@ async fn update_consumer_mem_used_with_custom_updater(
         let total_managed = total
             .saturating_sub(mem_jvm_direct_used) // jvm direct memory
             .saturating_sub(mem_unspillable); // unspillable memory
-        let consumer_mem_max = total_managed / num_spillables;
+        let total_managed = (total_managed as f64 * mm.spill_watermark) as usize;
+        let task_mem_max = total_managed / num_spillable_tasks.max(1);
+        let consumer_mem_max = task_mem_max / task_num_spillables.max(1);
         let consumer_mem_min = consumer_mem_max / 8;
 
         let total_overflowed = total_used > total_managed;
-        let consumer_overflowed = new_used > consumer_mem_max;
+        let task_overflowed = task_used > task_mem_max;
         let operation = if forced
-            || ((total_overflowed || consumer_overflowed)
+            || ((total_overflowed || task_overflowed)
                 && new_used > MIN_TRIGGER_SIZE
                 && new_used > old_used)
         {
-            if forced || (spillable && new_used > consumer_mem_min) {
+            // within the fair share, spills tasks using more than theirs
+            let victim = (!forced && !task_overflowed && consumer_info.task.is_some())
+                .then(|| mm.select_victim_above_fair_share(&consumer_info, task_mem_max))
+                .flatten();
+            if let Some(victim) = victim {
+                Operation::SpillVictim(victim)
+            } else if forced || (spillable && new_used > consumer_mem_min) {
                 Operation::Spill
             } else {
                 Operation::Wait
@@ -369,8 +850,19 @@ async fn update_consumer_mem_used_with_custom_updater(
     };
     let mut operation = operation;
 
+    // trigger spilling other task, which spills on its own task
+    if let Operation::SpillVictim(victim) = &operation {
+        log::info!(
+            "mem manager requesting {} (mem_used: {}) of task above fair share to spill for {consumer_name}",
+            victim.name,
+            ByteSize(victim.mem_used() as u64),
+        );
+        victim.request_spill();
+        return Ok(());
+    }
+
     // trigger waiting for resources
-    if operation == Operation::Wait {
+    if matches!(operation, Operation::Wait) {
         const WAIT_TIME: Duration = Duration::from_millis(10000);
 
         let mut mm_status = mm.status.lock();
@@ -385,7 +877,7 @@ async fn update_consumer_mem_used_with_custom_updater(
     }
 
     // trigger spilling
-    if operation == Operation::Spill {
+    if matches!(operation, Operation::Spill) {
         log::info!(
             "mem manager spilling {consumer_name} (mem_used: {}), total: {}/{}, unspillable: {}, jvm_direct: {}",
             ByteSize(mem_used as u64),
@@ -407,3 +899,267 @@ fn get_mem_jvm_direct_used() -> usize {
         0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc, Weak,
+    };
+
+    use async_trait::async_trait;
+    use datafusion::{
+        common::{DataFusionError, Result},
+        prelude::SessionConfig,
+    };
+    use tokio::sync::Barrier;
+
+    use crate::memmgr::{MemConsumer, MemConsumerInfo, MemManager, MemManagerConfig, MemTaskId};
+
+    const MB: usize = 1 << 20;
+
+    #[derive(Default)]
+    struct TestMemConsumer {
+        mem_consumer_info: Option<Weak<MemConsumerInfo>>,
+        num_spills: AtomicUsize,
+    }
+
+    impl TestMemConsumer {
+        fn register(spillable: bool) -> Result<Arc<Self>> {
+            let consumer = Arc::new(Self::default());
+            MemManager::register_consumer(consumer.clone(), spillable)?;
+            Ok(consumer)
+        }
+
+        fn register_with_task(task_id: MemTaskId) -> Result<Arc<Self>> {
+            MemManager::set_thread_task_id(Some(task_id));
+            let consumer = Self::register(true);
+            MemManager::set_thread_task_id(None);
+            consumer
+        }
+    }
+
+    #[async_trait]
+    impl MemConsumer for TestMemConsumer {
+        fn name(&self) -> &str {
+            "TestMemConsumer"
+        }
+
+        fn set_consumer_info(&mut self, consumer_info: Weak<MemConsumerInfo>) {
+            self.mem_consumer_info = Some(consumer_info);
+        }
+
+        fn get_consumer_info(&self) -> &Weak<MemConsumerInfo> {
+            self.mem_consumer_info
+                .as_ref()
+                .expect("consumer info not set")
+        }
+
+        async fn spill(&self) -> Result<()> {
+            self.num_spills.fetch_add(1, SeqCst);
+            self.update_mem_used(0).await
+        }
+    }
+
+    impl Drop for TestMemConsumer {
+        fn drop(&mut self) {
+            MemManager::deregister_consumer(self);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_isolated_manager() -> Result<()> {
+        let config = MemManagerConfig::new(64 * MB);
+        let holder = MemManager::with_isolated_manager(config.clone(), async {
+            let holder = TestMemConsumer::register(false)?;
+            holder.update_mem_used(50 * MB).await?;
+            assert_eq!(MemManager::get()?.mem_unspillable(), 50 * MB);
+            Ok::<_, DataFusionError>(holder)
+        })
+        .await?;
+
+        // memory of the other manager is not visible, so nothing is spilled
+        MemManager::with_isolated_manager(config, async {
+            let mm = MemManager::get()?;
+            assert_eq!(mm.total(), 64 * MB);
+            assert_eq!(mm.total_used(), 0);
+
+            let consumer = TestMemConsumer::register(true)?;
+            consumer.update_mem_used(MB).await?;
+            consumer.update_mem_used(40 * MB).await?;
+            assert_eq!(consumer.num_spills.load(SeqCst), 0);
+            assert_eq!(mm.total_used(), 40 * MB);
+            Ok::<_, DataFusionError>(())
+        })
+        .await?;
+
+        // consumers keep the manager they are registered to
+        assert_eq!(holder.consumer_info().mem_used(), 50 * MB);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spill_watermark() -> Result<()> {
+        let config = MemManagerConfig::new(64 * MB).with_spill_watermark(0.5);
+        let session_config = SessionConfig::new().with_extension(Arc::new(config.clone()));
+        let config = MemManagerConfig::from_session_config(&session_config).expect("config");
+        assert_eq!(config.spill_watermark, 0.5);
+
+        MemManager::with_isolated_manager(config.as_ref().clone(), async {
+            let consumer = TestMemConsumer::register(true)?;
+            consumer.update_mem_used(MB).await?;
+            consumer.update_mem_used(30 * MB).await?;
+            assert_eq!(consumer.num_spills.load(SeqCst), 0);
+
+            // exceeds half of total memory
+            consumer.update_mem_used(40 * MB).await?;
+            assert_eq!(consumer.num_spills.load(SeqCst), 1);
+            assert_eq!(consumer.consumer_info().mem_used(), 0);
+            Ok::<_, DataFusionError>(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_task_fair_share() -> Result<()> {
+        MemManager::with_isolated_manager(256 * MB, async {
+            let mm = MemManager::get()?;
+            let task0_consumers = (0..3)
+                .map(|_| TestMemConsumer::register_with_task((0, 0)))
+                .collect::<Result<Vec<_>>>()?;
+            for consumer in &task0_consumers {
+                consumer.update_mem_used(40 * MB).await?;
+            }
+            assert_eq!(mm.status.lock().num_spillable_tasks, 1);
+
+            // consumers of one task share a fair share, so the other task gets
+            // half of the memory instead of a quarter
+            let task1_consumer = TestMemConsumer::register_with_task((0, 1))?;
+            assert_eq!(mm.status.lock().num_spillable_tasks, 2);
+            task1_consumer.update_mem_used(MB).await?;
+            task1_consumer.update_mem_used(100 * MB).await?;
+            assert_eq!(task1_consumer.num_spills.load(SeqCst), 0);
+
+            // exceeds the fair share of the task
+            task1_consumer.update_mem_used(130 * MB).await?;
+            assert_eq!(task1_consumer.num_spills.load(SeqCst), 1);
+            for consumer in &task0_consumers {
+                assert_eq!(consumer.num_spills.load(SeqCst), 0);
+            }
+
+            // tasks are removed with their last consumers
+            drop(task0_consumers);
+            assert_eq!(mm.status.lock().num_spillable_tasks, 1);
+            assert!(mm.tasks.lock().get(&(0, 0)).is_none());
+            Ok::<_, DataFusionError>(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_reserve_prefers_tasks_above_fair_share() -> Result<()> {
+        MemManager::with_isolated_manager(100 * MB, async {
+            // task 0 uses 40MB of its 33MB share, task 1 is within its share
+            // but has the largest consumer
+            let task0_consumers = (0..2)
+                .map(|_| TestMemConsumer::register_with_task((0, 0)))
+                .collect::<Result<Vec<_>>>()?;
+            for consumer in &task0_consumers {
+                consumer.update_mem_used(20 * MB).await?;
+            }
+            let task1_consumer = TestMemConsumer::register_with_task((0, 1))?;
+            task1_consumer.update_mem_used(30 * MB).await?;
+
+            // task 0 is requested to spill and the reservation waits until it
+            // spills on its own task
+            let task2_consumer = TestMemConsumer::register_with_task((0, 2))?;
+            let (reserved, spilled) = tokio::join!(
+                MemManager::reserve(task2_consumer.as_ref(), 35 * MB),
+                async {
+                    while !task0_consumers
+                        .iter()
+                        .any(|consumer| consumer.consumer_info().spill_requested.load(SeqCst))
+                    {
+                        tokio::task::yield_now().await;
+                    }
+                    for consumer in &task0_consumers {
+                        consumer.update_mem_used_with_diff(0).await?;
+                    }
+                    Ok::<_, DataFusionError>(())
+                },
+            );
+            reserved?;
+            spilled?;
+            assert_eq!(task2_consumer.consumer_info().mem_used(), 35 * MB);
+            let task0_spills: usize = task0_consumers
+                .iter()
+                .map(|consumer| consumer.num_spills.load(SeqCst))
+                .sum();
+            assert_eq!(task0_spills, 1);
+            assert_eq!(task1_consumer.num_spills.load(SeqCst), 0);
+            Ok::<_, DataFusionError>(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_victim_spills_on_its_own_task() -> Result<()> {
+        MemManager::with_isolated_manager(128 * MB, async {
+            // task 0 takes most memory while being the only task
+            let task0_consumer = TestMemConsumer::register_with_task((0, 0))?;
+            task0_consumer.update_mem_used(MB).await?;
+            task0_consumer.update_mem_used(100 * MB).await?;
+
+            // task 1 within its fair share only requests task 0 to spill
+            let task1_consumer = TestMemConsumer::register_with_task((0, 1))?;
+            task1_consumer.update_mem_used(MB).await?;
+            task1_consumer.update_mem_used(40 * MB).await?;
+            assert_eq!(task1_consumer.num_spills.load(SeqCst), 0);
+            assert_eq!(task0_consumer.num_spills.load(SeqCst), 0);
+            assert!(task0_consumer.consumer_info().spill_requested.load(SeqCst));
+
+            // task 0 spills on its next update
+            task0_consumer.update_mem_used_with_diff(0).await?;
+            assert_eq!(task0_consumer.num_spills.load(SeqCst), 1);
+            assert_eq!(task0_consumer.consumer_info().mem_used(), 0);
+            assert!(!task0_consumer.consumer_info().spill_requested.load(SeqCst));
+            Ok::<_, DataFusionError>(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_tasks_spill_fairly() -> Result<()> {
+        const NUM_TASKS: usize = 4;
+        MemManager::with_isolated_manager(128 * MB, async {
+            let consumers = (0..NUM_TASKS)
+                .map(|partition_id| TestMemConsumer::register_with_task((0, partition_id)))
+                .collect::<Result<Vec<_>>>()?;
+
+            // tasks grow in lockstep, every task inserts once in each round
+            let barrier = Barrier::new(NUM_TASKS);
+            futures::future::try_join_all(consumers.iter().map(|consumer| {
+                let barrier = &barrier;
+                async move {
+                    for _ in 0..128 {
+                        consumer.update_mem_used_with_diff(4 * MB as isize).await?;
+                        barrier.wait().await;
+                    }
+                    Ok::<_, DataFusionError>(())
+                }
+            }))
+            .await?;
+
+            let num_spills = consumers
+                .iter()
+                .map(|consumer| consumer.num_spills.load(SeqCst))
+                .collect::<Vec<_>>();
+            let min_spills = *num_spills.iter().min().unwrap();
+            let max_spills = *num_spills.iter().max().unwrap();
+            assert!(min_spills > 0, "num spills: {num_spills:?}");
+            assert!(max_spills <= min_spills * 2, "num spills: {num_spills:?}");
+            Ok::<_, DataFusionError>(())
+        })
+        .await
+    }
+}
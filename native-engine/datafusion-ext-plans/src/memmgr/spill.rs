@@ -16,23 +16,42 @@ use std::{
     any::Any,
     fs,
     fs::{File, OpenOptions},
-    io::{BufReader, BufWriter, Cursor, Read, Seek, Write},
-    sync::Arc,
-    time::Duration,
+    io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::SeqCst},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use arrow::{array::ArrayRef, datatypes::SchemaRef};
 use blaze_jni_bridge::{
-    conf, conf::StringConf, is_jni_bridge_inited, jni_bridge::LocalRef, jni_call, jni_call_static,
-    jni_get_string, jni_new_direct_byte_buffer, jni_new_global_ref,
+    conf,
+    conf::{BooleanConf, IntConf, LongConf, StringConf},
+    is_jni_bridge_inited,
+    jni_bridge::LocalRef,
+    jni_call, jni_call_static, jni_get_string, jni_new_direct_byte_buffer, jni_new_global_ref,
+};
+use bytesize::ByteSize;
+use datafusion::{
+    common::Result,
+    parquet::file::reader::Length,
+    physical_plan::metrics::{Count, Time},
+};
+use datafusion_ext_commons::io::{
+    read_one_batch, read_one_batch_with_encodings, write_one_batch, write_one_batch_with_encodings,
 };
-use datafusion::{common::Result, parquet::file::reader::Length, physical_plan::metrics::Time};
 use jni::{objects::GlobalRef, sys::jlong};
 use log::warn;
 use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 
 use crate::{
-    common::ipc_compression::{IoCompressionReader, IoCompressionWriter},
-    memmgr::metrics::SpillMetrics,
+    common::{
+        error::BlazeError,
+        ipc_compression::{IoCompressionReader, IoCompressionWriter},
+    },
+    memmgr::{metrics::SpillMetrics, spill_cipher::SpillCipher, MemManager},
 };
 
 pub type SpillCompressedReader<'a> = IoCompressionReader<BufReader<Box<dyn Read + Send + 'a>>>;
@@ -44,6 +63,38 @@ pub trait Spill: Send + Sync {
     fn get_buf_reader<'a>(&'a self) -> BufReader<Box<dyn Read + Send + 'a>>;
     fn get_buf_writer<'a>(&'a mut self) -> BufWriter<Box<dyn Write + Send + 'a>>;
 
+    /// returns number of bytes written into this spill so far
+    fn written_size(&self) -> u64;
+
+    /// marks the spill as completed and appends a trailer containing total
+    /// length and checksum of written data. the trailer is verified when
+    /// reading, so that truncated or corrupted spill data fails loudly instead
+    /// of producing bad output. must be called after all writers are finished.
+    fn complete(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// returns number of bytes of this spill stored on disk
+    fn disk_usage(&self) -> u64 {
+        0
+    }
+
+    /// returns number of bytes of this spill still resident in memory, which
+    /// should be included in memory accounting of the spill owner.
+    fn resident_mem_size(&self) -> usize {
+        0
+    }
+
+    /// moves resident data into underlying storage, releasing its memory.
+    fn flush_resident(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// publishes spill count and disk usage into spill metrics. only the part
+    /// not yet published is recorded, so this can be called every time a spill
+    /// completes, and is called again when the spill is dropped.
+    fn publish_metrics(&self) {}
+
     fn get_compressed_reader(&self) -> SpillCompressedReader<'_> {
         IoCompressionReader::try_new(spill_compression_codec(), self.get_buf_reader())
             .expect("error creating compression reader")
@@ -71,6 +122,10 @@ impl Spill for Vec<u8> {
     fn get_buf_writer<'a>(&'a mut self) -> BufWriter<Box<dyn Write + Send + 'a>> {
         BufWriter::new(Box::new(self))
     }
+
+    fn written_size(&self) -> u64 {
+        self.len() as u64
+    }
 }
 
 fn spill_compression_codec() -> &'static str {
@@ -87,6 +142,238 @@ fn spill_compression_codec() -> &'static str {
         .as_str()
 }
 
+fn spill_direct_to_disk_threshold() -> usize {
+    static THRESHOLD: OnceCell<usize> = OnceCell::new();
+    *THRESHOLD.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SPILL_DIRECT_TO_DISK_THRESHOLD
+                .value()
+                .unwrap_or(268435456) as usize
+        } else {
+            268435456 // for testing
+        }
+    })
+}
+
+fn spill_resident_threshold() -> usize {
+    static THRESHOLD: OnceCell<usize> = OnceCell::new();
+    *THRESHOLD.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SPILL_RESIDENT_THRESHOLD.value().unwrap_or(1048576) as usize
+        } else {
+            1048576 // for testing
+        }
+    })
+}
+
+fn spill_column_encoding_enabled() -> bool {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    *ENABLED.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SPILL_COLUMN_ENCODING_ENABLE.value().unwrap_or(false)
+        } else {
+            false // for testing
+        }
+    })
+}
+
+fn spill_encryption_enabled() -> bool {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    *ENABLED.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SPILL_ENCRYPTION_ENABLE.value().unwrap_or(false)
+        } else {
+            false // for testing
+        }
+    })
+}
+
+pub(super) fn spill_placement_seed() -> Option<u64> {
+    static SEED: OnceCell<i64> = OnceCell::new();
+    let seed = *SEED.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SPILL_PLACEMENT_SEED.value().unwrap_or(-1)
+        } else {
+            -1 // for testing
+        }
+    });
+    (seed >= 0).then_some(seed as u64)
+}
+
+/// returns the max bytes of disk used by spills of a consumer, None for no
+/// limit
+pub fn spill_max_disk_bytes() -> Option<u64> {
+    static MAX_DISK_BYTES: OnceCell<Option<u64>> = OnceCell::new();
+    *MAX_DISK_BYTES.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SPILL_MAX_DISK_BYTES
+                .value()
+                .ok()
+                .and_then(|max_disk_bytes| u64::try_from(max_disk_bytes).ok())
+        } else {
+            None // for testing
+        }
+    })
+}
+
+/// fails with ResourcesExhausted if spills use more disk than
+/// `max_disk_bytes`, so that a task fails before the disk is full
+pub fn check_spill_disk_usage<'a>(
+    consumer_name: &str,
+    spills: impl IntoIterator<Item = &'a dyn Spill>,
+    max_disk_bytes: Option<u64>,
+) -> Result<()> {
+    let Some(max_disk_bytes) = max_disk_bytes else {
+        return Ok(());
+    };
+    let disk_usage = spills
+        .into_iter()
+        .map(|spill| spill.disk_usage())
+        .sum::<u64>();
+    if disk_usage > max_disk_bytes {
+        return Err(BlazeError::SpillDiskExhausted {
+            consumer: consumer_name.to_owned(),
+            used: disk_usage,
+            max: max_disk_bytes,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// chooses spill directories as a deterministic function of a seed and a
+/// monotonic counter
+pub struct SpillPlacement {
+    seed: u64,
+    counter: AtomicU64,
+}
+
+impl SpillPlacement {
+    /// uses a time-based seed if seed is not specified
+    pub fn new(seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        Self {
+            seed,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// returns index of the directory for the next spill
+    pub fn next_dir_index(&self, num_dirs: usize) -> usize {
+        assert!(num_dirs > 0, "no spill directories");
+        let n = self.counter.fetch_add(1, SeqCst);
+
+        // splitmix64
+        let step = (n + 1).wrapping_mul(0x9e3779b97f4a7c15);
+        let mut z = self.seed.wrapping_add(step);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z % num_dirs as u64) as usize
+    }
+}
+
+fn try_new_spill_cipher() -> Result<Option<SpillCipher>> {
+    spill_encryption_enabled()
+        .then(SpillCipher::try_new)
+        .transpose()
+}
+
+/// serializes batches in spills and shuffle segments, so that the format can
+/// be swapped without changing the writers and readers of batches. a batch
+/// must be read back with the serializer it was written with.
+pub trait SpillSerializer: Send + Sync {
+    fn write_batch(&self, num_rows: usize, cols: &[ArrayRef], output: &mut dyn Write)
+        -> Result<()>;
+
+    /// reads the next batch, returns None at the end of input
+    fn read_batch(
+        &self,
+        input: &mut dyn Read,
+        schema: &SchemaRef,
+    ) -> Result<Option<(usize, Vec<ArrayRef>)>>;
+}
+
+/// the default serializer, see [`write_one_batch`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultSpillSerializer;
+
+impl SpillSerializer for DefaultSpillSerializer {
+    fn write_batch(
+        &self,
+        num_rows: usize,
+        cols: &[ArrayRef],
+        output: &mut dyn Write,
+    ) -> Result<()> {
+        write_one_batch(num_rows, cols, output)
+    }
+
+    fn read_batch(
+        &self,
+        input: &mut dyn Read,
+        schema: &SchemaRef,
+    ) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+        read_one_batch(input, schema)
+    }
+}
+
+/// writes a batch into spill, columns are encoded with per-column encodings
+/// if spark.blaze.spill.columnEncoding.enable is set.
+pub fn write_spill_batch(num_rows: usize, cols: &[ArrayRef], output: impl Write) -> Result<()> {
+    if spill_column_encoding_enabled() {
+        write_one_batch_with_encodings(num_rows, cols, output)
+    } else {
+        write_one_batch(num_rows, cols, output)
+    }
+}
+
+/// reads a batch written by [`write_spill_batch`].
+pub fn read_spill_batch(
+    input: impl Read,
+    schema: &SchemaRef,
+) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+    if spill_column_encoding_enabled() {
+        read_one_batch_with_encodings(input, schema)
+    } else {
+        read_one_batch(input, schema)
+    }
+}
+
+/// creates a spill which writes directly into a disk file, bypassing on-heap
+/// spill manager. used for very large spills to keep memory usage low.
+pub fn try_new_disk_spill(spill_metrics: &SpillMetrics) -> Result<Box<dyn Spill>> {
+    Ok(Box::new(FileSpill::try_new(spill_metrics)?))
+}
+
+/// creates a spill according to the expected spill size, spills larger than
+/// spark.blaze.spill.directToDisk.threshold are written directly to disk,
+/// spills smaller than spark.blaze.spill.resident.threshold are kept in memory
+/// until they grow larger than the threshold.
+///
+/// the owner of a resident spill must include [`Spill::resident_mem_size`] in
+/// its memory usage, and call [`Spill::flush_resident`] when spilling.
+pub fn try_new_spill_with_size_hint(
+    spill_metrics: &SpillMetrics,
+    expected_size: usize,
+) -> Result<Box<dyn Spill>> {
+    if expected_size >= spill_direct_to_disk_threshold() {
+        return try_new_disk_spill(spill_metrics);
+    }
+    if expected_size < spill_resident_threshold() {
+        return Ok(Box::new(ResidentSpill::new(spill_metrics)));
+    }
+    try_new_spill(spill_metrics)
+}
+
 pub fn try_new_spill(spill_metrics: &SpillMetrics) -> Result<Box<dyn Spill>> {
     if !is_jni_bridge_inited() || jni_call_static!(JniBridge.isDriverSide() -> bool)? {
         Ok(Box::new(FileSpill::try_new(spill_metrics)?))
@@ -101,11 +388,352 @@ pub fn try_new_spill(spill_metrics: &SpillMetrics) -> Result<Box<dyn Spill>> {
     }
 }
 
+/// A spill which keeps data in memory until it grows larger than the resident
+/// threshold, then flushes all data into a normal spill
+struct ResidentSpill {
+    resident: Vec<u8>,
+    flushed: Option<Box<dyn Spill>>,
+    completed: bool,
+    threshold: usize,
+    spill_metrics: SpillMetrics,
+    published_metrics: PublishedSpillMetrics,
+}
+
+impl ResidentSpill {
+    fn new(spill_metrics: &SpillMetrics) -> Self {
+        Self {
+            resident: vec![],
+            flushed: None,
+            completed: false,
+            threshold: spill_resident_threshold(),
+            spill_metrics: spill_metrics.clone(),
+            published_metrics: PublishedSpillMetrics::default(),
+        }
+    }
+
+    fn new_flushed_spill(spill_metrics: &SpillMetrics) -> Result<Box<dyn Spill>> {
+        // spill count is published by the resident spill, do not count again
+        try_new_spill(&SpillMetrics {
+            mem_spill_count: Count::new(),
+            ..spill_metrics.clone()
+        })
+    }
+}
+
+impl Spill for ResidentSpill {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_buf_reader<'a>(&'a self) -> BufReader<Box<dyn Read + Send + 'a>> {
+        match &self.flushed {
+            Some(flushed) => flushed.get_buf_reader(),
+            None => BufReader::new(Box::new(Cursor::new(&self.resident))),
+        }
+    }
+
+    fn get_buf_writer<'a>(&'a mut self) -> BufWriter<Box<dyn Write + Send + 'a>> {
+        if self.flushed.is_some() {
+            return self.flushed.as_mut().unwrap().get_buf_writer();
+        }
+        BufWriter::new(Box::new(ResidentSpillWriter {
+            resident: &mut self.resident,
+            flushed_slot: Some(&mut self.flushed),
+            flushed_writer: None,
+            threshold: self.threshold,
+            spill_metrics: &self.spill_metrics,
+        }))
+    }
+
+    fn written_size(&self) -> u64 {
+        match &self.flushed {
+            Some(flushed) => flushed.written_size(),
+            None => self.resident.len() as u64,
+        }
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        self.completed = true;
+        if let Some(flushed) = &mut self.flushed {
+            flushed.complete()?;
+        }
+        Ok(())
+    }
+
+    fn disk_usage(&self) -> u64 {
+        self.flushed
+            .as_ref()
+            .map(|flushed| flushed.disk_usage())
+            .unwrap_or(0)
+    }
+
+    fn resident_mem_size(&self) -> usize {
+        self.resident.len()
+    }
+
+    fn flush_resident(&mut self) -> Result<()> {
+        if self.flushed.is_some() || self.resident.is_empty() {
+            return Ok(());
+        }
+        let mut flushed = Self::new_flushed_spill(&self.spill_metrics)?;
+        let mut writer = flushed.get_buf_writer();
+        writer.write_all(&self.resident)?;
+        writer.flush()?;
+        drop(writer);
+        if self.completed {
+            flushed.complete()?;
+        }
+        self.resident = vec![];
+        self.flushed = Some(flushed);
+        Ok(())
+    }
+
+    fn publish_metrics(&self) {
+        // disk usage is published by the flushed spill
+        self.published_metrics.publish(&self.spill_metrics, 0);
+        if let Some(flushed) = &self.flushed {
+            flushed.publish_metrics();
+        }
+    }
+}
+
+impl Drop for ResidentSpill {
+    fn drop(&mut self) {
+        self.publish_metrics();
+    }
+}
+
+struct ResidentSpillWriter<'a> {
+    resident: &'a mut Vec<u8>,
+    flushed_slot: Option<&'a mut Option<Box<dyn Spill>>>,
+    flushed_writer: Option<BufWriter<Box<dyn Write + Send + 'a>>>,
+    threshold: usize,
+    spill_metrics: &'a SpillMetrics,
+}
+
+impl Write for ResidentSpillWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.flushed_writer.is_none() {
+            if self.resident.len() + buf.len() <= self.threshold {
+                self.resident.extend_from_slice(buf);
+                return Ok(buf.len());
+            }
+
+            // resident data grows too large, flush into a normal spill
+            let flushed = ResidentSpill::new_flushed_spill(self.spill_metrics)
+                .map_err(std::io::Error::other)?;
+            let flushed_slot = self.flushed_slot.take().expect("missing flushed slot");
+            let mut flushed_writer = flushed_slot.insert(flushed).get_buf_writer();
+            flushed_writer.write_all(self.resident)?;
+            *self.resident = vec![];
+            self.flushed_writer = Some(flushed_writer);
+        }
+        self.flushed_writer.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.flushed_writer {
+            Some(flushed_writer) => flushed_writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Tracks the metrics already published by a spill, so that they can be
+/// published incrementally without double counting
+#[derive(Default)]
+struct PublishedSpillMetrics {
+    counted: AtomicBool,
+    disk_usage: AtomicU64,
+}
+
+impl PublishedSpillMetrics {
+    fn publish(&self, spill_metrics: &SpillMetrics, disk_usage: u64) {
+        if !self.counted.swap(true, SeqCst) {
+            spill_metrics.mem_spill_count.add(1);
+        }
+        let published_disk_usage = self.disk_usage.fetch_max(disk_usage, SeqCst);
+        spill_metrics
+            .disk_spill_size
+            .add(disk_usage.saturating_sub(published_disk_usage) as usize);
+    }
+}
+
+const SPILL_TRAILER_SIZE: usize = 12; // data length (u64) + checksum (u32)
+
+/// Tracks length and checksum of data written into a spill, which are
+/// appended as a trailer when the spill is completed
+#[derive(Default)]
+struct SpillTrailer {
+    data_len: AtomicU64,
+    checksum: Mutex<Adler32>,
+    completed: AtomicBool,
+}
+
+impl SpillTrailer {
+    fn update(&self, data: &[u8]) {
+        assert!(!self.completed.load(SeqCst), "writing to a completed spill");
+        self.checksum.lock().update(data);
+        self.data_len.fetch_add(data.len() as u64, SeqCst);
+    }
+
+    fn data_len(&self) -> u64 {
+        self.data_len.load(SeqCst)
+    }
+
+    /// returns trailer bytes to be appended, or None if already completed
+    fn complete(&self) -> Option<[u8; SPILL_TRAILER_SIZE]> {
+        if self.completed.swap(true, SeqCst) {
+            return None;
+        }
+        let mut trailer = [0u8; SPILL_TRAILER_SIZE];
+        trailer[0..8].copy_from_slice(&self.data_len().to_le_bytes());
+        trailer[8..12].copy_from_slice(&self.checksum.lock().finish().to_le_bytes());
+        Some(trailer)
+    }
+
+    fn wrap_reader<'a>(&self, reader: impl Read + Send + 'a) -> Box<dyn Read + Send + 'a> {
+        if self.completed.load(SeqCst) {
+            Box::new(VerifiedSpillReader {
+                inner: reader,
+                expected_len: self.data_len(),
+                read_len: 0,
+                checksum: Adler32::default(),
+            })
+        } else {
+            Box::new(reader)
+        }
+    }
+}
+
+/// A reader which reads data of a completed spill, verifying its length and
+/// checksum against the trailer
+struct VerifiedSpillReader<R: Read> {
+    inner: R,
+    expected_len: u64,
+    read_len: u64,
+    checksum: Adler32,
+}
+
+impl<R: Read> VerifiedSpillReader<R> {
+    fn verify_trailer(&mut self) -> std::io::Result<()> {
+        let mut trailer = [0u8; SPILL_TRAILER_SIZE];
+        if let Err(e) = self.inner.read_exact(&mut trailer) {
+            return Err(match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => spill_corrupted_err(format!(
+                    "missing trailer after {} bytes",
+                    self.expected_len
+                )),
+                _ => e,
+            });
+        }
+        let trailer_len = u64::from_le_bytes(trailer[0..8].try_into().unwrap());
+        let trailer_checksum = u32::from_le_bytes(trailer[8..12].try_into().unwrap());
+        if trailer_len != self.read_len {
+            return Err(spill_corrupted_err(format!(
+                "expected {} bytes, found {}",
+                trailer_len, self.read_len
+            )));
+        }
+        if trailer_checksum != self.checksum.finish() {
+            return Err(spill_corrupted_err(format!(
+                "checksum mismatch, expected {:#010x}, found {:#010x}",
+                trailer_checksum,
+                self.checksum.finish(),
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for VerifiedSpillReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.expected_len - self.read_len;
+        if remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let max_len = buf.len().min(remaining as usize);
+        let n = self.inner.read(&mut buf[..max_len])?;
+        if n == 0 {
+            return Err(spill_corrupted_err(format!(
+                "expected {} bytes, found {}",
+                self.expected_len, self.read_len
+            )));
+        }
+        self.checksum.update(&buf[..n]);
+        self.read_len += n as u64;
+
+        // verify trailer as soon as all data is read
+        if self.read_len == self.expected_len {
+            self.verify_trailer()?;
+        }
+        Ok(n)
+    }
+}
+
+pub(super) fn spill_corrupted_err(msg: String) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("spill corrupted: {msg}"),
+    )
+}
+
+/// Adler-32 checksum, which is independent of how data is chunked
+#[derive(Clone, Copy)]
+struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Self {
+        Self { a: 1, b: 0 }
+    }
+}
+
+impl Adler32 {
+    const MOD: u32 = 65521;
+    const NMAX: usize = 5552; // max bytes before b may overflow u32
+
+    fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(Self::NMAX) {
+            for &byte in chunk {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= Self::MOD;
+            self.b %= Self::MOD;
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
 /// A spill structure which write data to temporary files
 /// used in driver side or executor side with on-heap memory is full
-struct FileSpill(File, SpillMetrics, Option<String>);
+struct FileSpill(
+    File,
+    SpillMetrics,
+    Option<String>,
+    PublishedSpillMetrics,
+    SpillTrailer,
+    Option<SpillCipher>,
+);
 impl FileSpill {
     fn try_new(spill_metrics: &SpillMetrics) -> Result<Self> {
+        Self::try_new_with_cipher(spill_metrics, try_new_spill_cipher()?)
+    }
+
+    fn try_new_with_cipher(
+        spill_metrics: &SpillMetrics,
+        cipher: Option<SpillCipher>,
+    ) -> Result<Self> {
         if is_jni_bridge_inited() {
             let file_name = jni_get_string!(
                 jni_call_static!(JniBridge.getDirectWriteSpillToDiskFile() -> JObject)?
@@ -117,11 +745,38 @@ impl FileSpill {
                 .truncate(true)
                 .write(true)
                 .read(true)
-                .open(&file_name)?;
-            Ok(Self(file, spill_metrics.clone(), Some(file_name)))
+                .open(&file_name)
+                .map_err(|source| BlazeError::SpillIo {
+                    path: Some(file_name.clone()),
+                    source,
+                })?;
+            Ok(Self(
+                file,
+                spill_metrics.clone(),
+                Some(file_name),
+                PublishedSpillMetrics::default(),
+                SpillTrailer::default(),
+                cipher,
+            ))
         } else {
-            let file = tempfile::tempfile()?;
-            Ok(Self(file, spill_metrics.clone(), None))
+            // use spill dirs of the mem manager if configured
+            let file = match MemManager::get() {
+                Ok(mm) if !mm.spill_dirs().is_empty() => {
+                    let dirs = mm.spill_dirs();
+                    let dir_index = mm.spill_placement().next_dir_index(dirs.len());
+                    tempfile::tempfile_in(&dirs[dir_index])
+                }
+                _ => tempfile::tempfile(),
+            }
+            .map_err(|source| BlazeError::SpillIo { path: None, source })?;
+            Ok(Self(
+                file,
+                spill_metrics.clone(),
+                None,
+                PublishedSpillMetrics::default(),
+                SpillTrailer::default(),
+                cipher,
+            ))
         }
     }
 }
@@ -139,30 +794,56 @@ impl Spill for FileSpill {
         let mut file_cloned = self.0.try_clone().expect("File.try_clone() returns error");
         file_cloned.sync_data().expect("error synchronizing data");
         file_cloned.rewind().expect("error rewinding");
-        BufReader::with_capacity(
-            65536,
-            Box::new(IoTimeReadWrapper(
-                file_cloned,
-                self.1.mem_spill_iotime.clone(),
-            )),
-        )
+        let reader = IoTimeReadWrapper(file_cloned, self.1.mem_spill_iotime.clone());
+        BufReader::with_capacity(65536, self.4.wrap_reader(decrypted(&self.5, reader)))
     }
 
     fn get_buf_writer<'a>(&'a mut self) -> BufWriter<Box<dyn Write + Send + 'a>> {
         let file_cloned = self.0.try_clone().expect("File.try_clone() returns error");
+        let writer = IoTimeWriteWrapper(file_cloned, self.1.mem_spill_iotime.clone());
         BufWriter::with_capacity(
             65536,
-            Box::new(IoTimeWriteWrapper(
-                file_cloned,
-                self.1.mem_spill_iotime.clone(),
+            Box::new(TrailerUpdatingWriteWrapper(
+                encrypted(&self.5, writer),
+                &self.4,
             )),
         )
     }
+
+    fn written_size(&self) -> u64 {
+        self.4.data_len()
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        if let Some(trailer) = self.4.complete() {
+            let _timer = self.1.mem_spill_iotime.timer();
+            let trailer = match &self.5 {
+                Some(cipher) => cipher.seal_segment(&trailer)?.1,
+                None => trailer.to_vec(),
+            };
+            self.0
+                .seek(SeekFrom::End(0))
+                .and_then(|_| self.0.write_all(&trailer))
+                .map_err(|source| BlazeError::SpillIo {
+                    path: self.2.clone(),
+                    source,
+                })?;
+        }
+        Ok(())
+    }
+
+    fn disk_usage(&self) -> u64 {
+        self.0.len()
+    }
+
+    fn publish_metrics(&self) {
+        self.3.publish(&self.1, self.disk_usage());
+    }
 }
 
 impl Drop for FileSpill {
     fn drop(&mut self) {
-        self.1.disk_spill_size.add(self.0.len() as usize);
+        self.publish_metrics();
         self.1
             .disk_spill_iotime
             .add_duration(Duration::from_nanos(self.1.mem_spill_iotime.value() as u64));
@@ -187,6 +868,9 @@ impl OnHeapSpill {
             Arc::new(RawOnHeapSpill {
                 hsm: jni_new_global_ref!(hsm.as_obj())?,
                 spill_id,
+                trailer: SpillTrailer::default(),
+                published_metrics: PublishedSpillMetrics::default(),
+                cipher: try_new_spill_cipher()?,
             }),
             spill_metrics.clone(),
         ))
@@ -198,6 +882,33 @@ impl OnHeapSpill {
         Ok(usage)
     }
 
+    /// writes a prefix of buf, which is sealed as a segment if spill
+    /// encryption is enabled. returns number of written bytes of buf.
+    fn write_data(&self, buf: &[u8]) -> std::io::Result<usize> {
+        match &self.0.cipher {
+            Some(cipher) => {
+                let (n, segment) = cipher.seal_segment(buf)?;
+                self.write_raw(&segment)?;
+                self.1.mem_spill_size.add(segment.len());
+                Ok(n)
+            }
+            None => {
+                self.write_raw(buf)?;
+                self.1.mem_spill_size.add(buf.len());
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn write_raw(&self, buf: &[u8]) -> std::io::Result<()> {
+        let _timer = self.1.mem_spill_iotime.timer();
+        let buf = jni_new_direct_byte_buffer!(buf)?;
+        jni_call!(BlazeOnHeapSpillManager(
+            self.0.hsm.as_obj()).writeSpill(self.0.spill_id, buf.as_obj()) -> ()
+        )?;
+        Ok(())
+    }
+
     fn get_disk_iotime(&self) -> Result<u64> {
         let iotime = jni_call!(BlazeOnHeapSpillManager(self.0.hsm.as_obj())
             .getSpillDiskIOTime(self.0.spill_id) -> jlong)? as u64;
@@ -216,26 +927,45 @@ impl Spill for OnHeapSpill {
 
     fn get_buf_reader<'a>(&'a self) -> BufReader<Box<dyn Read + Send + 'a>> {
         let cloned = Self(self.0.clone(), self.1.clone());
-        BufReader::with_capacity(65536, Box::new(cloned))
+        BufReader::with_capacity(
+            65536,
+            self.0
+                .trailer
+                .wrap_reader(decrypted(&self.0.cipher, cloned)),
+        )
     }
 
     fn get_buf_writer<'a>(&'a mut self) -> BufWriter<Box<dyn Write + Send + 'a>> {
         let cloned = Self(self.0.clone(), self.1.clone());
         BufWriter::with_capacity(1048576, Box::new(cloned))
     }
+
+    fn written_size(&self) -> u64 {
+        self.0.trailer.data_len()
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        if let Some(trailer) = self.0.trailer.complete() {
+            let written = self.write_data(&trailer)?;
+            assert_eq!(written, trailer.len());
+        }
+        Ok(())
+    }
+
+    fn disk_usage(&self) -> u64 {
+        self.get_disk_usage().unwrap_or(0)
+    }
+
+    fn publish_metrics(&self) {
+        self.0.published_metrics.publish(&self.1, self.disk_usage());
+    }
 }
 
 impl Write for OnHeapSpill {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let _timer = self.1.mem_spill_iotime.timer();
-        let write_len = buf.len();
-        let buf = jni_new_direct_byte_buffer!(buf)?;
-
-        jni_call!(BlazeOnHeapSpillManager(
-            self.0.hsm.as_obj()).writeSpill(self.0.spill_id, buf.as_obj()) -> ()
-        )?;
-        self.1.mem_spill_size.add(write_len);
-        Ok(write_len)
+        let n = self.write_data(buf)?;
+        self.0.trailer.update(&buf[..n]);
+        Ok(n)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -256,10 +986,7 @@ impl Read for OnHeapSpill {
 
 impl Drop for OnHeapSpill {
     fn drop(&mut self) {
-        self.1.mem_spill_count.add(1);
-        self.1
-            .disk_spill_size
-            .add(self.get_disk_usage().unwrap_or(0) as usize);
+        self.publish_metrics();
         self.1
             .disk_spill_iotime
             .add_duration(Duration::from_nanos(self.get_disk_iotime().unwrap_or(0)));
@@ -269,6 +996,9 @@ impl Drop for OnHeapSpill {
 struct RawOnHeapSpill {
     hsm: GlobalRef,
     spill_id: i32,
+    trailer: SpillTrailer,
+    published_metrics: PublishedSpillMetrics,
+    cipher: Option<SpillCipher>,
 }
 
 impl Drop for RawOnHeapSpill {
@@ -278,8 +1008,31 @@ impl Drop for RawOnHeapSpill {
     }
 }
 
+/// decrypts data read from the spill if encryption is enabled
+fn decrypted<'a>(
+    cipher: &'a Option<SpillCipher>,
+    reader: impl Read + Send + 'a,
+) -> Box<dyn Read + Send + 'a> {
+    match cipher {
+        Some(cipher) => Box::new(cipher.wrap_reader(reader)),
+        None => Box::new(reader),
+    }
+}
+
+/// encrypts data written into the spill if encryption is enabled
+fn encrypted<'a>(
+    cipher: &'a Option<SpillCipher>,
+    writer: impl Write + Send + 'a,
+) -> Box<dyn Write + Send + 'a> {
+    match cipher {
+        Some(cipher) => Box::new(cipher.wrap_writer(writer)),
+        None => Box::new(writer),
+    }
+}
+
 struct IoTimeReadWrapper<R: Read>(R, Time);
 struct IoTimeWriteWrapper<W: Write>(W, Time);
+struct TrailerUpdatingWriteWrapper<'a, W: Write>(W, &'a SpillTrailer);
 
 impl<R: Read> Read for IoTimeReadWrapper<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
@@ -300,6 +1053,18 @@ impl<W: Write> Write for IoTimeWriteWrapper<W> {
     }
 }
 
+impl<W: Write> Write for TrailerUpdatingWriteWrapper<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.1.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
 pub struct OwnedSpillBufReader<'a> {
     spill: Box<dyn Spill>,
     buf_reader: BufReader<Box<dyn Read + Send + 'a>>,
@@ -326,3 +1091,247 @@ impl<'a> OwnedSpillBufReader<'a> {
         &mut self.buf_reader
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use datafusion::{common::Result, physical_plan::metrics::ExecutionPlanMetricsSet};
+
+    use crate::memmgr::{
+        metrics::SpillMetrics,
+        spill::{
+            try_new_disk_spill, try_new_spill_with_size_hint, FileSpill, OwnedSpillBufReader,
+            ResidentSpill, Spill, SpillPlacement,
+        },
+        spill_cipher::SpillCipher,
+    };
+
+    fn write_and_read_back(spill: &mut Box<dyn Spill>, data: &[u8]) -> Result<Vec<u8>> {
+        let mut writer = spill.get_buf_writer();
+        writer.write_all(data)?;
+        drop(writer);
+
+        let mut read_back = vec![];
+        spill.get_buf_reader().read_to_end(&mut read_back)?;
+        Ok(read_back)
+    }
+
+    #[test]
+    fn test_disk_spill_round_trip() -> Result<()> {
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let data = (0..100000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let mut mem_spill: Box<dyn Spill> = Box::new(vec![]);
+        let mut disk_spill = try_new_disk_spill(&spill_metrics)?;
+        let mem_read_back = write_and_read_back(&mut mem_spill, &data)?;
+        let disk_read_back = write_and_read_back(&mut disk_spill, &data)?;
+        assert_eq!(mem_read_back, data);
+        assert_eq!(disk_read_back, mem_read_back);
+
+        // disk usage is available right after writing
+        assert_eq!(disk_spill.written_size(), data.len() as u64);
+        disk_spill.publish_metrics();
+        assert_eq!(spill_metrics.disk_spill_size.value(), data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_with_size_hint() -> Result<()> {
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let mut spill = try_new_spill_with_size_hint(&spill_metrics, usize::MAX)?;
+        let data = b"hello spill".to_vec();
+        assert_eq!(write_and_read_back(&mut spill, &data)?, data);
+        assert_eq!(spill.written_size(), data.len() as u64);
+        Ok(())
+    }
+
+    fn write_completed_disk_spill(
+        spill_metrics: &SpillMetrics,
+        data: &[u8],
+    ) -> Result<Box<dyn Spill>> {
+        let mut spill = try_new_disk_spill(spill_metrics)?;
+        let mut writer = spill.get_buf_writer();
+        writer.write_all(data)?;
+        drop(writer);
+        spill.complete()?;
+        Ok(spill)
+    }
+
+    fn file_of(spill: &mut Box<dyn Spill>) -> &mut std::fs::File {
+        &mut spill
+            .as_any_mut()
+            .downcast_mut::<FileSpill>()
+            .expect("expect FileSpill")
+            .0
+    }
+
+    #[test]
+    fn test_completed_spill_round_trip() -> Result<()> {
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let data = (0..100000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let spill = write_completed_disk_spill(&spill_metrics, &data)?;
+        assert_eq!(spill.written_size(), data.len() as u64);
+
+        let mut read_back = vec![];
+        spill.get_buf_reader().read_to_end(&mut read_back)?;
+        assert_eq!(read_back, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated_spill_fails_loudly() -> Result<()> {
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let data = (0..100000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut spill = write_completed_disk_spill(&spill_metrics, &data)?;
+
+        // simulate storage dropping the tail of spill data
+        file_of(&mut spill).set_len(50000)?;
+
+        // merging a range of the spill into output must not silently truncate
+        let mut reader = OwnedSpillBufReader::from(spill);
+        let mut output = vec![];
+        let err = std::io::copy(
+            &mut reader.buf_reader().take(data.len() as u64),
+            &mut output,
+        )
+        .expect_err("reading truncated spill should fail");
+        assert!(
+            err.to_string()
+                .contains("spill corrupted: expected 100000 bytes, found 50000"),
+            "unexpected error: {err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupted_spill_fails_loudly() -> Result<()> {
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let data = (0..100000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut spill = write_completed_disk_spill(&spill_metrics, &data)?;
+
+        // flip one byte in the middle of spill data
+        let file = file_of(&mut spill);
+        file.seek(SeekFrom::Start(12345))?;
+        file.write_all(&[!data[12345]])?;
+
+        let mut read_back = vec![];
+        let err = spill
+            .get_buf_reader()
+            .read_to_end(&mut read_back)
+            .expect_err("reading corrupted spill should fail");
+        assert!(
+            err.to_string()
+                .contains("spill corrupted: checksum mismatch"),
+            "unexpected error: {err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_spill_round_trip() -> Result<()> {
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let data = (0..3000000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut spill: Box<dyn Spill> = Box::new(FileSpill::try_new_with_cipher(
+            &spill_metrics,
+            Some(SpillCipher::try_new()?),
+        )?);
+        assert_eq!(write_and_read_back(&mut spill, &data)?, data);
+        spill.complete()?;
+        assert_eq!(spill.written_size(), data.len() as u64);
+
+        let mut read_back = vec![];
+        spill.get_buf_reader().read_to_end(&mut read_back)?;
+        assert_eq!(read_back, data);
+
+        // on-disk bytes do not contain plaintext
+        let mut on_disk = vec![];
+        let file = file_of(&mut spill);
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut on_disk)?;
+        assert!(on_disk.len() > data.len());
+        assert!(!on_disk.windows(251).any(|window| window == &data[..251]));
+
+        // tampered data fails to decrypt
+        file.seek(SeekFrom::Start(12345))?;
+        file.write_all(&[!on_disk[12345]])?;
+        let err = spill
+            .get_buf_reader()
+            .read_to_end(&mut vec![])
+            .expect_err("reading tampered spill should fail");
+        assert!(
+            err.to_string()
+                .contains("spill corrupted: error decrypting segment 0"),
+            "unexpected error: {err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resident_spill() -> Result<()> {
+        let is_flushed = |spill: &Box<dyn Spill>| {
+            spill
+                .as_any()
+                .downcast_ref::<ResidentSpill>()
+                .expect("expect ResidentSpill")
+                .flushed
+                .is_some()
+        };
+
+        // small spill never touches disk
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let mut spill = try_new_spill_with_size_hint(&spill_metrics, 0)?;
+        let data = (0..1000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        assert_eq!(write_and_read_back(&mut spill, &data)?, data);
+        spill.complete()?;
+        spill.publish_metrics();
+        assert!(!is_flushed(&spill));
+        assert_eq!(spill.resident_mem_size(), data.len());
+        assert_eq!(spill_metrics.mem_spill_count.value(), 1);
+        assert_eq!(spill_metrics.disk_spill_size.value(), 0);
+
+        // flushing releases resident memory, data is still readable
+        spill.flush_resident()?;
+        spill.publish_metrics();
+        assert!(is_flushed(&spill));
+        assert_eq!(spill.resident_mem_size(), 0);
+        assert_eq!(spill_metrics.mem_spill_count.value(), 1);
+        assert!(spill_metrics.disk_spill_size.value() > 0);
+        let mut read_back = vec![];
+        spill.get_buf_reader().read_to_end(&mut read_back)?;
+        assert_eq!(read_back, data);
+
+        // large spill is flushed to disk when exceeding the threshold
+        let spill_metrics = SpillMetrics::new(&ExecutionPlanMetricsSet::new(), 0);
+        let mut spill = try_new_spill_with_size_hint(&spill_metrics, 0)?;
+        let data = (0..3000000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        assert_eq!(write_and_read_back(&mut spill, &data)?, data);
+        spill.complete()?;
+        spill.publish_metrics();
+        assert!(is_flushed(&spill));
+        assert_eq!(spill.resident_mem_size(), 0);
+        assert_eq!(spill.written_size(), data.len() as u64);
+        assert_eq!(spill_metrics.mem_spill_count.value(), 1);
+        assert!(spill_metrics.disk_spill_size.value() >= data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_placement_seed() {
+        let dirs = |placement: &SpillPlacement| {
+            (0..100)
+                .map(|_| placement.next_dir_index(4))
+                .collect::<Vec<_>>()
+        };
+        let dirs1 = dirs(&SpillPlacement::new(Some(37)));
+        let dirs2 = dirs(&SpillPlacement::new(Some(37)));
+        let dirs3 = dirs(&SpillPlacement::new(Some(38)));
+        assert_eq!(dirs1, dirs2);
+        assert_ne!(dirs1, dirs3);
+
+        // all directories are used
+        for dir in 0..4 {
+            assert!(dirs1.contains(&dir));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    sync::atomic::{AtomicU64, Ordering::SeqCst},
+};
+
+use datafusion::common::Result;
+use datafusion_ext_commons::df_execution_err;
+use once_cell::sync::OnceCell;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf::{Salt, HKDF_SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::memmgr::spill::spill_corrupted_err;
+
+const SEGMENT_HEADER_SIZE: usize = 4; // ciphertext length (u32)
+const MAX_SEGMENT_SIZE: usize = 1048576;
+
+/// Encrypts spill data with AES-256-GCM
+///
+/// data is sealed in independent segments, each is prefixed with the length
+/// of its ciphertext and uses the segment index as nonce, so that segments
+/// can neither be modified nor reordered. each spill uses its own key derived
+/// from the process secret and a random salt.
+pub struct SpillCipher {
+    key: LessSafeKey,
+    num_sealed_segments: AtomicU64,
+}
+
+impl SpillCipher {
+    pub fn try_new() -> Result<Self> {
+        let mut salt = [0u8; 32];
+        if SystemRandom::new().fill(&mut salt).is_err() {
+            return df_execution_err!("error generating spill key salt");
+        }
+        let prk = Salt::new(HKDF_SHA256, &salt).extract(process_secret()?);
+        let Ok(okm) = prk.expand(&[b"blaze spill".as_slice()], &AES_256_GCM) else {
+            return df_execution_err!("error deriving spill key");
+        };
+        Ok(Self {
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            num_sealed_segments: AtomicU64::new(0),
+        })
+    }
+
+    /// seals a prefix of data as the next segment, returns the number of
+    /// sealed bytes and the segment to be written.
+    pub fn seal_segment(&self, data: &[u8]) -> std::io::Result<(usize, Vec<u8>)> {
+        let plaintext = &data[..data.len().min(MAX_SEGMENT_SIZE)];
+        let tag_len = AES_256_GCM.tag_len();
+        let segment_idx = self.num_sealed_segments.fetch_add(1, SeqCst);
+
+        let mut segment = Vec::with_capacity(SEGMENT_HEADER_SIZE + plaintext.len() + tag_len);
+        segment.extend_from_slice(&((plaintext.len() + tag_len) as u32).to_le_bytes());
+        segment.extend_from_slice(plaintext);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                segment_nonce(segment_idx),
+                Aad::empty(),
+                &mut segment[SEGMENT_HEADER_SIZE..],
+            )
+            .map_err(|_| std::io::Error::other("error encrypting spill segment"))?;
+        segment.extend_from_slice(tag.as_ref());
+        Ok((plaintext.len(), segment))
+    }
+
+    pub fn wrap_writer<'a>(&'a self, inner: impl Write + Send + 'a) -> impl Write + Send + 'a {
+        EncryptingWriter {
+            inner,
+            cipher: self,
+        }
+    }
+
+    pub fn wrap_reader<'a>(&'a self, inner: impl Read + Send + 'a) -> impl Read + Send + 'a {
+        DecryptingReader {
+            inner,
+            cipher: self,
+            segment_idx: 0,
+            segment: vec![],
+            plaintext_len: 0,
+            pos: 0,
+        }
+    }
+}
+
+fn process_secret() -> Result<&'static [u8; 32]> {
+    static SECRET: OnceCell<[u8; 32]> = OnceCell::new();
+    SECRET.get_or_try_init(|| {
+        let mut secret = [0u8; 32];
+        if SystemRandom::new().fill(&mut secret).is_err() {
+            return df_execution_err!("error generating spill process secret");
+        }
+        Ok(secret)
+    })
+}
+
+fn segment_nonce(segment_idx: u64) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[NONCE_LEN - 8..].copy_from_slice(&segment_idx.to_le_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+struct EncryptingWriter<'a, W: Write> {
+    inner: W,
+    cipher: &'a SpillCipher,
+}
+
+impl<W: Write> Write for EncryptingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let (n, segment) = self.cipher.seal_segment(buf)?;
+        self.inner.write_all(&segment)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct DecryptingReader<'a, R: Read> {
+    inner: R,
+    cipher: &'a SpillCipher,
+    segment_idx: u64,
+    segment: Vec<u8>,
+    plaintext_len: usize,
+    pos: usize,
+}
+
+impl<R: Read> DecryptingReader<'_, R> {
+    /// reads and decrypts the next segment, returns false if there are no
+    /// more segments
+    fn next_segment(&mut self) -> std::io::Result<bool> {
+        let mut header = [0u8; SEGMENT_HEADER_SIZE];
+        let mut header_len = 0;
+        while header_len < SEGMENT_HEADER_SIZE {
+            match self.inner.read(&mut header[header_len..]) {
+                Ok(0) if header_len == 0 => return Ok(false),
+                Ok(0) => {
+                    return Err(spill_corrupted_err(format!(
+                        "truncated header of encrypted segment {}",
+                        self.segment_idx
+                    )));
+                }
+                Ok(n) => header_len += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let tag_len = AES_256_GCM.tag_len();
+        let ciphertext_len = u32::from_le_bytes(header) as usize;
+        if ciphertext_len < tag_len || ciphertext_len > MAX_SEGMENT_SIZE + tag_len {
+            return Err(spill_corrupted_err(format!(
+                "invalid length of encrypted segment {}: {ciphertext_len}",
+                self.segment_idx
+            )));
+        }
+        self.segment.resize(ciphertext_len, 0);
+        if let Err(e) = self.inner.read_exact(&mut self.segment) {
+            return Err(match e.kind() {
+                ErrorKind::UnexpectedEof => {
+                    spill_corrupted_err(format!("truncated encrypted segment {}", self.segment_idx))
+                }
+                _ => e,
+            });
+        }
+        let plaintext = self
+            .cipher
+            .key
+            .open_in_place(
+                segment_nonce(self.segment_idx),
+                Aad::empty(),
+                &mut self.segment,
+            )
+            .map_err(|_| {
+                spill_corrupted_err(format!("error decrypting segment {}", self.segment_idx))
+            })?;
+        self.plaintext_len = plaintext.len();
+        self.pos = 0;
+        self.segment_idx += 1;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.plaintext_len {
+            if buf.is_empty() || !self.next_segment()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.plaintext_len - self.pos);
+        buf[..n].copy_from_slice(&self.segment[self.pos..][..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use datafusion::common::Result;
+
+    use crate::memmgr::spill_cipher::SpillCipher;
+
+    #[test]
+    fn test_segments_round_trip() -> Result<()> {
+        let cipher = SpillCipher::try_new()?;
+        let data = (0..3000000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        // chunks larger than max segment size are split into multiple segments
+        let mut encrypted = vec![];
+        let mut writer = cipher.wrap_writer(&mut encrypted);
+        for chunk in data.chunks(1234567) {
+            writer.write_all(chunk)?;
+        }
+        drop(writer);
+        assert!(encrypted.len() > data.len());
+
+        let mut decrypted = vec![];
+        cipher
+            .wrap_reader(encrypted.as_slice())
+            .read_to_end(&mut decrypted)?;
+        assert_eq!(decrypted, data);
+
+        // segments cannot be decrypted with another spill's key
+        let other_cipher = SpillCipher::try_new()?;
+        let err = other_cipher
+            .wrap_reader(encrypted.as_slice())
+            .read_to_end(&mut vec![])
+            .expect_err("decrypting with another key should fail");
+        assert!(
+            err.to_string()
+                .contains("spill corrupted: error decrypting segment 0"),
+            "unexpected error: {err}"
+        );
+        Ok(())
+    }
+}
@@ -202,7 +202,7 @@ fn execute_orc_scan(
             sender.exclude_time(exec_ctx.baseline_metrics().elapsed_compute());
             let _timer = exec_ctx.baseline_metrics().elapsed_compute().timer();
             while let Some(batch) = stream.next().await.transpose()? {
-                sender.send(batch).await;
+                sender.send(batch).await?;
             }
             Ok(())
         }))
@@ -249,7 +249,7 @@ fn execute_parquet_scan(
             sender.exclude_time(exec_ctx.baseline_metrics().elapsed_compute());
             let _timer = exec_ctx.baseline_metrics().elapsed_compute().timer();
             while let Some(batch) = stream.next().await.transpose()? {
-                sender.send(batch).await;
+                sender.send(batch).await?;
             }
             Ok(())
         }))
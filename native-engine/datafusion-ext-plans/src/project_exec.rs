@@ -223,7 +223,7 @@ fn execute_project_with_filtering(
                 exec_ctx
                     .baseline_metrics()
                     .record_output(output_batch.num_rows());
-                sender.send(output_batch).await;
+                sender.send(output_batch).await?;
             }
             Ok(())
         }))
@@ -76,6 +76,7 @@ impl RenameColumnsExec {
                 .zip(input_schema.fields())
                 .map(|(new_name, field)| {
                     Field::new(new_name, field.data_type().clone(), field.is_nullable())
+                        .with_metadata(field.metadata().clone())
                 })
                 .collect::<Fields>(),
         ));
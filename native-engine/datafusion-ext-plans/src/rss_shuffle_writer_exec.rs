@@ -37,7 +37,8 @@ use crate::{
     memmgr::MemManager,
     shuffle::{
         rss_single_repartitioner::RssSingleShuffleRepartitioner,
-        rss_sort_repartitioner::RssSortShuffleRepartitioner, Partitioning, ShuffleRepartitioner,
+        rss_sort_repartitioner::RssSortShuffleRepartitioner, writer_factory::validate_partitioning,
+        Partitioning, ShuffleRepartitioner,
     },
     sort_exec::create_default_ascending_sort_exec,
 };
@@ -113,6 +114,7 @@ impl ExecutionPlan for RssShuffleWriterExec {
         partition: usize,
         context: Arc<TaskContext>,
     ) -> Result<SendableRecordBatchStream> {
+        validate_partitioning(&self.partitioning, &self.schema())?;
         let exec_ctx = ExecutionContext::new(context, partition, self.schema(), &self.metrics);
         let output_io_time = exec_ctx.register_timer_metric("output_io_time");
 
@@ -128,14 +130,16 @@ impl ExecutionPlan for RssShuffleWriterExec {
                 rss_partition_writer,
                 output_io_time,
             )),
-            Partitioning::HashPartitioning(..) | Partitioning::RangePartitioning(..) => {
+            Partitioning::HashPartitioning(..)
+            | Partitioning::RangePartitioning(..)
+            | Partitioning::PartitionIdPartitioning(..) => {
                 let partitioner = Arc::new(RssSortShuffleRepartitioner::new(
                     partition,
                     rss_partition_writer,
                     self.partitioning.clone(),
                     output_io_time,
                 ));
-                MemManager::register_consumer(partitioner.clone(), true);
+                MemManager::register_consumer(partitioner.clone(), true)?;
                 partitioner
             }
             Partitioning::RoundRobinPartitioning(..) => {
@@ -161,7 +165,7 @@ impl ExecutionPlan for RssShuffleWriterExec {
                     self.partitioning.clone(),
                     output_io_time,
                 ));
-                MemManager::register_consumer(partitioner.clone(), true);
+                MemManager::register_consumer(partitioner.clone(), true)?;
                 partitioner
             }
             p => unreachable!("unsupported partitioning: {:?}", p),
@@ -0,0 +1,139 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+use arrow::record_batch::RecordBatch;
+use parking_lot::Mutex as SyncMutex;
+
+const NUM_SHARDS: usize = 16;
+
+/// queue of inserted batches not yet added to buffered data. inserters push
+/// to one of several shards and never wait for the buffered data lock, the
+/// lock holder takes all queued batches at once. every batch is taken exactly
+/// once, so concurrent inserts and spills never lose or duplicate batches.
+pub struct ShardedBatchQueue {
+    shards: Vec<SyncMutex<(Vec<RecordBatch>, usize)>>,
+    next_shard: AtomicUsize,
+    num_rows: AtomicUsize,
+    mem_used: AtomicUsize,
+}
+
+impl Default for ShardedBatchQueue {
+    fn default() -> Self {
+        Self {
+            shards: (0..NUM_SHARDS).map(|_| SyncMutex::default()).collect(),
+            next_shard: AtomicUsize::new(0),
+            num_rows: AtomicUsize::new(0),
+            mem_used: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl ShardedBatchQueue {
+    /// pushes batches to a single shard, keeping their order
+    pub fn push(&self, batches: Vec<RecordBatch>, mem_used: usize) {
+        let num_rows = batches.iter().map(|batch| batch.num_rows()).sum::<usize>();
+        self.num_rows.fetch_add(num_rows, SeqCst);
+        self.mem_used.fetch_add(mem_used, SeqCst);
+        let shard_idx = self.next_shard.fetch_add(1, SeqCst) % NUM_SHARDS;
+        let mut shard = self.shards[shard_idx].lock();
+        shard.0.extend(batches);
+        shard.1 += mem_used;
+    }
+
+    /// takes all queued batches and their memory usage. rows and memory of
+    /// taken batches are still counted until released with [`Self::release`],
+    /// so that they are never missed while being moved to buffered data
+    pub fn take(&self) -> (Vec<RecordBatch>, usize) {
+        let mut batches = vec![];
+        let mut mem_used = 0;
+        for shard in &self.shards {
+            let mut shard = shard.lock();
+            batches.append(&mut shard.0);
+            mem_used += std::mem::take(&mut shard.1);
+        }
+        (batches, mem_used)
+    }
+
+    pub fn release(&self, num_rows: usize, mem_used: usize) {
+        self.num_rows.fetch_sub(num_rows, SeqCst);
+        self.mem_used.fetch_sub(mem_used, SeqCst);
+    }
+
+    /// drops all queued batches
+    pub fn clear(&self) {
+        let (batches, mem_used) = self.take();
+        let num_rows = batches.iter().map(|batch| batch.num_rows()).sum();
+        self.release(num_rows, mem_used);
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.num_rows.load(SeqCst)
+    }
+
+    pub fn mem_used(&self) -> usize {
+        self.mem_used.load(SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{AsArray, Int32Array},
+        datatypes::{DataType, Field, Int32Type, Schema},
+        record_batch::RecordBatch,
+    };
+    use datafusion::common::Result;
+
+    use crate::shuffle::batch_queue::ShardedBatchQueue;
+
+    #[test]
+    fn test_concurrent_push_and_take() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let queue = ShardedBatchQueue::default();
+        let taken = std::thread::scope(|scope| {
+            for i in 0..8 {
+                let (queue, schema) = (&queue, schema.clone());
+                scope.spawn(move || {
+                    for j in 0..100 {
+                        let array = Int32Array::from(vec![i * 100 + j]);
+                        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(array)]);
+                        queue.push(vec![batch.expect("batch")], 1);
+                    }
+                });
+            }
+            let mut taken = vec![];
+            while taken.len() < 800 {
+                let (batches, mem_used) = queue.take();
+                assert_eq!(mem_used, batches.len());
+                queue.release(batches.len(), mem_used);
+                taken.extend(batches);
+            }
+            taken
+        });
+        assert_eq!(queue.num_rows(), 0);
+        assert_eq!(queue.mem_used(), 0);
+
+        let mut values = taken
+            .iter()
+            .map(|batch| batch.column(0).as_primitive::<Int32Type>().value(0))
+            .collect::<Vec<_>>();
+        values.sort_unstable();
+        assert_eq!(values, (0..800).collect::<Vec<_>>());
+        Ok(())
+    }
+}
@@ -12,23 +12,41 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::Write;
+use std::{io::Write, ops::Range, sync::Arc};
 
-use arrow::record_batch::RecordBatch;
-use blaze_jni_bridge::{is_task_running, jni_call};
+use arrow::{datatypes::SchemaRef, record_batch::RecordBatch};
+use blaze_jni_bridge::{
+    conf,
+    conf::{BooleanConf, IntConf, StringConf},
+    is_jni_bridge_inited, jni_call,
+};
+use bytes::Bytes;
 use bytesize::ByteSize;
-use count_write::CountWrite;
-use datafusion::{common::Result, physical_plan::metrics::Time};
+use datafusion::{
+    common::Result,
+    parquet::{
+        arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ProjectionMask},
+        file::{
+            footer::{decode_footer, decode_metadata},
+            FOOTER_SIZE,
+        },
+    },
+    physical_expr::expressions::Column,
+    physical_plan::metrics::Time,
+};
 use datafusion_ext_commons::{
     algorithm::rdx_sort::radix_sort_by_key,
     arrow::{
         array_size::BatchSize,
-        selection::{create_batch_interleaver, BatchInterleaver},
+        selection::{
+            create_batch_interleaver, create_batch_ranges_interleaver, BatchRangesInterleaver,
+        },
     },
-    compute_suggested_batch_size_for_output, df_execution_err,
+    compute_suggested_batch_size_for_output, df_execution_err, suggested_batch_mem_size,
 };
-use itertools::Itertools;
+use itertools::{Either, Itertools};
 use jni::objects::GlobalRef;
+use once_cell::sync::OnceCell;
 #[cfg(test)]
 use parking_lot::Mutex;
 
@@ -38,54 +56,160 @@ use crate::{
         offsetted::{Offsetted, OffsettedMergeIterator},
         timer_helper::TimerHelper,
     },
+    memmgr::spill::{DefaultSpillSerializer, SpillSerializer},
     shuffle::{
-        evaluate_hashes, evaluate_partition_ids, evaluate_range_partition_ids,
-        evaluate_robin_partition_ids, rss::RssWriter, Partitioning,
+        cancellation::CancellationToken, coalesced_partition_count, combiner::ShuffleCombiner,
+        evaluate_expr_partition_ids, evaluate_hash_partition_ids, evaluate_range_partition_ids,
+        evaluate_robin_partition_ids, remap_partition_ids, rss::RssWriter,
+        segment_format::SegmentFormat, NullKeysPartitioning, PartitionRowCounter, Partitioning,
     },
 };
 
+// ratio of serialized spill size to in-memory size of batches, accounting
+// for block headers and a little padding saved by serialization
+const SPILL_SIZE_OVERHEAD_FACTOR: f64 = 1.05;
+
 pub struct BufferedData {
     partition_id: usize,
     partitioning: Partitioning,
+    partition_id_mapping: Option<Arc<[u32]>>,
+    num_output_partitions: usize,
     staging_batches: Vec<RecordBatch>,
     staging_num_rows: usize,
+    num_batches: usize,
+    max_buffered_batches: Option<usize>,
+    batch_stats: bool,
     staging_mem_used: usize,
     sorted_batches: Vec<RecordBatch>,
     sorted_offsets: Vec<Vec<u32>>,
     num_rows: usize,
     sorted_mem_used: usize,
     output_io_time: Time,
+    stable_order: bool,
+    spill_format: SpillFormat,
+    null_keys: NullKeysPartitioning,
+    sub_batch_mem_size: Option<usize>,
+    combiner: Option<Arc<dyn ShuffleCombiner>>,
+    serializer: Arc<dyn SpillSerializer>,
+    cancellation: CancellationToken,
+    partition_rows: PartitionRowCounter,
+}
+
+/// format of partition segments in spills and shuffle data files, see
+/// [`SegmentFormat`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpillFormat {
+    /// compressed ipc blocks, read with `IpcCompressionReader`
+    Ipc,
+    /// each partition segment is a standalone parquet file (or several
+    /// concatenated files after merging), read with [`read_parquet_segment`].
+    /// the layout is private to blaze: neither data files nor merged segments
+    /// are valid parquet files for other readers.
+    Parquet,
 }
 
 impl BufferedData {
     pub fn new(partitioning: Partitioning, partition_id: usize, output_io_time: Time) -> Self {
         Self {
             partition_id,
+            num_output_partitions: partitioning.partition_count(),
             partitioning,
+            partition_id_mapping: None,
             staging_batches: vec![],
             staging_num_rows: 0,
+            num_batches: 0,
+            max_buffered_batches: shuffle_max_buffered_batches(),
+            batch_stats: shuffle_batch_stats_enabled(),
             staging_mem_used: 0,
             sorted_batches: vec![],
             sorted_offsets: vec![],
             num_rows: 0,
             sorted_mem_used: 0,
             output_io_time,
+            stable_order: shuffle_stable_order_enabled(),
+            spill_format: shuffle_spill_format(),
+            null_keys: shuffle_null_keys_partitioning(),
+            sub_batch_mem_size: shuffle_sub_batch_mem_size(),
+            combiner: None,
+            serializer: Arc::new(DefaultSpillSerializer),
+            cancellation: CancellationToken::default(),
+            partition_rows: PartitionRowCounter::default(),
         }
     }
 
     pub fn drain(&mut self) -> Self {
-        std::mem::replace(
-            self,
-            Self::new(
-                self.partitioning.clone(),
-                self.partition_id,
-                self.output_io_time.clone(),
-            ),
-        )
+        let mut drained = Self::new(
+            self.partitioning.clone(),
+            self.partition_id,
+            self.output_io_time.clone(),
+        );
+        drained.stable_order = self.stable_order;
+        drained.spill_format = self.spill_format;
+        drained.null_keys = self.null_keys;
+        drained.sub_batch_mem_size = self.sub_batch_mem_size;
+        drained.max_buffered_batches = self.max_buffered_batches;
+        drained.batch_stats = self.batch_stats;
+        drained.combiner = self.combiner.clone();
+        drained.serializer = self.serializer.clone();
+        drained.cancellation = self.cancellation.clone();
+        drained.partition_rows = self.partition_rows.clone();
+        drained.partition_id_mapping = self.partition_id_mapping.clone();
+        drained.num_output_partitions = self.num_output_partitions;
+        std::mem::replace(self, drained)
+    }
+
+    pub fn partitioning(&self) -> &Partitioning {
+        &self.partitioning
+    }
+
+    /// remaps evaluated partition ids with the given mapping, output data is
+    /// written with the remapped partitions
+    pub fn set_partition_id_mapping(&mut self, partition_id_mapping: Arc<[u32]>) {
+        self.num_output_partitions = coalesced_partition_count(&partition_id_mapping);
+        self.partition_id_mapping = Some(partition_id_mapping);
+    }
+
+    /// combines rows with equal keys of each partition before writing
+    pub fn set_combiner(&mut self, combiner: Arc<dyn ShuffleCombiner>) {
+        self.combiner = Some(combiner);
+    }
+
+    /// keeps rows of each partition in insertion order, see
+    /// [`sort_batches_by_partition_id`]
+    pub fn set_stable_order(&mut self, stable_order: bool) {
+        self.stable_order = stable_order;
+    }
+
+    /// serializes batches of ipc segments with the given serializer
+    pub fn set_serializer(&mut self, serializer: Arc<dyn SpillSerializer>) {
+        self.serializer = serializer;
+    }
+
+    /// counts rows of each partition when written
+    pub fn set_partition_rows(&mut self, partition_rows: PartitionRowCounter) {
+        self.partition_rows = partition_rows;
+    }
+
+    /// stops writing at the next partition once cancelled
+    pub fn set_cancellation(&mut self, cancellation: CancellationToken) {
+        self.cancellation = cancellation;
+    }
+
+    /// caps the number of buffered batches, see [`Self::is_full`]
+    pub fn set_max_buffered_batches(&mut self, max_buffered_batches: Option<usize>) {
+        self.max_buffered_batches = max_buffered_batches;
+    }
+
+    /// returns true if the number of buffered batches reaches the cap, the
+    /// data should be spilled regardless of memory usage
+    pub fn is_full(&self) -> bool {
+        self.max_buffered_batches
+            .is_some_and(|max_buffered_batches| self.num_batches >= max_buffered_batches)
     }
 
     pub fn add_batch(&mut self, batch: RecordBatch) -> Result<()> {
         // first add to staging, mem used is doubled for later sorting
+        self.num_batches += 1;
         self.num_rows += batch.num_rows();
         self.staging_num_rows += batch.num_rows();
         self.staging_mem_used += batch.get_batch_mem_size() * 2;
@@ -105,8 +229,11 @@ impl BufferedData {
         let (offsets, sorted_batch) = sort_batches_by_partition_id(
             staging_batches,
             &self.partitioning,
+            self.partition_id_mapping.as_deref(),
             sorted_num_rows,
             self.partition_id,
+            self.stable_order,
+            self.null_keys,
         )?;
         self.staging_num_rows = 0;
         self.staging_mem_used = 0;
@@ -117,11 +244,11 @@ impl BufferedData {
         Ok(())
     }
 
-    // write buffered data to spill/target file, returns uncompressed size and
-    // offsets to each partition
-    pub fn write<W: Write>(mut self, mut w: W) -> Result<Vec<u64>> {
+    // write buffered data to spill/target file, returns offsets to each
+    // partition
+    pub fn write<W: Write + Send>(mut self, w: W) -> Result<Vec<u64>> {
         if self.num_rows == 0 {
-            return Ok(vec![0; self.partitioning.partition_count() + 1]);
+            return Ok(vec![0; self.num_output_partitions + 1]);
         }
 
         let mem_used = ByteSize(self.mem_used() as u64);
@@ -132,24 +259,27 @@ impl BufferedData {
         }
 
         let output_io_time = self.output_io_time.clone();
-        let num_partitions = self.partitioning.partition_count();
-        let mut writer = IpcCompressionWriter::new(CountWrite::from(&mut w));
+        let num_partitions = self.num_output_partitions;
+        let format = SegmentFormat::new(self.spill_format, self.sorted_batches[0].schema())
+            .with_serializer(self.serializer.clone());
+        let mut writer = format.writer(w, self.stat_columns());
         let mut offsets = vec![];
+        let combiner = self.combiner.clone();
+        let chunk_mem_size = suggested_batch_mem_size();
+        let cancellation = self.cancellation.clone();
+        let partition_rows = self.partition_rows.clone();
         let mut iter = self.into_sorted_batches()?;
 
         while let Some((partition_id, batch_iter)) = iter.next_partition_chunk() {
-            if !is_task_running() {
-                df_execution_err!("task completed/killed")?;
-            }
+            cancellation.check()?;
 
-            offsets.resize(partition_id + 1, writer.inner().count());
-            for batch in batch_iter {
-                output_io_time
-                    .with_timer(|| writer.write_batch(batch.num_rows(), batch.columns()))?;
-            }
-            output_io_time.with_timer(|| writer.finish_current_buf())?;
+            offsets.resize(partition_id + 1, writer.count());
+            let batch_iter =
+                combine_partition_batches(combiner.as_ref(), batch_iter, chunk_mem_size)?
+                    .inspect(|batch| partition_rows.add(partition_id, batch.num_rows()));
+            writer.write_segment(batch_iter, &output_io_time)?;
         }
-        offsets.resize(num_partitions + 1, writer.inner().count());
+        offsets.resize(num_partitions + 1, writer.count());
 
         let compressed_size = ByteSize(offsets.last().cloned().unwrap_or_default());
         log::info!("all buffered data drained, compressed_size={compressed_size}");
@@ -170,17 +300,22 @@ impl BufferedData {
         }
 
         let output_io_time = self.output_io_time.clone();
+        let combiner = self.combiner.clone();
+        let chunk_mem_size = suggested_batch_mem_size();
+        let mut writer = IpcCompressionWriter::new(RssWriter::new(rss_partition_writer.clone(), 0))
+            .with_stat_columns(self.stat_columns())
+            .with_serializer(self.serializer.clone());
+        let cancellation = self.cancellation.clone();
+        let partition_rows = self.partition_rows.clone();
         let mut iter = self.into_sorted_batches()?;
-        let mut writer = IpcCompressionWriter::new(RssWriter::new(rss_partition_writer.clone(), 0));
 
         while let Some((partition_id, batch_iter)) = iter.next_partition_chunk() {
-            if !is_task_running() {
-                df_execution_err!("task completed/killed")?;
-            }
+            cancellation.check()?;
 
             // write all batches with this part id
             writer.set_output(RssWriter::new(rss_partition_writer.clone(), partition_id));
-            for batch in batch_iter {
+            for batch in combine_partition_batches(combiner.as_ref(), batch_iter, chunk_mem_size)? {
+                partition_rows.add(partition_id, batch.num_rows());
                 output_io_time
                     .with_timer(|| writer.write_batch(batch.num_rows(), batch.columns()))?;
             }
@@ -194,14 +329,27 @@ impl BufferedData {
         Ok(())
     }
 
+    // hash partitioning keys referring to input columns, which are most likely
+    // join keys filtered by the reduce side
+    fn stat_columns(&self) -> Vec<usize> {
+        match &self.partitioning {
+            Partitioning::HashPartitioning(exprs, ..) if self.batch_stats => exprs
+                .iter()
+                .filter_map(|expr| Some(expr.as_any().downcast_ref::<Column>()?.index()))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
     fn into_sorted_batches(self) -> Result<PartitionedBatchesIterator<'static>> {
         let num_rows = self.num_rows;
         let sub_batch_size = compute_suggested_batch_size_for_output(self.mem_used(), num_rows);
-        let num_partitions = self.partitioning.partition_count();
+        let num_partitions = self.num_output_partitions;
         PartitionedBatchesIterator::try_new(
             self.sorted_batches,
             self.sorted_offsets,
             sub_batch_size,
+            self.sub_batch_mem_size,
             num_partitions,
         )
     }
@@ -210,15 +358,36 @@ impl BufferedData {
         self.sorted_mem_used + self.staging_mem_used
     }
 
+    pub fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    /// estimates the number of bytes written if the buffered data is spilled
+    /// now, without actually serializing it
+    pub fn estimated_spill_size(&self) -> usize {
+        // staging mem used is doubled for sorting, and sorted mem used
+        // includes partition offsets, neither is written into spills
+        let data_size = self.staging_mem_used / 2
+            + self
+                .sorted_batches
+                .iter()
+                .map(|batch| batch.get_batch_mem_size())
+                .sum::<usize>();
+        (data_size as f64 * SPILL_SIZE_OVERHEAD_FACTOR) as usize
+    }
+
     pub fn is_empty(&self) -> bool {
         self.sorted_batches.is_empty() && self.staging_batches.is_empty()
     }
 }
 
 struct PartitionedBatchesIterator<'a> {
-    batch_interleaver: BatchInterleaver,
+    batch_interleaver: BatchRangesInterleaver,
     merge_iter: OffsettedMergeIterator<'a, u32, usize>,
     batch_size: usize,
+    batch_mem_size: Option<usize>,
+    row_mem_sizes: Vec<usize>,
+    remaining_range: Option<(usize, Range<usize>)>,
     last_chunk_partition_id: Option<usize>,
 }
 
@@ -227,10 +396,16 @@ impl<'a> PartitionedBatchesIterator<'a> {
         batches: Vec<RecordBatch>,
         batch_offsets: Vec<Vec<u32>>,
         sub_batch_size: usize,
+        sub_batch_mem_size: Option<usize>,
         num_partitions: usize,
     ) -> Result<Self> {
+        // rows of a sorted batch are estimated to have the same size
+        let row_mem_sizes = batches
+            .iter()
+            .map(|batch| batch.get_batch_mem_size() / batch.num_rows().max(1))
+            .collect();
         Ok(Self {
-            batch_interleaver: create_batch_interleaver(&batches, true)?,
+            batch_interleaver: create_batch_ranges_interleaver(&batches, true)?,
             merge_iter: OffsettedMergeIterator::new(
                 num_partitions,
                 batch_offsets
@@ -240,6 +415,9 @@ impl<'a> PartitionedBatchesIterator<'a> {
                     .collect(),
             ),
             batch_size: sub_batch_size,
+            batch_mem_size: sub_batch_mem_size,
+            row_mem_sizes,
+            remaining_range: None,
             last_chunk_partition_id: None,
         })
     }
@@ -261,32 +439,285 @@ impl<'a> PartitionedBatchesIterator<'a> {
         batches_iter.last_chunk_partition_id = Some(chunk_partition_id);
 
         let batch_iter = chunk.batching(|chunk| {
-            let mut indices = vec![];
-            for (batch_idx, range) in chunk {
-                indices.extend(range.map(|offset| (*batch_idx, offset as usize)));
-                if indices.len() >= batches_iter.batch_size {
-                    break;
+            let mut ranges = vec![];
+            let mut num_rows = 0;
+            let mut mem_size = 0;
+            while num_rows < batches_iter.batch_size {
+                let (batch_idx, mut range) = match batches_iter.remaining_range.take() {
+                    Some(remaining_range) => remaining_range,
+                    None => match chunk.next() {
+                        Some((&mut batch_idx, range)) => {
+                            (batch_idx, range.start as usize..range.end as usize)
+                        }
+                        None => break,
+                    },
+                };
+                if range.is_empty() {
+                    continue;
+                }
+
+                // cap estimated bytes of the sub-batch, the remaining rows of
+                // the range go to the next sub-batch. at least one row is taken
+                if let Some(batch_mem_size) = batches_iter.batch_mem_size {
+                    let row_mem_size = batches_iter.row_mem_sizes[batch_idx].max(1);
+                    let max_rows = (batch_mem_size.saturating_sub(mem_size) / row_mem_size)
+                        .max(usize::from(num_rows == 0));
+                    if range.len() > max_rows {
+                        batches_iter.remaining_range =
+                            Some((batch_idx, range.start + max_rows..range.end));
+                        range.end = range.start + max_rows;
+                    }
+                    mem_size += range.len() * row_mem_size;
+                    if range.is_empty() {
+                        break;
+                    }
                 }
+                num_rows += range.len();
+                ranges.push((batch_idx, range));
             }
 
-            if indices.is_empty() {
+            if ranges.is_empty() {
                 return None;
             }
             let batch_interleaver = &mut batches_iter.batch_interleaver;
-            let output_batch = batch_interleaver(&indices).expect("error interleaving batches");
+            let output_batch = batch_interleaver(&ranges).expect("error interleaving batches");
             return Some(output_batch);
         });
         Some((chunk_partition_id, batch_iter))
     }
 }
 
-fn sort_batches_by_partition_id(
+// combines all batches of a partition if a combiner is set. combined rows are
+// written in sub-batches no larger than the input ones. batches are combined
+// in chunks of about `chunk_mem_size` bytes together with rows combined so
+// far, which is valid because combining is associative, so the partition is
+// never held in memory as a whole.
+fn combine_partition_batches(
+    combiner: Option<&Arc<dyn ShuffleCombiner>>,
+    batch_iter: impl Iterator<Item = RecordBatch>,
+    chunk_mem_size: usize,
+) -> Result<impl Iterator<Item = RecordBatch>> {
+    let Some(combiner) = combiner else {
+        return Ok(Either::Left(batch_iter));
+    };
+    let mut combined: Option<RecordBatch> = None;
+    let mut chunk = vec![];
+    let mut chunk_size = 0;
+    let mut sub_batch_size = 1;
+    let mut batch_iter = batch_iter.peekable();
+    while let Some(batch) = batch_iter.next() {
+        sub_batch_size = sub_batch_size.max(batch.num_rows());
+        chunk_size += batch.get_batch_mem_size();
+        chunk.push(batch);
+        if chunk_size >= chunk_mem_size || batch_iter.peek().is_none() {
+            let inputs = combined.take().into_iter().chain(chunk.drain(..)).collect();
+            combined = Some(combiner.combine(inputs)?);
+            chunk_size = 0;
+        }
+    }
+    let Some(combined) = combined else {
+        return Ok(Either::Right(Either::Left(std::iter::empty())));
+    };
+    let num_rows = combined.num_rows();
+    Ok(Either::Right(Either::Right(
+        (0..num_rows)
+            .step_by(sub_batch_size.max(1))
+            .map(move |offset| combined.slice(offset, sub_batch_size.min(num_rows - offset))),
+    )))
+}
+
+/// reads a partition segment written in [`SpillFormat::Parquet`]
+pub fn read_parquet_segment(segment: Bytes) -> Result<Vec<RecordBatch>> {
+    read_parquet_segment_with_projection(segment, None)
+}
+
+/// reads a partition segment written in [`SpillFormat::Parquet`], only the
+/// column chunks of projected columns are decoded
+pub fn read_parquet_segment_with_projection(
+    segment: Bytes,
+    projection: Option<&[usize]>,
+) -> Result<Vec<RecordBatch>> {
+    let mut batches = vec![];
+    for file in split_parquet_files(segment)? {
+        let mut builder = ParquetRecordBatchReaderBuilder::try_new(file)?;
+        if let Some(projection) = projection {
+            let mask = ProjectionMask::roots(builder.parquet_schema(), projection.iter().cloned());
+            builder = builder.with_projection(mask);
+        }
+        for batch in builder.build()? {
+            batches.push(batch?);
+        }
+    }
+    Ok(batches)
+}
+
+// merged partition segments are concatenated parquet files, one for each
+// spill, with no index of the files. split them from the end, using the
+// footer of each file to find where the file starts.
+fn split_parquet_files(segment: Bytes) -> Result<Vec<Bytes>> {
+    let mut files = vec![];
+    let mut end = segment.len();
+
+    while end > 0 {
+        if end < FOOTER_SIZE + 4 {
+            df_execution_err!("invalid parquet segment: truncated")?;
+        }
+        let footer: [u8; FOOTER_SIZE] = segment[end - FOOTER_SIZE..end].try_into().unwrap();
+        let metadata_len = decode_footer(&footer)?;
+        let metadata_start = match (end - FOOTER_SIZE).checked_sub(metadata_len) {
+            Some(metadata_start) => metadata_start,
+            None => df_execution_err!("invalid parquet segment: bad metadata length")?,
+        };
+        let metadata = decode_metadata(&segment[metadata_start..end - FOOTER_SIZE])?;
+
+        // metadata is written right after column chunks and page indexes
+        let mut content_len = 4; // leading magic
+        for column in metadata.row_groups().iter().flat_map(|rg| rg.columns()) {
+            let (start, len) = column.byte_range();
+            content_len = content_len.max(start + len);
+            if let (Some(offset), Some(len)) =
+                (column.column_index_offset(), column.column_index_length())
+            {
+                content_len = content_len.max(offset as u64 + len as u64);
+            }
+            if let (Some(offset), Some(len)) =
+                (column.offset_index_offset(), column.offset_index_length())
+            {
+                content_len = content_len.max(offset as u64 + len as u64);
+            }
+        }
+        let start = match metadata_start.checked_sub(content_len as usize) {
+            Some(start) if &segment[start..start + 4] == b"PAR1" => start,
+            _ => df_execution_err!("invalid parquet segment: file start not found")?,
+        };
+        files.push(segment.slice(start..end));
+        end = start;
+    }
+    files.reverse();
+    Ok(files)
+}
+
+/// reads a partition segment written in the configured spill format
+pub fn read_segment(segment: Bytes, schema: &SchemaRef) -> Result<Vec<RecordBatch>> {
+    SegmentFormat::new(shuffle_spill_format(), schema.clone()).read_segment(segment)
+}
+
+pub(crate) fn shuffle_spill_format() -> SpillFormat {
+    static FORMAT: OnceCell<SpillFormat> = OnceCell::new();
+    *FORMAT.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            match conf::SHUFFLE_SPILL_FORMAT.value().as_deref() {
+                Ok("parquet") => SpillFormat::Parquet,
+                _ => SpillFormat::Ipc,
+            }
+        } else {
+            SpillFormat::Ipc // for testing
+        }
+    })
+}
+
+pub(crate) fn shuffle_stable_order_enabled() -> bool {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    *ENABLED.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_STABLE_ORDER_ENABLE.value().unwrap_or(false)
+        } else {
+            false // for testing
+        }
+    })
+}
+
+fn shuffle_sub_batch_mem_size() -> Option<usize> {
+    static MEM_SIZE: OnceCell<Option<usize>> = OnceCell::new();
+    *MEM_SIZE.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_SUB_BATCH_MEM_SIZE
+                .value()
+                .ok()
+                .and_then(|mem_size| usize::try_from(mem_size).ok())
+                .filter(|&mem_size| mem_size > 0)
+        } else {
+            None // for testing
+        }
+    })
+}
+
+pub(crate) fn shuffle_batch_stats_enabled() -> bool {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    *ENABLED.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_BATCH_STATS_ENABLE.value().unwrap_or(false)
+        } else {
+            false // for testing
+        }
+    })
+}
+
+fn shuffle_max_buffered_batches() -> Option<usize> {
+    static MAX_BATCHES: OnceCell<Option<usize>> = OnceCell::new();
+    *MAX_BATCHES.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_MAX_BUFFERED_BATCHES
+                .value()
+                .ok()
+                .and_then(|max_batches| usize::try_from(max_batches).ok())
+                .filter(|&max_batches| max_batches > 0)
+        } else {
+            Some(100000) // for testing
+        }
+    })
+}
+
+pub(crate) fn shuffle_null_keys_partitioning() -> NullKeysPartitioning {
+    static NULL_KEYS: OnceCell<NullKeysPartitioning> = OnceCell::new();
+    *NULL_KEYS.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            let default = NullKeysPartitioning::default();
+            NullKeysPartitioning {
+                null_hash_seed: conf::SHUFFLE_NULL_KEYS_HASH_SEED
+                    .value()
+                    .unwrap_or(default.null_hash_seed),
+                null_partition_id: conf::SHUFFLE_NULL_KEYS_PARTITION
+                    .value()
+                    .ok()
+                    .and_then(|partition_id| u32::try_from(partition_id).ok()),
+            }
+        } else {
+            NullKeysPartitioning::default() // for testing
+        }
+    })
+}
+
+// sort rows by partition id. with stable_order, rows in the same partition
+// keep their original (batch_idx, row_idx) order, which makes output bytes
+// reproducible at the cost of a slower comparison sort.
+pub(crate) fn sort_batches_by_partition_id(
     batches: Vec<RecordBatch>,
     partitioning: &Partitioning,
+    partition_id_mapping: Option<&[u32]>,
     current_num_rows: usize,
     partition_id: usize,
+    stable_order: bool,
+    null_keys: NullKeysPartitioning,
 ) -> Result<(Vec<u32>, RecordBatch)> {
-    let num_partitions = partitioning.partition_count();
+    let num_partitions = partition_id_mapping
+        .map(coalesced_partition_count)
+        .unwrap_or(partitioning.partition_count());
+
+    // fast path: all rows go to the only partition, keep rows in original order
+    if num_partitions == 1 {
+        let num_rows = batches.iter().map(|batch| batch.num_rows()).sum::<usize>();
+        let batches_interleaver = create_batch_interleaver(&batches, true)?;
+        let sorted_batch = batches_interleaver(
+            &batches
+                .iter()
+                .enumerate()
+                .flat_map(|(batch_idx, batch)| (0..batch.num_rows()).map(move |i| (batch_idx, i)))
+                .collect::<Vec<_>>(),
+        )?;
+        return Ok((vec![0, num_rows as u32], sorted_batch));
+    }
+
     let mut round_robin_start_rows =
         (partition_id * 1000193 + current_num_rows) % partitioning.partition_count();
 
@@ -294,13 +725,11 @@ fn sort_batches_by_partition_id(
     let mut partition_indices = batches
         .iter()
         .enumerate()
-        .flat_map(|(batch_idx, batch)| {
-            let part_ids = match partitioning {
+        .map(|(batch_idx, batch)| {
+            let mut part_ids = match partitioning {
                 Partitioning::HashPartitioning(..) => {
-                    // compute partition indices
-                    let hashes = evaluate_hashes(partitioning, &batch)
-                        .expect(&format!("error evaluating hashes with {partitioning}"));
-                    evaluate_partition_ids(hashes, partitioning.partition_count())
+                    evaluate_hash_partition_ids(partitioning, &batch, null_keys)
+                        .expect(&format!("error evaluating hashes with {partitioning}"))
                 }
                 Partitioning::RoundRobinPartitioning(..) => {
                     let part_ids =
@@ -312,22 +741,38 @@ fn sort_batches_by_partition_id(
                 Partitioning::RangePartitioning(sort_expr, _, bounds) => {
                     evaluate_range_partition_ids(&batch, sort_expr, bounds).unwrap()
                 }
+                Partitioning::PartitionIdPartitioning(expr, num_partitions) => {
+                    evaluate_expr_partition_ids(expr, *num_partitions, batch)?
+                }
                 _ => unreachable!("unsupported partitioning: {:?}", partitioning),
             };
-            part_ids
+            if let Some(partition_id_mapping) = partition_id_mapping {
+                remap_partition_ids(&mut part_ids, partition_id_mapping);
+            }
+            Ok(part_ids
                 .into_iter()
                 .enumerate()
-                .map(move |(row_idx, part_id)| (part_id, batch_idx as u32, row_idx as u32))
+                .map(move |(row_idx, part_id)| (part_id, batch_idx as u32, row_idx as u32)))
         })
-        .collect::<Vec<_>>();
+        .flatten_ok()
+        .collect::<Result<Vec<_>>>()?;
 
     // sort
     let mut part_counts = vec![0; num_partitions];
-    radix_sort_by_key(
-        &mut partition_indices,
-        &mut part_counts,
-        |&(part_id, ..)| part_id as usize,
-    );
+    if stable_order {
+        // (part_id, batch_idx, row_idx) is unique, so unstable sort is
+        // deterministic
+        partition_indices.sort_unstable();
+        partition_indices
+            .iter()
+            .for_each(|&(part_id, ..)| part_counts[part_id as usize] += 1);
+    } else {
+        radix_sort_by_key(
+            &mut partition_indices,
+            &mut part_counts,
+            |&(part_id, ..)| part_id as usize,
+        );
+    }
 
     // compute partitions
     let mut partition_offsets = Vec::with_capacity(num_partitions + 1);
@@ -351,11 +796,16 @@ fn sort_batches_by_partition_id(
 
 #[cfg(test)]
 mod test {
-    use std::sync::Arc;
+    use std::{io::Cursor, sync::Arc};
 
     use arrow::{
-        array::{ArrayRef, Int32Array},
-        datatypes::{DataType, Field, Schema},
+        array::{
+            ArrayRef, AsArray, BinaryArray, BooleanArray, Float64Array, Int32Array, StringArray,
+            StructArray,
+        },
+        buffer::NullBuffer,
+        compute::concat_batches,
+        datatypes::{DataType, Field, Fields, Int32Type, Schema},
         record_batch::RecordBatch,
         row::{RowConverter, Rows, SortField},
     };
@@ -363,10 +813,18 @@ mod test {
     use datafusion::{
         assert_batches_eq,
         common::Result,
-        physical_expr::{expressions::Column, PhysicalSortExpr},
+        physical_expr::{expressions::Column, PhysicalExpr, PhysicalSortExpr},
     };
+    use datafusion_ext_commons::{io::recover_named_batch, spark_hash::create_murmur3_hashes};
 
     use super::*;
+    use crate::{
+        common::ipc_compression::{BatchStatsPredicate, IpcCompressionReader},
+        shuffle::{
+            combiner::SumByKeyCombiner, evaluate_hashes, evaluate_partition_ids, HashAlgorithm,
+            PartitionIdAssignment, NUM_EVALUATE_HASHES,
+        },
+    };
 
     fn build_table_i32(
         a: (&str, &Vec<i32>),
@@ -399,8 +857,15 @@ mod test {
         );
 
         let round_robin_partitioning = Partitioning::RoundRobinPartitioning(4);
-        let (_parts, sorted_batch) =
-            sort_batches_by_partition_id(vec![record_batch], &round_robin_partitioning, 3, 0)?;
+        let (_parts, sorted_batch) = sort_batches_by_partition_id(
+            vec![record_batch],
+            &round_robin_partitioning,
+            None,
+            3,
+            0,
+            false,
+            NullKeysPartitioning::default(),
+        )?;
 
         let expected = vec![
             "+----+---+---+",
@@ -453,8 +918,15 @@ mod test {
 
         let range_repartitioning =
             Partitioning::RangePartitioning(sort_exprs, partition_num, Arc::from(rows));
-        let (_parts, sorted_batch) =
-            sort_batches_by_partition_id(vec![record_batch], &range_repartitioning, 0, 0)?;
+        let (_parts, sorted_batch) = sort_batches_by_partition_id(
+            vec![record_batch],
+            &range_repartitioning,
+            None,
+            0,
+            0,
+            false,
+            NullKeysPartitioning::default(),
+        )?;
 
         let expected = vec![
             "+----+---+---+",
@@ -515,8 +987,15 @@ mod test {
 
         let range_repartitioning =
             Partitioning::RangePartitioning(sort_exprs, partition_num, Arc::from(rows));
-        let (_parts, sorted_batch) =
-            sort_batches_by_partition_id(vec![record_batch], &range_repartitioning, 0, 0)?;
+        let (_parts, sorted_batch) = sort_batches_by_partition_id(
+            vec![record_batch],
+            &range_repartitioning,
+            None,
+            0,
+            0,
+            false,
+            NullKeysPartitioning::default(),
+        )?;
 
         let expected = vec![
             "+----+---+---+",
@@ -537,4 +1016,735 @@ mod test {
         assert_batches_eq!(expected, &vec![sorted_batch]);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_stable_order() -> Result<()> {
+        let batches = (0..5)
+            .map(|i| {
+                let a = (0..1000).map(|j| (i * 1000 + j) % 13).collect::<Vec<_>>();
+                let b = (0..1000).map(|j| i * 1000 + j).collect::<Vec<_>>();
+                let c = (0..1000).map(|j| j % 7).collect::<Vec<_>>();
+                build_table_i32(("a", &a), ("b", &b), ("c", &c))
+            })
+            .collect::<Vec<_>>();
+        let hash_partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0))],
+            4,
+            HashAlgorithm::default(),
+        );
+
+        // column b identifies the (batch_idx, row_idx) of each row, returns
+        // whether rows of every partition are in insertion order
+        let in_insertion_order = |stable_order: bool| -> Result<bool> {
+            let (parts, sorted_batch) = sort_batches_by_partition_id(
+                batches.clone(),
+                &hash_partitioning,
+                None,
+                0,
+                0,
+                stable_order,
+                NullKeysPartitioning::default(),
+            )?;
+            let sorted_b = sorted_batch.column(1).as_primitive::<Int32Type>();
+            Ok(parts.iter().tuple_windows().all(|(&beg, &end)| {
+                sorted_b.values()[beg as usize..end as usize]
+                    .iter()
+                    .map(|&b| (b / 1000, b % 1000))
+                    .tuple_windows()
+                    .all(|(prev, cur)| prev < cur)
+            }))
+        };
+
+        // rows in each partition keep their insertion order only with
+        // stable_order, the default radix sort swaps rows in place
+        assert!(in_insertion_order(true)?);
+        assert!(!in_insertion_order(false)?);
+
+        // output data files are identical across runs
+        let write_data_file = || -> Result<Vec<u8>> {
+            let mut data = BufferedData::new(hash_partitioning.clone(), 0, Time::new());
+            data.stable_order = true;
+            for batch in &batches {
+                data.add_batch(batch.clone())?;
+            }
+            let mut data_file = vec![];
+            data.write(&mut data_file)?;
+            Ok(data_file)
+        };
+        assert_eq!(write_data_file()?, write_data_file()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sub_batch_mem_size() -> Result<()> {
+        // 1000 wide rows of about 1KB each
+        let batch = RecordBatch::try_from_iter([
+            (
+                "a",
+                Arc::new(Int32Array::from_iter_values(0..1000)) as ArrayRef,
+            ),
+            (
+                "b",
+                Arc::new(BinaryArray::from_iter_values(
+                    (0..1000).map(|i| vec![(i % 251) as u8; 1024]),
+                )),
+            ),
+        ])?;
+        let schema = batch.schema();
+        let sub_batch_mem_size = 65536;
+        let max_rows = sub_batch_mem_size / 1024;
+
+        let write_sub_batches = |sub_batch_mem_size: Option<usize>| -> Result<Vec<RecordBatch>> {
+            let hash_partitioning = Partitioning::HashPartitioning(
+                vec![Arc::new(Column::new("a", 0))],
+                3,
+                HashAlgorithm::default(),
+            );
+            let mut data = BufferedData::new(hash_partitioning, 0, Time::new());
+            data.sub_batch_mem_size = sub_batch_mem_size;
+            data.add_batch(batch.clone())?;
+            let mut data_file = vec![];
+            let offsets = data.write(&mut data_file)?;
+
+            let mut sub_batches = vec![];
+            for (&beg, &end) in offsets.iter().tuple_windows() {
+                let segment = data_file[beg as usize..end as usize].to_vec();
+                let mut reader = IpcCompressionReader::new(Cursor::new(segment));
+                while let Some((_, cols)) = reader.read_batch(&schema)? {
+                    sub_batches.push(RecordBatch::try_new(schema.clone(), cols)?);
+                }
+            }
+            Ok(sub_batches)
+        };
+        let sorted_values = |batches: &[RecordBatch]| -> Result<Vec<i32>> {
+            let a = concat_batches(&schema, batches)?.column(0).clone();
+            let mut values = a.as_primitive::<Int32Type>().values().to_vec();
+            values.sort_unstable();
+            Ok(values)
+        };
+
+        // without the byte cap, sub-batches are only capped by rows
+        let sub_batches = write_sub_batches(None)?;
+        assert!(sub_batches.iter().any(|b| b.num_rows() > max_rows));
+
+        // with the byte cap, every sub-batch is within the cap
+        let capped_sub_batches = write_sub_batches(Some(sub_batch_mem_size))?;
+        assert!(capped_sub_batches.len() > sub_batches.len());
+        assert!(capped_sub_batches
+            .iter()
+            .all(|b| b.num_rows() > 0 && b.num_rows() <= max_rows));
+        assert_eq!(
+            sorted_values(&capped_sub_batches)?,
+            (0..1000).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_stats() -> Result<()> {
+        let a = (0..1000).collect::<Vec<_>>();
+        let batch = build_table_i32(("a", &a), ("b", &a), ("c", &a));
+        let schema = batch.schema();
+        let hash_partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0))],
+            4,
+            HashAlgorithm::default(),
+        );
+        let mut data = BufferedData::new(hash_partitioning, 0, Time::new());
+        data.batch_stats = true;
+        data.stable_order = true; // sub-batches cover ascending key ranges
+        data.sub_batch_mem_size = Some(1024);
+        data.add_batch(batch)?;
+        let mut data_file = vec![];
+        let offsets = data.write(&mut data_file)?;
+
+        let read_values = |predicate: Option<BatchStatsPredicate>| -> Result<(Vec<i32>, usize)> {
+            let mut values = vec![];
+            let mut num_skipped = 0;
+            for (&beg, &end) in offsets.iter().tuple_windows() {
+                let segment = data_file[beg as usize..end as usize].to_vec();
+                let mut reader = IpcCompressionReader::new(Cursor::new(segment));
+                if let Some(predicate) = predicate {
+                    reader = reader.with_predicate(predicate);
+                }
+                while let Some((_, cols)) = reader.read_batch(&schema)? {
+                    values.extend(cols[0].as_primitive::<Int32Type>().values());
+                }
+                num_skipped += reader.num_skipped_blocks();
+            }
+            values.sort_unstable();
+            Ok((values, num_skipped))
+        };
+
+        // all rows are read without predicates
+        assert_eq!(read_values(None)?, (a.clone(), 0));
+
+        // sub-batches without keys in range are skipped, matching rows are
+        // never lost
+        let (values, num_skipped) = read_values(Some(BatchStatsPredicate::new(0, 100, 199)))?;
+        assert!(num_skipped > 0);
+        assert!(values.len() < 1000);
+        assert!((100..200).all(|v| values.binary_search(&v).is_ok()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashes_evaluated_once_across_spill() -> Result<()> {
+        let batches = (0..3)
+            .map(|i| {
+                let a = (i * 1000..(i + 1) * 1000).collect::<Vec<_>>();
+                build_table_i32(("a", &a), ("b", &a), ("c", &a))
+            })
+            .collect::<Vec<_>>();
+        let hash_partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0))],
+            4,
+            HashAlgorithm::default(),
+        );
+        let num_evaluate_hashes = || NUM_EVALUATE_HASHES.with(|num| num.get());
+        let num_evaluate_hashes_before = num_evaluate_hashes();
+
+        // partition ids are evaluated once when staging batches are sorted,
+        // spilling writes the sorted rows without rehashing
+        let mut data = BufferedData::new(hash_partitioning, 0, Time::new());
+        data.add_batch(batches[0].clone())?;
+        data.add_batch(batches[1].clone())?;
+        let mut spill = vec![];
+        let spill_offsets = data.drain().write(&mut spill)?;
+        assert_eq!(spill_offsets.len(), 5);
+        assert_eq!(num_evaluate_hashes() - num_evaluate_hashes_before, 2000);
+
+        data.add_batch(batches[2].clone())?;
+        let mut output = vec![];
+        data.write(&mut output)?;
+        assert_eq!(num_evaluate_hashes() - num_evaluate_hashes_before, 3000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_single_partition_fast_path() -> Result<()> {
+        // all rows have the same key, so they are in the same partition with
+        // any partition count
+        let batches = (0..5)
+            .map(|i| {
+                let a = vec![7; 1000];
+                let b = (0..1000).map(|j| i * 1000 + j).collect::<Vec<_>>();
+                let c = (0..1000).map(|j| j % 7).collect::<Vec<_>>();
+                build_table_i32(("a", &a), ("b", &b), ("c", &c))
+            })
+            .collect::<Vec<_>>();
+
+        let write_data_file = |num_partitions: usize| -> Result<(Vec<u8>, Vec<u64>)> {
+            let hash_partitioning = Partitioning::HashPartitioning(
+                vec![Arc::new(Column::new("a", 0))],
+                num_partitions,
+                HashAlgorithm::default(),
+            );
+            let mut data = BufferedData::new(hash_partitioning, 0, Time::new());
+            for batch in &batches {
+                data.add_batch(batch.clone())?;
+            }
+            let mut data_file = vec![];
+            let offsets = data.write(&mut data_file)?;
+            Ok((data_file, offsets))
+        };
+        let num_evaluate_hashes = || NUM_EVALUATE_HASHES.with(|num| num.get());
+
+        // single partition output skips hashing
+        let num_evaluate_hashes_before = num_evaluate_hashes();
+        let (single_data, single_offsets) = write_data_file(1)?;
+        assert_eq!(num_evaluate_hashes(), num_evaluate_hashes_before);
+        assert_eq!(single_offsets, vec![0, single_data.len() as u64]);
+
+        // same bytes as the only non-empty partition of a general run
+        let (data, offsets) = write_data_file(2)?;
+        assert!(num_evaluate_hashes() > num_evaluate_hashes_before);
+        let partitions = offsets
+            .iter()
+            .tuple_windows()
+            .map(|(&beg, &end)| &data[beg as usize..end as usize])
+            .filter(|partition| !partition.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(partitions, vec![&single_data[..]]);
+
+        // rows are kept in original order
+        let schema = batches[0].schema();
+        let mut reader = IpcCompressionReader::new(Cursor::new(single_data));
+        let mut b = vec![];
+        while let Some((_, cols)) = reader.read_batch(&schema)? {
+            let col = cols[1].as_any().downcast_ref::<Int32Array>().unwrap();
+            b.extend(col.values().iter().cloned());
+        }
+        assert_eq!(b, (0..5000).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parquet_spill_format() -> Result<()> {
+        let a: ArrayRef = Arc::new(Int32Array::from_iter(
+            (0..1000).map(|i| Some(i).filter(|i| i % 11 != 0)),
+        ));
+        let b: ArrayRef = Arc::new(StringArray::from_iter_values(
+            (0..1000).map(|i| format!("s{i}")),
+        ));
+        let c: ArrayRef = Arc::new(Float64Array::from_iter_values(
+            (0..1000).map(|i| i as f64 / 3.0),
+        ));
+        let d: ArrayRef = Arc::new(BooleanArray::from_iter((0..1000).map(|i| Some(i % 3 == 0))));
+        let batch = RecordBatch::try_from_iter(vec![("a", a), ("b", b), ("c", c), ("d", d)])?;
+        let schema = batch.schema();
+        let hash_partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0))],
+            4,
+            HashAlgorithm::default(),
+        );
+
+        let write_data_file = |spill_format: SpillFormat| -> Result<(Vec<u8>, Vec<u64>)> {
+            let mut data = BufferedData::new(hash_partitioning.clone(), 0, Time::new());
+            data.spill_format = spill_format;
+            data.add_batch(batch.clone())?;
+            let mut data_file = vec![];
+            let offsets = data.write(&mut data_file)?;
+            Ok((data_file, offsets))
+        };
+        let (ipc_data, ipc_offsets) = write_data_file(SpillFormat::Ipc)?;
+        let (parquet_data, parquet_offsets) = write_data_file(SpillFormat::Parquet)?;
+        let parquet_data = Bytes::from(parquet_data);
+
+        // each partition segment is readable alone and contains the same rows
+        let mut num_rows = 0;
+        for ((ipc_beg, ipc_end), (parquet_beg, parquet_end)) in ipc_offsets
+            .into_iter()
+            .tuple_windows()
+            .zip(parquet_offsets.into_iter().tuple_windows())
+        {
+            let mut ipc_reader = IpcCompressionReader::new(Cursor::new(
+                ipc_data[ipc_beg as usize..ipc_end as usize].to_vec(),
+            ));
+            let mut ipc_batches = vec![];
+            while let Some((num_rows, cols)) = ipc_reader.read_batch(&schema)? {
+                ipc_batches.push(recover_named_batch(num_rows, &cols, schema.clone())?);
+            }
+            let parquet_batches = read_parquet_segment(
+                parquet_data.slice(parquet_beg as usize..parquet_end as usize),
+            )?;
+            assert_eq!(
+                concat_batches(&schema, &parquet_batches)?,
+                concat_batches(&schema, &ipc_batches)?,
+            );
+            num_rows += parquet_batches.iter().map(|b| b.num_rows()).sum::<usize>();
+        }
+        assert_eq!(num_rows, 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_single_row() -> Result<()> {
+        // a single 8MB row between two small rows. with round robin over 2
+        // partitions the huge row is the only row of its partition
+        let huge_value = (0..8 << 20)
+            .map(|i| (i * 31 % 251) as u8)
+            .collect::<Vec<_>>();
+        let batch = RecordBatch::try_from_iter([
+            ("a", Arc::new(Int32Array::from(vec![1, 2, 3])) as ArrayRef),
+            (
+                "b",
+                Arc::new(BinaryArray::from_iter_values([
+                    &b"small"[..],
+                    &huge_value,
+                    &b"small"[..],
+                ])),
+            ),
+        ])?;
+        let schema = batch.schema();
+
+        let mut data = BufferedData::new(Partitioning::RoundRobinPartitioning(2), 0, Time::new());
+        data.add_batch(batch)?;
+        let mut data_file = vec![];
+        let offsets = data.write(&mut data_file)?;
+        assert_eq!(offsets.len(), 3);
+        assert!(offsets.iter().tuple_windows().all(|(beg, end)| beg <= end));
+        assert_eq!(offsets.last().cloned(), Some(data_file.len() as u64));
+
+        let mut num_huge_rows = 0;
+        for (&beg, &end) in offsets.iter().tuple_windows() {
+            let segment = &data_file[beg as usize..end as usize];
+            let mut reader = IpcCompressionReader::new(Cursor::new(segment.to_vec()));
+            let mut batches = vec![];
+            while let Some((num_rows, cols)) = reader.read_batch(&schema)? {
+                batches.push(RecordBatch::try_new(schema.clone(), cols)?);
+                assert!(num_rows > 0);
+            }
+            let values = batches
+                .iter()
+                .flat_map(|batch| {
+                    let b = batch.column(1).as_binary::<i32>();
+                    b.iter().map(|v| v.unwrap().to_vec()).collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+
+            // the huge row is written as its own sub-batch
+            if values.contains(&huge_value) {
+                assert_eq!(batches.len(), 1);
+                assert_eq!(batches[0].num_rows(), 1);
+                num_huge_rows += 1;
+            } else {
+                assert_eq!(values, vec![b"small".to_vec(); 2]);
+            }
+        }
+        assert_eq!(num_huge_rows, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parquet_spill_format_projection() -> Result<()> {
+        let cols: Vec<(String, ArrayRef)> = (0..10)
+            .map(|i| {
+                let col: ArrayRef = Arc::new(Int32Array::from_iter(
+                    (0..1000).map(|j| Some(j * 10 + i).filter(|v| v % 7 != 0)),
+                ));
+                (format!("c{i}"), col)
+            })
+            .collect();
+        let batch = RecordBatch::try_from_iter(cols)?;
+        let schema = batch.schema();
+        let hash_partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("c0", 0))],
+            4,
+            HashAlgorithm::default(),
+        );
+
+        // write two spills and merge them by copying partition segments
+        let mut spills = vec![];
+        for spill_batch in [batch.slice(0, 600), batch.slice(600, 400)] {
+            let mut data = BufferedData::new(hash_partitioning.clone(), 0, Time::new());
+            data.spill_format = SpillFormat::Parquet;
+            data.add_batch(spill_batch)?;
+            let mut spill = vec![];
+            let offsets = data.write(&mut spill)?;
+            spills.push((Bytes::from(spill), offsets));
+        }
+        let merged_segments = (0..4)
+            .map(|partition_id| {
+                let segments = spills
+                    .iter()
+                    .map(|(spill, offsets)| {
+                        spill.slice(
+                            offsets[partition_id] as usize..offsets[partition_id + 1] as usize,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                (Bytes::from(segments.concat()), segments)
+            })
+            .collect::<Vec<_>>();
+
+        // read back only 2 of 10 columns
+        let projection = [3, 8];
+        let projected_schema = Arc::new(schema.project(&projection)?);
+        let mut num_rows = 0;
+        for (merged_segment, segments) in merged_segments {
+            let mut expected = vec![];
+            for segment in segments {
+                for batch in read_parquet_segment(segment)? {
+                    expected.push(batch.project(&projection)?);
+                }
+            }
+            let projected =
+                read_parquet_segment_with_projection(merged_segment, Some(&projection))?;
+            assert!(projected.iter().all(|b| b.num_columns() == 2));
+            assert_eq!(
+                concat_batches(&projected_schema, &projected)?,
+                concat_batches(&projected_schema, &expected)?,
+            );
+            num_rows += projected.iter().map(|b| b.num_rows()).sum::<usize>();
+        }
+        assert_eq!(num_rows, 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_null_keys_partitioning() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let build_batch = |a: Vec<Option<i32>>| {
+            let b = Int32Array::from((0..a.len() as i32).collect::<Vec<_>>());
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int32Array::from(a)), Arc::new(b)],
+            )
+        };
+        let hash_partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0))],
+            8,
+            HashAlgorithm::default(),
+        );
+        let null_keys = |null_hash_seed, null_partition_id| NullKeysPartitioning {
+            null_hash_seed,
+            null_partition_id,
+        };
+
+        // null rows are hashed to the configured seed, or routed to the null
+        // partition
+        let null_batch = build_batch(vec![None; 100])?;
+        for (null_keys, expected_partition_id) in [
+            (NullKeysPartitioning::default(), 2), // pmod(42, 8)
+            (null_keys(7, None), 7),
+            (null_keys(42, Some(5)), 5),
+            (null_keys(7, Some(0)), 0),
+        ] {
+            let part_ids = evaluate_hash_partition_ids(&hash_partitioning, &null_batch, null_keys)?;
+            assert_eq!(part_ids, vec![expected_partition_id; 100], "{null_keys:?}");
+        }
+        let out_of_range = null_keys(42, Some(8));
+        assert!(
+            evaluate_hash_partition_ids(&hash_partitioning, &null_batch, out_of_range).is_err()
+        );
+
+        // other rows are hash partitioned into the remaining partitions
+        let a = (0..1000)
+            .map(|i| Some(i).filter(|i| i % 3 != 0))
+            .collect::<Vec<_>>();
+        let batch = build_batch(a.clone())?;
+        let part_ids =
+            evaluate_hash_partition_ids(&hash_partitioning, &batch, null_keys(42, Some(5)))?;
+        let hashes = create_murmur3_hashes(1000, &[batch.column(0).clone()], 42);
+        for (i, &part_id) in part_ids.iter().enumerate() {
+            let expected_part_id = match a[i] {
+                None => 5,
+                Some(_) => match hashes[i].rem_euclid(7) as u32 {
+                    part_id if part_id >= 5 => part_id + 1,
+                    part_id => part_id,
+                },
+            };
+            assert_eq!(part_id, expected_part_id, "row {i}");
+        }
+
+        // null rows are written into the null partition
+        let mut data = BufferedData::new(hash_partitioning, 0, Time::new());
+        data.null_keys = null_keys(42, Some(5));
+        data.add_batch(null_batch)?;
+        let mut data_file = vec![];
+        let offsets = data.write(&mut data_file)?;
+        let non_empty_partitions = offsets
+            .iter()
+            .tuple_windows()
+            .positions(|(beg, end)| beg < end)
+            .collect::<Vec<_>>();
+        assert_eq!(non_empty_partitions, vec![5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_struct_keys_partitioning() -> Result<()> {
+        let fields = Fields::from(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Utf8, true),
+        ]);
+        let s = StructArray::new(
+            fields.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![
+                    Some(1),
+                    Some(2),
+                    Some(0),
+                    None,
+                    Some(-1),
+                ])),
+                Arc::new(StringArray::from(vec![
+                    Some("a"),
+                    None,
+                    Some("x"),
+                    Some("bc"),
+                    Some("hello"),
+                ])),
+            ],
+            Some(NullBuffer::from(vec![true, true, false, true, true])),
+        );
+        let c = Int32Array::from(vec![10, 20, 30, 40, 50]);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("s", DataType::Struct(fields), true),
+            Field::new("c", DataType::Int32, false),
+        ]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(s), Arc::new(c)])?;
+        let hash_partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("s", 0)), Arc::new(Column::new("c", 1))],
+            8,
+            HashAlgorithm::default(),
+        );
+
+        // generated with pmod(Murmur3Hash(Seq(s, c), 42).eval(), 8) in Spark
+        let part_ids = evaluate_hash_partition_ids(
+            &hash_partitioning,
+            &batch,
+            NullKeysPartitioning::default(),
+        )?;
+        assert_eq!(part_ids, vec![3, 3, 5, 4, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hive_hash_partitioning() -> Result<()> {
+        let a = Int32Array::from(vec![Some(1), Some(-7), None, Some(123456), Some(0)]);
+        let s = StringArray::from(vec![
+            Some("hello"),
+            Some("abc"),
+            Some("apache spark"),
+            None,
+            Some(""),
+        ]);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("s", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(a), Arc::new(s)])?;
+        let hash_partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0)), Arc::new(Column::new("s", 1))],
+            8,
+            HashAlgorithm::HiveHash,
+        );
+
+        // same as hive bucket ids: (HiveHash(Seq(a, s)) & Int.MaxValue) % 8
+        let part_ids = evaluate_hash_partition_ids(
+            &hash_partitioning,
+            &batch,
+            NullKeysPartitioning::default(),
+        )?;
+        assert_eq!(part_ids, vec![1, 1, 3, 0, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_id_assignment_uniformity() -> Result<()> {
+        // hive hashes of integers are the integers themselves, so keys sharing
+        // low bits are all assigned to the same partition by pmod
+        let num_rows = 10000;
+        let keys: ArrayRef = Arc::new(Int32Array::from_iter_values((0..num_rows).map(|i| i * 64)));
+        let hashes = evaluate_hashes(&[keys], num_rows as usize, HashAlgorithm::HiveHash)?;
+        let partition_counts = |num_partitions: usize, assignment| {
+            let mut counts = vec![0; num_partitions];
+            for part_id in evaluate_partition_ids(hashes.clone(), num_partitions, assignment) {
+                counts[part_id as usize] += 1;
+            }
+            counts
+        };
+
+        let pmod_counts = partition_counts(64, PartitionIdAssignment::Pmod);
+        assert_eq!(pmod_counts[0], num_rows);
+        assert_eq!(pmod_counts.iter().filter(|&&count| count > 0).count(), 1);
+
+        // about 156 rows per partition
+        let fibonacci_counts = partition_counts(64, PartitionIdAssignment::Fibonacci);
+        let (min, max) = (
+            fibonacci_counts.iter().min().cloned().unwrap_or_default(),
+            fibonacci_counts.iter().max().cloned().unwrap_or_default(),
+        );
+        assert!(min >= 140 && max <= 170, "{fibonacci_counts:?}");
+
+        // ids are in range for any partition count, also for negative hashes
+        let hashes = vec![i32::MIN, -1, 0, 1, i32::MAX];
+        for num_partitions in [1, 7, 200] {
+            let part_ids = evaluate_partition_ids(
+                hashes.clone(),
+                num_partitions,
+                PartitionIdAssignment::Fibonacci,
+            );
+            assert!(part_ids.iter().all(|&id| (id as usize) < num_partitions));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_id_partitioning() -> Result<()> {
+        // partition ids precomputed by a custom partitioner
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("pid", DataType::Int32, true),
+            Field::new("v", DataType::Int32, false),
+        ]));
+        let batch = |pids: Vec<Option<i32>>| {
+            let values = Int32Array::from_iter_values(0..pids.len() as i32);
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int32Array::from(pids)), Arc::new(values)],
+            )
+        };
+        let partitioning =
+            Partitioning::PartitionIdPartitioning(Arc::new(Column::new("pid", 0)), 4);
+
+        let (offsets, sorted_batch) = sort_batches_by_partition_id(
+            vec![batch(vec![Some(3), Some(0), Some(2), Some(0), Some(3)])?],
+            &partitioning,
+            None,
+            0,
+            0,
+            true,
+            NullKeysPartitioning::default(),
+        )?;
+        assert_eq!(offsets, vec![0, 2, 2, 3, 5]);
+        let values = sorted_batch
+            .column(1)
+            .as_primitive::<Int32Type>()
+            .values()
+            .to_vec();
+        assert_eq!(values, vec![1, 3, 2, 0, 4]);
+
+        // out-of-range and null ids are reported with their row index
+        let pid_expr: Arc<dyn PhysicalExpr> = Arc::new(Column::new("pid", 0));
+        let err =
+            evaluate_expr_partition_ids(&pid_expr, 4, &batch(vec![Some(1), Some(4)])?).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("partition id 4 out of range [0, 4) at row 1"));
+        let err = evaluate_expr_partition_ids(&pid_expr, 4, &batch(vec![Some(-1)])?).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("partition id -1 out of range [0, 4) at row 0"));
+        let err =
+            evaluate_expr_partition_ids(&pid_expr, 4, &batch(vec![Some(0), None])?).unwrap_err();
+        assert!(err.to_string().contains("null partition id at row 1"));
+        Ok(())
+    }
+
+    // records the number of input rows of each combining
+    struct RecordingCombiner(SumByKeyCombiner, Mutex<Vec<usize>>);
+
+    impl ShuffleCombiner for RecordingCombiner {
+        fn combine(&self, batches: Vec<RecordBatch>) -> Result<RecordBatch> {
+            let num_rows = batches.iter().map(|batch| batch.num_rows()).sum();
+            self.1.lock().push(num_rows);
+            self.0.combine(batches)
+        }
+    }
+
+    #[test]
+    fn test_combine_partition_batches_in_chunks() -> Result<()> {
+        let batches = (0..10)
+            .map(|i| {
+                build_table_i32(
+                    ("a", &(0..100).map(|j| j % 10).collect()),
+                    ("b", &vec![i; 100]),
+                    ("c", &vec![1; 100]),
+                )
+            })
+            .collect::<Vec<_>>();
+        let schema = batches[0].schema();
+        let recording = Arc::new(RecordingCombiner(
+            SumByKeyCombiner::try_new(&schema, vec![0], vec![1, 2])?,
+            Mutex::default(),
+        ));
+        let combiner: Arc<dyn ShuffleCombiner> = recording.clone();
+
+        // every 3 batches are combined with 10 rows combined so far
+        let chunk_mem_size = batches[0].get_batch_mem_size() * 3;
+        let batch_iter = batches.clone().into_iter();
+        let combined = combine_partition_batches(Some(&combiner), batch_iter, chunk_mem_size)?
+            .collect::<Vec<_>>();
+        assert_eq!(*recording.1.lock(), vec![300, 310, 310, 110]);
+
+        // same result as combining the whole partition at once
+        let expected = recording.0.combine(batches)?;
+        assert_eq!(concat_batches(&schema, &combined)?, expected);
+        Ok(())
+    }
 }
@@ -0,0 +1,439 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::File,
+    io::{Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use datafusion::{
+    common::Result,
+    error::DataFusionError,
+    physical_plan::metrics::{Count, Time},
+};
+use datafusion_ext_commons::df_execution_err;
+use itertools::Itertools;
+use parking_lot::Mutex as SyncMutex;
+use tokio::sync::Mutex;
+
+use crate::{
+    common::{
+        error::BlazeError,
+        execution_context::ExecutionContext,
+        ipc_compression::IpcCompressionWriter,
+        timer_helper::{TimedWriter, TimerHelper},
+    },
+    memmgr::spill::spill_max_disk_bytes,
+    shuffle::{
+        buffered_data::{
+            shuffle_null_keys_partitioning, shuffle_stable_order_enabled,
+            sort_batches_by_partition_id,
+        },
+        cancellation::CancellationToken,
+        output_io::{remove_partial_output_files, RetryPolicy, ShuffleOutputWrite},
+        sort_repartitioner::{encode_index, shuffle_index_format},
+        PartitionRowCounter, Partitioning, ShuffleOutputStats, ShuffleRepartitioner,
+    },
+};
+
+// compressed blocks of each partition are finished at this size, so that
+// memory is bounded by number of partitions while blocks are still large
+// enough to compress well
+const PARTITION_BUF_SIZE: usize = 262144;
+
+type PartitionWriter = IpcCompressionWriter<TimedWriter<File>>;
+
+/// writes each output partition into its own temporary file as batches come
+/// in, then concatenates them into the output file, like spark's
+/// BypassMergeSortShuffleWriter. nothing is buffered or sorted, so it is only
+/// suitable for a small number of output partitions.
+pub struct BypassMergeShuffleRepartitioner {
+    exec_ctx: Arc<ExecutionContext>,
+    output_data_file: String,
+    output_index_file: String,
+    partitioning: Partitioning,
+    // taken while writing, None if a previous write failed
+    partition_writers: Mutex<Option<PartitionWriters>>,
+    output_io_time: Time,
+    max_spill_disk_bytes: Option<u64>,
+    cancellation: CancellationToken,
+    input_rows: Count,
+    partition_rows: PartitionRowCounter,
+    output_stats: SyncMutex<Option<ShuffleOutputStats>>,
+}
+
+struct PartitionWriters {
+    writers: Vec<Option<PartitionWriter>>,
+    num_rows: usize,
+}
+
+impl PartitionWriters {
+    /// returns number of bytes written into temporary files
+    fn disk_usage(&self) -> Result<u64> {
+        let mut disk_usage = 0;
+        for writer in self.writers.iter().flatten() {
+            disk_usage += (&writer.inner().0).stream_position()?;
+        }
+        Ok(disk_usage)
+    }
+}
+
+impl BypassMergeShuffleRepartitioner {
+    pub fn new(
+        exec_ctx: Arc<ExecutionContext>,
+        output_data_file: String,
+        output_index_file: String,
+        partitioning: Partitioning,
+        output_io_time: Time,
+    ) -> Self {
+        let num_partitions = partitioning.partition_count();
+        let input_rows = exec_ctx.register_counter_metric("input_rows");
+        Self {
+            exec_ctx,
+            output_data_file,
+            output_index_file,
+            partitioning,
+            partition_writers: Mutex::new(Some(PartitionWriters {
+                writers: (0..num_partitions).map(|_| None).collect(),
+                num_rows: 0,
+            })),
+            output_io_time,
+            max_spill_disk_bytes: spill_max_disk_bytes(),
+            cancellation: CancellationToken::default(),
+            input_rows,
+            partition_rows: PartitionRowCounter::default(),
+            output_stats: SyncMutex::default(),
+        }
+    }
+
+    /// fails once temporary partition files use more disk than
+    /// `max_spill_disk_bytes`. None for no limit
+    pub fn with_max_spill_disk_bytes(mut self, max_spill_disk_bytes: Option<u64>) -> Self {
+        self.max_spill_disk_bytes = max_spill_disk_bytes;
+        self
+    }
+
+    /// shares the cancellation token with the caller, which may cancel writing
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+}
+
+/// temporary files are placed next to the output file, so they are in spark
+/// local dirs and removed once dropped
+fn create_partition_writer(dir: &Path, output_io_time: &Time) -> Result<PartitionWriter> {
+    let file = tempfile::tempfile_in(dir)?;
+    Ok(IpcCompressionWriter::new(output_io_time.wrap_writer(file))
+        .with_target_buf_size(PARTITION_BUF_SIZE))
+}
+
+#[async_trait]
+impl ShuffleRepartitioner for BypassMergeShuffleRepartitioner {
+    async fn insert_batch(&self, input: RecordBatch) -> Result<()> {
+        self.cancellation.check()?;
+        let num_rows = input.num_rows();
+        self.input_rows.add(num_rows);
+
+        // partitioning and writing run on blocking threads, writers are
+        // moved out of the lock meanwhile
+        let mut partition_writers_locked = self.partition_writers.lock().await;
+        let Some(mut partition_writers) = partition_writers_locked.take() else {
+            return df_execution_err!("shuffle writer failed in a previous write");
+        };
+        let partitioning = self.partitioning.clone();
+        let partition_id = self.exec_ctx.partition_id();
+        let partition_rows = self.partition_rows.clone();
+        let dir = Path::new(&self.output_data_file)
+            .parent()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."));
+        let output_io_time = self.output_io_time.clone();
+        let (partition_writers, disk_usage) = tokio::task::spawn_blocking(move || {
+            let (offsets, sorted_batch) = sort_batches_by_partition_id(
+                vec![input],
+                &partitioning,
+                None,
+                partition_writers.num_rows,
+                partition_id,
+                shuffle_stable_order_enabled(),
+                shuffle_null_keys_partitioning(),
+            )?;
+            partition_writers.num_rows += num_rows;
+
+            for (partition_id, (&beg, &end)) in offsets.iter().tuple_windows().enumerate() {
+                if beg == end {
+                    continue;
+                }
+                let writer = match &mut partition_writers.writers[partition_id] {
+                    Some(writer) => writer,
+                    writer => writer.insert(create_partition_writer(&dir, &output_io_time)?),
+                };
+                let partition_batch = sorted_batch.slice(beg as usize, (end - beg) as usize);
+                writer.write_batch(partition_batch.num_rows(), partition_batch.columns())?;
+                partition_rows.add(partition_id, partition_batch.num_rows());
+            }
+            let disk_usage = partition_writers.disk_usage()?;
+            Ok::<_, DataFusionError>((partition_writers, disk_usage))
+        })
+        .await
+        .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+        *partition_writers_locked = Some(partition_writers);
+        drop(partition_writers_locked);
+
+        if let Some(max_disk_bytes) = self.max_spill_disk_bytes {
+            if disk_usage > max_disk_bytes {
+                return Err(BlazeError::SpillDiskExhausted {
+                    consumer: "BypassMergeShuffleRepartitioner".to_string(),
+                    used: disk_usage,
+                    max: max_disk_bytes,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+
+    async fn shuffle_write(&self) -> Result<()> {
+        self.cancellation.check()?;
+        let Some(PartitionWriters { writers, .. }) = self.partition_writers.lock().await.take()
+        else {
+            return df_execution_err!("shuffle writer failed in a previous write");
+        };
+        let data_file = self.output_data_file.clone();
+        let index_file = self.output_index_file.clone();
+        let output_io_time = self.output_io_time.clone();
+        let cancellation = self.cancellation.clone();
+
+        let offsets = tokio::task::spawn_blocking(move || {
+            let _output_io_timer = output_io_time.timer();
+            let write = || {
+                let retry_policy = RetryPolicy::default();
+                let mut output_data = ShuffleOutputWrite::create(&data_file, retry_policy)?;
+                let mut output_index = ShuffleOutputWrite::create(&index_file, retry_policy)?;
+
+                // concatenate partition files into the data file
+                let mut offsets = Vec::with_capacity(writers.len() + 1);
+                let mut offset = 0;
+                for writer in writers {
+                    cancellation.check()?;
+                    offsets.push(offset);
+                    if let Some(mut writer) = writer {
+                        // exclude io timer because it is already included
+                        output_io_time.exclude_timer(|| writer.finish_segment())?;
+                        let partition_file = &mut writer.inner_mut().0;
+                        partition_file.rewind()?;
+                        offset += std::io::copy(partition_file, &mut output_data)?;
+                    }
+                }
+                offsets.push(offset);
+                output_data.flush()?;
+
+                // write index file
+                output_index.write_all(&encode_index(&offsets, shuffle_index_format())?)?;
+                output_index.flush()?;
+                Ok::<_, DataFusionError>(offsets)
+            };
+            write().inspect_err(|_| remove_partial_output_files(&[&data_file, &index_file]))
+        })
+        .await
+        .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+
+        let num_partitions = offsets.len() - 1;
+        *self.output_stats.lock() =
+            Some(ShuffleOutputStats::from_offsets(&offsets).with_row_counts(
+                self.input_rows.value() as u64,
+                self.partition_rows.partition_rows(num_partitions),
+            ));
+        Ok(())
+    }
+
+    fn output_stats(&self) -> Option<ShuffleOutputStats> {
+        self.output_stats.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{AsArray, Int32Array},
+        datatypes::{DataType, Field, Int32Type, Schema},
+        record_batch::RecordBatch,
+    };
+    use bytes::Bytes;
+    use datafusion::{
+        common::Result,
+        physical_expr::expressions::Column,
+        physical_plan::metrics::{ExecutionPlanMetricsSet, Time},
+        prelude::SessionContext,
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        common::execution_context::ExecutionContext,
+        memmgr::MemManager,
+        shuffle::{
+            buffered_data::read_segment,
+            bypass_repartitioner::BypassMergeShuffleRepartitioner,
+            cancellation::CancellationToken,
+            sort_repartitioner::{read_index_file, SortShuffleRepartitioner},
+            HashAlgorithm, Partitioning, ShuffleRepartitioner,
+        },
+    };
+
+    // returns sorted values of column "a" of each output partition
+    async fn shuffle_values(
+        repartitioner: Arc<dyn ShuffleRepartitioner>,
+        batches: Vec<RecordBatch>,
+        data_file: &str,
+        index_file: &str,
+    ) -> Result<Vec<Vec<i32>>> {
+        let schema = batches[0].schema();
+        for batch in batches {
+            repartitioner.insert_batch(batch).await?;
+        }
+        repartitioner.shuffle_write().await?;
+
+        let data = Bytes::from(std::fs::read(data_file)?);
+        let offsets = read_index_file(index_file)?;
+        let mut partitions = vec![];
+        for (&beg, &end) in offsets.iter().zip(&offsets[1..]) {
+            let segment = data.slice(beg as usize..end as usize);
+            let mut values = read_segment(segment, &schema)?
+                .iter()
+                .flat_map(|batch| {
+                    let col = batch.column(0).as_primitive::<Int32Type>();
+                    col.values().to_vec()
+                })
+                .collect::<Vec<_>>();
+            values.sort_unstable();
+            partitions.push(values);
+        }
+        Ok(partitions)
+    }
+
+    #[tokio::test]
+    async fn test_bypass_merge_matches_sort() -> Result<()> {
+        MemManager::init(1000000);
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batches = (0..5)
+            .map(|i| {
+                let values = (i * 100..i * 100 + 100).collect::<Vec<i32>>();
+                RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))])
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let partitioning = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0))],
+            8,
+            HashAlgorithm::default(),
+        );
+
+        let session_ctx = SessionContext::new();
+        let metrics = ExecutionPlanMetricsSet::new();
+        let exec_ctx = ExecutionContext::new(session_ctx.task_ctx(), 0, schema, &metrics);
+        let output_dir = tempfile::tempdir()?;
+        let path = |name: &str| output_dir.path().join(name).to_string_lossy().to_string();
+
+        let bypass = Arc::new(BypassMergeShuffleRepartitioner::new(
+            exec_ctx.clone(),
+            path("bypass.data"),
+            path("bypass.index"),
+            partitioning.clone(),
+            Time::new(),
+        ));
+        let bypass_partitions = shuffle_values(
+            bypass.clone(),
+            batches.clone(),
+            &path("bypass.data"),
+            &path("bypass.index"),
+        )
+        .await?;
+
+        let sort = Arc::new(SortShuffleRepartitioner::new(
+            exec_ctx.clone(),
+            path("sort.data"),
+            path("sort.index"),
+            partitioning,
+            Time::new(),
+        ));
+        MemManager::register_consumer(sort.clone(), true)?;
+        let sort_partitions =
+            shuffle_values(sort, batches, &path("sort.data"), &path("sort.index")).await?;
+
+        assert_eq!(bypass_partitions.len(), 8);
+        assert_eq!(bypass_partitions.concat().len(), 500);
+        assert_eq!(bypass_partitions, sort_partitions);
+
+        // only output files are left
+        assert_eq!(std::fs::read_dir(output_dir.path())?.count(), 4);
+        let output_stats = bypass.output_stats().expect("output stats");
+        assert_eq!(output_stats.partition_bytes.len(), 8);
+        assert_eq!(output_stats.input_rows, Some(500));
+        assert_eq!(output_stats.written_rows(), 500);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bypass_merge_cancel_and_disk_cap() -> Result<()> {
+        // random values are not compressible, so that blocks are written into
+        // temporary files
+        let mut rng = StdRng::seed_from_u64(0);
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let values = (0..1 << 19).map(|_| rng.gen()).collect::<Vec<i32>>();
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))])?;
+        let session_ctx = SessionContext::new();
+        let metrics = ExecutionPlanMetricsSet::new();
+        let exec_ctx = ExecutionContext::new(session_ctx.task_ctx(), 0, schema, &metrics);
+        let output_dir = tempfile::tempdir()?;
+        let path = |name: &str| output_dir.path().join(name).to_string_lossy().to_string();
+        let new_repartitioner = || {
+            BypassMergeShuffleRepartitioner::new(
+                exec_ctx.clone(),
+                path("data"),
+                path("index"),
+                Partitioning::RoundRobinPartitioning(4),
+                Time::new(),
+            )
+        };
+
+        // temporary files count as spills for the disk cap
+        let repartitioner = new_repartitioner().with_max_spill_disk_bytes(Some(100));
+        let err = repartitioner.insert_batch(batch.clone()).await.unwrap_err();
+        assert!(
+            err.to_string().contains("BLAZE_SPILL_DISK_EXHAUSTED"),
+            "{err}"
+        );
+
+        let cancellation = CancellationToken::default();
+        let repartitioner = new_repartitioner().with_cancellation(cancellation.clone());
+        repartitioner.insert_batch(batch.clone()).await?;
+        repartitioner.cancel();
+        assert!(cancellation.is_cancelled());
+        let err = repartitioner.insert_batch(batch).await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+        let err = repartitioner.shuffle_write().await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+        Ok(())
+    }
+}
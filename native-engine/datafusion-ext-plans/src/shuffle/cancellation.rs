@@ -0,0 +1,93 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use blaze_jni_bridge::is_task_running;
+use datafusion::common::Result;
+use parking_lot::Mutex;
+
+use crate::common::error::BlazeError;
+
+/// spark task state is polled through jni, at most once per interval
+const TASK_RUNNING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// cancellation of shuffle writing, shared by clones. a token is cancelled
+/// explicitly with [`Self::cancel`], or once spark reports the task is no
+/// longer running (e.g. a killed speculative attempt). writers check it at
+/// batch and partition boundaries so that cancelled tasks stop promptly.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    last_polled: Arc<Mutex<Option<Instant>>>,
+}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(SeqCst) {
+            return true;
+        }
+        if self.should_poll_task_running() && !is_task_running() {
+            self.cancel();
+            return true;
+        }
+        false
+    }
+
+    /// returns an error if cancelled
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(BlazeError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    fn should_poll_task_running(&self) -> bool {
+        let mut last_polled = self.last_polled.lock();
+        if last_polled.is_some_and(|polled| polled.elapsed() < TASK_RUNNING_POLL_INTERVAL) {
+            return false;
+        }
+        *last_polled = Some(Instant::now());
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::shuffle::cancellation::CancellationToken;
+
+    #[test]
+    fn test_poll_task_running_rate_limited() {
+        let cancellation = CancellationToken::default();
+        assert!(cancellation.should_poll_task_running());
+        assert!(!cancellation.should_poll_task_running());
+
+        // clones share the poll state
+        assert!(!cancellation.clone().should_poll_task_running());
+        assert!(!cancellation.is_cancelled());
+        cancellation.clone().cancel();
+        assert!(cancellation.is_cancelled());
+        assert!(cancellation.check().is_err());
+    }
+}
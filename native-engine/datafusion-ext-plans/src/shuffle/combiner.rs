@@ -0,0 +1,152 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashMap, sync::Arc};
+
+use arrow::{
+    array::{
+        downcast_primitive_array, Array, ArrayRef, ArrowNativeTypeOp, ArrowPrimitiveType,
+        BooleanBufferBuilder, PrimitiveArray, UInt32Array,
+    },
+    buffer::NullBuffer,
+    compute::{concat_batches, take},
+    datatypes::SchemaRef,
+    record_batch::{RecordBatch, RecordBatchOptions},
+    row::{RowConverter, SortField},
+};
+use datafusion::common::Result;
+use datafusion_ext_commons::df_execution_err;
+
+/// combines rows with equal keys of a shuffle partition before they are
+/// written (map-side combine). rows with equal keys always go to the same
+/// partition. combining must be associative and keep the schema, so that the
+/// reduce side gets the same result as without combining.
+pub trait ShuffleCombiner: Send + Sync {
+    fn combine(&self, batches: Vec<RecordBatch>) -> Result<RecordBatch>;
+}
+
+/// sums value columns grouped by key columns, every column must be either a
+/// key or a value column.
+pub struct SumByKeyCombiner {
+    key_columns: Vec<usize>,
+    sum_columns: Vec<usize>,
+    key_sort_fields: Vec<SortField>,
+}
+
+impl SumByKeyCombiner {
+    pub fn try_new(
+        schema: &SchemaRef,
+        key_columns: Vec<usize>,
+        sum_columns: Vec<usize>,
+    ) -> Result<Self> {
+        for i in 0..schema.fields().len() {
+            if key_columns.contains(&i) == sum_columns.contains(&i) {
+                return df_execution_err!(
+                    "SumByKeyCombiner: column {i} must be either a key or a sum column"
+                );
+            }
+        }
+        for &i in &sum_columns {
+            let data_type = schema.field(i).data_type();
+            if !data_type.is_primitive() {
+                return df_execution_err!("SumByKeyCombiner: unsupported sum type: {data_type}");
+            }
+        }
+        let key_sort_fields = key_columns
+            .iter()
+            .map(|&i| SortField::new(schema.field(i).data_type().clone()))
+            .collect();
+        Ok(Self {
+            key_columns,
+            sum_columns,
+            key_sort_fields,
+        })
+    }
+}
+
+impl ShuffleCombiner for SumByKeyCombiner {
+    fn combine(&self, batches: Vec<RecordBatch>) -> Result<RecordBatch> {
+        let batch = match batches.len() {
+            0 => return df_execution_err!("SumByKeyCombiner: no input batches"),
+            1 => batches.into_iter().next().unwrap(),
+            _ => concat_batches(&batches[0].schema(), &batches)?,
+        };
+
+        // assign group ids by keys, in order of first occurrence
+        let converter = RowConverter::new(self.key_sort_fields.clone())?;
+        let key_cols = self
+            .key_columns
+            .iter()
+            .map(|&i| batch.column(i).clone())
+            .collect::<Vec<_>>();
+        let rows = converter.convert_columns(&key_cols)?;
+        let mut groups = HashMap::with_capacity(batch.num_rows());
+        let mut first_row_indices = vec![];
+        let group_ids = (0..batch.num_rows())
+            .map(|i| {
+                *groups.entry(rows.row(i)).or_insert_with(|| {
+                    first_row_indices.push(i as u32);
+                    first_row_indices.len() as u32 - 1
+                })
+            })
+            .collect::<Vec<_>>();
+        let num_groups = first_row_indices.len();
+        let first_row_indices = UInt32Array::from(first_row_indices);
+
+        let cols = batch
+            .columns()
+            .iter()
+            .enumerate()
+            .map(|(i, col)| {
+                if self.sum_columns.contains(&i) {
+                    sum_by_group(col, &group_ids, num_groups)
+                } else {
+                    Ok(take(col, &first_row_indices, None)?)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RecordBatch::try_new_with_options(
+            batch.schema(),
+            cols,
+            &RecordBatchOptions::new().with_row_count(Some(num_groups)),
+        )?)
+    }
+}
+
+// sums non-null values of each group, groups with only null values get null
+fn sum_by_group(array: &ArrayRef, group_ids: &[u32], num_groups: usize) -> Result<ArrayRef> {
+    fn sum<T: ArrowPrimitiveType>(
+        array: &PrimitiveArray<T>,
+        group_ids: &[u32],
+        num_groups: usize,
+    ) -> ArrayRef {
+        let mut sums = vec![T::Native::default(); num_groups];
+        let mut valids = BooleanBufferBuilder::new(num_groups);
+        valids.append_n(num_groups, false);
+        for (i, &group_id) in group_ids.iter().enumerate() {
+            if array.is_valid(i) {
+                let group_id = group_id as usize;
+                sums[group_id] = sums[group_id].add_wrapping(array.value(i));
+                valids.set_bit(group_id, true);
+            }
+        }
+        let sums = PrimitiveArray::<T>::new(sums.into(), Some(NullBuffer::new(valids.finish())));
+        Arc::new(sums.with_data_type(array.data_type().clone()))
+    }
+
+    Ok(downcast_primitive_array! {
+        array => sum(array, group_ids, num_groups),
+        data_type => return df_execution_err!("unsupported sum type: {data_type}"),
+    })
+}
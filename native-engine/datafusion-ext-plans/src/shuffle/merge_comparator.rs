@@ -0,0 +1,235 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use arrow::{
+    array::ArrayRef,
+    datatypes::SchemaRef,
+    record_batch::RecordBatch,
+    row::{Row, RowConverter, Rows, SortField},
+};
+use datafusion::{common::Result, physical_expr::PhysicalSortExpr};
+use datafusion_ext_commons::{
+    algorithm::loser_tree::{ComparableForLoserTree, LoserTree},
+    arrow::selection::create_batch_interleaver,
+};
+
+/// orders rows when merging spills of a partition, so that partitions of
+/// sorted spills are merged into sorted output instead of being concatenated.
+/// rows are merged in the order of their keys, rows with equal keys in the
+/// order of spills.
+pub trait MergeComparator: Send + Sync {
+    fn sort_keys(&self, batch: &RecordBatch) -> Result<Rows>;
+}
+
+/// orders rows by sort expressions, e.g. the ones of range partitioning
+pub struct SortExprsComparator {
+    sort_exprs: Vec<PhysicalSortExpr>,
+    row_converter: RowConverter,
+}
+
+impl SortExprsComparator {
+    pub fn try_new(sort_exprs: Vec<PhysicalSortExpr>, schema: &SchemaRef) -> Result<Self> {
+        let sort_fields = sort_exprs
+            .iter()
+            .map(|sort_expr| {
+                Ok(SortField::new_with_options(
+                    sort_expr.expr.data_type(schema)?,
+                    sort_expr.options,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            sort_exprs,
+            row_converter: RowConverter::new(sort_fields)?,
+        })
+    }
+}
+
+impl MergeComparator for SortExprsComparator {
+    fn sort_keys(&self, batch: &RecordBatch) -> Result<Rows> {
+        let key_cols = self
+            .sort_exprs
+            .iter()
+            .map(|sort_expr| sort_expr.expr.evaluate(batch)?.into_array(batch.num_rows()))
+            .collect::<Result<Vec<ArrayRef>>>()?;
+        Ok(self.row_converter.convert_columns(&key_cols)?)
+    }
+}
+
+/// merges runs of batches, each sorted by `comparator`, into sorted batches
+/// of at most `batch_size` rows
+pub fn merge_sorted_runs(
+    runs: Vec<Vec<RecordBatch>>,
+    comparator: &dyn MergeComparator,
+    batch_size: usize,
+) -> Result<Vec<RecordBatch>> {
+    let mut staged_batches = vec![];
+    let mut cursors = vec![];
+    for (run_idx, run) in runs.into_iter().enumerate() {
+        let run = run
+            .into_iter()
+            .filter(|batch| batch.num_rows() > 0)
+            .collect::<Vec<_>>();
+        let keys = run
+            .iter()
+            .map(|batch| comparator.sort_keys(batch))
+            .collect::<Result<Vec<_>>>()?;
+        cursors.push(RunCursor {
+            run_idx,
+            first_batch_idx: staged_batches.len(),
+            keys,
+            batch_idx: 0,
+            row_idx: 0,
+        });
+        staged_batches.extend(run);
+    }
+    if staged_batches.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let batch_interleaver = create_batch_interleaver(&staged_batches, false)?;
+    let mut cursors = LoserTree::new(cursors);
+    let mut merged = vec![];
+    let mut indices = vec![];
+    while !cursors.peek().finished() {
+        let mut min_cursor = cursors.peek_mut();
+        indices.push((
+            min_cursor.first_batch_idx + min_cursor.batch_idx,
+            min_cursor.row_idx,
+        ));
+        min_cursor.advance();
+        drop(min_cursor);
+
+        if indices.len() >= batch_size.max(1) {
+            merged.push(batch_interleaver(&indices)?);
+            indices.clear();
+        }
+    }
+    if !indices.is_empty() {
+        merged.push(batch_interleaver(&indices)?);
+    }
+    Ok(merged)
+}
+
+struct RunCursor {
+    run_idx: usize,
+    first_batch_idx: usize,
+    keys: Vec<Rows>,
+    batch_idx: usize,
+    row_idx: usize,
+}
+
+impl RunCursor {
+    fn finished(&self) -> bool {
+        self.batch_idx == self.keys.len()
+    }
+
+    fn cur_row(&self) -> Row<'_> {
+        self.keys[self.batch_idx].row(self.row_idx)
+    }
+
+    fn advance(&mut self) {
+        self.row_idx += 1;
+        if self.row_idx == self.keys[self.batch_idx].num_rows() {
+            self.batch_idx += 1;
+            self.row_idx = 0;
+        }
+    }
+}
+
+impl ComparableForLoserTree for RunCursor {
+    #[inline(always)]
+    fn lt(&self, other: &Self) -> bool {
+        if self.finished() {
+            return false;
+        }
+        if other.finished() {
+            return true;
+        }
+        (self.cur_row(), self.run_idx) < (other.cur_row(), other.run_idx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::{AsArray, Int32Array},
+        datatypes::{DataType, Field, Int32Type, Schema},
+        record_batch::RecordBatch,
+    };
+    use datafusion::{
+        common::Result,
+        physical_expr::{expressions::Column, PhysicalSortExpr},
+    };
+
+    use crate::shuffle::merge_comparator::{merge_sorted_runs, SortExprsComparator};
+
+    #[test]
+    fn test_merge_sorted_runs() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("key", DataType::Int32, false),
+            Field::new("run", DataType::Int32, false),
+        ]));
+        let batch = |keys: Vec<i32>, run: i32| {
+            let runs = Int32Array::from(vec![run; keys.len()]);
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int32Array::from(keys)), Arc::new(runs)],
+            )
+        };
+        let runs = vec![
+            vec![
+                batch(vec![1, 4, 4], 0)?,
+                batch(vec![], 0)?,
+                batch(vec![9], 0)?,
+            ],
+            vec![],
+            vec![batch(vec![0, 4], 2)?, batch(vec![5, 10, 11], 2)?],
+        ];
+        let comparator = SortExprsComparator::try_new(
+            vec![PhysicalSortExpr {
+                expr: Arc::new(Column::new("key", 0)),
+                options: Default::default(),
+            }],
+            &schema,
+        )?;
+        let merged = merge_sorted_runs(runs, &comparator, 4)?;
+        assert_eq!(
+            merged
+                .iter()
+                .map(|batch| batch.num_rows())
+                .collect::<Vec<_>>(),
+            vec![4, 4, 1],
+        );
+
+        // rows with equal keys are in the order of runs
+        let values = |col: usize| {
+            merged
+                .iter()
+                .flat_map(|batch| {
+                    batch
+                        .column(col)
+                        .as_primitive::<Int32Type>()
+                        .values()
+                        .to_vec()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(0), vec![0, 1, 4, 4, 4, 5, 9, 10, 11]);
+        assert_eq!(values(1), vec![2, 0, 0, 0, 2, 2, 0, 2, 2]);
+        Ok(())
+    }
+}
@@ -21,12 +21,15 @@ use std::{
 };
 
 use arrow::{
-    array::ArrayRef,
-    error::Result as ArrowResult,
+    array::{Array, ArrayRef, AsArray},
+    buffer::BooleanBuffer,
+    datatypes::Int32Type,
+    error::{ArrowError, Result as ArrowResult},
     record_batch::RecordBatch,
     row::{Row, RowConverter, Rows, SortField},
 };
 use async_trait::async_trait;
+use blaze_jni_bridge::{conf, conf::StringConf, is_jni_bridge_inited};
 use bytesize::ByteSize;
 use datafusion::{
     common::Result,
@@ -34,24 +37,201 @@ use datafusion::{
     physical_expr::{PhysicalExpr, PhysicalSortExpr},
     physical_plan::SendableRecordBatchStream,
 };
-use datafusion_ext_commons::{arrow::array_size::BatchSize, spark_hash::create_murmur3_hashes};
+use datafusion_ext_commons::{
+    arrow::array_size::BatchSize,
+    df_execution_err,
+    spark_hash::{create_hive_hashes, create_murmur3_hashes, create_xxhash64_hashes},
+};
 use futures::StreamExt;
+use once_cell::sync::OnceCell;
 use parking_lot::Mutex as SyncMutex;
 
-use crate::common::execution_context::ExecutionContext;
+use crate::common::execution_context::{ExecutionContext, SpawnPolicy};
 
 pub mod single_repartitioner;
 pub mod sort_repartitioner;
 
+mod batch_queue;
 pub mod buffered_data;
+pub mod bypass_repartitioner;
+pub mod cancellation;
+pub mod combiner;
+pub mod merge_comparator;
+pub mod output_io;
 mod rss;
 pub mod rss_single_repartitioner;
 pub mod rss_sort_repartitioner;
+pub mod segment_format;
+pub mod writer_factory;
 
 #[async_trait]
 pub trait ShuffleRepartitioner: Send + Sync {
     async fn insert_batch(&self, input: RecordBatch) -> Result<()>;
+
+    /// inserts multiple batches at once, the final state must be the same as
+    /// calling insert_batch() on each batch.
+    async fn insert_batches(&self, inputs: Vec<RecordBatch>) -> Result<()> {
+        for input in inputs {
+            self.insert_batch(input).await?;
+        }
+        Ok(())
+    }
+
+    /// inserts all batches of the input stream, the final state must be the
+    /// same as calling insert_batch() on each batch. spills triggered by
+    /// memory pressure complete before the next batch is pulled.
+    async fn insert_stream(&self, mut input: SendableRecordBatchStream) -> Result<()> {
+        while let Some(batch) = input.next().await.transpose()? {
+            self.insert_batch(batch).await?;
+        }
+        Ok(())
+    }
+
     async fn shuffle_write(&self) -> Result<()>;
+
+    /// reserves memory before inserting a large batch, other consumers are
+    /// spilled if necessary. returns ResourcesExhausted if not satisfied.
+    async fn reserve(&self, _bytes: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// cancels writing, e.g. when the task is killed. pending and later calls
+    /// return errors at the next batch or partition boundary.
+    fn cancel(&self) {}
+
+    /// releases resources after shuffle_write(), returning teardown errors
+    /// which cannot be reported from drop.
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// returns a snapshot of current state for monitoring, must not block.
+    fn stats(&self) -> ShuffleRepartitionerStats {
+        ShuffleRepartitionerStats::default()
+    }
+
+    /// returns sizes of output partitions after shuffle_write(), None if not
+    /// written or not supported.
+    fn output_stats(&self) -> Option<ShuffleOutputStats> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShuffleRepartitionerStats {
+    pub num_buffered_rows: usize,
+    pub num_spills: usize,
+    pub buffered_bytes: usize,
+}
+
+/// per-partition byte sizes of shuffle output
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShuffleOutputStats {
+    pub partition_bytes: Vec<u64>,
+    /// number of rows inserted into the repartitioner, None if not counted
+    pub input_rows: Option<u64>,
+    /// number of rows written into each partition, empty if not counted
+    pub partition_rows: Vec<u64>,
+    /// sizes of non-empty partitions in ascending order
+    sorted_non_empty_bytes: Vec<u64>,
+}
+
+impl ShuffleOutputStats {
+    pub fn from_offsets(offsets: &[u64]) -> Self {
+        let partition_bytes = offsets
+            .windows(2)
+            .map(|w| w[1].saturating_sub(w[0]))
+            .collect::<Vec<_>>();
+        let mut sorted_non_empty_bytes = partition_bytes
+            .iter()
+            .cloned()
+            .filter(|&bytes| bytes > 0)
+            .collect::<Vec<_>>();
+        sorted_non_empty_bytes.sort_unstable();
+        Self {
+            partition_bytes,
+            sorted_non_empty_bytes,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_row_counts(mut self, input_rows: u64, partition_rows: Vec<u64>) -> Self {
+        self.input_rows = Some(input_rows);
+        self.partition_rows = partition_rows;
+        self
+    }
+
+    /// total number of written rows
+    pub fn written_rows(&self) -> u64 {
+        self.partition_rows.iter().sum()
+    }
+
+    /// median size of non-empty partitions. empty partitions are excluded so
+    /// that shuffles with few distinct keys are not reported as skewed.
+    pub fn median_bytes(&self) -> u64 {
+        self.percentile_bytes(0.5)
+    }
+
+    pub fn p99_bytes(&self) -> u64 {
+        self.percentile_bytes(0.99)
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.sorted_non_empty_bytes.last().cloned().unwrap_or(0)
+    }
+
+    /// ratio of the largest partition to the median, 0 if all partitions are
+    /// empty
+    pub fn skew_ratio(&self) -> f64 {
+        match self.median_bytes() {
+            0 => 0.0,
+            median => self.max_bytes() as f64 / median as f64,
+        }
+    }
+
+    /// returns id of the largest partition if it exceeds `warning_ratio`
+    /// times of the median, 0 to disable
+    pub fn skewed_partition_id(&self, warning_ratio: f64) -> Option<usize> {
+        if warning_ratio <= 0.0 || self.skew_ratio() <= warning_ratio {
+            return None;
+        }
+        let max_bytes = self.max_bytes();
+        self.partition_bytes
+            .iter()
+            .position(|&bytes| bytes == max_bytes)
+    }
+
+    fn percentile_bytes(&self, percentile: f64) -> u64 {
+        let sorted = &self.sorted_non_empty_bytes;
+        if sorted.is_empty() {
+            return 0;
+        }
+        let idx = ((sorted.len() - 1) as f64 * percentile).round() as usize;
+        sorted[idx]
+    }
+}
+
+/// counts rows written into each output partition, shared by clones
+#[derive(Clone, Debug, Default)]
+pub struct PartitionRowCounter {
+    partition_rows: Arc<SyncMutex<Vec<u64>>>,
+}
+
+impl PartitionRowCounter {
+    pub fn add(&self, partition_id: usize, num_rows: usize) {
+        let mut partition_rows = self.partition_rows.lock();
+        if partition_rows.len() <= partition_id {
+            partition_rows.resize(partition_id + 1, 0);
+        }
+        partition_rows[partition_id] += num_rows as u64;
+    }
+
+    /// returns number of rows of each partition
+    pub fn partition_rows(&self, num_partitions: usize) -> Vec<u64> {
+        let mut partition_rows = self.partition_rows.lock().clone();
+        partition_rows.resize(num_partitions, 0);
+        partition_rows
+    }
 }
 
 impl dyn ShuffleRepartitioner {
@@ -64,9 +244,12 @@ impl dyn ShuffleRepartitioner {
         let mut coalesced = exec_ctx.coalesce_with_default_batch_size(input);
 
         // process all input batches
+        // shuffle writing does heavy synchronous io, run it on blocking threads
         Ok(exec_ctx
             .clone()
-            .output_with_sender("Shuffle", move |_| async move {
+            .output_with_sender_builder("Shuffle")
+            .with_spawn_policy(SpawnPolicy::Blocking)
+            .build(move |_| async move {
                 let batches_num_rows = AtomicUsize::default();
                 let batches_mem_size = AtomicUsize::default();
                 while let Some(batch) = coalesced.next().await.transpose()? {
@@ -98,6 +281,9 @@ impl dyn ShuffleRepartitioner {
                 self.shuffle_write()
                     .await
                     .map_err(|err| err.context("shuffle: executing shuffle_write() error"))?;
+                self.close()
+                    .await
+                    .map_err(|err| err.context("shuffle: executing close() error"))?;
                 log::info!("finishing shuffle writing");
                 Ok::<_, DataFusionError>(())
             }))
@@ -111,11 +297,14 @@ pub enum Partitioning {
     RoundRobinPartitioning(usize),
     /// Allocate rows based on a hash of one of more expressions and the
     /// specified number of partitions
-    HashPartitioning(Vec<Arc<dyn PhysicalExpr>>, usize),
+    HashPartitioning(Vec<Arc<dyn PhysicalExpr>>, usize, HashAlgorithm),
     /// Single partitioning scheme with a known number of partitions
     SinglePartitioning(),
     /// Range partitioning
     RangePartitioning(Vec<PhysicalSortExpr>, usize, Arc<Rows>),
+    /// Allocate rows to partition ids evaluated directly by an int32
+    /// expression, like spark's partitionIdExpression of custom partitioners
+    PartitionIdPartitioning(Arc<dyn PhysicalExpr>, usize),
 }
 
 impl Partitioning {
@@ -123,7 +312,10 @@ impl Partitioning {
     pub fn partition_count(&self) -> usize {
         use Partitioning::*;
         match self {
-            RoundRobinPartitioning(n) | HashPartitioning(_, n) | RangePartitioning(_, n, _) => *n,
+            RoundRobinPartitioning(n)
+            | HashPartitioning(_, n, _)
+            | RangePartitioning(_, n, _)
+            | PartitionIdPartitioning(_, n) => *n,
             SinglePartitioning() => 1,
         }
     }
@@ -133,13 +325,17 @@ impl fmt::Display for Partitioning {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Partitioning::RoundRobinPartitioning(size) => write!(f, "RoundRobinBatch({size})"),
-            Partitioning::HashPartitioning(phy_exprs, size) => {
+            Partitioning::HashPartitioning(phy_exprs, size, hash_algorithm) => {
                 let phy_exprs_str = phy_exprs
                     .iter()
                     .map(|e| format!("{e}"))
                     .collect::<Vec<String>>()
                     .join(", ");
-                write!(f, "Hash([{phy_exprs_str}], {size})")
+                if *hash_algorithm == HashAlgorithm::default() {
+                    write!(f, "Hash([{phy_exprs_str}], {size})")
+                } else {
+                    write!(f, "Hash([{phy_exprs_str}], {size}, {hash_algorithm:?})")
+                }
             }
             Partitioning::SinglePartitioning() => {
                 write!(f, "SinglePartitioning()")
@@ -152,29 +348,205 @@ impl fmt::Display for Partitioning {
                     .join(", ");
                 write!(f, "Range([{phy_exprs_str}], {size}, {:?})", bounds)
             }
+            Partitioning::PartitionIdPartitioning(expr, size) => {
+                write!(f, "PartitionId({expr}, {size})")
+            }
+        }
+    }
+}
+
+// number of rows hashed by evaluate_hashes()
+#[cfg(test)]
+thread_local! {
+    static NUM_EVALUATE_HASHES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// identical seed as spark hash partitioning
+const SPARK_HASH_SEED: i32 = 42;
+
+// 2^64 / golden ratio
+const FIBONACCI_MULTIPLIER: u64 = 0x9e3779b97f4a7c15;
+
+/// hash function of hash partitioning. partition id is evaluated from the
+/// hash with [`PartitionIdAssignment`], `pmod(hash, num_partitions)` by
+/// default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// spark HashPartitioning
+    Murmur3 { seed: i32 },
+    /// hive bucketing, hash is masked to non-negative like
+    /// `(hash & Integer.MAX_VALUE) % num_buckets`
+    HiveHash,
+    /// lower 32 bits of spark XxHash64
+    XxHash64 { seed: i64 },
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        Self::Murmur3 {
+            seed: SPARK_HASH_SEED,
         }
     }
 }
 
-fn evaluate_hashes(partitioning: &Partitioning, batch: &RecordBatch) -> ArrowResult<Vec<i32>> {
-    match partitioning {
-        Partitioning::HashPartitioning(exprs, _) => {
-            let arrays = exprs
-                .iter()
-                .map(|expr| Ok(expr.evaluate(batch)?.into_array(batch.num_rows())?))
-                .collect::<Result<Vec<_>>>()?;
+/// partitioning of rows whose hash partitioning keys are all null
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NullKeysPartitioning {
+    /// hash of rows with all null keys. spark skips nulls when hashing, so
+    /// these rows are hashed to the seed by default.
+    pub null_hash_seed: i32,
+    /// routes rows with all null keys to this partition, other rows are then
+    /// hash partitioned into the remaining partitions.
+    pub null_partition_id: Option<u32>,
+}
+
+/// maps hashes of hash partitioning to partition ids
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PartitionIdAssignment {
+    /// `pmod(hash, num_partitions)`, identical to spark
+    #[default]
+    Pmod,
+    /// fibonacci hashing, all bits of the hash are mixed before reducing to
+    /// the partition range, so that structured hashes (e.g. hive hashes of
+    /// integers) are not clustered with power-of-two partition counts. not
+    /// compatible with partitioning of non-native shuffles.
+    Fibonacci,
+}
+
+impl Default for NullKeysPartitioning {
+    fn default() -> Self {
+        Self {
+            null_hash_seed: SPARK_HASH_SEED,
+            null_partition_id: None,
+        }
+    }
+}
+
+fn evaluate_hashes(
+    keys: &[ArrayRef],
+    num_rows: usize,
+    hash_algorithm: HashAlgorithm,
+) -> Result<Vec<i32>> {
+    #[cfg(test)]
+    NUM_EVALUATE_HASHES.with(|num| num.set(num.get() + num_rows));
+
+    Ok(match hash_algorithm {
+        HashAlgorithm::Murmur3 { seed } => create_murmur3_hashes(num_rows, keys, seed),
+        HashAlgorithm::HiveHash => create_hive_hashes(num_rows, keys)?
+            .into_iter()
+            .map(|hash| hash & i32::MAX)
+            .collect(),
+        HashAlgorithm::XxHash64 { seed } => create_xxhash64_hashes(num_rows, keys, seed)
+            .into_iter()
+            .map(|hash| hash as i32)
+            .collect(),
+    })
+}
+
+fn evaluate_hash_partition_ids(
+    partitioning: &Partitioning,
+    batch: &RecordBatch,
+    null_keys: NullKeysPartitioning,
+) -> ArrowResult<Vec<u32>> {
+    let Partitioning::HashPartitioning(exprs, num_partitions, hash_algorithm) = partitioning else {
+        unreachable!("unsupported partitioning: {:?}", partitioning);
+    };
+    let num_partitions = *num_partitions;
+    let keys = exprs
+        .iter()
+        .map(|expr| Ok(expr.evaluate(batch)?.into_array(batch.num_rows())?))
+        .collect::<Result<Vec<_>>>()?;
+    let mut hashes = evaluate_hashes(&keys, batch.num_rows(), *hash_algorithm)?;
+
+    // fast path: rows with all null keys are already hashed to the seed
+    let all_null_keys = if null_keys == NullKeysPartitioning::default() {
+        None
+    } else {
+        evaluate_all_null_keys(&keys)
+    };
+    if let Some(all_null_keys) = &all_null_keys {
+        for i in all_null_keys.set_indices() {
+            hashes[i] = null_keys.null_hash_seed;
+        }
+    }
+
+    let assignment = shuffle_partition_id_assignment();
+    let Some(null_partition_id) = null_keys.null_partition_id else {
+        return Ok(evaluate_partition_ids(hashes, num_partitions, assignment));
+    };
+    if null_partition_id as usize >= num_partitions {
+        return Err(ArrowError::InvalidArgumentError(format!(
+            "null keys partition {null_partition_id} out of range, num_partitions={num_partitions}"
+        )));
+    }
+    if num_partitions == 1 {
+        return Ok(vec![0; batch.num_rows()]);
+    }
+
+    // other rows are hash partitioned into the remaining partitions
+    let mut part_ids = evaluate_partition_ids(hashes, num_partitions - 1, assignment);
+    for part_id in &mut part_ids {
+        if *part_id >= null_partition_id {
+            *part_id += 1;
+        }
+    }
+    if let Some(all_null_keys) = &all_null_keys {
+        for i in all_null_keys.set_indices() {
+            part_ids[i] = null_partition_id;
+        }
+    }
+    Ok(part_ids)
+}
 
-            // compute hash array, use identical seed as spark hash partition
-            Ok(create_murmur3_hashes(arrays[0].len(), &arrays, 42))
+fn shuffle_partition_id_assignment() -> PartitionIdAssignment {
+    static ASSIGNMENT: OnceCell<PartitionIdAssignment> = OnceCell::new();
+    *ASSIGNMENT.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            match conf::SHUFFLE_PARTITION_ID_ASSIGNMENT.value().as_deref() {
+                Ok("fibonacci") => PartitionIdAssignment::Fibonacci,
+                _ => PartitionIdAssignment::Pmod,
+            }
+        } else {
+            PartitionIdAssignment::Pmod // for testing
         }
-        _ => unreachable!("unsupported partitioning: {:?}", partitioning),
+    })
+}
+
+// returns a mask of rows whose keys are all null, or None if there are no
+// such rows
+fn evaluate_all_null_keys(keys: &[ArrayRef]) -> Option<BooleanBuffer> {
+    let mut any_valid: Option<BooleanBuffer> = None;
+    for key in keys {
+        let valid = key.logical_nulls()?.into_inner();
+        any_valid = Some(match any_valid {
+            Some(any_valid) => &any_valid | &valid,
+            None => valid,
+        });
     }
+    let all_null_keys = !&any_valid?;
+    (all_null_keys.count_set_bits() > 0).then_some(all_null_keys)
 }
 
-fn evaluate_partition_ids(mut hashes: Vec<i32>, num_partitions: usize) -> Vec<u32> {
-    // evaluate part_id = pmod(hash, num_partitions)
-    for h in &mut hashes {
-        *h = h.rem_euclid(num_partitions as i32);
+fn evaluate_partition_ids(
+    mut hashes: Vec<i32>,
+    num_partitions: usize,
+    assignment: PartitionIdAssignment,
+) -> Vec<u32> {
+    match assignment {
+        PartitionIdAssignment::Pmod => {
+            // evaluate part_id = pmod(hash, num_partitions)
+            for h in &mut hashes {
+                *h = h.rem_euclid(num_partitions as i32);
+            }
+        }
+        PartitionIdAssignment::Fibonacci => {
+            // mix into the high 32 bits, then multiply-shift them into
+            // 0..num_partitions
+            for h in &mut hashes {
+                let mixed = ((*h as u32 as u64).wrapping_mul(FIBONACCI_MULTIPLIER) >> 32) as u32;
+                *h = ((mixed as u64 * num_partitions as u64) >> 32) as i32;
+            }
+        }
     }
 
     unsafe {
@@ -183,6 +555,22 @@ fn evaluate_partition_ids(mut hashes: Vec<i32>, num_partitions: usize) -> Vec<u3
     }
 }
 
+/// returns number of partitions after remapping with a partition id mapping
+pub fn coalesced_partition_count(partition_id_mapping: &[u32]) -> usize {
+    partition_id_mapping
+        .iter()
+        .max()
+        .map(|&max_partition_id| max_partition_id as usize + 1)
+        .unwrap_or(0)
+}
+
+// remap evaluated partition ids, e.g. coalescing small partitions in AQE
+fn remap_partition_ids(part_ids: &mut [u32], partition_id_mapping: &[u32]) {
+    for part_id in part_ids {
+        *part_id = partition_id_mapping[*part_id as usize];
+    }
+}
+
 fn evaluate_robin_partition_ids(
     partitioning: &Partitioning,
     batch: &RecordBatch,
@@ -197,6 +585,36 @@ fn evaluate_robin_partition_ids(
     vec_u32
 }
 
+// evaluates partition ids with the expression of PartitionIdPartitioning,
+// which must be non-null int32 values in [0, num_partitions)
+fn evaluate_expr_partition_ids(
+    expr: &Arc<dyn PhysicalExpr>,
+    num_partitions: usize,
+    batch: &RecordBatch,
+) -> Result<Vec<u32>> {
+    let partition_ids = expr.evaluate(batch)?.into_array(batch.num_rows())?;
+    let Some(partition_ids) = partition_ids.as_primitive_opt::<Int32Type>() else {
+        return df_execution_err!(
+            "partition id expression {expr} returns {}, expected Int32",
+            partition_ids.data_type(),
+        );
+    };
+    partition_ids
+        .iter()
+        .enumerate()
+        .map(|(row_idx, partition_id)| match partition_id {
+            Some(partition_id) if (partition_id as u32 as usize) < num_partitions => {
+                Ok(partition_id as u32)
+            }
+            Some(partition_id) => df_execution_err!(
+                "partition id {partition_id} out of range [0, {num_partitions}) \
+                    at row {row_idx}, evaluated by {expr}"
+            ),
+            None => df_execution_err!("null partition id at row {row_idx}, evaluated by {expr}"),
+        })
+        .collect()
+}
+
 fn evaluate_range_partition_ids(
     batch: &RecordBatch,
     sort_expr: &Vec<PhysicalSortExpr>,
@@ -0,0 +1,242 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
+    time::Duration,
+};
+
+/// bounded retries with exponential backoff for transient io failures of
+/// shuffle output files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// runs `op` until it succeeds, fails with an unrecoverable error or
+    /// runs out of retries
+    pub fn retry<T>(&self, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut backoff = self.initial_backoff;
+        let mut num_retries = 0;
+        loop {
+            match op() {
+                Err(err) if is_retryable(&err) && num_retries < self.max_retries => {
+                    log::warn!(
+                        "shuffle output io error, retrying in {backoff:?} ({}/{}): {err}",
+                        num_retries + 1,
+                        self.max_retries,
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    num_retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// errors which may succeed if the operation is retried later. a failed
+/// write with these errors has written nothing, so it can be retried as is.
+fn is_retryable(err: &Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::TimedOut
+    )
+}
+
+/// writer of a shuffle output file, retrying transient failures and
+/// reporting running out of disk space with the file path and size
+pub struct ShuffleOutputWrite<W: Write> {
+    inner: W,
+    path: String,
+    num_written_bytes: u64,
+    retry_policy: RetryPolicy,
+}
+
+impl ShuffleOutputWrite<File> {
+    /// creates (or truncates) the output file
+    pub fn create(path: &str, retry_policy: RetryPolicy) -> Result<Self> {
+        let file = retry_policy.retry(|| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+        })?;
+        Ok(Self::new(file, path, retry_policy))
+    }
+}
+
+impl<W: Write> ShuffleOutputWrite<W> {
+    pub fn new(inner: W, path: &str, retry_policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            path: path.to_string(),
+            num_written_bytes: 0,
+            retry_policy,
+        }
+    }
+
+    fn classify(&self, err: Error, num_bytes: usize) -> Error {
+        if err.kind() == ErrorKind::StorageFull {
+            return Error::new(
+                ErrorKind::StorageFull,
+                format!(
+                    "no space left on device for shuffle output at {}: \
+                        {} bytes written, {num_bytes} bytes attempted",
+                    self.path, self.num_written_bytes,
+                ),
+            );
+        }
+        err
+    }
+}
+
+impl<W: Write> Write for ShuffleOutputWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let inner = &mut self.inner;
+        match self.retry_policy.retry(|| inner.write(buf)) {
+            Ok(len) => {
+                self.num_written_bytes += len as u64;
+                Ok(len)
+            }
+            Err(err) => Err(self.classify(err, buf.len())),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let inner = &mut self.inner;
+        self.retry_policy
+            .retry(|| inner.flush())
+            .map_err(|err| self.classify(err, 0))
+    }
+}
+
+/// removes partially written output files after a failed attempt, so that
+/// retrying the task starts clean
+pub fn remove_partial_output_files(paths: &[&str]) {
+    for path in paths {
+        match std::fs::remove_file(path) {
+            Ok(()) => log::warn!("removed partial shuffle output file: {path}"),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => log::warn!("error removing partial shuffle output file {path}: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Error, ErrorKind, Result, Write},
+        time::Duration,
+    };
+
+    use crate::shuffle::output_io::{RetryPolicy, ShuffleOutputWrite};
+
+    const NO_BACKOFF: RetryPolicy = RetryPolicy {
+        max_retries: 3,
+        initial_backoff: Duration::ZERO,
+    };
+
+    // fails the first `num_failures` calls with the given error kind
+    struct ErroringWrite {
+        output: Vec<u8>,
+        num_failures: usize,
+        error_kind: ErrorKind,
+    }
+
+    impl Write for ErroringWrite {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            if self.num_failures > 0 {
+                self.num_failures -= 1;
+                return Err(Error::from(self.error_kind));
+            }
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn erroring_write(num_failures: usize, error_kind: ErrorKind) -> ErroringWrite {
+        ErroringWrite {
+            output: vec![],
+            num_failures,
+            error_kind,
+        }
+    }
+
+    #[test]
+    fn test_retryable_errors() -> Result<()> {
+        for error_kind in [ErrorKind::WouldBlock, ErrorKind::TimedOut] {
+            let inner = erroring_write(3, error_kind);
+            let mut w = ShuffleOutputWrite::new(inner, "data", NO_BACKOFF);
+            w.write_all(b"hello")?;
+            w.flush()?;
+            assert_eq!(w.inner.output, b"hello");
+
+            // fails after running out of retries
+            let inner = erroring_write(4, error_kind);
+            let mut w = ShuffleOutputWrite::new(inner, "data", NO_BACKOFF);
+            assert_eq!(w.write_all(b"hello").unwrap_err().kind(), error_kind);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fatal_errors() -> Result<()> {
+        // no space left fails fast with path and size
+        let mut w = ShuffleOutputWrite::new(
+            erroring_write(0, ErrorKind::StorageFull),
+            "data",
+            NO_BACKOFF,
+        );
+        w.write_all(b"hello")?;
+        w.inner.num_failures = 1;
+        let err = w.write_all(b"world!").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(
+            err.to_string(),
+            "no space left on device for shuffle output at data: \
+                5 bytes written, 6 bytes attempted"
+        );
+        assert_eq!(w.inner.num_failures, 0);
+
+        // other errors are not retried
+        let mut w = ShuffleOutputWrite::new(
+            erroring_write(1, ErrorKind::PermissionDenied),
+            "data",
+            NO_BACKOFF,
+        );
+        let err = w.write_all(b"hello").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        w.write_all(b"hello")?;
+        assert_eq!(w.inner.output, b"hello");
+        Ok(())
+    }
+}
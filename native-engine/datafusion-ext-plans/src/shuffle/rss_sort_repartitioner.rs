@@ -93,12 +93,15 @@ impl ShuffleRepartitioner for RssSortShuffleRepartitioner {
         self.update_mem_used(mem_used).await?;
 
         // add batch to buffered data
-        let mem_used = {
+        let (mem_used, is_full) = {
             let mut data = self.data.lock().await;
             data.add_batch(input)?;
-            data.mem_used()
+            (data.mem_used(), data.is_full())
         };
         self.update_mem_used(mem_used).await?;
+        if is_full {
+            return self.force_spill().await;
+        }
 
         // we are likely to spill more frequently because the cost of spilling a shuffle
         // repartition is lower than other consumers.
@@ -0,0 +1,437 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+use arrow::{
+    datatypes::SchemaRef,
+    record_batch::{RecordBatch, RecordBatchOptions},
+};
+use blaze_jni_bridge::{conf, conf::BooleanConf, is_jni_bridge_inited};
+use bytes::Bytes;
+use count_write::CountWrite;
+use datafusion::{common::Result, parquet::arrow::ArrowWriter, physical_plan::metrics::Time};
+use itertools::Itertools;
+use once_cell::sync::OnceCell;
+
+use crate::{
+    common::{
+        error::BlazeError,
+        ipc_compression::{IpcCompressionReader, IpcCompressionWriter},
+        timer_helper::TimerHelper,
+    },
+    memmgr::spill::{DefaultSpillSerializer, SpillSerializer},
+    shuffle::{
+        buffered_data::{read_parquet_segment, SpillFormat},
+        merge_comparator::MergeComparator,
+    },
+};
+
+/// layout of partition segments in spills and shuffle data files, used by
+/// both the spill writer and the merger.
+///
+/// spills are merged by copying the bytes of their segments, so every format
+/// must guarantee that segments of a partition written independently and
+/// concatenated byte-wise are still a valid segment containing the rows of
+/// all of them. for example, a format writing the schema only once per stream
+/// breaks this. with validation enabled, merged output is decoded and row
+/// counts are checked against the merged spills. with a merge comparator,
+/// segments are decoded and merged in sorted order instead.
+#[derive(Clone)]
+pub struct SegmentFormat {
+    spill_format: SpillFormat,
+    schema: SchemaRef,
+    validation: bool,
+    serializer: Arc<dyn SpillSerializer>,
+    merge_comparator: Option<Arc<dyn MergeComparator>>,
+}
+
+impl SegmentFormat {
+    pub fn new(spill_format: SpillFormat, schema: SchemaRef) -> Self {
+        Self {
+            spill_format,
+            schema,
+            validation: shuffle_merge_validation_enabled(),
+            serializer: Arc::new(DefaultSpillSerializer),
+            merge_comparator: None,
+        }
+    }
+
+    /// serializes batches of [`SpillFormat::Ipc`] segments with the given
+    /// serializer, parquet segments are not affected
+    pub fn with_serializer(mut self, serializer: Arc<dyn SpillSerializer>) -> Self {
+        self.serializer = serializer;
+        self
+    }
+
+    /// decodes merged output and checks row counts of each partition, which
+    /// is expensive and only meant for debugging
+    pub fn with_validation(mut self, validation: bool) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// merges segments of a partition in the order of the comparator, which
+    /// requires every segment to be sorted by it
+    pub fn with_merge_comparator(mut self, merge_comparator: Arc<dyn MergeComparator>) -> Self {
+        self.merge_comparator = Some(merge_comparator);
+        self
+    }
+
+    pub fn merge_comparator(&self) -> Option<&Arc<dyn MergeComparator>> {
+        self.merge_comparator.as_ref()
+    }
+
+    pub fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    /// creates a writer of consecutive partition segments, stat columns are
+    /// only written in [`SpillFormat::Ipc`]
+    pub fn writer<W: Write + Send>(&self, output: W, stat_columns: Vec<usize>) -> SegmentWriter<W> {
+        let output = match self.spill_format {
+            SpillFormat::Ipc => SegmentOutput::Ipc(
+                IpcCompressionWriter::new(CountWrite::from(output))
+                    .with_stat_columns(stat_columns)
+                    .with_serializer(self.serializer.clone()),
+            ),
+            SpillFormat::Parquet => SegmentOutput::Parquet(CountWrite::from(output)),
+        };
+        SegmentWriter {
+            output,
+            schema: self.schema.clone(),
+        }
+    }
+
+    /// appends a segment of `len` bytes to the output. consecutive segments of
+    /// the same partition form the merged segment of the partition. returns
+    /// the number of bytes written.
+    pub fn append_segment(
+        &self,
+        input: &mut impl Read,
+        len: u64,
+        output: &mut impl Write,
+    ) -> Result<u64> {
+        let copied = std::io::copy(&mut input.take(len), output)?;
+        if copied != len {
+            return Err(BlazeError::ShuffleCorruption {
+                partition: None,
+                message: format!("truncated shuffle segment: {copied} of {len} bytes"),
+            }
+            .into());
+        }
+        Ok(copied)
+    }
+
+    /// reads all batches of a segment, or of concatenated segments
+    pub fn read_segment(&self, segment: Bytes) -> Result<Vec<RecordBatch>> {
+        if self.spill_format == SpillFormat::Parquet {
+            return read_parquet_segment(segment);
+        }
+        let mut reader = IpcCompressionReader::new(Cursor::new(segment))
+            .with_serializer(self.serializer.clone());
+        let mut batches = vec![];
+        while let Some((num_rows, cols)) = reader.read_batch(&self.schema)? {
+            batches.push(RecordBatch::try_new_with_options(
+                self.schema.clone(),
+                cols,
+                &RecordBatchOptions::new().with_row_count(Some(num_rows)),
+            )?);
+        }
+        Ok(batches)
+    }
+
+    fn num_rows(&self, segment: Bytes) -> Result<usize> {
+        Ok(self
+            .read_segment(segment)?
+            .iter()
+            .map(|batch| batch.num_rows())
+            .sum())
+    }
+
+    /// creates a validator for merging spills with `num_partitions`
+    /// partitions, or None if validation is disabled
+    pub fn merge_validator(&self, num_partitions: usize) -> Option<MergeValidator> {
+        self.validation.then(|| MergeValidator {
+            format: self.clone(),
+            num_rows: vec![0; num_partitions],
+        })
+    }
+}
+
+/// writes partition segments in a [`SegmentFormat`]
+pub struct SegmentWriter<W: Write> {
+    output: SegmentOutput<W>,
+    schema: SchemaRef,
+}
+
+enum SegmentOutput<W: Write> {
+    Ipc(IpcCompressionWriter<CountWrite<W>>),
+    Parquet(CountWrite<W>),
+}
+
+impl<W: Write + Send> SegmentWriter<W> {
+    /// writes all batches as the segment of a partition. only writing is
+    /// timed, not producing the batches.
+    pub fn write_segment(
+        &mut self,
+        batches: impl Iterator<Item = RecordBatch>,
+        output_io_time: &Time,
+    ) -> Result<()> {
+        match &mut self.output {
+            SegmentOutput::Ipc(writer) => {
+                for batch in batches {
+                    output_io_time
+                        .with_timer(|| writer.write_batch(batch.num_rows(), batch.columns()))?;
+                }
+                output_io_time.with_timer(|| writer.finish_segment())?;
+            }
+            SegmentOutput::Parquet(output) => {
+                // each segment is a standalone parquet file
+                let mut writer = ArrowWriter::try_new(output, self.schema.clone(), None)?;
+                for batch in batches {
+                    output_io_time.with_timer(|| writer.write(&batch))?;
+                }
+                output_io_time.with_timer(|| writer.close())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// returns the number of bytes written, which is the offset of the next
+    /// segment
+    pub fn count(&self) -> u64 {
+        match &self.output {
+            SegmentOutput::Ipc(writer) => writer.inner().count(),
+            SegmentOutput::Parquet(output) => output.count(),
+        }
+    }
+}
+
+/// checks that merged output has all rows of the merged spills
+pub struct MergeValidator {
+    format: SegmentFormat,
+    num_rows: Vec<usize>,
+}
+
+impl MergeValidator {
+    /// decodes a spill segment to be appended to the merged partition
+    pub fn add_spill_segment(&mut self, partition_id: usize, segment: Bytes) -> Result<()> {
+        match self.format.num_rows(segment) {
+            Ok(num_rows) => self.num_rows[partition_id] += num_rows,
+            Err(e) => {
+                return Err(BlazeError::ShuffleCorruption {
+                    partition: Some(partition_id),
+                    message: format!(
+                        "shuffle merge validation failed: spill segment of partition \
+                            {partition_id} cannot be decoded: {e}"
+                    ),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// decodes each partition of the merged data file and compares its number
+    /// of rows with the added spill segments
+    pub fn validate(&self, data_file: &str, offsets: &[u64]) -> Result<()> {
+        let mut data = File::open(data_file)?;
+        for (partition_id, (&beg, &end)) in offsets.iter().tuple_windows().enumerate() {
+            let expected = self.num_rows[partition_id];
+            let mut segment = vec![0; (end - beg) as usize];
+            data.seek(SeekFrom::Start(beg))?;
+            data.read_exact(&mut segment)?;
+            let num_rows = match self.format.num_rows(Bytes::from(segment)) {
+                Ok(num_rows) => num_rows,
+                Err(e) => {
+                    return Err(BlazeError::ShuffleCorruption {
+                        partition: Some(partition_id),
+                        message: format!(
+                            "shuffle merge validation failed: partition {partition_id} \
+                                cannot be decoded after merging: {e}"
+                        ),
+                    }
+                    .into());
+                }
+            };
+            if num_rows != expected {
+                return Err(BlazeError::ShuffleCorruption {
+                    partition: Some(partition_id),
+                    message: format!(
+                        "shuffle merge validation failed: partition {partition_id} has \
+                            {num_rows} rows after merging, expected {expected} rows from spills"
+                    ),
+                }
+                .into());
+            }
+        }
+        log::info!(
+            "shuffle merge validation passed: {} partitions, {} rows",
+            self.num_rows.len(),
+            self.num_rows.iter().sum::<usize>(),
+        );
+        Ok(())
+    }
+}
+
+fn shuffle_merge_validation_enabled() -> bool {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    *ENABLED.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_MERGE_VALIDATION_ENABLE
+                .value()
+                .unwrap_or(false)
+        } else {
+            false // for testing
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, sync::Arc};
+
+    use arrow::{
+        array::{AsArray, Int32Array},
+        datatypes::{DataType, Field, Int32Type, Schema},
+        record_batch::RecordBatch,
+    };
+    use bytes::Bytes;
+    use datafusion::{common::Result, physical_plan::metrics::Time};
+    use itertools::Itertools;
+
+    use crate::shuffle::{buffered_data::SpillFormat, segment_format::SegmentFormat};
+
+    fn build_batch(values: Vec<i32>) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from(values))]).unwrap()
+    }
+
+    // writes each list of batches as the segment of a partition, returns
+    // the written data and offsets
+    fn write_segments(
+        format: &SegmentFormat,
+        partitions: Vec<Vec<RecordBatch>>,
+    ) -> Result<(Vec<u8>, Vec<u64>)> {
+        let mut data = vec![];
+        let mut writer = format.writer(&mut data, vec![]);
+        let mut offsets = vec![];
+        for batches in partitions {
+            offsets.push(writer.count());
+            writer.write_segment(batches.into_iter(), &Time::new())?;
+        }
+        offsets.push(writer.count());
+        drop(writer);
+        Ok((data, offsets))
+    }
+
+    #[test]
+    fn test_concatenated_segments() -> Result<()> {
+        let schema = build_batch(vec![]).schema();
+        for spill_format in [SpillFormat::Ipc, SpillFormat::Parquet] {
+            let format = SegmentFormat::new(spill_format, schema.clone()).with_validation(true);
+            let spills = [
+                write_segments(
+                    &format,
+                    vec![
+                        vec![build_batch(vec![1, 2]), build_batch(vec![3])],
+                        vec![build_batch(vec![4])],
+                    ],
+                )?,
+                write_segments(
+                    &format,
+                    vec![vec![build_batch(vec![5, 6])], vec![build_batch(vec![7])]],
+                )?,
+            ];
+
+            // merge segments of each partition by copying bytes
+            let mut validator = format.merge_validator(2).expect("validation enabled");
+            let output_dir = tempfile::tempdir()?;
+            let data_file = output_dir.path().join("data");
+            let mut output = std::fs::File::create(&data_file)?;
+            let mut offsets = vec![0];
+            for partition_id in 0..2 {
+                let mut len = 0;
+                for (data, spill_offsets) in &spills {
+                    let (beg, end) = (spill_offsets[partition_id], spill_offsets[partition_id + 1]);
+                    let segment = &data[beg as usize..end as usize];
+                    validator.add_spill_segment(partition_id, Bytes::copy_from_slice(segment))?;
+                    len += format.append_segment(&mut &segment[..], end - beg, &mut output)?;
+                }
+                offsets.push(offsets[partition_id] + len);
+            }
+            output.flush()?;
+            validator.validate(&data_file.to_string_lossy(), &offsets)?;
+
+            // merged segments are readable as a single segment
+            let merged = std::fs::read(&data_file)?;
+            let values = offsets
+                .iter()
+                .tuple_windows()
+                .map(|(&beg, &end)| {
+                    let segment = Bytes::copy_from_slice(&merged[beg as usize..end as usize]);
+                    Ok(format
+                        .read_segment(segment)?
+                        .iter()
+                        .flat_map(|batch| {
+                            batch
+                                .column(0)
+                                .as_primitive::<Int32Type>()
+                                .values()
+                                .to_vec()
+                        })
+                        .collect::<Vec<_>>())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(values, vec![vec![1, 2, 3, 5, 6], vec![4, 7]]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_validation_failure() -> Result<()> {
+        let format = SegmentFormat::new(SpillFormat::Ipc, build_batch(vec![]).schema());
+        assert!(format.merge_validator(1).is_none());
+        let format = format.with_validation(true);
+
+        // the merged output lost the rows of the second spill
+        let (spill1, _) = write_segments(&format, vec![vec![build_batch(vec![1, 2])]])?;
+        let (spill2, _) = write_segments(&format, vec![vec![build_batch(vec![3])]])?;
+        let mut validator = format.merge_validator(1).expect("validation enabled");
+        validator.add_spill_segment(0, Bytes::from(spill1.clone()))?;
+        validator.add_spill_segment(0, Bytes::from(spill2))?;
+
+        let output_dir = tempfile::tempdir()?;
+        let data_file = output_dir.path().join("data");
+        std::fs::write(&data_file, &spill1)?;
+        let data_file = data_file.to_string_lossy();
+        let err = validator
+            .validate(&data_file, &[0, spill1.len() as u64])
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("partition 0 has 2 rows after merging, expected 3 rows from spills"));
+
+        // garbage cannot be decoded
+        std::fs::write(&*data_file, b"garbage!")?;
+        let err = validator.validate(&data_file, &[0, 8]).unwrap_err();
+        assert!(err.to_string().contains("cannot be decoded after merging"));
+        Ok(())
+    }
+}
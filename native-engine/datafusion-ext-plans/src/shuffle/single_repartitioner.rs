@@ -28,7 +28,11 @@ use crate::{
         ipc_compression::IpcCompressionWriter,
         timer_helper::{TimedWriter, TimerHelper},
     },
-    shuffle::ShuffleRepartitioner,
+    shuffle::{
+        cancellation::CancellationToken,
+        sort_repartitioner::{encode_index, shuffle_index_format},
+        ShuffleRepartitioner,
+    },
 };
 
 pub struct SingleShuffleRepartitioner {
@@ -36,6 +40,7 @@ pub struct SingleShuffleRepartitioner {
     output_index_file: String,
     output_data: Arc<Mutex<Option<IpcCompressionWriter<TimedWriter<File>>>>>,
     output_io_time: Time,
+    cancellation: CancellationToken,
 }
 
 impl SingleShuffleRepartitioner {
@@ -45,9 +50,16 @@ impl SingleShuffleRepartitioner {
             output_index_file,
             output_data: Arc::new(Mutex::default()),
             output_io_time,
+            cancellation: CancellationToken::default(),
         }
     }
 
+    /// shares the cancellation token with the caller, which may cancel writing
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
     fn get_output_writer<'a>(
         &self,
         output_data: &'a mut Option<IpcCompressionWriter<TimedWriter<File>>>,
@@ -70,6 +82,7 @@ impl SingleShuffleRepartitioner {
 #[async_trait]
 impl ShuffleRepartitioner for SingleShuffleRepartitioner {
     async fn insert_batch(&self, input: RecordBatch) -> Result<()> {
+        self.cancellation.check()?;
         let mut output_data = self.output_data.lock().await;
         let output_writer = self.get_output_writer(&mut *output_data)?;
         output_writer.write_batch(input.num_rows(), input.columns())?;
@@ -77,6 +90,7 @@ impl ShuffleRepartitioner for SingleShuffleRepartitioner {
     }
 
     async fn shuffle_write(&self) -> Result<()> {
+        self.cancellation.check()?;
         let mut output_data = std::mem::take(&mut *self.output_data.lock().await);
 
         // write index file
@@ -88,10 +102,9 @@ impl ShuffleRepartitioner for SingleShuffleRepartitioner {
                     .truncate(true)
                     .open(&self.output_index_file)?,
             );
-            output_writer.finish_current_buf()?;
+            output_writer.finish_segment()?;
             let offset = output_writer.inner_mut().0.stream_position()?;
-            output_index.write_all(&[0u8; 8])?;
-            output_index.write_all(&(offset as i64).to_le_bytes()[..])?;
+            output_index.write_all(&encode_index(&[0, offset], shuffle_index_format())?)?;
         } else {
             // write empty data file and index file
             let _output_data = self.output_io_time.wrap_writer(
@@ -108,8 +121,48 @@ impl ShuffleRepartitioner for SingleShuffleRepartitioner {
                     .truncate(true)
                     .open(&self.output_index_file)?,
             );
-            output_index.write_all(&[0u8; 16])?;
+            output_index.write_all(&encode_index(&[0, 0], shuffle_index_format())?)?;
         }
         Ok(())
     }
+
+    fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{array::Int32Array, record_batch::RecordBatch};
+    use datafusion::{common::Result, physical_plan::metrics::Time};
+
+    use crate::shuffle::{
+        cancellation::CancellationToken, single_repartitioner::SingleShuffleRepartitioner,
+        ShuffleRepartitioner,
+    };
+
+    #[tokio::test]
+    async fn test_single_cancel() -> Result<()> {
+        let batch = RecordBatch::try_from_iter(vec![(
+            "a",
+            Arc::new(Int32Array::from_iter_values(0..100)) as _,
+        )])?;
+        let output_dir = tempfile::tempdir()?;
+        let file = |name: &str| output_dir.path().join(name).to_string_lossy().to_string();
+        let cancellation = CancellationToken::default();
+        let repartitioner =
+            SingleShuffleRepartitioner::new(file("data"), file("index"), Time::new())
+                .with_cancellation(cancellation.clone());
+
+        repartitioner.insert_batch(batch.clone()).await?;
+        repartitioner.cancel();
+        assert!(cancellation.is_cancelled());
+        let err = repartitioner.insert_batch(batch).await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+        let err = repartitioner.shuffle_write().await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+        Ok(())
+    }
 }
@@ -13,43 +13,104 @@
 // limitations under the License.
 
 use std::{
-    fs::OpenOptions,
-    io::{Read, Write},
-    sync::{Arc, Weak},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+        Arc, Weak,
+    },
 };
 
-use arrow::record_batch::RecordBatch;
+use arrow::{
+    array::{ArrayRef, UInt32Array},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    record_batch::RecordBatch,
+};
 use async_trait::async_trait;
+use blaze_jni_bridge::{
+    conf,
+    conf::{BooleanConf, DoubleConf},
+    is_jni_bridge_inited,
+};
+use bytes::Bytes;
 use bytesize::ByteSize;
 use datafusion::{
     common::{DataFusionError, Result},
-    physical_plan::metrics::Time,
+    physical_plan::{
+        metrics::{Count, Time},
+        SendableRecordBatchStream,
+    },
+};
+use datafusion_ext_commons::{
+    arrow::array_size::BatchSize,
+    batch_size, df_execution_err,
+    io::{decode_shuffle_index, encode_non_empty_bitmap, encode_sparse_index, ShuffleIndexFormat},
 };
-use datafusion_ext_commons::{arrow::array_size::BatchSize, df_execution_err};
 use futures::lock::Mutex;
+use itertools::Itertools;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex as SyncMutex;
+use tokio::sync::mpsc::UnboundedSender;
 
 use crate::{
     common::{
+        error::BlazeError,
         execution_context::ExecutionContext,
         offsetted::{Offsetted, OffsettedMergeIterator},
         timer_helper::TimerHelper,
     },
     memmgr::{
-        spill::{try_new_spill, OwnedSpillBufReader, Spill},
+        spill::{
+            check_spill_disk_usage, spill_max_disk_bytes, try_new_spill_with_size_hint,
+            OwnedSpillBufReader, Spill, SpillSerializer,
+        },
         MemConsumer, MemConsumerInfo, MemManager,
     },
-    shuffle::{buffered_data::BufferedData, Partitioning, ShuffleRepartitioner},
+    shuffle::{
+        batch_queue::ShardedBatchQueue,
+        buffered_data::{shuffle_spill_format, BufferedData},
+        cancellation::CancellationToken,
+        coalesced_partition_count,
+        combiner::ShuffleCombiner,
+        merge_comparator::{merge_sorted_runs, MergeComparator},
+        output_io::{remove_partial_output_files, RetryPolicy, ShuffleOutputWrite},
+        segment_format::SegmentFormat,
+        PartitionRowCounter, Partitioning, ShuffleOutputStats, ShuffleRepartitioner,
+        ShuffleRepartitionerStats,
+    },
 };
 
+/// partitioned data spilled by a repartitioner, with offsets of each partition
+pub type ShuffleSpill = Offsetted<u64, Box<dyn Spill>>;
+
 pub struct SortShuffleRepartitioner {
     exec_ctx: Arc<ExecutionContext>,
     mem_consumer_info: Option<Weak<MemConsumerInfo>>,
     output_data_file: String,
     output_index_file: String,
     data: Mutex<BufferedData>,
-    spills: Mutex<Vec<Offsetted<u64, Box<dyn Spill>>>>,
+    data_num_rows: AtomicUsize,
+    data_mem_used: AtomicUsize,
+    queued_batches: ShardedBatchQueue,
+    spills: Mutex<Vec<ShuffleSpill>>,
+    num_spills: AtomicUsize,
+    segment_format: SegmentFormat,
     num_output_partitions: usize,
     output_io_time: Time,
+    append: bool,
+    index_format: ShuffleIndexFormat,
+    max_spill_disk_bytes: Option<u64>,
+    sync_on_close: bool,
+    cancellation: CancellationToken,
+    output_started: AtomicBool,
+    output_written: AtomicBool,
+    closed: AtomicBool,
+    input_rows: Count,
+    partition_rows: PartitionRowCounter,
+    output_stats: SyncMutex<Option<ShuffleOutputStats>>,
 }
 
 impl SortShuffleRepartitioner {
@@ -62,96 +123,321 @@ impl SortShuffleRepartitioner {
     ) -> Self {
         let partition_id = exec_ctx.partition_id();
         let num_output_partitions = partitioning.partition_count();
+        let segment_format = SegmentFormat::new(shuffle_spill_format(), exec_ctx.output_schema());
+        let input_rows = exec_ctx.register_counter_metric("input_rows");
+        let partition_rows = PartitionRowCounter::default();
+        let mut data = BufferedData::new(partitioning, partition_id, output_io_time.clone());
+        data.set_partition_rows(partition_rows.clone());
         Self {
             exec_ctx,
             mem_consumer_info: None,
             output_data_file,
             output_index_file,
-            data: Mutex::new(BufferedData::new(
-                partitioning,
-                partition_id,
-                output_io_time.clone(),
-            )),
+            data: Mutex::new(data),
+            data_num_rows: AtomicUsize::new(0),
+            data_mem_used: AtomicUsize::new(0),
+            queued_batches: ShardedBatchQueue::default(),
             spills: Mutex::default(),
+            num_spills: AtomicUsize::new(0),
+            segment_format,
             num_output_partitions,
             output_io_time,
+            append: false,
+            index_format: shuffle_index_format(),
+            max_spill_disk_bytes: spill_max_disk_bytes(),
+            sync_on_close: shuffle_sync_on_close_enabled(),
+            cancellation: CancellationToken::default(),
+            output_started: AtomicBool::new(false),
+            output_written: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            input_rows,
+            partition_rows,
+            output_stats: SyncMutex::default(),
         }
     }
-}
 
-#[async_trait]
-impl MemConsumer for SortShuffleRepartitioner {
-    fn name(&self) -> &str {
-        "SortShuffleRepartitioner"
+    /// appends to existing output files instead of truncating them
+    pub fn with_append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
     }
 
-    fn set_consumer_info(&mut self, consumer_info: Weak<MemConsumerInfo>) {
-        self.mem_consumer_info = Some(consumer_info);
+    /// appends a bitmap of non-empty partitions to the index file, so that
+    /// readers can skip empty partitions without comparing offsets
+    pub fn with_index_bitmap(mut self, index_bitmap: bool) -> Self {
+        self.index_format = match index_bitmap {
+            true => ShuffleIndexFormat::DenseWithBitmap,
+            false => ShuffleIndexFormat::Dense,
+        };
+        self
     }
 
-    fn get_consumer_info(&self) -> &Weak<MemConsumerInfo> {
-        self.mem_consumer_info
-            .as_ref()
-            .expect("consumer info not set")
+    /// sets the format of the index file, see [`ShuffleIndexFormat`]
+    pub fn with_index_format(mut self, index_format: ShuffleIndexFormat) -> Self {
+        self.index_format = index_format;
+        self
     }
 
-    async fn spill(&self) -> Result<()> {
-        let data = self.data.lock().await.drain();
+    /// fails spilling with ResourcesExhausted once spills use more disk than
+    /// `max_spill_disk_bytes`. None for no limit
+    pub fn with_max_spill_disk_bytes(mut self, max_spill_disk_bytes: Option<u64>) -> Self {
+        self.max_spill_disk_bytes = max_spill_disk_bytes;
+        self
+    }
+
+    /// syncs written output files to disk in close(), off by default
+    pub fn with_sync_on_close(mut self, sync_on_close: bool) -> Self {
+        self.sync_on_close = sync_on_close;
+        self
+    }
+
+    /// shares the cancellation token with the caller, which may cancel writing
+    /// in addition to the task being killed
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.data.get_mut().set_cancellation(cancellation.clone());
+        self.cancellation = cancellation;
+        self
+    }
+
+    /// decodes merged output and checks that no rows of spills are lost or
+    /// duplicated by merging, for debugging
+    pub fn with_merge_validation(mut self, merge_validation: bool) -> Self {
+        self.segment_format = self.segment_format.with_validation(merge_validation);
+        self
+    }
+
+    /// serializes batches of spills and output segments with the given
+    /// serializer instead of the default one. readers of the output must use
+    /// the same serializer.
+    pub fn with_spill_serializer(mut self, serializer: Arc<dyn SpillSerializer>) -> Self {
+        self.data.get_mut().set_serializer(serializer.clone());
+        self.segment_format = self.segment_format.with_serializer(serializer);
+        self
+    }
+
+    /// remaps each evaluated partition id to `partition_id_mapping[id]`, so
+    /// that output files contain the coalesced partitions
+    pub fn with_partition_id_mapping(mut self, partition_id_mapping: Vec<u32>) -> Result<Self> {
+        let data = self.data.get_mut();
+        let num_partitions = data.partitioning().partition_count();
+        if partition_id_mapping.len() != num_partitions {
+            return df_execution_err!(
+                "partition id mapping length {} does not match partition count {num_partitions}",
+                partition_id_mapping.len(),
+            );
+        }
+        self.num_output_partitions = coalesced_partition_count(&partition_id_mapping);
+        data.set_partition_id_mapping(partition_id_mapping.into());
+        Ok(self)
+    }
+
+    /// combines rows with equal keys of each partition in every spill and the
+    /// final output before they are written (map-side combine)
+    pub fn with_combiner(mut self, combiner: Arc<dyn ShuffleCombiner>) -> Self {
+        self.data.get_mut().set_combiner(combiner);
+        self
+    }
+
+    /// merges spills of each partition in the order of the comparator instead
+    /// of concatenating them, e.g. for globally sorted range partitioned
+    /// output. input must be sorted by the comparator, and rows of each
+    /// partition are kept in insertion order. this decodes and re-encodes all
+    /// spilled data, so it is much slower than the default merge.
+    pub fn with_merge_comparator(mut self, merge_comparator: Arc<dyn MergeComparator>) -> Self {
+        self.data.get_mut().set_stable_order(true);
+        self.segment_format = self.segment_format.with_merge_comparator(merge_comparator);
+        self
+    }
+
+    /// spills buffered data when the number of buffered batches reaches the
+    /// cap, regardless of memory usage. None for no cap
+    pub fn with_max_buffered_batches(mut self, max_buffered_batches: Option<usize>) -> Self {
+        self.data
+            .get_mut()
+            .set_max_buffered_batches(max_buffered_batches);
+        self
+    }
+
+    /// estimates the number of bytes written if buffered data is spilled now
+    pub async fn estimated_spill_bytes(&self) -> Result<usize> {
+        let mut data = self.data.lock().await;
+        self.add_queued_batches(&mut data)?;
+        Ok(data.estimated_spill_size())
+    }
+
+    /// merges `k` consecutive spills with the smallest total size into a
+    /// single spill, reducing fan-in of the final merge in `shuffle_write()`.
+    /// the merged spill takes the place of the merged ones, so spills stay in
+    /// insertion order. spills are kept unchanged if merging fails. partition
+    /// data is copied as is unless a merge comparator is set.
+    pub async fn compact_spills(&self, k: usize) -> Result<()> {
+        let mut spills_locked = self.spills.lock().await;
+        if k < 2 || spills_locked.len() < 2 {
+            return Ok(());
+        }
+        let spill_sizes = spills_locked
+            .iter()
+            .map(|spill| spill.offsets().last().cloned().unwrap_or_default() as usize)
+            .collect::<Vec<_>>();
+        let k = k.min(spill_sizes.len());
+        let start = (0..=spill_sizes.len() - k)
+            .min_by_key(|&start| spill_sizes[start..start + k].iter().sum::<usize>())
+            .unwrap_or_default();
+        let compacted_range = start..start + k;
+        let spill_size_hint = spill_sizes[compacted_range.clone()].iter().sum();
         let spill_metrics = self.exec_ctx.spill_metrics().clone();
-        let spill = tokio::task::spawn_blocking(move || {
-            let mut spill = try_new_spill(&spill_metrics)?;
-            let offsets = data.write(spill.get_buf_writer())?;
-            Ok::<_, DataFusionError>(Offsetted::new(offsets, spill))
+        let num_output_partitions = self.num_output_partitions;
+        let segment_format = self.segment_format.clone();
+
+        // spills are only read while merging and given back in any case
+        let spills = std::mem::take(&mut *spills_locked);
+        let compacted_range_cloned = compacted_range.clone();
+        let (mut spills, merged) = tokio::task::spawn_blocking(move || {
+            let merge = || {
+                let mut spill = try_new_spill_with_size_hint(&spill_metrics, spill_size_hint)?;
+                let mut writer = spill.get_buf_writer();
+                let readers = spill_readers(&spills[compacted_range_cloned]);
+                let offsets = if let Some(comparator) = segment_format.merge_comparator() {
+                    merge_spills_sorted(
+                        readers,
+                        num_output_partitions,
+                        &segment_format,
+                        comparator.as_ref(),
+                        &mut writer,
+                        |_, _, _| Ok(()),
+                    )?
+                } else {
+                    let mut merge_iter =
+                        OffsettedMergeIterator::new(num_output_partitions, readers);
+                    while let Some((_, reader, range)) = merge_iter.next() {
+                        let len = range.end - range.start;
+                        segment_format.append_segment(reader, len, &mut writer)?;
+                    }
+                    merge_iter.merged_offsets().to_vec()
+                };
+                writer.flush()?;
+                drop(writer);
+                spill.complete()?;
+                spill.publish_metrics();
+                Ok::<_, DataFusionError>(Offsetted::new(offsets, spill))
+            };
+            let merged = catch_unwind(AssertUnwindSafe(merge))
+                .unwrap_or_else(|_| df_execution_err!("shuffle spill compaction panicked"));
+            (spills, merged)
         })
         .await
-        .expect("tokio spawn_blocking error")?;
+        .or_else(|e| df_execution_err!("shuffle spill compaction error: {e:?}"))?;
 
-        self.spills.lock().await.push(spill);
-        self.update_mem_used(0).await?;
+        let merged = match merged {
+            Ok(merged) => merged,
+            Err(err) => {
+                *spills_locked = spills;
+                return Err(err);
+            }
+        };
+        spills.splice(compacted_range, [merged]);
+        let resident_mem_size = resident_mem_size(&spills);
+        self.num_spills.store(spills.len(), SeqCst);
+        *spills_locked = spills;
+        drop(spills_locked);
+        let mem_used = self.buffered_mem_used() + resident_mem_size;
+        self.update_mem_used(mem_used).await?;
         Ok(())
     }
-}
 
-impl Drop for SortShuffleRepartitioner {
-    fn drop(&mut self) {
-        MemManager::deregister_consumer(self);
+    /// spills all buffered data and takes out all spills, which can then be
+    /// merged with spills of other repartitioners by `merge_shuffle_spills()`
+    pub async fn take_spills(&self) -> Result<Vec<ShuffleSpill>> {
+        self.set_spillable(false);
+        self.spill().await?;
+        let spills = std::mem::take(&mut *self.spills.lock().await);
+        self.num_spills.store(0, SeqCst);
+        self.update_mem_used(0).await?;
+        Ok(spills)
     }
-}
 
-#[async_trait]
-impl ShuffleRepartitioner for SortShuffleRepartitioner {
-    async fn insert_batch(&self, input: RecordBatch) -> Result<()> {
-        // update memory usage before adding to buffered data
-        let mem_used = self.data.lock().await.mem_used() + input.get_batch_mem_size() * 2;
-        self.update_mem_used(mem_used).await?;
+    /// writes output files like `shuffle_write()`, and outputs batches of each
+    /// partition as soon as it is written, in partition id order. output
+    /// batches have a leading `partition_id` column followed by input columns.
+    pub fn shuffle_write_with_output(self: &Arc<Self>) -> Result<SendableRecordBatchStream> {
+        if self.append {
+            return df_execution_err!("shuffle write with output does not support appending");
+        }
+        let input_schema = self.exec_ctx.output_schema();
+        let partition_id_field = Arc::new(Field::new("partition_id", DataType::UInt32, false));
+        let output_schema = Arc::new(Schema::new(
+            std::iter::once(partition_id_field)
+                .chain(input_schema.fields().iter().cloned())
+                .collect::<Vec<_>>(),
+        ));
+        let output_exec_ctx = self.exec_ctx.with_new_output_schema(output_schema.clone());
+        let repartitioner = self.clone();
 
-        // add batch to buffered data
-        let mem_used = {
-            let mut data = self.data.lock().await;
-            data.add_batch(input)?;
-            data.mem_used()
-        };
-        self.update_mem_used(mem_used).await?;
+        let output = output_exec_ctx.output_with_sender("ShuffleWrite", move |sender| async move {
+            let data_file = repartitioner.output_data_file.clone();
+            let index_file = repartitioner.output_index_file.clone();
+            let (written_tx, mut written_rx) = tokio::sync::mpsc::unbounded_channel();
 
-        // we are likely to spill more frequently because the cost of spilling a shuffle
-        // repartition is lower than other consumers.
-        let mem_used_percent = self.mem_used_percent();
-        if mem_used_percent > 0.8 {
-            log::info!(
-                "{} memory usage: {}, percent: {:.3}, spilling...",
-                self.name(),
-                ByteSize(mem_used as u64),
-                mem_used_percent,
-            );
-            self.force_spill().await?;
-        }
-        Ok(())
+            let write = async {
+                let offsets = repartitioner
+                    .write_output(data_file.clone(), index_file, Some(written_tx))
+                    .await?;
+                repartitioner.publish_output_stats(&offsets);
+                repartitioner.output_written.store(true, SeqCst);
+                Ok::<_, DataFusionError>(())
+            };
+            let output_written = async {
+                while let Some((partition_id, range)) = written_rx.recv().await {
+                    let data_file = data_file.clone();
+                    let segment_format = repartitioner.segment_format.clone();
+                    let batches = tokio::task::spawn_blocking(move || {
+                        let mut segment = vec![0; (range.end - range.start) as usize];
+                        let mut data = File::open(&data_file)?;
+                        data.seek(SeekFrom::Start(range.start))?;
+                        data.read_exact(&mut segment)?;
+                        segment_format.read_segment(Bytes::from(segment))
+                    })
+                    .await
+                    .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+
+                    for batch in batches {
+                        let num_rows = batch.num_rows();
+                        let partition_ids: ArrayRef =
+                            Arc::new(UInt32Array::from(vec![partition_id as u32; num_rows]));
+                        let columns = std::iter::once(partition_ids)
+                            .chain(batch.columns().iter().cloned())
+                            .collect();
+                        sender
+                            .send(RecordBatch::try_new(output_schema.clone(), columns)?)
+                            .await?;
+                    }
+                }
+                Ok::<_, DataFusionError>(())
+            };
+            futures::try_join!(write, output_written)?;
+            Ok(())
+        });
+        Ok(output)
     }
 
-    async fn shuffle_write(&self) -> Result<()> {
+    /// writes buffered data and spills into output files. if `written_tx` is
+    /// given, the partition id and data file range of each non-empty partition
+    /// is sent once the partition is completely written. returns offsets of
+    /// the written partitions.
+    async fn write_output(
+        &self,
+        data_file: String,
+        index_file: String,
+        written_tx: Option<UnboundedSender<(usize, Range<u64>)>>,
+    ) -> Result<Vec<u64>> {
+        self.cancellation.check()?;
+        self.output_started.store(true, SeqCst);
         self.set_spillable(false);
         let mut spills = std::mem::take(&mut *self.spills.lock().await);
-        let data = self.data.lock().await.drain();
+        self.num_spills.store(0, SeqCst);
+        let data = self.drain_data().await?;
+        let index_format = self.index_format;
+        let segment_format = self.segment_format.clone();
 
         log::info!(
             "{} starts outputting ({} spills + in_mem: {})",
@@ -160,8 +446,33 @@ impl ShuffleRepartitioner for SortShuffleRepartitioner {
             ByteSize(data.mem_used() as u64)
         );
 
-        let data_file = self.output_data_file.clone();
-        let index_file = self.output_index_file.clone();
+        // publish spill statistics
+        let num_spills = self.exec_ctx.register_counter_metric("num_spills");
+        let max_spill_bytes = self.exec_ctx.register_gauge_metric("max_spill_bytes");
+        let merged_partitions = self.exec_ctx.register_counter_metric("merged_partitions");
+        num_spills.add(spills.len());
+        max_spill_bytes.set_max(
+            spills
+                .iter()
+                .map(|spill| spill.offsets().last().cloned().unwrap_or_default() as usize)
+                .max()
+                .unwrap_or_default(),
+        );
+
+        // no input rows - write an empty data file and an index of zero
+        // offsets, reducers fetch them like any other output
+        if spills.is_empty() && data.num_rows() == 0 {
+            let num_output_partitions = self.num_output_partitions;
+            let output_io_time = self.output_io_time.clone();
+            let offsets = tokio::task::spawn_blocking(move || {
+                let _output_io_timer = output_io_time.timer();
+                write_empty_output(&data_file, &index_file, num_output_partitions, index_format)
+            })
+            .await
+            .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+            self.update_mem_used(0).await?;
+            return Ok(offsets);
+        }
 
         // no spills - directly write current batches into final file
         if spills.is_empty() {
@@ -170,101 +481,2557 @@ impl ShuffleRepartitioner for SortShuffleRepartitioner {
                 let output_io_time_cloned = output_io_time.clone();
                 let _output_io_timer = output_io_time_cloned.timer();
 
-                let mut output_data = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&data_file)?;
-                let mut output_index = OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&index_file)?;
-
-                // write data file
-                // exclude io timer because it is already included buffered_data.write()
-                let offsets = output_io_time.exclude_timer(|| data.write(&mut output_data))?;
-
-                // write index file
-                let mut offsets_data = vec![];
-                for offset in offsets {
-                    offsets_data.extend_from_slice(&(offset as i64).to_le_bytes()[..]);
-                }
-                output_index.write_all(&offsets_data)?;
+                let write = || {
+                    let retry_policy = RetryPolicy::default();
+                    let mut output_data = ShuffleOutputWrite::create(&data_file, retry_policy)?;
+                    let mut output_index = ShuffleOutputWrite::create(&index_file, retry_policy)?;
 
-                Ok::<(), DataFusionError>(())
+                    // write data file
+                    // exclude io timer because it is already included buffered_data.write()
+                    let offsets = output_io_time.exclude_timer(|| data.write(&mut output_data))?;
+                    output_data.flush()?;
+                    for (partition_id, (&beg, &end)) in offsets.iter().tuple_windows().enumerate()
+                    {
+                        notify_partition_written(&written_tx, partition_id, beg..end);
+                    }
+
+                    // write index file
+                    output_index.write_all(&encode_index(&offsets, index_format)?)?;
+                    output_index.flush()?;
+                    Ok::<_, DataFusionError>(offsets)
+                };
+                write().inspect_err(|_| remove_partial_output_files(&[&data_file, &index_file]))
             })
             .await
             .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
             self.update_mem_used(0).await?;
-            return Ok(());
+            return Ok(offsets);
         }
 
         // write rest data into a spill
         if !data.is_empty() {
             if self.mem_used_percent() < 0.5 {
-                let mut spill = Box::new(vec![]);
-                let writer = spill.get_buf_writer();
-                let offsets = data.write(writer)?;
-                self.update_mem_used(spill.len()).await?;
+                // writing is cpu heavy, do not block the executor
+                let (offsets, spill) = tokio::task::spawn_blocking(move || {
+                    let mut spill = Box::new(vec![]);
+                    let offsets = data.write(spill.get_buf_writer())?;
+                    Ok::<_, DataFusionError>((offsets, spill))
+                })
+                .await
+                .expect("tokio spawn_blocking error")?;
+                self.update_mem_used(spill.len() + resident_mem_size(&spills))
+                    .await?;
                 spills.push(Offsetted::new(offsets, spill));
             } else {
+                let spill_size_hint = data.mem_used();
                 let spill_metrics = self.exec_ctx.spill_metrics().clone();
                 let spill = tokio::task::spawn_blocking(move || {
-                    let mut spill = try_new_spill(&spill_metrics)?;
+                    let mut spill = try_new_spill_with_size_hint(&spill_metrics, spill_size_hint)?;
                     let offsets = data.write(spill.get_buf_writer())?;
+                    spill.complete()?;
+                    spill.publish_metrics();
                     Ok::<_, DataFusionError>(Offsetted::new(offsets, spill))
                 })
                 .await
                 .expect("tokio spawn_blocking error")?;
-                self.update_mem_used(0).await?;
                 spills.push(spill);
+                self.update_mem_used(resident_mem_size(&spills)).await?;
             }
         }
 
-        // append partition in each spills
+        // reserve memory for offsets used in merging, released after merged
         let num_output_partitions = self.num_output_partitions;
+        let offsets_mem_size = merge_offsets_mem_size(&spills, num_output_partitions);
+        self.update_mem_used_with_diff(offsets_mem_size as isize)
+            .await?;
+
+        // append partition in each spills
         let output_io_time = self.output_io_time.clone();
-        tokio::task::spawn_blocking(move || {
+        let progress = MergeProgress {
+            written_tx,
+            cancellation: self.cancellation.clone(),
+            merge_adjustments: self.exec_ctx.register_counter_metric("merge_adjustments"),
+        };
+        let merged = tokio::task::spawn_blocking(move || {
             let _output_io_timer = output_io_time.timer();
-            let mut output_data = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&data_file)?;
-            let mut output_index = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .open(&index_file)?;
-
-            let mut merge_iter = OffsettedMergeIterator::new(
+            let offsets = merge_spills(
+                spills,
                 num_output_partitions,
-                spills
-                    .into_iter()
-                    .map(|spill| spill.map_data(|s| OwnedSpillBufReader::from(s)))
-                    .collect(),
+                &segment_format,
+                &data_file,
+                &index_file,
+                index_format,
+                &progress,
+            )?;
+            merged_partitions.add(
+                offsets
+                    .iter()
+                    .tuple_windows()
+                    .filter(|(beg, end)| beg < end)
+                    .count(),
             );
+            Ok::<_, DataFusionError>(offsets)
+        })
+        .await
+        .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))?;
 
-            while let Some((_partition_id, reader, range)) = merge_iter.next() {
-                let mut reader = reader.buf_reader().take(range.end - range.start);
-                std::io::copy(&mut reader, &mut output_data)?;
-            }
-            let offsets = merge_iter.merged_offsets();
+        // spills are released even if merging fails
+        self.update_mem_used(0).await?;
+        merged
+    }
 
-            // write index file
-            let mut offsets_data = vec![];
-            for &offset in offsets {
-                offsets_data.extend_from_slice(&(offset as i64).to_le_bytes()[..]);
-            }
-            output_index.write_all(&offsets_data)?;
+    /// publishes sizes of output partitions, warns if the output is skewed
+    fn publish_output_stats(&self, offsets: &[u64]) {
+        let num_partitions = offsets.len().saturating_sub(1);
+        let stats = ShuffleOutputStats::from_offsets(offsets).with_row_counts(
+            self.input_rows.value() as u64,
+            self.partition_rows.partition_rows(num_partitions),
+        );
+        self.exec_ctx
+            .register_counter_metric("written_rows")
+            .add(stats.written_rows() as usize);
+        self.exec_ctx
+            .register_gauge_metric("skew_max_partition_bytes")
+            .set_max(stats.max_bytes() as usize);
+        // gauges are integers, the ratio is scaled to keep its fraction
+        self.exec_ctx
+            .register_gauge_metric("skew_ratio_x1000")
+            .set_max((stats.skew_ratio() * 1000.0) as usize);
+
+        if let Some(partition_id) = stats.skewed_partition_id(shuffle_skew_warning_ratio()) {
+            log::warn!(
+                "{} [partition={}] skewed shuffle output: skewed_partition_id={}, \
+                    max_bytes={}, median_bytes={}, p99_bytes={}, skew_ratio={:.1}",
+                self.name(),
+                self.exec_ctx.partition_id(),
+                partition_id,
+                stats.max_bytes(),
+                stats.median_bytes(),
+                stats.p99_bytes(),
+                stats.skew_ratio(),
+            );
+        }
+        *self.output_stats.lock() = Some(stats);
+    }
+}
+
+#[async_trait]
+impl MemConsumer for SortShuffleRepartitioner {
+    fn name(&self) -> &str {
+        "SortShuffleRepartitioner"
+    }
+
+    fn set_consumer_info(&mut self, consumer_info: Weak<MemConsumerInfo>) {
+        self.mem_consumer_info = Some(consumer_info);
+    }
 
-            Ok::<(), DataFusionError>(())
+    fn get_consumer_info(&self) -> &Weak<MemConsumerInfo> {
+        self.mem_consumer_info
+            .as_ref()
+            .expect("consumer info not set")
+    }
+
+    async fn spill(&self) -> Result<()> {
+        let data = self.drain_data().await?;
+        if !data.is_empty() {
+            self.fire_on_first_spill();
+        }
+        let mut spills_locked = self.spills.lock().await;
+        let mut spills = std::mem::take(&mut *spills_locked);
+        let spill_size_hint = data.mem_used();
+        let spill_metrics = self.exec_ctx.spill_metrics().clone();
+        let spills = tokio::task::spawn_blocking(move || {
+            // flush previous resident spills to release their memory
+            for spill in &mut spills {
+                spill.data_mut().flush_resident()?;
+            }
+            if !data.is_empty() {
+                let mut spill = try_new_spill_with_size_hint(&spill_metrics, spill_size_hint)?;
+                let offsets = data.write(spill.get_buf_writer())?;
+                spill.complete()?;
+                spill.publish_metrics();
+                spills.push(Offsetted::new(offsets, spill));
+            }
+            Ok::<_, DataFusionError>(spills)
         })
         .await
-        .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+        .expect("tokio spawn_blocking error")?;
 
-        self.update_mem_used(0).await?;
+        let resident_mem_size = resident_mem_size(&spills);
+        self.num_spills.store(spills.len(), SeqCst);
+        *spills_locked = spills;
+        let disk_usage_checked = check_spill_disk_usage(
+            self.name(),
+            spills_locked.iter().map(|spill| spill.data().as_ref()),
+            self.max_spill_disk_bytes,
+        );
+        drop(spills_locked);
+
+        // batches inserted during spilling are still buffered
+        self.update_mem_used(resident_mem_size + self.buffered_mem_used())
+            .await?;
+        disk_usage_checked
+    }
+}
+
+/// merges spills into a data file and its index file. spills may come from
+/// different repartitioners but must have the same number of partitions and
+/// schema. partition data is copied as is, returns offsets of the merged
+/// partitions.
+pub fn merge_shuffle_spills(
+    spills: Vec<ShuffleSpill>,
+    num_partitions: usize,
+    schema: SchemaRef,
+    data_file: &str,
+    index_file: &str,
+) -> Result<Vec<u64>> {
+    merge_spills(
+        spills,
+        num_partitions,
+        &SegmentFormat::new(shuffle_spill_format(), schema),
+        data_file,
+        index_file,
+        shuffle_index_format(),
+        &MergeProgress::default(),
+    )
+}
+
+/// reports partitions once written, and stops merging at partition boundaries
+/// once cancelled
+#[derive(Default)]
+struct MergeProgress {
+    written_tx: Option<UnboundedSender<(usize, Range<u64>)>>,
+    cancellation: CancellationToken,
+    merge_adjustments: Count,
+}
+
+fn merge_spills(
+    spills: Vec<ShuffleSpill>,
+    num_partitions: usize,
+    format: &SegmentFormat,
+    data_file: &str,
+    index_file: &str,
+    index_format: ShuffleIndexFormat,
+    progress: &MergeProgress,
+) -> Result<Vec<u64>> {
+    for spill in &spills {
+        if spill.offsets().len() != num_partitions + 1 {
+            return df_execution_err!(
+                "cannot merge shuffle spills: number of partitions mismatched ({} vs {})",
+                spill.offsets().len().saturating_sub(1),
+                num_partitions,
+            );
+        }
+    }
+    merge_spills_impl(
+        spills,
+        num_partitions,
+        format,
+        data_file,
+        index_file,
+        index_format,
+        progress,
+    )
+    .inspect_err(|_| remove_partial_output_files(&[data_file, index_file]))
+}
+
+fn merge_spills_impl(
+    spills: Vec<ShuffleSpill>,
+    num_partitions: usize,
+    format: &SegmentFormat,
+    data_file: &str,
+    index_file: &str,
+    index_format: ShuffleIndexFormat,
+    progress: &MergeProgress,
+) -> Result<Vec<u64>> {
+    let retry_policy = RetryPolicy::default();
+    let mut output_data = ShuffleOutputWrite::create(data_file, retry_policy)?;
+    let mut output_index = ShuffleOutputWrite::create(index_file, retry_policy)?;
+    let mut validator = format.merge_validator(num_partitions);
+
+    if let Some(comparator) = format.merge_comparator() {
+        let offsets = merge_spills_sorted(
+            spill_readers(&spills),
+            num_partitions,
+            format,
+            comparator.as_ref(),
+            &mut output_data,
+            |partition_id, segments, range| {
+                if let Some(validator) = &mut validator {
+                    for segment in segments {
+                        validator.add_spill_segment(partition_id, segment.clone())?;
+                    }
+                }
+                notify_partition_written(&progress.written_tx, partition_id, range);
+                progress.cancellation.check()
+            },
+        )?;
+        output_data.flush()?;
+        if let Some(validator) = &validator {
+            validator.validate(data_file, &offsets)?;
+        }
+        output_index.write_all(&encode_index(&offsets, index_format)?)?;
+        output_index.flush()?;
+        return Ok(offsets);
+    }
+
+    let mut merge_iter = OffsettedMergeIterator::new(
+        num_partitions,
+        spills
+            .into_iter()
+            .map(|spill| spill.map_data(|s| OwnedSpillBufReader::from(s)))
+            .collect(),
+    );
+
+    // a partition is completely written when the next partition starts
+    let mut pos = 0;
+    let mut cur_partition: Option<(usize, u64)> = None;
+    while let Some((partition_id, reader, range)) = merge_iter.next() {
+        if let Some((cur_partition_id, beg)) = cur_partition {
+            if cur_partition_id != partition_id {
+                notify_partition_written(&progress.written_tx, cur_partition_id, beg..pos);
+                cur_partition = None;
+                progress.cancellation.check()?;
+            }
+        }
+        cur_partition.get_or_insert((partition_id, pos));
+        let len = range.end - range.start;
+        pos += match &mut validator {
+            Some(validator) => {
+                // decode the segment before it is appended to the merged output
+                let mut segment = vec![0; len as usize];
+                reader.buf_reader().read_exact(&mut segment)?;
+                let segment = Bytes::from(segment);
+                validator.add_spill_segment(partition_id, segment.clone())?;
+                format.append_segment(&mut &segment[..], len, &mut output_data)?
+            }
+            None => format.append_segment(reader.buf_reader(), len, &mut output_data)?,
+        };
+    }
+    if let Some((partition_id, beg)) = cur_partition {
+        notify_partition_written(&progress.written_tx, partition_id, beg..pos);
+    }
+    progress.merge_adjustments.add(merge_iter.num_adjustments());
+    output_data.flush()?;
+    let offsets = merge_iter.merged_offsets().to_vec();
+    if let Some(validator) = &validator {
+        validator.validate(data_file, &offsets)?;
+    }
+
+    // write index file
+    output_index.write_all(&encode_index(&offsets, index_format)?)?;
+    output_index.flush()?;
+    Ok(offsets)
+}
+
+/// merges spills partition by partition in the order of `comparator` instead
+/// of copying segments, all segments of a partition are decoded in memory.
+/// `on_merged` is called with each partition, its segments in spills and its
+/// range in the output. returns offsets of the merged partitions.
+fn merge_spills_sorted<R: Read, W: Write + Send>(
+    mut spills: Vec<Offsetted<u64, R>>,
+    num_partitions: usize,
+    format: &SegmentFormat,
+    comparator: &dyn MergeComparator,
+    output: W,
+    mut on_merged: impl FnMut(usize, &[Bytes], Range<u64>) -> Result<()>,
+) -> Result<Vec<u64>> {
+    let mut writer = format.writer(output, vec![]);
+    let mut offsets = vec![0];
+    for partition_id in 0..num_partitions {
+        // spill readers are sequential, partitions are read in order
+        let mut segments = vec![];
+        for spill in &mut spills {
+            let range = spill.offset(partition_id);
+            if !range.is_empty() {
+                let mut segment = vec![0; (range.end - range.start) as usize];
+                spill.data_mut().read_exact(&mut segment)?;
+                segments.push(Bytes::from(segment));
+            }
+        }
+
+        let beg = writer.count();
+        if !segments.is_empty() {
+            let runs = segments
+                .iter()
+                .map(|segment| format.read_segment(segment.clone()))
+                .collect::<Result<Vec<_>>>()?;
+            let merged = merge_sorted_runs(runs, comparator, batch_size())?;
+            writer.write_segment(merged.into_iter(), &Time::new())?;
+        }
+        let end = writer.count();
+        offsets.push(end);
+        on_merged(partition_id, &segments, beg..end)?;
+    }
+    Ok(offsets)
+}
+
+/// opens a reader of each spill, with offsets of its partitions. spills are
+/// only borrowed, so they are kept if reading fails.
+fn spill_readers(
+    spills: &[ShuffleSpill],
+) -> Vec<Offsetted<u64, BufReader<Box<dyn Read + Send + '_>>>> {
+    spills
+        .iter()
+        .map(|spill| Offsetted::new(spill.offsets().to_vec(), spill.data().get_buf_reader()))
+        .collect()
+}
+
+fn notify_partition_written(
+    written_tx: &Option<UnboundedSender<(usize, Range<u64>)>>,
+    partition_id: usize,
+    range: Range<u64>,
+) {
+    if let Some(written_tx) = written_tx {
+        if !range.is_empty() {
+            // receiver is dropped if the output stream is closed
+            let _ = written_tx.send((partition_id, range));
+        }
+    }
+}
+
+/// returns memory used by spills which are still resident in memory
+fn resident_mem_size(spills: &[ShuffleSpill]) -> usize {
+    spills
+        .iter()
+        .map(|spill| spill.data().resident_mem_size())
+        .sum()
+}
+
+/// returns memory used by offsets of spills and merged offsets
+fn merge_offsets_mem_size(spills: &[ShuffleSpill], num_partitions: usize) -> usize {
+    let num_offsets = spills
+        .iter()
+        .map(|spill| spill.offsets().len())
+        .sum::<usize>()
+        + num_partitions
+        + 1;
+    num_offsets * size_of::<u64>()
+}
+
+/// merges appended output into the existing output, each partition in the
+/// merged data file consists of its existing data followed by appended data
+fn append_shuffle_output(
+    data_file: &str,
+    index_file: &str,
+    appended_data_file: &str,
+    appended_index_file: &str,
+    format: &SegmentFormat,
+    index_format: ShuffleIndexFormat,
+) -> Result<()> {
+    // no existing output, use appended output directly
+    if !Path::new(index_file).exists() {
+        std::fs::rename(appended_data_file, data_file)?;
+        std::fs::rename(appended_index_file, index_file)?;
+        return Ok(());
+    }
+
+    let old_offsets = read_index_file(index_file)?;
+    let new_offsets = read_index_file(appended_index_file)?;
+    if old_offsets.len() != new_offsets.len() {
+        return df_execution_err!(
+            "cannot append shuffle output: number of partitions mismatched ({} vs {})",
+            old_offsets.len().saturating_sub(1),
+            new_offsets.len().saturating_sub(1),
+        );
+    }
+    let mut old_data = File::open(data_file)?;
+    let mut new_data = File::open(appended_data_file)?;
+    let old_data_len = old_data.metadata()?.len();
+    if old_offsets.last().cloned().unwrap_or_default() != old_data_len {
+        return df_execution_err!(
+            "cannot append shuffle output: index does not match data file length {old_data_len}"
+        );
+    }
+
+    // existing data must be readable with the appended format and schema
+    if let Some((&beg, &end)) = old_offsets
+        .iter()
+        .tuple_windows()
+        .find(|(beg, end)| beg < end)
+    {
+        let mut segment = vec![0; (end - beg) as usize];
+        old_data.seek(SeekFrom::Start(beg))?;
+        old_data.read_exact(&mut segment)?;
+        let batches = match format.read_segment(Bytes::from(segment)) {
+            Ok(batches) => batches,
+            Err(e) => df_execution_err!("cannot append shuffle output: schema mismatched: {e}")?,
+        };
+        let data_types = |schema: &SchemaRef| {
+            let fields = schema.fields().iter();
+            fields.map(|f| f.data_type().clone()).collect::<Vec<_>>()
+        };
+        match batches.first() {
+            Some(batch) if data_types(&batch.schema()) == data_types(format.schema()) => {}
+            _ => df_execution_err!("cannot append shuffle output: schema mismatched")?,
+        }
+    }
+
+    let merged_data_file = format!("{data_file}.merging");
+    let merged_index_file = format!("{index_file}.merging");
+    let mut merged_data = BufWriter::new(File::create(&merged_data_file)?);
+    let mut merged_offsets = vec![0u64];
+    for (old_range, new_range) in old_offsets
+        .iter()
+        .tuple_windows()
+        .zip(new_offsets.iter().tuple_windows())
+    {
+        let mut merged_len = 0;
+        for (input, (&beg, &end)) in [(&mut old_data, old_range), (&mut new_data, new_range)] {
+            input.seek(SeekFrom::Start(beg))?;
+            merged_len += std::io::copy(&mut input.take(end - beg), &mut merged_data)?;
+        }
+        let merged_offset = merged_offsets.last().cloned().unwrap_or_default();
+        match merged_offset.checked_add(merged_len) {
+            Some(offset) => merged_offsets.push(offset),
+            None => df_execution_err!(
+                "cannot append shuffle output: offset overflow ({merged_offset} + {merged_len})"
+            )?,
+        }
+    }
+    merged_data
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    write_index_file(&merged_index_file, &merged_offsets, index_format)?;
+
+    std::fs::rename(merged_data_file, data_file)?;
+    std::fs::rename(merged_index_file, index_file)?;
+    std::fs::remove_file(appended_data_file)?;
+    std::fs::remove_file(appended_index_file)?;
+    Ok(())
+}
+
+pub(crate) fn read_index_file(index_file: &str) -> Result<Vec<u64>> {
+    Ok(decode_shuffle_index(&std::fs::read(index_file)?)?.offsets)
+}
+
+fn write_index_file(
+    index_file: &str,
+    offsets: &[u64],
+    index_format: ShuffleIndexFormat,
+) -> Result<()> {
+    std::fs::write(index_file, encode_index(offsets, index_format)?)?;
+    Ok(())
+}
+
+/// writes output of a task without any input rows, returns zero offsets of
+/// all partitions
+fn write_empty_output(
+    data_file: &str,
+    index_file: &str,
+    num_partitions: usize,
+    index_format: ShuffleIndexFormat,
+) -> Result<Vec<u64>> {
+    let offsets = vec![0; num_partitions + 1];
+    let write = || {
+        let retry_policy = RetryPolicy::default();
+        ShuffleOutputWrite::create(data_file, retry_policy)?.flush()?;
+        let mut output_index = ShuffleOutputWrite::create(index_file, retry_policy)?;
+        output_index.write_all(&encode_index(&offsets, index_format)?)?;
+        output_index.flush()?;
+        Ok::<_, DataFusionError>(())
+    };
+    write().inspect_err(|_| remove_partial_output_files(&[data_file, index_file]))?;
+    Ok(offsets)
+}
+
+// index files store offsets as i64 (as spark does), offsets must also be
+// monotonic so that every partition has a valid range. empty partitions are
+// omitted in the sparse format.
+pub(crate) fn encode_index(offsets: &[u64], index_format: ShuffleIndexFormat) -> Result<Vec<u8>> {
+    let mut offsets_data = Vec::with_capacity(offsets.len() * 8);
+    let mut last_offset = 0;
+    for &offset in offsets {
+        if offset < last_offset {
+            df_execution_err!("shuffle index offsets not monotonic: {offset} < {last_offset}")?;
+        }
+        let Ok(offset_i64) = i64::try_from(offset) else {
+            return df_execution_err!("shuffle index offset overflow: {offset}");
+        };
+        offsets_data.extend_from_slice(&offset_i64.to_le_bytes()[..]);
+        last_offset = offset;
+    }
+    match index_format {
+        ShuffleIndexFormat::Dense => {}
+        ShuffleIndexFormat::DenseWithBitmap => {
+            offsets_data.extend(encode_non_empty_bitmap(offsets));
+        }
+        ShuffleIndexFormat::Sparse => return Ok(encode_sparse_index(offsets)),
+    }
+    Ok(offsets_data)
+}
+
+pub(crate) fn shuffle_index_format() -> ShuffleIndexFormat {
+    static FORMAT: OnceCell<ShuffleIndexFormat> = OnceCell::new();
+    *FORMAT.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            if conf::SHUFFLE_INDEX_SPARSE_ENABLE.value().unwrap_or(false) {
+                return ShuffleIndexFormat::Sparse;
+            }
+            if conf::SHUFFLE_INDEX_BITMAP_ENABLE.value().unwrap_or(false) {
+                return ShuffleIndexFormat::DenseWithBitmap;
+            }
+        }
+        ShuffleIndexFormat::Dense
+    })
+}
+
+fn shuffle_sync_on_close_enabled() -> bool {
+    static ENABLED: OnceCell<bool> = OnceCell::new();
+    *ENABLED.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_SYNC_ON_CLOSE_ENABLE.value().unwrap_or(false)
+        } else {
+            false // for testing
+        }
+    })
+}
+
+fn shuffle_skew_warning_ratio() -> f64 {
+    static WARNING_RATIO: OnceCell<f64> = OnceCell::new();
+    *WARNING_RATIO.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_SKEW_WARNING_RATIO.value().unwrap_or(100.0)
+        } else {
+            100.0 // for testing
+        }
+    })
+}
+
+impl Drop for SortShuffleRepartitioner {
+    fn drop(&mut self) {
+        // release spills (including on-heap spills) and remove partial output
+        // left by a failed or cancelled write
+        self.spills.get_mut().clear();
+        if self.output_started.load(SeqCst) && !self.output_written.load(SeqCst) {
+            let suffix = if self.append { ".appending" } else { "" };
+            let data_file = format!("{}{suffix}", self.output_data_file);
+            let index_file = format!("{}{suffix}", self.output_index_file);
+            remove_partial_output_files(&[&data_file, &index_file]);
+        }
+
+        // safety net for callers not calling close()
+        if !self.closed.load(SeqCst) {
+            log::warn!("{} dropped without being closed", self.name());
+            MemManager::deregister_consumer(self);
+        }
+    }
+}
+
+impl SortShuffleRepartitioner {
+    /// rows of buffered data and queued batches, without locking
+    fn buffered_num_rows(&self) -> usize {
+        self.data_num_rows.load(SeqCst) + self.queued_batches.num_rows()
+    }
+
+    /// memory used by buffered data and queued batches, without locking
+    fn buffered_mem_used(&self) -> usize {
+        self.data_mem_used.load(SeqCst) + self.queued_batches.mem_used()
+    }
+
+    /// moves queued batches into buffered data, called with the data lock
+    /// held. returns true if the buffered data is full
+    fn add_queued_batches(&self, data: &mut BufferedData) -> Result<bool> {
+        let (batches, mem_used) = self.queued_batches.take();
+        let num_rows = batches.iter().map(|batch| batch.num_rows()).sum();
+        for batch in batches {
+            data.add_batch(batch)?;
+        }
+        self.data_num_rows.store(data.num_rows(), SeqCst);
+        self.data_mem_used.store(data.mem_used(), SeqCst);
+        self.queued_batches.release(num_rows, mem_used);
+        Ok(data.is_full())
+    }
+
+    /// takes out all buffered data, including queued batches
+    async fn drain_data(&self) -> Result<BufferedData> {
+        let mut data = self.data.lock().await;
+        self.add_queued_batches(&mut data)?;
+        self.data_num_rows.store(0, SeqCst);
+        self.data_mem_used.store(0, SeqCst);
+        Ok(data.drain())
+    }
+
+    async fn spill_if_necessary(&self, mem_used: usize, is_full: bool) -> Result<()> {
+        // defensive bound in case the memory manager is slow to react
+        if is_full {
+            log::info!(
+                "{} buffered batches reach the cap, memory usage: {}, spilling...",
+                self.name(),
+                ByteSize(mem_used as u64),
+            );
+            return self.force_spill().await;
+        }
+
+        // we are likely to spill more frequently because the cost of spilling a shuffle
+        // repartition is lower than other consumers.
+        let mem_used_percent = self.mem_used_percent();
+        if mem_used_percent > 0.8 {
+            log::info!(
+                "{} memory usage: {}, percent: {:.3}, spilling...",
+                self.name(),
+                ByteSize(mem_used as u64),
+                mem_used_percent,
+            );
+            self.force_spill().await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ShuffleRepartitioner for SortShuffleRepartitioner {
+    async fn insert_batch(&self, input: RecordBatch) -> Result<()> {
+        self.insert_batches(vec![input]).await
+    }
+
+    async fn insert_batches(&self, inputs: Vec<RecordBatch>) -> Result<()> {
+        self.cancellation.check()?;
+
+        // update memory usage before adding to buffered data, mem used is
+        // doubled for later sorting
+        let num_rows = inputs.iter().map(|input| input.num_rows()).sum::<usize>();
+        let inputs_mem_used = inputs
+            .iter()
+            .map(|input| input.get_batch_mem_size() * 2)
+            .sum::<usize>();
+        let mem_used = self.buffered_mem_used() + inputs_mem_used;
+        self.update_mem_used(mem_used).await?;
+
+        // queue batches without waiting for the data lock, the queue is
+        // drained into buffered data by whoever holds the lock
+        self.queued_batches.push(inputs, inputs_mem_used);
+        self.input_rows.add(num_rows);
+        let is_full = match self.data.try_lock() {
+            Some(mut data) => self.add_queued_batches(&mut data)?,
+            None => false,
+        };
+        let mem_used = self.buffered_mem_used();
+        self.update_mem_used(mem_used).await?;
+        self.spill_if_necessary(mem_used, is_full).await
+    }
+
+    async fn reserve(&self, bytes: usize) -> Result<()> {
+        MemManager::reserve(self, bytes).await
+    }
+
+    fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+
+    async fn shuffle_write(&self) -> Result<()> {
+        if !self.append {
+            let data_file = self.output_data_file.clone();
+            let index_file = self.output_index_file.clone();
+            let offsets = self.write_output(data_file, index_file, None).await?;
+            self.publish_output_stats(&offsets);
+            self.output_written.store(true, SeqCst);
+            return Ok(());
+        }
+
+        // write to temporary files first, then merge into the existing output
+        let appended_data_file = format!("{}.appending", self.output_data_file);
+        let appended_index_file = format!("{}.appending", self.output_index_file);
+        self.write_output(
+            appended_data_file.clone(),
+            appended_index_file.clone(),
+            None,
+        )
+        .await?;
+
+        let data_file = self.output_data_file.clone();
+        let index_file = self.output_index_file.clone();
+        let segment_format = self.segment_format.clone();
+        let index_format = self.index_format;
+        let output_io_time = self.output_io_time.clone();
+        let offsets = tokio::task::spawn_blocking(move || {
+            let _output_io_timer = output_io_time.timer();
+            append_shuffle_output(
+                &data_file,
+                &index_file,
+                &appended_data_file,
+                &appended_index_file,
+                &segment_format,
+                index_format,
+            )?;
+            read_index_file(&index_file)
+        })
+        .await
+        .or_else(|e| df_execution_err!("shuffle write error: {e:?}"))??;
+        self.publish_output_stats(&offsets);
+        self.output_written.store(true, SeqCst);
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        if self.closed.swap(true, SeqCst) {
+            return Ok(());
+        }
+
+        // release all memory and deregister, even if releasing fails
+        self.set_spillable(false);
+        self.spills.lock().await.clear();
+        self.num_spills.store(0, SeqCst);
+        self.data.lock().await.drain();
+        self.data_num_rows.store(0, SeqCst);
+        self.data_mem_used.store(0, SeqCst);
+        self.queued_batches.clear();
+        let release_result = self.update_mem_used(0).await;
+        MemManager::deregister_consumer(self);
+        release_result?;
+
+        // final flush: sync written output files to disk if enabled
+        if self.sync_on_close && self.output_written.load(SeqCst) {
+            let output_files = [
+                self.output_data_file.clone(),
+                self.output_index_file.clone(),
+            ];
+            let output_io_time = self.output_io_time.clone();
+            tokio::task::spawn_blocking(move || {
+                let _output_io_timer = output_io_time.timer();
+                for output_file in output_files {
+                    if let Err(source) = File::open(&output_file).and_then(|file| file.sync_all()) {
+                        let path = output_file;
+                        return Err(BlazeError::ShuffleIo { path, source }.into());
+                    }
+                }
+                Ok(())
+            })
+            .await
+            .or_else(|e| df_execution_err!("shuffle close error: {e:?}"))??;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> ShuffleRepartitionerStats {
+        // read from counters, never waits for locks
+        ShuffleRepartitionerStats {
+            num_buffered_rows: self.buffered_num_rows(),
+            num_spills: self.num_spills.load(SeqCst),
+            buffered_bytes: self.buffered_mem_used(),
+        }
+    }
+
+    fn output_stats(&self) -> Option<ShuffleOutputStats> {
+        self.output_stats.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        fs::File,
+        io::{BufReader, Cursor, Read, Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+            mpsc, Arc,
+        },
+        time::Duration,
+    };
+
+    use arrow::{
+        array::{ArrayRef, AsArray, Int32Array, UInt32Array},
+        compute::{concat_batches, filter_record_batch, kernels::cmp::eq},
+        datatypes::{DataType, Field, Int32Type, Schema, SchemaRef, UInt32Type},
+        record_batch::RecordBatch,
+        row::{RowConverter, SortField},
+    };
+    use bytes::Bytes;
+    use datafusion::{
+        common::{DataFusionError, Result},
+        physical_expr::{expressions::Column, PhysicalSortExpr},
+        physical_plan::{
+            common::collect,
+            metrics::{ExecutionPlanMetricsSet, Time},
+            stream::RecordBatchStreamAdapter,
+        },
+        prelude::SessionContext,
+    };
+    use datafusion_ext_commons::{
+        df_execution_err,
+        io::{decode_shuffle_index, ShuffleIndexFormat},
+        spark_hash::create_murmur3_hashes,
+    };
+    use itertools::Itertools;
+    use parking_lot::Mutex as SyncMutex;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use tempfile::TempDir;
+    use tokio::sync::oneshot;
+
+    use crate::{
+        common::{
+            execution_context::ExecutionContext, ipc_compression::IpcCompressionReader,
+            offsetted::Offsetted,
+        },
+        memmgr::{
+            spill::{DefaultSpillSerializer, SpillSerializer},
+            IsolatedMemManager, MemConsumer, MemManager,
+        },
+        shuffle::{
+            buffered_data::{read_segment, SpillFormat},
+            cancellation::CancellationToken,
+            combiner::SumByKeyCombiner,
+            merge_comparator::SortExprsComparator,
+            segment_format::SegmentFormat,
+            sort_repartitioner::{
+                append_shuffle_output, encode_index, merge_offsets_mem_size, merge_shuffle_spills,
+                merge_spills, read_index_file, write_index_file, MergeProgress, ShuffleSpill,
+                SortShuffleRepartitioner,
+            },
+            HashAlgorithm, Partitioning, ShuffleRepartitioner, ShuffleRepartitionerStats,
+        },
+    };
+
+    fn build_table_i32(
+        a: (&str, &Vec<i32>),
+        b: (&str, &Vec<i32>),
+        c: (&str, &Vec<i32>),
+    ) -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new(a.0, DataType::Int32, false),
+            Field::new(b.0, DataType::Int32, false),
+            Field::new(c.0, DataType::Int32, false),
+        ]);
+
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(Int32Array::from(a.1.clone())),
+                Arc::new(Int32Array::from(b.1.clone())),
+                Arc::new(Int32Array::from(c.1.clone())),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn hash_partitioning(num_partitions: usize) -> Partitioning {
+        Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0))],
+            num_partitions,
+            HashAlgorithm::default(),
+        )
+    }
+
+    /// execution context and output files of a test, consumers are registered
+    /// to a mem manager isolated from other tests
+    struct TestEnv {
+        exec_ctx: Arc<ExecutionContext>,
+        metrics: ExecutionPlanMetricsSet,
+        output_dir: TempDir,
+        data_file: PathBuf,
+        index_file: PathBuf,
+        _mem_manager: IsolatedMemManager,
+    }
+
+    impl TestEnv {
+        fn try_new(schema: SchemaRef, mem_total: usize) -> Result<Self> {
+            let mem_manager = MemManager::isolate(mem_total);
+            let session_ctx = SessionContext::new();
+            let metrics = ExecutionPlanMetricsSet::new();
+            let exec_ctx = ExecutionContext::new(session_ctx.task_ctx(), 0, schema, &metrics);
+            let output_dir = tempfile::tempdir()?;
+            let data_file = output_dir.path().join("data");
+            let index_file = output_dir.path().join("index");
+            Ok(Self {
+                exec_ctx,
+                metrics,
+                output_dir,
+                data_file,
+                index_file,
+                _mem_manager: mem_manager,
+            })
+        }
+
+        /// creates a registered repartitioner writing to the output files,
+        /// configured by `build`
+        fn new_repartitioner(
+            &self,
+            partitioning: Partitioning,
+            build: impl FnOnce(SortShuffleRepartitioner) -> Result<SortShuffleRepartitioner>,
+        ) -> Result<Arc<SortShuffleRepartitioner>> {
+            self.new_repartitioner_with_files(
+                &self.data_file,
+                &self.index_file,
+                partitioning,
+                build,
+            )
+        }
+
+        fn new_repartitioner_with_files(
+            &self,
+            data_file: &Path,
+            index_file: &Path,
+            partitioning: Partitioning,
+            build: impl FnOnce(SortShuffleRepartitioner) -> Result<SortShuffleRepartitioner>,
+        ) -> Result<Arc<SortShuffleRepartitioner>> {
+            let repartitioner = Arc::new(build(SortShuffleRepartitioner::new(
+                self.exec_ctx.clone(),
+                data_file.to_string_lossy().to_string(),
+                index_file.to_string_lossy().to_string(),
+                partitioning,
+                Time::new(),
+            ))?);
+            MemManager::register_consumer(repartitioner.clone(), true)?;
+            Ok(repartitioner)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spill_metrics_published_on_spill() -> Result<()> {
+        let record_batch = build_table_i32(
+            ("a", &vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10]),
+            ("b", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ("c", &vec![5, 6, 7, 8, 9, 0, 1, 2, 3, 4]),
+        );
+        let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), Ok)?;
+
+        repartitioner.insert_batch(record_batch).await?;
+        repartitioner.force_spill().await?;
+
+        // spill metrics are available before shuffle_write() is called
+        // small spill is kept resident and never touches disk
+        let spill_metrics = env.exec_ctx.spill_metrics();
+        assert!(spill_metrics.mem_spill_count.value() > 0);
+        assert_eq!(spill_metrics.disk_spill_size.value(), 0);
+
+        // spilling again flushes the resident spill
+        repartitioner.force_spill().await?;
+        assert_eq!(spill_metrics.mem_spill_count.value(), 1);
+        assert!(spill_metrics.disk_spill_size.value() > 0);
+
+        repartitioner.shuffle_write().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_on_first_spill_fired_once() -> Result<()> {
+        let record_batch = build_table_i32(
+            ("a", &vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10]),
+            ("b", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ("c", &vec![5, 6, 7, 8, 9, 0, 1, 2, 3, 4]),
+        );
+        let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), Ok)?;
+
+        let num_fired = Arc::new(AtomicUsize::new(0));
+        let num_fired_cloned = num_fired.clone();
+        repartitioner.set_on_first_spill(Box::new(move || {
+            num_fired_cloned.fetch_add(1, SeqCst);
+        }));
+
+        // spilling empty buffers does not fire the callback
+        repartitioner.force_spill().await?;
+        assert_eq!(num_fired.load(SeqCst), 0);
+
+        for _ in 0..3 {
+            repartitioner.insert_batch(record_batch.clone()).await?;
+            repartitioner.force_spill().await?;
+            assert_eq!(num_fired.load(SeqCst), 1);
+        }
+        repartitioner.shuffle_write().await?;
+        assert_eq!(num_fired.load(SeqCst), 1);
+        Ok(())
+    }
+
+    enum InsertMode {
+        Serial,
+        Bulk,
+        Stream,
+    }
+
+    async fn shuffle_with_inserts(
+        batches: Vec<RecordBatch>,
+        insert_mode: InsertMode,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let env = TestEnv::try_new(batches[0].schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), Ok)?;
+
+        match insert_mode {
+            InsertMode::Serial => {
+                for batch in batches {
+                    repartitioner.insert_batch(batch).await?;
+                }
+            }
+            InsertMode::Bulk => repartitioner.insert_batches(batches).await?,
+            InsertMode::Stream => {
+                let schema = batches[0].schema();
+                let stream = futures::stream::iter(batches.into_iter().map(Ok));
+                let input = Box::pin(RecordBatchStreamAdapter::new(schema, stream));
+                repartitioner.insert_stream(input).await?;
+            }
+        }
+        repartitioner.force_spill().await?;
+        repartitioner.shuffle_write().await?;
+        Ok((
+            std::fs::read(&env.data_file)?,
+            std::fs::read(&env.index_file)?,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_bulk_insert_matches_serial_insert() -> Result<()> {
+        let batches = (0..10)
+            .map(|i| {
+                let a = (0..100).map(|j| (i * 100 + j) % 37).collect::<Vec<_>>();
+                let b = (0..100).map(|j| i * 100 + j).collect::<Vec<_>>();
+                let c = (0..100).map(|j| j % 7).collect::<Vec<_>>();
+                build_table_i32(("a", &a), ("b", &b), ("c", &c))
+            })
+            .collect::<Vec<_>>();
+
+        let serial_output = shuffle_with_inserts(batches.clone(), InsertMode::Serial).await?;
+        let bulk_output = shuffle_with_inserts(batches, InsertMode::Bulk).await?;
+        assert!(!serial_output.0.is_empty());
+        assert_eq!(bulk_output, serial_output);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_insert_stream_matches_serial_insert() -> Result<()> {
+        let batches = (0..50)
+            .map(|i| {
+                let a = (0..20).map(|j| (i * 20 + j) % 37).collect::<Vec<_>>();
+                let b = (0..20).map(|j| i * 20 + j).collect::<Vec<_>>();
+                let c = (0..20).map(|j| j % 7).collect::<Vec<_>>();
+                build_table_i32(("a", &a), ("b", &b), ("c", &c))
+            })
+            .collect::<Vec<_>>();
+
+        let serial_output = shuffle_with_inserts(batches.clone(), InsertMode::Serial).await?;
+        let stream_output = shuffle_with_inserts(batches.clone(), InsertMode::Stream).await?;
+        assert!(!serial_output.0.is_empty());
+        assert_eq!(stream_output, serial_output);
+
+        // errors of the input stream are propagated
+        let schema = batches[0].schema();
+        let env = TestEnv::try_new(schema.clone(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), Ok)?;
+        let stream = futures::stream::iter(vec![
+            Ok(batches[0].clone()),
+            Err(DataFusionError::Execution("input error".to_string())),
+        ]);
+        let input = Box::pin(RecordBatchStreamAdapter::new(schema, stream));
+        let err = repartitioner.insert_stream(input).await.unwrap_err();
+        assert!(err.to_string().contains("input error"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spill_statistics_metrics() -> Result<()> {
+        let record_batch = build_table_i32(
+            ("a", &vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10]),
+            ("b", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ("c", &vec![5, 6, 7, 8, 9, 0, 1, 2, 3, 4]),
+        );
+        let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), Ok)?;
+
+        // produce two spills
+        for _ in 0..2 {
+            repartitioner.insert_batch(record_batch.clone()).await?;
+            repartitioner.force_spill().await?;
+        }
+        repartitioner.shuffle_write().await?;
+
+        let metrics = env.metrics.clone_inner();
+        let metric_value = |name: &str| {
+            metrics
+                .sum_by_name(name)
+                .unwrap_or_else(|| panic!("missing metric: {name}"))
+                .as_usize()
+        };
+        assert_eq!(metric_value("num_spills"), 2);
+        assert!(metric_value("max_spill_bytes") > 0);
+        assert!(metric_value("merged_partitions") > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_buffered_batches() -> Result<()> {
+        let record_batch = build_table_i32(
+            ("a", &vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10]),
+            ("b", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ("c", &vec![5, 6, 7, 8, 9, 0, 1, 2, 3, 4]),
+        );
+        let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), |repartitioner| {
+            Ok(repartitioner.with_max_buffered_batches(Some(3)))
+        })?;
+
+        // tiny batches only spill when the cap is reached
+        for i in 1..=7 {
+            repartitioner.insert_batch(record_batch.clone()).await?;
+            assert_eq!(repartitioner.spills.lock().await.len(), i / 3);
+            assert_eq!(repartitioner.data.lock().await.num_rows(), i % 3 * 10);
+        }
+        repartitioner
+            .insert_batches(vec![record_batch.clone(); 2])
+            .await?;
+        assert_eq!(repartitioner.spills.lock().await.len(), 3);
+        assert_eq!(repartitioner.data.lock().await.num_rows(), 0);
+
+        repartitioner.shuffle_write().await?;
+        let offsets = read_index_file(&env.index_file.to_string_lossy())?;
+        assert_eq!(offsets.len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_estimated_spill_bytes() -> Result<()> {
+        let mut rng = StdRng::seed_from_u64(37);
+        let batches = (0..10)
+            .map(|_| {
+                // random values are barely compressible
+                let mut random_col = || (0..500).map(|_| rng.random()).collect::<Vec<i32>>();
+                let (a, b, c) = (random_col(), random_col(), random_col());
+                build_table_i32(("a", &a), ("b", &b), ("c", &c))
+            })
+            .collect::<Vec<_>>();
+        let env = TestEnv::try_new(batches[0].schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), Ok)?;
+        assert_eq!(repartitioner.estimated_spill_bytes().await?, 0);
+
+        repartitioner.insert_batches(batches).await?;
+        let mem_used = repartitioner.data.lock().await.mem_used();
+        let estimated = repartitioner.estimated_spill_bytes().await?;
+        assert_eq!(repartitioner.data.lock().await.mem_used(), mem_used);
+
+        // compare with the size of actually spilled data
+        let data = repartitioner.data.lock().await.drain();
+        let mut spilled = vec![];
+        data.write(&mut spilled)?;
+        let ratio = spilled.len() as f64 / estimated as f64;
+        assert!(
+            (0.8..1.2).contains(&ratio),
+            "estimated: {estimated}, actual: {}",
+            spilled.len()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stats() -> Result<()> {
+        let record_batch = build_table_i32(
+            ("a", &vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10]),
+            ("b", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ("c", &vec![5, 6, 7, 8, 9, 0, 1, 2, 3, 4]),
+        );
+        let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), Ok)?;
+        assert_eq!(repartitioner.stats(), ShuffleRepartitionerStats::default());
+
+        repartitioner.insert_batch(record_batch.clone()).await?;
+        repartitioner.insert_batch(record_batch.clone()).await?;
+        let stats = repartitioner.stats();
+        assert_eq!(stats.num_buffered_rows, 20);
+        assert_eq!(stats.num_spills, 0);
+        assert!(stats.buffered_bytes > 0);
+
+        repartitioner.force_spill().await?;
+        let stats = repartitioner.stats();
+        assert_eq!(stats.num_buffered_rows, 0);
+        assert_eq!(stats.num_spills, 1);
+        assert_eq!(stats.buffered_bytes, 0);
+
+        repartitioner.insert_batch(record_batch.clone()).await?;
+        let stats = repartitioner.stats();
+        assert_eq!(stats.num_buffered_rows, 10);
+
+        // batches queued while buffered data is locked are counted
+        let data = repartitioner.data.lock().await;
+        repartitioner.insert_batch(record_batch).await?;
+        let queued_stats = repartitioner.stats();
+        assert_eq!(queued_stats.num_buffered_rows, 20);
+        assert!(queued_stats.buffered_bytes > stats.buffered_bytes);
+        drop(data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_input_shuffle_write() -> Result<()> {
+        let schema = build_table_i32(("a", &vec![]), ("b", &vec![]), ("c", &vec![])).schema();
+        let env = TestEnv::try_new(schema, 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), Ok)?;
+
+        // spilling without any data must not produce empty spills
+        repartitioner.force_spill().await?;
+        repartitioner.shuffle_write().await?;
+
+        assert_eq!(std::fs::read(&env.data_file)?.len(), 0);
+        assert_eq!(std::fs::read(&env.index_file)?, vec![0u8; 5 * 8]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_input_output_readable() -> Result<()> {
+        let empty_batch = build_table_i32(("a", &vec![]), ("b", &vec![]), ("c", &vec![]));
+        let schema = empty_batch.schema();
+        for (index_format, insert_empty_batch) in [
+            (ShuffleIndexFormat::Dense, false),
+            (ShuffleIndexFormat::Dense, true),
+            (ShuffleIndexFormat::Sparse, true),
+        ] {
+            let env = TestEnv::try_new(schema.clone(), 1000000)?;
+            let repartitioner = env.new_repartitioner(hash_partitioning(10), |repartitioner| {
+                Ok(repartitioner.with_index_format(index_format))
+            })?;
+            if insert_empty_batch {
+                repartitioner.insert_batch(empty_batch.clone()).await?;
+            }
+            repartitioner.shuffle_write().await?;
+
+            // both files exist and every partition reads zero batches
+            assert!(env.data_file.exists() && env.index_file.exists());
+            assert_eq!(std::fs::metadata(&env.data_file)?.len(), 0);
+            let offsets = read_index_file(&env.index_file.to_string_lossy())?;
+            assert_eq!(offsets, vec![0; 11]);
+            let partitions = read_partition_values(&env.data_file, &env.index_file, &schema)?;
+            assert_eq!(partitions, vec![Vec::<i32>::new(); 10]);
+            let stats = repartitioner.output_stats().expect("output stats");
+            assert_eq!(stats.written_rows(), 0);
+        }
+        Ok(())
+    }
+
+    // blocks the merge on the first decoded batch until released, which only
+    // happens in merge validation while spills are being merged
+    struct HandshakeSerializer(SyncMutex<Option<(oneshot::Sender<()>, mpsc::Receiver<()>)>>);
+
+    impl SpillSerializer for HandshakeSerializer {
+        fn write_batch(
+            &self,
+            num_rows: usize,
+            cols: &[ArrayRef],
+            output: &mut dyn Write,
+        ) -> Result<()> {
+            DefaultSpillSerializer.write_batch(num_rows, cols, output)
+        }
+
+        fn read_batch(
+            &self,
+            input: &mut dyn Read,
+            schema: &SchemaRef,
+        ) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+            if let Some((started_tx, release_rx)) = self.0.lock().take() {
+                let _ = started_tx.send(());
+                // only bounds the test if the executor is blocked
+                if release_rx.recv_timeout(Duration::from_secs(60)).is_err() {
+                    return df_execution_err!("merge not released, executor is blocked");
+                }
+            }
+            DefaultSpillSerializer.read_batch(input, schema)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_shuffle_write_does_not_block_executor() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..50).collect()),
+            ("b", &(50..100).collect()),
+            ("c", &(100..150).collect()),
+        );
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let (started_tx, started_rx) = oneshot::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let serializer = HandshakeSerializer(SyncMutex::new(Some((started_tx, release_rx))));
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), |repartitioner| {
+            Ok(repartitioner
+                .with_spill_serializer(Arc::new(serializer))
+                .with_merge_validation(true))
+        })?;
+        for i in 0..2 {
+            repartitioner.insert_batch(batch.slice(i * 25, 25)).await?;
+            repartitioner.force_spill().await?;
+        }
+
+        // the merge waits for a task on the only worker thread, which can only
+        // run if the merge does not occupy the worker
+        let writer = tokio::spawn(async move { repartitioner.shuffle_write().await });
+        let releaser = tokio::spawn(async move {
+            started_rx.await.expect("merge not started");
+            release_tx.send(()).expect("merge not waiting");
+        });
+        writer.await.expect("tokio spawn error")?;
+        releaser.await.expect("tokio spawn error");
+        assert!(std::fs::metadata(&env.data_file)?.len() > 0);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_merge_offsets_mem_reserved() -> Result<()> {
+        let num_partitions = 10000;
+        let record_batch = build_table_i32(
+            ("a", &vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10]),
+            ("b", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ("c", &vec![5, 6, 7, 8, 9, 0, 1, 2, 3, 4]),
+        );
+        let env = TestEnv::try_new(record_batch.schema(), 1000000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(num_partitions), Ok)?;
+        let num_spills = 4;
+        for _ in 0..num_spills {
+            repartitioner.insert_batch(record_batch.clone()).await?;
+            repartitioner.force_spill().await?;
+        }
+
+        // offsets of all spills and merged offsets
+        let offsets_mem_size = (num_spills + 1) * (num_partitions + 1) * size_of::<u64>();
+        assert_eq!(
+            merge_offsets_mem_size(&repartitioner.spills.lock().await, num_partitions),
+            offsets_mem_size,
+        );
+
+        // watch memory used while merging
+        let consumer_info = repartitioner.consumer_info();
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = tokio::spawn({
+            let done = done.clone();
+            async move {
+                let mut max_mem_used = 0;
+                while !done.load(SeqCst) {
+                    max_mem_used = max_mem_used.max(consumer_info.mem_used());
+                    tokio::task::yield_now().await;
+                }
+                (max_mem_used, consumer_info.mem_used())
+            }
+        });
+        tokio::spawn(async move {
+            let result = repartitioner.shuffle_write().await;
+            done.store(true, SeqCst);
+            result
+        })
+        .await
+        .expect("tokio spawn error")?;
+        let (max_mem_used, final_mem_used) = watcher.await.expect("tokio spawn error");
+
+        assert!(max_mem_used >= offsets_mem_size);
+        assert_eq!(final_mem_used, 0);
+        Ok(())
+    }
+
+    async fn shuffle_to_files(
+        record_batch: RecordBatch,
+        data_file: &Path,
+        index_file: &Path,
+        append: bool,
+    ) -> Result<()> {
+        let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner_with_files(
+            data_file,
+            index_file,
+            hash_partitioning(4),
+            |repartitioner| Ok(repartitioner.with_append(append)),
+        )?;
+        repartitioner.insert_batch(record_batch).await?;
+        repartitioner.shuffle_write().await
+    }
+
+    #[tokio::test]
+    async fn test_append_shuffle_write() -> Result<()> {
+        let batch1 = build_table_i32(
+            ("a", &vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10]),
+            ("b", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ("c", &vec![5, 6, 7, 8, 9, 0, 1, 2, 3, 4]),
+        );
+        let batch2 = build_table_i32(
+            ("a", &vec![1, 2, 3, 4, 5, 6]),
+            ("b", &vec![10, 11, 12, 13, 14, 15]),
+            ("c", &vec![0, 0, 0, 1, 1, 1]),
+        );
+        let schema = batch1.schema();
+
+        let output_dir = tempfile::tempdir()?;
+        let file = |name: &str| output_dir.path().join(name);
+        shuffle_to_files(batch1.clone(), &file("data1"), &file("index1"), false).await?;
+        shuffle_to_files(batch2.clone(), &file("data2"), &file("index2"), false).await?;
+        shuffle_to_files(batch1, &file("data"), &file("index"), true).await?;
+        shuffle_to_files(batch2, &file("data"), &file("index"), true).await?;
+        assert!(!file("data.appending").exists());
+        assert!(!file("index.appending").exists());
+
+        let read_partitions = |data_file: PathBuf, index_file: PathBuf| -> Result<Vec<Vec<u8>>> {
+            let data = std::fs::read(data_file)?;
+            let offsets = read_index_file(&index_file.to_string_lossy())?;
+            Ok(offsets
+                .iter()
+                .tuple_windows()
+                .map(|(&beg, &end)| data[beg as usize..end as usize].to_vec())
+                .collect())
+        };
+        let partitions1 = read_partitions(file("data1"), file("index1"))?;
+        let partitions2 = read_partitions(file("data2"), file("index2"))?;
+        let partitions = read_partitions(file("data"), file("index"))?;
+        assert_eq!(partitions.len(), 4);
+
+        let mut num_rows = 0;
+        for (i, partition) in partitions.into_iter().enumerate() {
+            assert_eq!(
+                partition,
+                [&partitions1[i][..], &partitions2[i][..]].concat()
+            );
+
+            let mut reader = IpcCompressionReader::new(Cursor::new(partition));
+            while let Some((batch_num_rows, _)) = reader.read_batch(&schema)? {
+                num_rows += batch_num_rows;
+            }
+        }
+        assert_eq!(num_rows, 16);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_parquet_shuffle_output() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &vec![1, 2, 3]),
+            ("b", &vec![4, 5, 6]),
+            ("c", &vec![7, 8, 9]),
+        );
+        let parquet_format = SegmentFormat::new(SpillFormat::Parquet, batch.schema());
+        let ipc_format = SegmentFormat::new(SpillFormat::Ipc, batch.schema());
+        let output_dir = tempfile::tempdir()?;
+        let file = |name: &str| output_dir.path().join(name).to_string_lossy().to_string();
+        let write_output = |name: &str| -> Result<()> {
+            let mut writer = parquet_format.writer(File::create(file(name))?, vec![]);
+            let mut offsets = vec![0];
+            for _ in 0..2 {
+                writer.write_segment(std::iter::once(batch.clone()), &Time::new())?;
+                offsets.push(writer.count());
+            }
+            let index_file = file(&format!("{name}.index"));
+            write_index_file(&index_file, &offsets, ShuffleIndexFormat::Dense)
+        };
+
+        // existing parquet output is probed with the format it is written in
+        write_output("data")?;
+        write_output("appended")?;
+        let append = |format: &SegmentFormat| {
+            append_shuffle_output(
+                &file("data"),
+                &file("data.index"),
+                &file("appended"),
+                &file("appended.index"),
+                format,
+                ShuffleIndexFormat::Dense,
+            )
+        };
+        let err = append(&ipc_format).unwrap_err();
+        assert!(err.to_string().contains("schema mismatched"), "{err}");
+        append(&parquet_format)?;
+
+        let data = Bytes::from(std::fs::read(file("data"))?);
+        let offsets = read_index_file(&file("data.index"))?;
+        assert_eq!(offsets.len(), 3);
+        for (&beg, &end) in offsets.iter().tuple_windows() {
+            let batches = parquet_format.read_segment(data.slice(beg as usize..end as usize))?;
+            assert_eq!(batches, vec![batch.clone(), batch.clone()]);
+        }
+        Ok(())
+    }
+
+    async fn shuffle_partition_values(
+        batch: RecordBatch,
+        partition_id_mapping: Option<Vec<u32>>,
+        spill: bool,
+    ) -> Result<Vec<Vec<i32>>> {
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), |repartitioner| {
+            if let Some(partition_id_mapping) = partition_id_mapping {
+                return repartitioner.with_partition_id_mapping(partition_id_mapping);
+            }
+            Ok(repartitioner)
+        })?;
+
+        let schema = batch.schema();
+        let (batch1, batch2) = (batch.slice(0, 10), batch.slice(10, batch.num_rows() - 10));
+        repartitioner.insert_batch(batch1).await?;
+        if spill {
+            repartitioner.force_spill().await?;
+        }
+        repartitioner.insert_batch(batch2).await?;
+        repartitioner.shuffle_write().await?;
+        read_partition_values(&env.data_file, &env.index_file, &schema)
+    }
+
+    /// reads sorted values of the first column in each output partition
+    fn read_partition_values(
+        data_file: &Path,
+        index_file: &Path,
+        schema: &SchemaRef,
+    ) -> Result<Vec<Vec<i32>>> {
+        let data = std::fs::read(data_file)?;
+        let offsets = read_index_file(&index_file.to_string_lossy())?;
+        let mut partitions = vec![];
+        for (&beg, &end) in offsets.iter().tuple_windows() {
+            let segment = data[beg as usize..end as usize].to_vec();
+            let mut reader = IpcCompressionReader::new(Cursor::new(segment));
+            let mut values = vec![];
+            while let Some((_, cols)) = reader.read_batch(schema)? {
+                let col = cols[0].as_any().downcast_ref::<Int32Array>().unwrap();
+                values.extend(col.values().iter().cloned());
+            }
+            values.sort_unstable();
+            partitions.push(values);
+        }
+        Ok(partitions)
+    }
+
+    #[tokio::test]
+    async fn test_compact_spills() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..50).collect()),
+            ("b", &(50..100).collect()),
+            ("c", &(100..150).collect()),
+        );
+        let expected = shuffle_partition_values(batch.clone(), None, false).await?;
+
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), Ok)?;
+
+        // produce 5 tiny spills and compact them into one
+        for i in 0..5 {
+            repartitioner.insert_batch(batch.slice(i * 10, 10)).await?;
+            repartitioner.force_spill().await?;
+        }
+        assert_eq!(repartitioner.spills.lock().await.len(), 5);
+        repartitioner.compact_spills(5).await?;
+        assert_eq!(repartitioner.spills.lock().await.len(), 1);
+
+        repartitioner.shuffle_write().await?;
+        let partitions = read_partition_values(&env.data_file, &env.index_file, &batch.schema())?;
+        assert_eq!(partitions, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_spills_in_place() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..100).collect()),
+            ("b", &(100..200).collect()),
+            ("c", &(200..300).collect()),
+        );
+        let expected = shuffle_partition_values(batch.clone(), None, false).await?;
+
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), Ok)?;
+
+        // the two small spills in the middle are the smallest window
+        let chunks = [0..40, 40..50, 50..60, 60..100];
+        for chunk in &chunks {
+            repartitioner
+                .insert_batch(batch.slice(chunk.start, chunk.len()))
+                .await?;
+            repartitioner.force_spill().await?;
+        }
+
+        // a failed merge keeps all spills
+        let mut broken_offsets = vec![1000; 9];
+        broken_offsets[0] = 0;
+        let broken_spill: ShuffleSpill = Offsetted::new(broken_offsets, Box::new(Vec::<u8>::new()));
+        repartitioner.spills.lock().await.push(broken_spill);
+        assert!(repartitioner.compact_spills(5).await.is_err());
+        assert_eq!(repartitioner.spills.lock().await.len(), 5);
+        repartitioner.spills.lock().await.pop();
+
+        repartitioner.compact_spills(2).await?;
+        assert_eq!(repartitioner.spills.lock().await.len(), 3);
+        repartitioner.shuffle_write().await?;
+        let partitions = read_partition_values(&env.data_file, &env.index_file, &batch.schema())?;
+        assert_eq!(partitions, expected);
+
+        // rows of each partition are still in the order of spills
+        let data = std::fs::read(&env.data_file)?;
+        let offsets = read_index_file(&env.index_file.to_string_lossy())?;
+        for (&beg, &end) in offsets.iter().tuple_windows() {
+            let segment = data[beg as usize..end as usize].to_vec();
+            let mut reader = IpcCompressionReader::new(Cursor::new(segment));
+            let mut chunk_ids = vec![];
+            while let Some((_, cols)) = reader.read_batch(&batch.schema())? {
+                for &value in cols[0].as_primitive::<Int32Type>().values() {
+                    chunk_ids.push(chunks.iter().position(|chunk| chunk.contains(&value)));
+                }
+            }
+            assert!(chunk_ids.is_sorted(), "{chunk_ids:?}");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sorted_merge_of_range_partitioned_spills() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Int32, false),
+        ]));
+        let sort_exprs = vec![PhysicalSortExpr {
+            expr: Arc::new(Column::new("a", 0)),
+            options: Default::default(),
+        }];
+        let bounds = Arc::new(Int32Array::from(vec![50, 100, 150])) as ArrayRef;
+        let bounds =
+            RowConverter::new(vec![SortField::new(DataType::Int32)])?.convert_columns(&[bounds])?;
+
+        let env = TestEnv::try_new(schema.clone(), 1000000)?;
+        let partitioning =
+            Partitioning::RangePartitioning(sort_exprs.clone(), 4, Arc::from(bounds));
+        let comparator = Arc::new(SortExprsComparator::try_new(sort_exprs, &schema)?);
+        let repartitioner = env.new_repartitioner(partitioning, |repartitioner| {
+            Ok(repartitioner.with_merge_comparator(comparator))
+        })?;
+
+        // each spill is sorted, values of spills are interleaved
+        for i in 0..6 {
+            let a = (i..200).step_by(6).collect::<Vec<i32>>();
+            let b = vec![i; a.len()];
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int32Array::from(a)), Arc::new(Int32Array::from(b))],
+            )?;
+            repartitioner.insert_batch(batch).await?;
+            repartitioner.force_spill().await?;
+        }
+        repartitioner.compact_spills(3).await?;
+        assert_eq!(repartitioner.spills.lock().await.len(), 4);
+        repartitioner.shuffle_write().await?;
+
+        // partitions are read in order without sorting
+        let data = std::fs::read(&env.data_file)?;
+        let offsets = read_index_file(&env.index_file.to_string_lossy())?;
+        let mut values = vec![];
+        for (&beg, &end) in offsets.iter().tuple_windows() {
+            let segment = data[beg as usize..end as usize].to_vec();
+            let mut reader = IpcCompressionReader::new(Cursor::new(segment));
+            while let Some((_, cols)) = reader.read_batch(&schema)? {
+                values.extend(cols[0].as_primitive::<Int32Type>().values().iter().cloned());
+            }
+        }
+        assert_eq!(values, (0..200).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_shuffle_spills() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..50).collect()),
+            ("b", &(50..100).collect()),
+            ("c", &(100..150).collect()),
+        );
+        let expected = shuffle_partition_values(batch.clone(), None, false).await?;
+
+        // two repartitioners produce spills of different parts of the input
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let (data_file, index_file) = (&env.data_file, &env.index_file);
+        let mut spills = vec![];
+        for partition_id in 0..2 {
+            let repartitioner = env.new_repartitioner(hash_partitioning(8), Ok)?;
+            let part = batch.slice(partition_id * 25, 25);
+            repartitioner.insert_batch(part.slice(0, 10)).await?;
+            if partition_id == 0 {
+                repartitioner.force_spill().await?;
+            }
+            repartitioner.insert_batch(part.slice(10, 15)).await?;
+            spills.extend(repartitioner.take_spills().await?);
+            assert_eq!(repartitioner.consumer_info().mem_used(), 0);
+            repartitioner.close().await?;
+        }
+        assert_eq!(spills.len(), 3);
+
+        let offsets = merge_shuffle_spills(
+            spills,
+            8,
+            batch.schema(),
+            &data_file.to_string_lossy(),
+            &index_file.to_string_lossy(),
+        )?;
+        assert_eq!(offsets, read_index_file(&index_file.to_string_lossy())?);
+        let partitions = read_partition_values(data_file, index_file, &batch.schema())?;
+        assert_eq!(partitions, expected);
+
+        // spills with different number of partitions cannot be merged
+        let spill = ShuffleSpill::new(vec![0, 0, 0], Box::new(vec![]));
+        assert!(merge_shuffle_spills(
+            vec![spill],
+            8,
+            batch.schema(),
+            &data_file.to_string_lossy(),
+            &index_file.to_string_lossy(),
+        )
+        .is_err());
+
+        // partially written output is removed if merging fails
+        let spill = ShuffleSpill::new(vec![0; 9], Box::new(vec![]));
+        assert!(data_file.exists());
+        assert!(merge_shuffle_spills(
+            vec![spill],
+            8,
+            batch.schema(),
+            &data_file.to_string_lossy(),
+            &env.output_dir.path().to_string_lossy(), // not writable as a file
+        )
+        .is_err());
+        assert!(!data_file.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merge_validation() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..100).collect()),
+            ("b", &(100..200).collect()),
+            ("c", &(200..300).collect()),
+        );
+        let expected = shuffle_partition_values(batch.clone(), None, false).await?;
+
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), |repartitioner| {
+            Ok(repartitioner.with_merge_validation(true))
+        })?;
+
+        // merged output of several spills passes validation
+        for i in 0..4 {
+            repartitioner.insert_batch(batch.slice(i * 25, 25)).await?;
+            repartitioner.force_spill().await?;
+        }
+        repartitioner.compact_spills(2).await?;
+        repartitioner.shuffle_write().await?;
+        let partitions = read_partition_values(&env.data_file, &env.index_file, &batch.schema())?;
+        assert_eq!(partitions, expected);
+        repartitioner.close().await?;
+
+        // spills which cannot be decoded fail the merge
+        let spill = ShuffleSpill::new(vec![0, 8], Box::new(b"garbage!".to_vec()));
+        let err = merge_spills(
+            vec![spill],
+            1,
+            &SegmentFormat::new(SpillFormat::Ipc, batch.schema()).with_validation(true),
+            &env.data_file.to_string_lossy(),
+            &env.index_file.to_string_lossy(),
+            ShuffleIndexFormat::Dense,
+            &MergeProgress::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("cannot be decoded"), "{err}");
+        assert!(!env.data_file.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_adjustments() -> Result<()> {
+        let schema = build_table_i32(("a", &vec![]), ("b", &vec![]), ("c", &vec![])).schema();
+        let output_dir = tempfile::tempdir()?;
+        let data_file = output_dir.path().join("data");
+        let index_file = output_dir.path().join("index");
+
+        // 4 non-empty partition segments in 3 spills
+        let spills = vec![
+            ShuffleSpill::new(vec![0, 3, 3, 5, 9], Box::new(b"aaaccdddd".to_vec())),
+            ShuffleSpill::new(vec![0, 0, 2, 2, 2], Box::new(b"BB".to_vec())),
+            ShuffleSpill::new(vec![0, 0, 0, 0, 0], Box::new(Vec::<u8>::new())),
+        ];
+        let progress = MergeProgress::default();
+        let offsets = merge_spills(
+            spills,
+            4,
+            &SegmentFormat::new(SpillFormat::Ipc, schema),
+            &data_file.to_string_lossy(),
+            &index_file.to_string_lossy(),
+            ShuffleIndexFormat::Dense,
+            &progress,
+        )?;
+        assert_eq!(offsets, vec![0, 3, 5, 7, 11]);
+        assert_eq!(std::fs::read(&data_file)?, b"aaaBBccdddd");
+        assert_eq!(progress.merge_adjustments.value(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skewed_output_stats() -> Result<()> {
+        let shuffle_output_stats = |a: Vec<i32>| async move {
+            let mut rng = StdRng::seed_from_u64(17);
+            let mut random_col = || (0..a.len()).map(|_| rng.random()).collect::<Vec<i32>>();
+            let (b, c) = (random_col(), random_col());
+            let batch = build_table_i32(("a", &a), ("b", &b), ("c", &c));
+            let env = TestEnv::try_new(batch.schema(), 1000000)?;
+            let repartitioner = env.new_repartitioner(hash_partitioning(8), Ok)?;
+            assert!(repartitioner.output_stats().is_none());
+            repartitioner.insert_batch(batch).await?;
+            repartitioner.shuffle_write().await?;
+            repartitioner.close().await?;
+
+            let stats = repartitioner.output_stats().expect("missing output stats");
+            let metrics = env.metrics.clone_inner();
+            let metric_value = |name: &str| metrics.sum_by_name(name).unwrap().as_usize();
+            assert_eq!(
+                metric_value("skew_max_partition_bytes"),
+                stats.max_bytes() as usize
+            );
+            assert_eq!(
+                metric_value("skew_ratio_x1000"),
+                (stats.skew_ratio() * 1000.0) as usize
+            );
+            Ok::<_, DataFusionError>(stats)
+        };
+
+        // uniformly distributed keys
+        let stats = shuffle_output_stats((0..20000).collect()).await?;
+        assert_eq!(stats.partition_bytes.len(), 8);
+        assert!(stats.partition_bytes.iter().all(|&bytes| bytes > 0));
+        assert!(stats.skew_ratio() < 2.0);
+        assert_eq!(stats.skewed_partition_id(100.0), None);
+
+        // most rows have the same key
+        let hot_key: ArrayRef = Arc::new(Int32Array::from(vec![7]));
+        let hot_key_partition_id = create_murmur3_hashes(1, &[hot_key], 42)[0].rem_euclid(8);
+        let hot_key_partition_id = hot_key_partition_id as usize;
+        let skewed_keys = (0..20).chain(std::iter::repeat_n(7, 20000)).collect();
+        let stats = shuffle_output_stats(skewed_keys).await?;
+        assert!(stats.skew_ratio() > 100.0);
+        assert!(stats.p99_bytes() <= stats.max_bytes());
+        assert_eq!(
+            stats.max_bytes(),
+            stats.partition_bytes[hot_key_partition_id]
+        );
+        assert_eq!(stats.skewed_partition_id(100.0), Some(hot_key_partition_id));
+        assert_eq!(stats.skewed_partition_id(0.0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_index() -> Result<()> {
+        // offsets beyond 4GB are kept as is
+        let offsets = vec![0, 1 << 32, 5 << 32, 5 << 32];
+        let index_data = encode_index(&offsets, ShuffleIndexFormat::Dense)?;
+        let decoded = index_data
+            .chunks_exact(8)
+            .map(|chunk| i64::from_le_bytes(chunk.try_into().unwrap()) as u64)
+            .collect::<Vec<_>>();
+        assert_eq!(decoded, offsets);
+
+        // offsets not representable in index files, or not monotonic
+        assert!(encode_index(&[0, u64::MAX], ShuffleIndexFormat::Dense).is_err());
+        assert!(encode_index(&[0, 10, 5], ShuffleIndexFormat::Dense).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_index_bitmap() -> Result<()> {
+        // 5 rows into 100 partitions, most partitions are empty
+        let record_batch = build_table_i32(
+            ("a", &vec![1, 2, 3, 4, 5]),
+            ("b", &vec![0; 5]),
+            ("c", &vec![0; 5]),
+        );
+        let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(100), |repartitioner| {
+            Ok(repartitioner.with_index_bitmap(true))
+        })?;
+        repartitioner.insert_batch(record_batch.clone()).await?;
+        repartitioner.shuffle_write().await?;
+
+        let index = decode_shuffle_index(&std::fs::read(&env.index_file)?)?;
+        assert_eq!(index.num_partitions(), 100);
+        assert!(index.non_empty_bitmap.is_some());
+        assert_eq!(
+            read_index_file(&env.index_file.to_string_lossy())?,
+            index.offsets,
+        );
+
+        // non-empty partitions are exactly the partitions of the keys
+        let keys: ArrayRef = record_batch.column(0).clone();
+        let key_partition_ids = create_murmur3_hashes(5, &[keys], 42)
+            .into_iter()
+            .map(|hash| hash.rem_euclid(100) as usize)
+            .collect::<Vec<_>>();
+        for partition_id in 0..100 {
+            let (beg, end) = (index.offsets[partition_id], index.offsets[partition_id + 1]);
+            assert_eq!(index.is_non_empty(partition_id), beg < end);
+            assert_eq!(
+                index.is_non_empty(partition_id),
+                key_partition_ids.contains(&partition_id),
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_row_count_round_trip() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..50).collect()),
+            ("b", &(50..100).collect()),
+            ("c", &(100..150).collect()),
+        );
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+
+        // 50 rows into 100 partitions, some partitions are empty
+        let repartitioner = env.new_repartitioner(hash_partitioning(100), Ok)?;
+        repartitioner.insert_batch(batch.slice(0, 20)).await?;
+        repartitioner.force_spill().await?;
+        repartitioner
+            .insert_batches(vec![batch.slice(20, 10), batch.slice(30, 20)])
+            .await?;
+        repartitioner.force_spill().await?;
+        repartitioner.shuffle_write().await?;
+
+        let stats = repartitioner.output_stats().expect("output stats");
+        assert_eq!(stats.input_rows, Some(50));
+        assert_eq!(stats.partition_rows.len(), 100);
+        assert_eq!(stats.written_rows(), 50);
+        assert!(stats.partition_rows.contains(&0));
+
+        // rows read from each partition match the written rows
+        let read_rows = read_partition_values(&env.data_file, &env.index_file, &batch.schema())?
+            .iter()
+            .map(|values| values.len() as u64)
+            .collect::<Vec<_>>();
+        assert_eq!(read_rows, stats.partition_rows);
+
+        let metrics = env.metrics.clone_inner();
+        let metric = |name: &str| metrics.sum_by_name(name).map(|v| v.as_usize());
+        assert_eq!(metric("input_rows"), Some(50));
+        assert_eq!(metric("written_rows"), Some(50));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_inserts_and_spills() -> Result<()> {
+        let (num_inserters, num_batches, batch_size) = (8, 50, 10);
+        let batch = move |start: i32| {
+            let values = (start..start + batch_size).collect::<Vec<_>>();
+            build_table_i32(("a", &values), ("b", &values), ("c", &values))
+        };
+        let schema = batch(0).schema();
+        let env = TestEnv::try_new(schema.clone(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), Ok)?;
+
+        // spills run concurrently with inserts from many tasks
+        let inserters = (0..num_inserters)
+            .map(|i| {
+                let repartitioner = repartitioner.clone();
+                tokio::spawn(async move {
+                    for j in 0..num_batches {
+                        let start = (i * num_batches + j) * batch_size;
+                        repartitioner.insert_batch(batch(start)).await?;
+                        tokio::task::yield_now().await;
+                    }
+                    Ok::<_, DataFusionError>(())
+                })
+            })
+            .collect::<Vec<_>>();
+        let spiller = tokio::spawn({
+            let repartitioner = repartitioner.clone();
+            async move {
+                for _ in 0..20 {
+                    repartitioner.force_spill().await?;
+                    tokio::task::yield_now().await;
+                }
+                Ok::<_, DataFusionError>(())
+            }
+        });
+        for inserter in inserters {
+            inserter.await.expect("inserter panicked")?;
+        }
+        spiller.await.expect("spiller panicked")?;
+        repartitioner.shuffle_write().await?;
+
+        // every inserted row is written exactly once
+        let mut values = read_partition_values(&env.data_file, &env.index_file, &schema)?.concat();
+        values.sort_unstable();
+        let num_rows = num_inserters * num_batches * batch_size;
+        assert_eq!(values, (0..num_rows).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sparse_index() -> Result<()> {
+        // 5 rows into 1000 partitions, most partitions are empty
+        let record_batch = build_table_i32(
+            ("a", &vec![1, 2, 3, 4, 5]),
+            ("b", &vec![0; 5]),
+            ("c", &vec![0; 5]),
+        );
+
+        let mut outputs = vec![];
+        for index_format in [ShuffleIndexFormat::Dense, ShuffleIndexFormat::Sparse] {
+            let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+            let repartitioner = env
+                .new_repartitioner(hash_partitioning(1000), |repartitioner| {
+                    Ok(repartitioner.with_index_format(index_format))
+                })?;
+            repartitioner.insert_batch(record_batch.clone()).await?;
+            repartitioner.shuffle_write().await?;
+            outputs.push((
+                std::fs::metadata(&env.index_file)?.len(),
+                read_index_file(&env.index_file.to_string_lossy())?,
+                std::fs::read(&env.data_file)?,
+            ));
+        }
+        let (dense_size, dense_offsets, dense_data) = &outputs[0];
+        let (sparse_size, sparse_offsets, sparse_data) = &outputs[1];
+        assert_eq!(*dense_size, 8 * 1001);
+        assert!(sparse_size * 50 < *dense_size);
+        assert_eq!(sparse_offsets, dense_offsets);
+        assert_eq!(sparse_data, dense_data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_single_compressed_partition() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..60).collect()),
+            ("b", &(60..120).collect()),
+            ("c", &(120..180).collect()),
+        );
+        let expected = shuffle_partition_values(batch.clone(), None, false).await?;
+
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), Ok)?;
+
+        // each partition is merged from several spills
+        for i in 0..3 {
+            repartitioner.insert_batch(batch.slice(i * 20, 20)).await?;
+            repartitioner.force_spill().await?;
+        }
+        repartitioner.shuffle_write().await?;
+
+        // decode partitions one by one in reverse order, reading only the
+        // byte range of each partition
+        let offsets = read_index_file(&env.index_file.to_string_lossy())?;
+        let mut data = File::open(&env.data_file)?;
+        for partition_id in (0..8).rev() {
+            let (beg, end) = (offsets[partition_id], offsets[partition_id + 1]);
+            data.seek(SeekFrom::Start(beg))?;
+            let input = BufReader::new(data.try_clone()?.take(end - beg));
+            let mut reader = IpcCompressionReader::new(input);
+            let mut values = vec![];
+            while let Some((_, cols)) = reader.read_batch(&batch.schema())? {
+                values.extend(cols[0].as_primitive::<Int32Type>().values().iter().cloned());
+            }
+            values.sort_unstable();
+            assert_eq!(values, expected[partition_id]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reserve_spills_other_consumers() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..50).collect()),
+            ("b", &(50..100).collect()),
+            ("c", &(100..150).collect()),
+        );
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        MemManager::set_thread_task_id(Some((0, 0)));
+        let holder = env.new_repartitioner(hash_partitioning(4), Ok)?;
+        let reserver = env.new_repartitioner(hash_partitioning(4), Ok)?;
+        MemManager::set_thread_task_id(None);
+        holder.insert_batch(batch).await?;
+
+        // more than total memory cannot be reserved, other consumers of the
+        // same task are spilled inline before giving up
+        let total = MemManager::get()?.total();
+        let err = reserver.reserve(total + 1).await.unwrap_err();
+        assert!(matches!(err, DataFusionError::ResourcesExhausted(_)));
+        assert_eq!(holder.stats().num_spills, 1);
+        assert_eq!(holder.stats().num_buffered_rows, 0);
+        assert_eq!(reserver.consumer_info().mem_used(), 0);
+
+        // satisfied reservation grows mem used of the reserver
+        let bytes = 1 + total / 100;
+        reserver.reserve(bytes).await?;
+        assert_eq!(reserver.consumer_info().mem_used(), bytes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_spill_disk_bytes() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..100).collect()),
+            ("b", &(100..200).collect()),
+            ("c", &(200..300).collect()),
+        );
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), |repartitioner| {
+            Ok(repartitioner.with_max_spill_disk_bytes(Some(4096)))
+        })?;
+
+        // spilling fails once flushed spills exceed the cap
+        let mut num_spills = 0;
+        let err = loop {
+            repartitioner.insert_batch(batch.clone()).await?;
+            if let Err(err) = repartitioner.force_spill().await {
+                break err;
+            }
+            num_spills += 1;
+            assert!(num_spills < 1000, "spill disk usage not capped");
+        };
+        assert!(num_spills > 1);
+        assert!(matches!(err, DataFusionError::ResourcesExhausted(_)));
+        assert!(
+            err.to_string().contains("spark.blaze.spill.maxDiskBytes"),
+            "{err}"
+        );
+        repartitioner.close().await?;
+        Ok(())
+    }
+
+    // writes int32 columns as raw little-endian values
+    #[derive(Default)]
+    struct RawInt32Serializer {
+        num_written: AtomicUsize,
+    }
+
+    impl SpillSerializer for RawInt32Serializer {
+        fn write_batch(
+            &self,
+            num_rows: usize,
+            cols: &[ArrayRef],
+            output: &mut dyn Write,
+        ) -> Result<()> {
+            output.write_all(&(num_rows as u32).to_le_bytes())?;
+            for col in cols {
+                for value in col.as_primitive::<Int32Type>().values() {
+                    output.write_all(&value.to_le_bytes())?;
+                }
+            }
+            self.num_written.fetch_add(1, SeqCst);
+            Ok(())
+        }
+
+        fn read_batch(
+            &self,
+            input: &mut dyn Read,
+            schema: &SchemaRef,
+        ) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+            let mut buf = [0u8; 4];
+            match input.read_exact(&mut buf) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            let num_rows = u32::from_le_bytes(buf) as usize;
+            let mut cols: Vec<ArrayRef> = vec![];
+            for _ in schema.fields() {
+                let mut values = Vec::with_capacity(num_rows);
+                for _ in 0..num_rows {
+                    input.read_exact(&mut buf)?;
+                    values.push(i32::from_le_bytes(buf));
+                }
+                cols.push(Arc::new(Int32Array::from(values)));
+            }
+            Ok(Some((num_rows, cols)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_spill_serializer() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..50).collect()),
+            ("b", &(50..100).collect()),
+            ("c", &(100..150).collect()),
+        );
+        let expected = shuffle_partition_values(batch.clone(), None, true).await?;
+
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let serializer = Arc::new(RawInt32Serializer::default());
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), |repartitioner| {
+            Ok(repartitioner.with_spill_serializer(serializer.clone()))
+        })?;
+
+        // spilled and in-memory batches are both merged into the output
+        repartitioner.insert_batch(batch.slice(0, 10)).await?;
+        repartitioner.force_spill().await?;
+        let num_spill_batches = serializer.num_written.load(SeqCst);
+        assert!(num_spill_batches > 0);
+        repartitioner.insert_batch(batch.slice(10, 40)).await?;
+        repartitioner.shuffle_write().await?;
+        assert!(serializer.num_written.load(SeqCst) > num_spill_batches);
+
+        let format = SegmentFormat::new(SpillFormat::Ipc, batch.schema())
+            .with_serializer(serializer.clone());
+        let data = Bytes::from(std::fs::read(&env.data_file)?);
+        let offsets = read_index_file(&env.index_file.to_string_lossy())?;
+        let mut partitions = vec![];
+        for (&beg, &end) in offsets.iter().tuple_windows() {
+            let mut values = format
+                .read_segment(data.slice(beg as usize..end as usize))?
+                .iter()
+                .flat_map(|batch| {
+                    let col = batch.column(0).as_primitive::<Int32Type>();
+                    col.values().to_vec()
+                })
+                .collect::<Vec<_>>();
+            values.sort_unstable();
+            partitions.push(values);
+        }
+        assert_eq!(partitions, expected);
+        Ok(())
+    }
+
+    // cancels the token once a batch is decoded, which only happens in merge
+    // validation while spills are being merged
+    struct CancellingSerializer(CancellationToken);
+
+    impl SpillSerializer for CancellingSerializer {
+        fn write_batch(
+            &self,
+            num_rows: usize,
+            cols: &[ArrayRef],
+            output: &mut dyn Write,
+        ) -> Result<()> {
+            DefaultSpillSerializer.write_batch(num_rows, cols, output)
+        }
+
+        fn read_batch(
+            &self,
+            input: &mut dyn Read,
+            schema: &SchemaRef,
+        ) -> Result<Option<(usize, Vec<ArrayRef>)>> {
+            self.0.cancel();
+            DefaultSpillSerializer.read_batch(input, schema)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_during_merge() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..50).collect()),
+            ("b", &(50..100).collect()),
+            ("c", &(100..150).collect()),
+        );
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let cancellation = CancellationToken::default();
+        let repartitioner = env.new_repartitioner(hash_partitioning(8), |repartitioner| {
+            Ok(repartitioner
+                .with_cancellation(cancellation.clone())
+                .with_spill_serializer(Arc::new(CancellingSerializer(cancellation.clone())))
+                .with_merge_validation(true))
+        })?;
+
+        for i in 0..2 {
+            repartitioner.insert_batch(batch.slice(i * 25, 25)).await?;
+            repartitioner.force_spill().await?;
+        }
+        let err = repartitioner.shuffle_write().await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+        assert!(cancellation.is_cancelled());
+        assert_eq!(repartitioner.consumer_info().mem_used(), 0);
+
+        // later calls fail without doing any work
+        let err = repartitioner.insert_batch(batch.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+        assert_eq!(repartitioner.consumer_info().mem_used(), 0);
+
+        // dropping without close() leaves nothing behind
+        let consumer_info = repartitioner.get_consumer_info().clone();
+        drop(repartitioner);
+        assert!(consumer_info.upgrade().is_none());
+        assert_eq!(std::fs::read_dir(env.output_dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partition_id_mapping() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..30).collect()),
+            ("b", &(30..60).collect()),
+            ("c", &(60..90).collect()),
+        );
+        let partition_id_mapping = vec![0, 0, 0, 1, 1, 2, 2, 2];
+        let original = shuffle_partition_values(batch.clone(), None, false).await?;
+        assert_eq!(original.len(), 8);
+
+        for spill in [false, true] {
+            let coalesced =
+                shuffle_partition_values(batch.clone(), Some(partition_id_mapping.clone()), spill)
+                    .await?;
+            assert_eq!(coalesced.len(), 3);
+
+            for (coalesced_id, values) in coalesced.iter().enumerate() {
+                let mut expected = partition_id_mapping
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &mapped_id)| mapped_id as usize == coalesced_id)
+                    .flat_map(|(original_id, _)| original[original_id].clone())
+                    .collect::<Vec<_>>();
+                expected.sort_unstable();
+                assert_eq!(values, &expected);
+            }
+        }
+
+        // mapping length must match the partition count
+        assert!(shuffle_partition_values(batch, Some(vec![0, 1, 2]), false)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_combiner() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..100).map(|i| i % 7).collect()),
+            ("b", &(0..100).collect()),
+            ("c", &(0..100).map(|i| i * 2).collect()),
+        );
+        let combiner = SumByKeyCombiner::try_new(&batch.schema(), vec![0], vec![1, 2])?;
+
+        let env = TestEnv::try_new(batch.schema(), 1000000)?;
+        let repartitioner = env.new_repartitioner(hash_partitioning(4), |repartitioner| {
+            Ok(repartitioner.with_combiner(Arc::new(combiner)))
+        })?;
+
+        // rows are combined in the spill and in the final output
+        repartitioner.insert_batch(batch.clone()).await?;
+        repartitioner.force_spill().await?;
+        repartitioner.insert_batch(batch.clone()).await?;
+        repartitioner.shuffle_write().await?;
+
+        let data = std::fs::read(&env.data_file)?;
+        let offsets = read_index_file(&env.index_file.to_string_lossy())?;
+        let mut num_rows = 0;
+        let mut sums: HashMap<i32, (i32, i32)> = HashMap::new();
+        for (&beg, &end) in offsets.iter().tuple_windows() {
+            let segment = data[beg as usize..end as usize].to_vec();
+            let mut reader = IpcCompressionReader::new(Cursor::new(segment));
+            while let Some((batch_num_rows, cols)) = reader.read_batch(&batch.schema())? {
+                num_rows += batch_num_rows;
+                for i in 0..batch_num_rows {
+                    let sum = sums.entry(cols[0].as_primitive::<Int32Type>().value(i));
+                    let sum = sum.or_default();
+                    sum.0 += cols[1].as_primitive::<Int32Type>().value(i);
+                    sum.1 += cols[2].as_primitive::<Int32Type>().value(i);
+                }
+            }
+        }
+        // at most one row per key in each spill and in the output
+        assert!(num_rows <= 14);
+
+        let mut expected: HashMap<i32, (i32, i32)> = HashMap::new();
+        for i in 0..100 {
+            let sum = expected.entry(i % 7).or_default();
+            sum.0 += i * 2;
+            sum.1 += i * 4;
+        }
+        assert_eq!(sums, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_close_and_drop() -> Result<()> {
+        let record_batch = build_table_i32(
+            ("a", &vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10]),
+            ("b", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            ("c", &vec![5, 6, 7, 8, 9, 0, 1, 2, 3, 4]),
+        );
+        let env = TestEnv::try_new(record_batch.schema(), 1000000)?;
+        let file = |name: &str| env.output_dir.path().join(name);
+        let new_repartitioner = |name: &str, sync_on_close: bool| {
+            env.new_repartitioner_with_files(
+                &file(&format!("{name}.data")),
+                &file(&format!("{name}.index")),
+                hash_partitioning(4),
+                |repartitioner| Ok(repartitioner.with_sync_on_close(sync_on_close)),
+            )
+        };
+
+        // close succeeds and deregisters
+        let repartitioner = new_repartitioner("ok", true)?;
+        let consumer_info = repartitioner.get_consumer_info().clone();
+        repartitioner.insert_batch(record_batch.clone()).await?;
+        repartitioner.shuffle_write().await?;
+        repartitioner.close().await?;
+        assert!(consumer_info.upgrade().is_none());
+        repartitioner.close().await?; // closing again is no-op
+        drop(repartitioner);
+
+        // output files are not synced by default
+        let repartitioner = new_repartitioner("unsynced", false)?;
+        repartitioner.insert_batch(record_batch.clone()).await?;
+        repartitioner.shuffle_write().await?;
+        std::fs::remove_file(file("unsynced.data"))?;
+        repartitioner.close().await?;
+        drop(repartitioner);
+
+        // close returns error if final flush fails, but still deregisters
+        let repartitioner = new_repartitioner("failed", true)?;
+        let consumer_info = repartitioner.get_consumer_info().clone();
+        repartitioner.insert_batch(record_batch.clone()).await?;
+        repartitioner.shuffle_write().await?;
+        std::fs::remove_file(file("failed.data"))?;
+        assert!(repartitioner.close().await.is_err());
+        assert!(consumer_info.upgrade().is_none());
+        drop(repartitioner);
+
+        // drop without close still deregisters
+        let repartitioner = new_repartitioner("dropped", false)?;
+        let consumer_info = repartitioner.get_consumer_info().clone();
+        repartitioner.insert_batch(record_batch).await?;
+        assert!(consumer_info.upgrade().is_some());
+        drop(repartitioner);
+        assert!(consumer_info.upgrade().is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shuffle_write_with_output() -> Result<()> {
+        let batch = build_table_i32(
+            ("a", &(0..100).collect()),
+            ("b", &(0..100).map(|i| i * 3).collect()),
+            ("c", &(0..100).map(|i| i % 7).collect()),
+        );
+        let schema = batch.schema();
+
+        for spill in [false, true] {
+            let env = TestEnv::try_new(schema.clone(), 1000000)?;
+            let repartitioner = env.new_repartitioner(hash_partitioning(8), Ok)?;
+
+            repartitioner.insert_batch(batch.slice(0, 40)).await?;
+            if spill {
+                repartitioner.force_spill().await?;
+            }
+            repartitioner.insert_batch(batch.slice(40, 60)).await?;
+            let output = repartitioner.shuffle_write_with_output()?;
+            let output_batches = collect(output).await?;
+
+            // partitions arrive in ascending partition id order
+            let output_partition_ids = output_batches
+                .iter()
+                .flat_map(|batch| {
+                    batch
+                        .column(0)
+                        .as_primitive::<UInt32Type>()
+                        .values()
+                        .to_vec()
+                })
+                .collect::<Vec<_>>();
+            assert!(output_partition_ids.is_sorted());
+            assert_eq!(output_partition_ids.len(), batch.num_rows());
+
+            // output batches match the written files
+            let data = std::fs::read(&env.data_file)?;
+            let offsets = read_index_file(&env.index_file.to_string_lossy())?;
+            for (partition_id, (&beg, &end)) in offsets.iter().tuple_windows().enumerate() {
+                let segment = Bytes::copy_from_slice(&data[beg as usize..end as usize]);
+                let file_batches = read_segment(segment, &schema)?;
+                let output_partition_batches = output_batches
+                    .iter()
+                    .map(|batch| {
+                        let partition_ids = batch.column(0).as_primitive::<UInt32Type>();
+                        let mask =
+                            eq(partition_ids, &UInt32Array::new_scalar(partition_id as u32))?;
+                        let batch =
+                            RecordBatch::try_new(schema.clone(), batch.columns()[1..].to_vec())?;
+                        Ok(filter_record_batch(&batch, &mask)?)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                assert_eq!(
+                    concat_batches(&schema, &output_partition_batches)?,
+                    concat_batches(&schema, &file_batches)?,
+                    "partition: {partition_id}, spill: {spill}"
+                );
+            }
+            repartitioner.close().await?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,366 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use arrow::{
+    array::new_empty_array,
+    datatypes::{DataType, SchemaRef},
+};
+use blaze_jni_bridge::{
+    conf,
+    conf::{IntConf, StringConf},
+    is_jni_bridge_inited,
+};
+use datafusion::{common::Result, physical_plan::metrics::Time};
+use datafusion_ext_commons::{df_execution_err, spark_hash::create_hive_hashes};
+use once_cell::sync::OnceCell;
+
+use crate::{
+    common::execution_context::ExecutionContext,
+    memmgr::MemManager,
+    shuffle::{
+        buffered_data::{shuffle_batch_stats_enabled, shuffle_spill_format, SpillFormat},
+        bypass_repartitioner::BypassMergeShuffleRepartitioner,
+        single_repartitioner::SingleShuffleRepartitioner,
+        sort_repartitioner::SortShuffleRepartitioner,
+        HashAlgorithm, Partitioning, ShuffleRepartitioner,
+    },
+};
+
+// identical default as spark.shuffle.sort.bypassMergeThreshold
+const DEFAULT_BYPASS_MERGE_THRESHOLD: usize = 200;
+
+/// shuffle writer mode configured by spark.blaze.shuffle.writer, sort by
+/// default
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShuffleWriterMode {
+    /// bypass merge for few output partitions, sort otherwise
+    Auto,
+    Sort,
+    Bypass,
+}
+
+/// shuffle writer implementation chosen for a partitioning
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShuffleWriterKind {
+    Single,
+    Sort,
+    BypassMerge,
+}
+
+/// creates the shuffle writer for the partitioning, see
+/// [`select_shuffle_writer`] for how it is chosen. round robin input is
+/// expected to be sorted already.
+pub fn create_shuffle_repartitioner(
+    exec_ctx: Arc<ExecutionContext>,
+    partitioning: Partitioning,
+    output_data_file: String,
+    output_index_file: String,
+    output_io_time: Time,
+) -> Result<Arc<dyn ShuffleRepartitioner>> {
+    validate_partitioning(&partitioning, &exec_ctx.output_schema())?;
+
+    let (kind, reason) = select_shuffle_writer(
+        &partitioning,
+        shuffle_writer_mode(),
+        shuffle_bypass_merge_threshold(),
+        shuffle_spill_format() == SpillFormat::Parquet || shuffle_batch_stats_enabled(),
+    );
+    log::info!(
+        "[partition={}] using {kind:?} shuffle writer for {partitioning}: {reason}",
+        exec_ctx.partition_id(),
+    );
+
+    Ok(match kind {
+        ShuffleWriterKind::Single => Arc::new(SingleShuffleRepartitioner::new(
+            output_data_file,
+            output_index_file,
+            output_io_time,
+        )),
+        ShuffleWriterKind::Sort => {
+            let repartitioner = Arc::new(SortShuffleRepartitioner::new(
+                exec_ctx,
+                output_data_file,
+                output_index_file,
+                partitioning,
+                output_io_time,
+            ));
+            MemManager::register_consumer(repartitioner.clone(), true)?;
+            repartitioner
+        }
+        ShuffleWriterKind::BypassMerge => Arc::new(BypassMergeShuffleRepartitioner::new(
+            exec_ctx,
+            output_data_file,
+            output_index_file,
+            partitioning,
+            output_io_time,
+        )),
+    })
+}
+
+/// chooses a shuffle writer like spark's SortShuffleManager does, returning
+/// the reason of the choice. bypass merge falls back to sort if the output
+/// requires features only supported by the sort writer (parquet output and
+/// batch stats).
+pub fn select_shuffle_writer(
+    partitioning: &Partitioning,
+    mode: ShuffleWriterMode,
+    bypass_merge_threshold: usize,
+    requires_sort_writer: bool,
+) -> (ShuffleWriterKind, String) {
+    let num_partitions = partitioning.partition_count();
+    if num_partitions == 1 {
+        return (
+            ShuffleWriterKind::Single,
+            "single output partition".to_string(),
+        );
+    }
+    match mode {
+        ShuffleWriterMode::Sort => (ShuffleWriterKind::Sort, "configured".to_string()),
+        _ if requires_sort_writer => (
+            ShuffleWriterKind::Sort,
+            "bypass merge does not support parquet output or batch stats".to_string(),
+        ),
+        ShuffleWriterMode::Bypass => (ShuffleWriterKind::BypassMerge, "configured".to_string()),
+        ShuffleWriterMode::Auto if num_partitions <= bypass_merge_threshold => (
+            ShuffleWriterKind::BypassMerge,
+            format!(
+                "{num_partitions} partitions <= bypass merge threshold {bypass_merge_threshold}"
+            ),
+        ),
+        ShuffleWriterMode::Auto => (
+            ShuffleWriterKind::Sort,
+            format!(
+                "{num_partitions} partitions > bypass merge threshold {bypass_merge_threshold}"
+            ),
+        ),
+    }
+}
+
+/// checks that rows can be partitioned with the partitioning, so that errors
+/// are reported before any input is consumed
+pub fn validate_partitioning(partitioning: &Partitioning, schema: &SchemaRef) -> Result<()> {
+    let name = match partitioning {
+        Partitioning::RoundRobinPartitioning(..) => "RoundRobinPartitioning",
+        Partitioning::HashPartitioning(..) => "HashPartitioning",
+        Partitioning::SinglePartitioning() => "SinglePartitioning",
+        Partitioning::RangePartitioning(..) => "RangePartitioning",
+        Partitioning::PartitionIdPartitioning(..) => "PartitionIdPartitioning",
+    };
+    let num_partitions = partitioning.partition_count();
+    if num_partitions == 0 {
+        return df_execution_err!("unsupported {name}: number of partitions must be positive");
+    }
+
+    match partitioning {
+        Partitioning::HashPartitioning(exprs, _, hash_algorithm) => {
+            for expr in exprs {
+                let data_type = match expr.data_type(schema) {
+                    Ok(data_type) => data_type,
+                    Err(err) => {
+                        return df_execution_err!("unsupported {name}: invalid key {expr}: {err}");
+                    }
+                };
+                if *hash_algorithm == HashAlgorithm::HiveHash
+                    && create_hive_hashes(0, &[new_empty_array(&data_type)]).is_err()
+                {
+                    return df_execution_err!(
+                        "unsupported {name}: key {expr} of type {data_type} cannot be hive hashed"
+                    );
+                }
+            }
+        }
+        Partitioning::RangePartitioning(sort_exprs, _, bounds) => {
+            for sort_expr in sort_exprs {
+                if let Err(err) = sort_expr.expr.data_type(schema) {
+                    return df_execution_err!(
+                        "unsupported {name}: invalid key {}: {err}",
+                        sort_expr.expr,
+                    );
+                }
+            }
+            if bounds.num_rows() >= num_partitions {
+                return df_execution_err!(
+                    "unsupported {name}: {} bounds for {num_partitions} partitions",
+                    bounds.num_rows(),
+                );
+            }
+        }
+        Partitioning::PartitionIdPartitioning(expr, _) => match expr.data_type(schema) {
+            Ok(DataType::Int32) => {}
+            Ok(data_type) => {
+                return df_execution_err!(
+                    "unsupported {name}: partition id {expr} of type {data_type}, expected Int32"
+                );
+            }
+            Err(err) => {
+                return df_execution_err!("unsupported {name}: invalid partition id {expr}: {err}");
+            }
+        },
+        Partitioning::RoundRobinPartitioning(..) | Partitioning::SinglePartitioning() => {}
+    }
+    Ok(())
+}
+
+fn shuffle_writer_mode() -> ShuffleWriterMode {
+    static MODE: OnceCell<ShuffleWriterMode> = OnceCell::new();
+    *MODE.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            match conf::SHUFFLE_WRITER.value().as_deref() {
+                Ok("auto") => ShuffleWriterMode::Auto,
+                Ok("sort") => ShuffleWriterMode::Sort,
+                Ok("bypass") => ShuffleWriterMode::Bypass,
+                other => {
+                    log::warn!("unsupported shuffle writer {other:?}, fall back to sort");
+                    ShuffleWriterMode::Sort
+                }
+            }
+        } else {
+            ShuffleWriterMode::Sort // for testing
+        }
+    })
+}
+
+fn shuffle_bypass_merge_threshold() -> usize {
+    static THRESHOLD: OnceCell<usize> = OnceCell::new();
+    *THRESHOLD.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SHUFFLE_BYPASS_MERGE_THRESHOLD
+                .value()
+                .ok()
+                .and_then(|threshold| usize::try_from(threshold).ok())
+                .unwrap_or(DEFAULT_BYPASS_MERGE_THRESHOLD)
+        } else {
+            DEFAULT_BYPASS_MERGE_THRESHOLD // for testing
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::{
+        array::Int32Array,
+        datatypes::{DataType, Field, Schema},
+        row::{RowConverter, SortField},
+    };
+    use datafusion::{
+        common::Result,
+        physical_expr::{expressions::Column, PhysicalSortExpr},
+    };
+
+    use crate::shuffle::{
+        writer_factory::{
+            select_shuffle_writer, validate_partitioning, ShuffleWriterKind, ShuffleWriterMode,
+        },
+        HashAlgorithm, Partitioning,
+    };
+
+    fn hash_partitioning(num_partitions: usize) -> Partitioning {
+        Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("a", 0))],
+            num_partitions,
+            HashAlgorithm::default(),
+        )
+    }
+
+    #[test]
+    fn test_select_shuffle_writer() {
+        let select = |num_partitions, mode, requires_sort_writer| {
+            select_shuffle_writer(
+                &hash_partitioning(num_partitions),
+                mode,
+                200,
+                requires_sort_writer,
+            )
+            .0
+        };
+        use ShuffleWriterKind::{BypassMerge, Single};
+        use ShuffleWriterMode::{Auto, Bypass};
+        assert_eq!(select(1, ShuffleWriterMode::Sort, false), Single);
+        assert_eq!(select(1, Bypass, false), Single);
+        assert_eq!(select(200, Auto, false), BypassMerge);
+        assert_eq!(select(201, Auto, false), ShuffleWriterKind::Sort);
+        assert_eq!(select(10000, Bypass, false), BypassMerge);
+        assert_eq!(
+            select(10, ShuffleWriterMode::Sort, false),
+            ShuffleWriterKind::Sort
+        );
+
+        // falls back to sort
+        assert_eq!(select(10, Auto, true), ShuffleWriterKind::Sort);
+        assert_eq!(select(10, Bypass, true), ShuffleWriterKind::Sort);
+    }
+
+    #[test]
+    fn test_validate_partitioning() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Float64, false),
+        ]));
+        validate_partitioning(&hash_partitioning(10), &schema)?;
+        validate_partitioning(&Partitioning::RoundRobinPartitioning(10), &schema)?;
+
+        let err = validate_partitioning(&hash_partitioning(0), &schema).unwrap_err();
+        assert!(err.to_string().contains("unsupported HashPartitioning"));
+
+        let invalid_key = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("c", 2))],
+            10,
+            HashAlgorithm::default(),
+        );
+        let err = validate_partitioning(&invalid_key, &schema).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unsupported HashPartitioning: invalid key c@2"));
+
+        let float_hive_hash = Partitioning::HashPartitioning(
+            vec![Arc::new(Column::new("b", 1))],
+            10,
+            HashAlgorithm::HiveHash,
+        );
+        let err = validate_partitioning(&float_hive_hash, &schema).unwrap_err();
+        assert!(err.to_string().contains("cannot be hive hashed"));
+
+        // range partitioning with too many bounds
+        let converter = RowConverter::new(vec![SortField::new(DataType::Int32)])?;
+        let bounds =
+            Arc::new(converter.convert_columns(&[Arc::new(Int32Array::from(vec![1, 2, 3]))])?);
+        let sort_exprs = vec![PhysicalSortExpr {
+            expr: Arc::new(Column::new("a", 0)),
+            options: Default::default(),
+        }];
+        let range = |num_partitions| {
+            Partitioning::RangePartitioning(sort_exprs.clone(), num_partitions, bounds.clone())
+        };
+        validate_partitioning(&range(4), &schema)?;
+        let err = validate_partitioning(&range(3), &schema).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unsupported RangePartitioning: 3 bounds for 3 partitions"));
+
+        // partition id expressions must evaluate to int32
+        let partition_id = |name, index| {
+            Partitioning::PartitionIdPartitioning(Arc::new(Column::new(name, index)), 10)
+        };
+        validate_partitioning(&partition_id("a", 0), &schema)?;
+        let err = validate_partitioning(&partition_id("b", 1), &schema).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unsupported PartitionIdPartitioning"));
+        Ok(())
+    }
+}
@@ -34,11 +34,7 @@ use once_cell::sync::OnceCell;
 
 use crate::{
     common::execution_context::ExecutionContext,
-    memmgr::MemManager,
-    shuffle::{
-        single_repartitioner::SingleShuffleRepartitioner,
-        sort_repartitioner::SortShuffleRepartitioner, Partitioning, ShuffleRepartitioner,
-    },
+    shuffle::{writer_factory::create_shuffle_repartitioner, Partitioning},
     sort_exec::create_default_ascending_sort_exec,
 };
 
@@ -116,51 +112,32 @@ impl ExecutionPlan for ShuffleWriterExec {
 
         let mut input = self.input.clone();
 
-        let repartitioner: Arc<dyn ShuffleRepartitioner> = match &self.partitioning {
-            p if p.partition_count() == 1 => Arc::new(SingleShuffleRepartitioner::new(
-                self.output_data_file.clone(),
-                self.output_index_file.clone(),
-                output_time,
-            )),
-            Partitioning::HashPartitioning(..) | Partitioning::RangePartitioning(..) => {
-                let partitioner = Arc::new(SortShuffleRepartitioner::new(
-                    exec_ctx.clone(),
-                    self.output_data_file.clone(),
-                    self.output_index_file.clone(),
-                    self.partitioning.clone(),
-                    output_time,
-                ));
-                MemManager::register_consumer(partitioner.clone(), true);
-                partitioner
-            }
-            Partitioning::RoundRobinPartitioning(..) => {
-                input = create_default_ascending_sort_exec(
-                    input,
-                    self.input
-                        .schema()
-                        .fields()
-                        .iter()
-                        .enumerate()
-                        .map(|(index, field)| {
-                            Arc::new(Column::new(&field.name(), index)) as PhysicalExprRef
-                        })
-                        .collect::<Vec<_>>()
-                        .as_ref(),
-                    None,
-                    false, // do not record output metric
-                );
-                let partitioner = Arc::new(SortShuffleRepartitioner::new(
-                    exec_ctx.clone(),
-                    self.output_data_file.clone(),
-                    self.output_index_file.clone(),
-                    self.partitioning.clone(),
-                    output_time,
-                ));
-                MemManager::register_consumer(partitioner.clone(), true);
-                partitioner
-            }
-            p => unreachable!("unsupported partitioning: {:?}", p),
-        };
+        // round robin partitioning sorts input first, so that output is
+        // deterministic
+        if matches!(self.partitioning, Partitioning::RoundRobinPartitioning(n) if n > 1) {
+            input = create_default_ascending_sort_exec(
+                input,
+                self.input
+                    .schema()
+                    .fields()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, field)| {
+                        Arc::new(Column::new(&field.name(), index)) as PhysicalExprRef
+                    })
+                    .collect::<Vec<_>>()
+                    .as_ref(),
+                None,
+                false, // do not record output metric
+            );
+        }
+        let repartitioner = create_shuffle_repartitioner(
+            exec_ctx.clone(),
+            self.partitioning.clone(),
+            self.output_data_file.clone(),
+            self.output_index_file.clone(),
+            output_time,
+        )?;
 
         let input = exec_ctx.execute_with_input_stats(&input)?;
         repartitioner.execute(exec_ctx, input)
@@ -33,6 +33,7 @@ use arrow::{
     row::{Row, RowConverter, RowParser, Rows, SortField},
 };
 use async_trait::async_trait;
+use blaze_jni_bridge::{conf, conf::IntConf, is_jni_bridge_inited};
 use bytesize::ByteSize;
 use datafusion::{
     common::{DataFusionError, Result, Statistics},
@@ -53,7 +54,7 @@ use datafusion_ext_commons::{
         selection::{create_batch_interleaver, take_batch, BatchInterleaver},
     },
     compute_suggested_batch_size_for_kway_merge,
-    io::{read_len, read_one_batch, write_len, write_one_batch},
+    io::{read_len, write_len},
 };
 use futures::{lock::Mutex, StreamExt};
 use once_cell::sync::OnceCell;
@@ -67,7 +68,7 @@ use crate::{
     },
     memmgr::{
         metrics::SpillMetrics,
-        spill::{try_new_spill, Spill, SpillCompressedReader},
+        spill::{read_spill_batch, try_new_spill, write_spill_batch, Spill, SpillCompressedReader},
         MemConsumer, MemConsumerInfo, MemManager,
     },
 };
@@ -77,6 +78,17 @@ use crate::{
 const SPILL_OFFHEAP_MEM_COST: usize = 200000;
 const SPILL_MERGING_SIZE: usize = 32;
 
+fn max_merge_fanin() -> usize {
+    static FANIN: OnceCell<usize> = OnceCell::new();
+    *FANIN.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SORT_MAX_MERGE_FANIN.value().unwrap_or(256).max(2) as usize
+        } else {
+            256 // for testing
+        }
+    })
+}
+
 #[derive(Debug)]
 pub struct SortExec {
     input: Arc<dyn ExecutionPlan>,
@@ -210,6 +222,7 @@ struct ExternalSorter {
     record_output: bool,
     data: Arc<Mutex<BufferedData>>,
     spills: Mutex<Vec<LevelSpill>>,
+    max_merge_fanin: usize,
     num_total_rows: AtomicUsize,
     mem_total_size: AtomicUsize,
 }
@@ -301,10 +314,11 @@ impl BufferedData {
         for (key_collector, batch) in
             self.into_sorted_batches::<SqueezeKeyCollector>(sub_batch_size, limit)?
         {
-            write_one_batch(batch.num_rows(), batch.columns(), &mut writer)?;
+            write_spill_batch(batch.num_rows(), batch.columns(), &mut writer)?;
             writer.write_all(&key_collector.store)?;
         }
         writer.finish()?;
+        spill.complete()?;
         Ok(())
     }
 
@@ -312,6 +326,21 @@ impl BufferedData {
         self.sorted_batches_mem_used + self.sorted_key_stores_mem_used
     }
 
+    /// merges all sorted batches and keeps only the first `limit` rows
+    fn into_top_rows(self, limit: usize) -> Result<Self> {
+        let mut top_rows = Self::default();
+        for (key_collector, batch) in
+            self.into_sorted_batches::<SqueezeKeyCollector>(limit, limit)?
+        {
+            top_rows.num_rows += batch.num_rows();
+            top_rows.sorted_batches_mem_used += batch.get_batch_mem_size();
+            top_rows.sorted_key_stores_mem_used += key_collector.store.len();
+            top_rows.sorted_key_stores.push(key_collector.store.into());
+            top_rows.sorted_batches.push(batch);
+        }
+        Ok(top_rows)
+    }
+
     fn add_batch(&mut self, batch: RecordBatch, sorter: &ExternalSorter) -> Result<()> {
         self.num_rows += batch.num_rows();
         let (key_rows, batch) = sorter.prune_sort_keys_from_batch.prune(batch)?;
@@ -522,10 +551,11 @@ impl ExecuteWithColumnPruning for SortExec {
             record_output: self.record_output,
             data: Default::default(),
             spills: Default::default(),
+            max_merge_fanin: max_merge_fanin(),
             num_total_rows: Default::default(),
             mem_total_size: Default::default(),
         });
-        MemManager::register_consumer(sorter.clone(), true);
+        MemManager::register_consumer(sorter.clone(), true)?;
 
         let elapsed_compute = exec_ctx.baseline_metrics().elapsed_compute().clone();
         let input = exec_ctx.execute_with_input_stats(&self.input)?;
@@ -587,6 +617,12 @@ impl ExternalSorter {
         let mem_used = {
             let mut data = self.data.lock().await;
             data.add_batch(batch, self)?;
+
+            // with a limit, only top rows are kept in memory, so that sorting
+            // with a small limit never spills
+            if data.num_rows >= self.limit.saturating_mul(2) {
+                *data = std::mem::take(&mut *data).into_top_rows(self.limit)?;
+            }
             data.mem_used()
         };
         self.update_mem_used(mem_used).await?;
@@ -609,7 +645,7 @@ impl ExternalSorter {
             self.mem_total_size(),
             self.num_total_rows(),
         );
-        let mut spills: Vec<Box<dyn Spill>> = spills.into_iter().map(|spill| spill.spill).collect();
+        let spills: Vec<Box<dyn Spill>> = spills.into_iter().map(|spill| spill.spill).collect();
 
         // no spills -- output in-mem batches
         if spills.is_empty() {
@@ -625,13 +661,22 @@ impl ExternalSorter {
                             .baseline_metrics()
                             .record_output(batch.num_rows());
                     }
-                    sender.send(batch).await;
+                    sender.send(batch).await?;
                 }
                 self.update_mem_used(0).await?;
             }
             return Ok(());
         }
 
+        // merge in multiple passes if there are too many spills
+        let mut spills = merge_spills_to_fanin(
+            spills,
+            self.max_merge_fanin,
+            self.exec_ctx.spill_metrics(),
+            sub_batch_size,
+            self.limit,
+            self.prune_sort_keys_from_batch.pruned_schema(),
+        )?;
         let mut merger = ExternalMerger::<SimpleKeyCollector>::try_new(
             &mut spills,
             self.prune_sort_keys_from_batch.pruned_schema(),
@@ -650,7 +695,7 @@ impl ExternalSorter {
                     .baseline_metrics()
                     .record_output(batch.num_rows());
             }
-            sender.send(batch).await;
+            sender.send(batch).await?;
         }
         self.update_mem_used(0).await?;
         Ok(())
@@ -746,7 +791,7 @@ impl<'a> SpillCursor<'a> {
     }
 
     fn load_next_batch(&mut self) -> Result<bool> {
-        if let Some((num_rows, cols)) = read_one_batch(&mut self.input, &self.pruned_schema)? {
+        if let Some((num_rows, cols)) = read_spill_batch(&mut self.input, &self.pruned_schema)? {
             let batch = RecordBatch::try_new_with_options(
                 self.pruned_schema.clone(),
                 cols,
@@ -848,7 +893,10 @@ impl<KC: KeyCollector> ExternalMerger<'_, KC> {
         let pruned_schema = self.pruned_schema.clone();
 
         // collect merged records to staging
-        if self.num_total_output_rows < self.limit {
+        if self.num_total_output_rows < self.limit
+            && !self.cursors.is_empty()
+            && !self.cursors.peek().finished
+        {
             let mut min_cursor = self.cursors.peek_mut();
             while !min_cursor.finished && self.staging_num_rows < self.sub_batch_size {
                 if !pruned_schema.fields().is_empty() {
@@ -946,7 +994,7 @@ fn merge_spills(
     )?;
 
     while let Some((key_collector, pruned_batch)) = merger.next().transpose()? {
-        write_one_batch(
+        write_spill_batch(
             pruned_batch.num_rows(),
             pruned_batch.columns(),
             &mut output_writer,
@@ -954,9 +1002,46 @@ fn merge_spills(
         output_writer.write_all(&key_collector.store)?;
     }
     output_writer.finish()?;
+    output_spill.complete()?;
     Ok(output_spill)
 }
 
+/// merges spills in multiple passes until there are no more than `max_fanin`
+/// spills, so that the final merge does not open too many spills at a time.
+fn merge_spills_to_fanin(
+    mut spills: Vec<Box<dyn Spill>>,
+    max_fanin: usize,
+    spill_metrics: &SpillMetrics,
+    sub_batch_size: usize,
+    limit: usize,
+    pruned_schema: SchemaRef,
+) -> Result<Vec<Box<dyn Spill>>> {
+    let max_fanin = max_fanin.max(2);
+    while spills.len() > max_fanin {
+        log::info!(
+            "merging {} spills with max fan-in {max_fanin}",
+            spills.len()
+        );
+        let mut merged_spills = vec![];
+        let mut spills_iter = spills.into_iter();
+        loop {
+            let group = spills_iter.by_ref().take(max_fanin).collect::<Vec<_>>();
+            if group.is_empty() {
+                break;
+            }
+            merged_spills.push(merge_spills(
+                group,
+                spill_metrics,
+                sub_batch_size,
+                limit,
+                pruned_schema.clone(),
+            )?);
+        }
+        spills = merged_spills;
+    }
+    Ok(spills)
+}
+
 fn create_zero_column_batch(num_rows: usize) -> RecordBatch {
     static EMPTY_SCHEMA: OnceCell<SchemaRef> = OnceCell::new();
     let empty_schema = EMPTY_SCHEMA
@@ -1287,20 +1372,29 @@ mod test {
     use std::sync::Arc;
 
     use arrow::{
-        array::Int32Array,
+        array::{AsArray, Int32Array},
         compute::SortOptions,
-        datatypes::{DataType, Field, Schema},
+        datatypes::{DataType, Field, Int32Type, Schema},
         record_batch::RecordBatch,
     };
     use datafusion::{
         assert_batches_eq,
         common::Result,
         physical_expr::{expressions::Column, PhysicalSortExpr},
-        physical_plan::{common, memory::MemoryExec, ExecutionPlan},
+        physical_plan::{
+            common, memory::MemoryExec, metrics::ExecutionPlanMetricsSet, ExecutionPlan,
+        },
         prelude::SessionContext,
     };
 
-    use crate::{memmgr::MemManager, sort_exec::SortExec};
+    use crate::{
+        common::execution_context::ExecutionContext,
+        memmgr::{spill::try_new_spill, MemManager},
+        sort_exec::{
+            merge_spills_to_fanin, BufferedData, ExternalMerger, ExternalSorter,
+            PruneSortKeysFromBatch, SimpleKeyCollector, SortExec,
+        },
+    };
 
     fn build_table_i32(
         a: (&str, &Vec<i32>),
@@ -1368,6 +1462,82 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_multi_pass_merge() -> Result<()> {
+        MemManager::init(1000000);
+        let schema = build_table_i32(("a", &vec![]), ("b", &vec![]), ("c", &vec![])).schema();
+        let session_ctx = SessionContext::new();
+        let exec_ctx = ExecutionContext::new(
+            session_ctx.task_ctx(),
+            0,
+            schema.clone(),
+            &ExecutionPlanMetricsSet::new(),
+        );
+        let sort_exprs = vec![PhysicalSortExpr {
+            expr: Arc::new(Column::new("a", 0)),
+            options: SortOptions::default(),
+        }];
+        let prune_sort_keys_from_batch = Arc::new(PruneSortKeysFromBatch::try_new(
+            schema,
+            &[0, 1, 2],
+            &sort_exprs,
+        )?);
+        let sorter = Arc::new(ExternalSorter {
+            exec_ctx: exec_ctx.clone(),
+            mem_consumer_info: None,
+            prune_sort_keys_from_batch: prune_sort_keys_from_batch.clone(),
+            limit: usize::MAX,
+            record_output: false,
+            data: Default::default(),
+            spills: Default::default(),
+            max_merge_fanin: 10,
+            num_total_rows: Default::default(),
+            mem_total_size: Default::default(),
+        });
+        MemManager::register_consumer(sorter.clone(), true)?;
+
+        // create 100 spills with interleaved keys
+        let mut spills = vec![];
+        for i in 0..100 {
+            let values = (0..10).rev().map(|j| j * 100 + i).collect::<Vec<_>>();
+            let batch = build_table_i32(("a", &values), ("b", &values), ("c", &values));
+            let mut data = BufferedData::default();
+            data.add_batch(batch, &sorter)?;
+            let mut spill = try_new_spill(exec_ctx.spill_metrics())?;
+            data.try_into_spill(&mut spill, 10, usize::MAX)?;
+            spills.push(spill);
+        }
+
+        // first pass merges 100 spills into 10, second pass is the final merge
+        let pruned_schema = prune_sort_keys_from_batch.pruned_schema();
+        let mut spills = merge_spills_to_fanin(
+            spills,
+            sorter.max_merge_fanin,
+            exec_ctx.spill_metrics(),
+            10,
+            usize::MAX,
+            pruned_schema.clone(),
+        )?;
+        assert_eq!(spills.len(), 10);
+
+        let mut merger = ExternalMerger::<SimpleKeyCollector>::try_new(
+            &mut spills,
+            pruned_schema,
+            10,
+            usize::MAX,
+        )?;
+        let mut keys = vec![];
+        let mut values = vec![];
+        while let Some((key_collector, pruned_batch)) = merger.next().transpose()? {
+            let batch = prune_sort_keys_from_batch.restore(pruned_batch, key_collector)?;
+            keys.extend_from_slice(batch.column(0).as_primitive::<Int32Type>().values());
+            values.extend_from_slice(batch.column(1).as_primitive::<Int32Type>().values());
+        }
+        assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+        assert_eq!(values, keys);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1375,7 +1545,7 @@ mod fuzztest {
     use std::sync::Arc;
 
     use arrow::{
-        array::{ArrayRef, UInt32Array},
+        array::{ArrayRef, Int32Array, StringArray, UInt32Array},
         compute::{concat_batches, SortOptions},
         record_batch::RecordBatch,
     };
@@ -1385,6 +1555,7 @@ mod fuzztest {
         physical_plan::{memory::MemoryExec, ExecutionPlan},
         prelude::{SessionConfig, SessionContext},
     };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
 
     use crate::{memmgr::MemManager, sort_exec::SortExec};
 
@@ -1470,4 +1641,190 @@ mod fuzztest {
         assert!(a == b);
         Ok(())
     }
+    #[tokio::test]
+    async fn fuzztest_with_sort_options() -> Result<()> {
+        MemManager::init(10000);
+        let session_ctx =
+            SessionContext::new_with_config(SessionConfig::new().with_batch_size(1000));
+        let task_ctx = session_ctx.task_ctx();
+        let mut rng = StdRng::seed_from_u64(37);
+        let n = 100000;
+
+        // keys have many duplicates and nulls, a unique id is used as the last
+        // sort key so that the output order is deterministic
+        let mut batches = vec![];
+        let mut num_rows = 0;
+        while num_rows < n {
+            let batch_size = (n - num_rows).min(rng.random_range(1..2000));
+            let rand_key1: ArrayRef = Arc::new(
+                (0..batch_size)
+                    .map(|_| Some(rng.random_range(0..20)).filter(|v| v % 7 != 0))
+                    .collect::<Int32Array>(),
+            );
+            let rand_key2: ArrayRef = Arc::new(
+                (0..batch_size)
+                    .map(|_| Some(rng.random_range(0..100).to_string()).filter(|v| v != "0"))
+                    .collect::<StringArray>(),
+            );
+            let id: ArrayRef = Arc::new(
+                (num_rows..num_rows + batch_size)
+                    .map(|id| id as u32)
+                    .collect::<UInt32Array>(),
+            );
+            let batch = RecordBatch::try_from_iter_with_nullable(vec![
+                ("k1", rand_key1, true),
+                ("k2", rand_key2, true),
+                ("id", id, false),
+            ])?;
+            num_rows += batch.num_rows();
+            batches.push(batch);
+        }
+        let schema = batches[0].schema();
+
+        for _ in 0..4 {
+            let mut random_options = || SortOptions {
+                descending: rng.random(),
+                nulls_first: rng.random(),
+            };
+            let sort_exprs = vec![
+                PhysicalSortExpr {
+                    expr: Arc::new(Column::new("k1", 0)),
+                    options: random_options(),
+                },
+                PhysicalSortExpr {
+                    expr: Arc::new(Column::new("k2", 1)),
+                    options: random_options(),
+                },
+                PhysicalSortExpr {
+                    expr: Arc::new(Column::new("id", 2)),
+                    options: random_options(),
+                },
+            ];
+
+            let input = Arc::new(MemoryExec::try_new(
+                &[batches.clone()],
+                schema.clone(),
+                None,
+            )?);
+            let sort = Arc::new(SortExec::new(input, sort_exprs.clone(), None));
+            let output = datafusion::physical_plan::collect(sort, task_ctx.clone()).await?;
+            let a = concat_batches(&schema, &output)?;
+
+            let input = Arc::new(MemoryExec::try_new(
+                &[batches.clone()],
+                schema.clone(),
+                None,
+            )?);
+            let sort = Arc::new(datafusion::physical_plan::sorts::sort::SortExec::new(
+                sort_exprs.clone(),
+                input,
+            ));
+            let output = datafusion::physical_plan::collect(sort, task_ctx.clone()).await?;
+            let b = concat_batches(&schema, &output)?;
+
+            assert_eq!(a.num_rows(), n);
+            assert!(a == b, "sort exprs: {sort_exprs:?}");
+        }
+        Ok(())
+    }
+    /// generates batches with many duplicated keys and nulls
+    fn random_batches(rng: &mut StdRng, n: usize) -> Result<Vec<RecordBatch>> {
+        let mut batches = vec![];
+        let mut num_rows = 0;
+        while num_rows < n {
+            let batch_size = (n - num_rows).min(10000);
+            let key1: ArrayRef = Arc::new(
+                (0..batch_size)
+                    .map(|_| Some(rng.random_range(0..100)).filter(|v| v % 17 != 0))
+                    .collect::<Int32Array>(),
+            );
+            let key2: ArrayRef = Arc::new(
+                (0..batch_size)
+                    .map(|_| rng.random::<i32>())
+                    .collect::<Int32Array>(),
+            );
+            let val: ArrayRef = Arc::new(
+                (num_rows..num_rows + batch_size)
+                    .map(|v| v as u32)
+                    .collect::<UInt32Array>(),
+            );
+            let batch = RecordBatch::try_from_iter_with_nullable(vec![
+                ("k1", key1, true),
+                ("k2", key2, false),
+                ("v", val, false),
+            ])?;
+            num_rows += batch.num_rows();
+            batches.push(batch);
+        }
+        Ok(batches)
+    }
+
+    fn mixed_sort_exprs() -> Vec<PhysicalSortExpr> {
+        vec![
+            PhysicalSortExpr {
+                expr: Arc::new(Column::new("k1", 0)),
+                options: SortOptions {
+                    descending: true,
+                    nulls_first: true,
+                },
+            },
+            PhysicalSortExpr {
+                expr: Arc::new(Column::new("k2", 1)),
+                options: SortOptions {
+                    descending: false,
+                    nulls_first: false,
+                },
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn fuzztest_top_rows() -> Result<()> {
+        MemManager::init(10000);
+        let session_ctx =
+            SessionContext::new_with_config(SessionConfig::new().with_batch_size(10000));
+        let task_ctx = session_ctx.task_ctx();
+        let mut rng = StdRng::seed_from_u64(37);
+
+        // input is large enough to trigger spilling without a limit
+        for (n, fetch) in [(2000000, 1), (2000000, 100), (2000000, 10000), (1000, 5000)] {
+            let batches = random_batches(&mut rng, n)?;
+            let schema = batches[0].schema();
+            let sort_exprs = mixed_sort_exprs();
+
+            let input = Arc::new(MemoryExec::try_new(
+                &[batches.clone()],
+                schema.clone(),
+                None,
+            )?);
+            let sort = Arc::new(SortExec::new(input, sort_exprs.clone(), Some(fetch)));
+            let output = datafusion::physical_plan::collect(sort.clone(), task_ctx.clone()).await?;
+            let a = concat_batches(&schema, &output)?;
+            let metrics = sort.metrics().expect("sort metrics");
+            let metric_value =
+                |name: &str| metrics.sum_by_name(name).map(|v| v.as_usize()).unwrap_or(0);
+            assert_eq!(metric_value("mem_spill_count"), 0);
+            assert_eq!(metric_value("disk_spill_size"), 0);
+
+            let input = Arc::new(MemoryExec::try_new(
+                &[batches.clone()],
+                schema.clone(),
+                None,
+            )?);
+            let sort = Arc::new(
+                datafusion::physical_plan::sorts::sort::SortExec::new(sort_exprs, input)
+                    .with_fetch(Some(fetch)),
+            );
+            let output = datafusion::physical_plan::collect(sort, task_ctx.clone()).await?;
+            let b = concat_batches(&schema, &output)?;
+
+            // rows with tied keys may be output in any order
+            assert_eq!(a.num_rows(), fetch.min(n));
+            assert!(
+                a.project(&[0, 1])? == b.project(&[0, 1])?,
+                "n={n}, fetch={fetch}"
+            );
+        }
+        Ok(())
+    }
 }
@@ -16,6 +16,7 @@ use std::{any::Any, fmt::Formatter, pin::Pin, sync::Arc};
 
 use arrow::{compute::SortOptions, datatypes::SchemaRef};
 use async_trait::async_trait;
+use blaze_jni_bridge::{conf, conf::IntConf, is_jni_bridge_inited};
 use datafusion::{
     common::{DataFusionError, JoinSide},
     error::Result,
@@ -58,11 +59,25 @@ pub struct SortMergeJoinExec {
     join_type: JoinType,
     sort_options: Vec<SortOptions>,
     join_params: OnceCell<JoinParams>,
+    group_spill_threshold: usize,
     schema: SchemaRef,
     metrics: ExecutionPlanMetricsSet,
     props: OnceCell<PlanProperties>,
 }
 
+fn smj_group_spill_threshold() -> usize {
+    static THRESHOLD: OnceCell<usize> = OnceCell::new();
+    *THRESHOLD.get_or_init(|| {
+        if is_jni_bridge_inited() {
+            conf::SMJ_GROUP_SPILL_MEM_SIZE_THRESHOLD
+                .value()
+                .unwrap_or(67108864) as usize
+        } else {
+            67108864 // for testing
+        }
+    })
+}
+
 impl SortMergeJoinExec {
     pub fn try_new(
         schema: SchemaRef,
@@ -80,6 +95,7 @@ impl SortMergeJoinExec {
             join_type,
             sort_options,
             join_params: OnceCell::new(),
+            group_spill_threshold: smj_group_spill_threshold(),
             metrics: ExecutionPlanMetricsSet::new(),
             props: OnceCell::new(),
         })
@@ -105,11 +121,18 @@ impl SortMergeJoinExec {
             join_type: join_params.join_type,
             sort_options: join_params.sort_options.clone(),
             join_params: OnceCell::with_value(join_params),
+            group_spill_threshold: smj_group_spill_threshold(),
             metrics: ExecutionPlanMetricsSet::new(),
             props: OnceCell::new(),
         })
     }
 
+    /// buffered key groups larger than this size are spilled while joining
+    pub fn with_group_spill_threshold(mut self, group_spill_threshold: usize) -> Self {
+        self.group_spill_threshold = group_spill_threshold;
+        self
+    }
+
     fn create_join_params(&self, projection: &[usize]) -> Result<JoinParams> {
         let left_schema = self.left.schema();
         let right_schema = self.right.schema();
@@ -170,12 +193,20 @@ impl SortMergeJoinExec {
             &self.metrics,
         );
         let exec_ctx_cloned = exec_ctx.clone();
+        let group_spill_threshold = self.group_spill_threshold;
         let left = exec_ctx.execute(&self.left)?;
         let right = exec_ctx.execute(&self.right)?;
         let output = exec_ctx_cloned
             .clone()
             .output_with_sender("SortMergeJoin", move |sender| {
-                execute_join(left, right, join_params, exec_ctx_cloned, sender)
+                execute_join(
+                    left,
+                    right,
+                    join_params,
+                    group_spill_threshold,
+                    exec_ctx_cloned,
+                    sender,
+                )
             });
         Ok(exec_ctx.coalesce_with_default_batch_size(output))
     }
@@ -233,14 +264,17 @@ impl ExecutionPlan for SortMergeJoinExec {
         self: Arc<Self>,
         children: Vec<Arc<dyn ExecutionPlan>>,
     ) -> Result<Arc<dyn ExecutionPlan>> {
-        Ok(Arc::new(SortMergeJoinExec::try_new(
-            self.schema(),
-            children[0].clone(),
-            children[1].clone(),
-            self.on.clone(),
-            self.join_type,
-            self.sort_options.clone(),
-        )?))
+        Ok(Arc::new(
+            SortMergeJoinExec::try_new(
+                self.schema(),
+                children[0].clone(),
+                children[1].clone(),
+                self.on.clone(),
+                self.join_type,
+                self.sort_options.clone(),
+            )?
+            .with_group_spill_threshold(self.group_spill_threshold),
+        ))
     }
 
     fn execute(
@@ -265,6 +299,7 @@ pub async fn execute_join(
     lstream: SendableRecordBatchStream,
     rstream: SendableRecordBatchStream,
     join_params: JoinParams,
+    group_spill_threshold: usize,
     exec_ctx: Arc<ExecutionContext>,
     sender: Arc<WrappedRecordBatchSender>,
 ) -> Result<()> {
@@ -296,10 +331,30 @@ pub async fn execute_join(
 
     let join_type = join_params.join_type;
     let mut joiner: Pin<Box<dyn Joiner + Send>> = match join_type {
-        Inner => Box::pin(InnerJoiner::new(join_params, sender)),
-        Left => Box::pin(LeftOuterJoiner::new(join_params, sender)),
-        Right => Box::pin(RightOuterJoiner::new(join_params, sender)),
-        Full => Box::pin(FullOuterJoiner::new(join_params, sender)),
+        Inner => Box::pin(InnerJoiner::new(
+            join_params,
+            sender,
+            exec_ctx.clone(),
+            group_spill_threshold,
+        )),
+        Left => Box::pin(LeftOuterJoiner::new(
+            join_params,
+            sender,
+            exec_ctx.clone(),
+            group_spill_threshold,
+        )),
+        Right => Box::pin(RightOuterJoiner::new(
+            join_params,
+            sender,
+            exec_ctx.clone(),
+            group_spill_threshold,
+        )),
+        Full => Box::pin(FullOuterJoiner::new(
+            join_params,
+            sender,
+            exec_ctx.clone(),
+            group_spill_threshold,
+        )),
         LeftSemi => Box::pin(LeftSemiJoiner::new(join_params, sender)),
         RightSemi => Box::pin(RightSemiJoiner::new(join_params, sender)),
         LeftAnti => Box::pin(LeftAntiJoiner::new(join_params, sender)),
@@ -177,7 +177,7 @@ impl ExecutionPlan for UnionExec {
                             )?;
                         }
                         exec_ctx.baseline_metrics().record_output(batch.num_rows());
-                        sender.send(batch).await;
+                        sender.send(batch).await?;
                     }
                 }
                 Ok(())
@@ -256,7 +256,7 @@ fn execute_window(
                 exec_ctx
                     .baseline_metrics()
                     .record_output(output_batch.num_rows());
-                sender.send(output_batch).await;
+                sender.send(output_batch).await?;
             }
             Ok(())
         }))
@@ -483,4 +483,92 @@ mod test {
         assert_batches_eq!(expected, &batches);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_window_across_batches() -> Result<(), Box<dyn std::error::Error>> {
+        let session_ctx = SessionContext::new();
+        let task_ctx = session_ctx.task_ctx();
+
+        // partitions and runs of equal order keys span coalesced batches
+        let num_rows = 25000;
+        let a: Vec<i32> = (0..num_rows).map(|i| i / 3000).collect();
+        let b: Vec<i32> = (0..num_rows).map(|i| i % 3000 / 7).collect();
+        let c: Vec<i32> = (0..num_rows).map(|i| i * 7919 % 101 - 50).collect();
+        let batch = build_table_i32(("a1", &a), ("b1", &b), ("c1", &c));
+        let batches = (0..num_rows as usize)
+            .step_by(500)
+            .map(|offset| batch.slice(offset, 500))
+            .collect::<Vec<_>>();
+        let input = Arc::new(MemoryExec::try_new(&[batches], batch.schema(), None)?);
+
+        let rank_expr = |rank_type, name| {
+            WindowExpr::new(
+                WindowFunction::RankLike(rank_type),
+                vec![],
+                Arc::new(Field::new(name, DataType::Int32, false)),
+                DataType::Int32,
+            )
+        };
+        let agg_expr = |agg_func, name, data_type: DataType| {
+            WindowExpr::new(
+                WindowFunction::Agg(agg_func),
+                vec![Arc::new(Column::new("c1", 2))],
+                Arc::new(Field::new(name, data_type.clone(), false)),
+                data_type,
+            )
+        };
+        let window_exprs = vec![
+            rank_expr(WindowRankType::RowNumber, "row_number"),
+            rank_expr(WindowRankType::Rank, "rank"),
+            rank_expr(WindowRankType::DenseRank, "dense_rank"),
+            agg_expr(AggFunction::Sum, "sum", DataType::Int64),
+            agg_expr(AggFunction::Min, "min", DataType::Int32),
+            agg_expr(AggFunction::Max, "max", DataType::Int32),
+        ];
+        let window = Arc::new(WindowExec::try_new(
+            input,
+            window_exprs,
+            vec![Arc::new(Column::new("a1", 0))],
+            vec![PhysicalSortExpr {
+                expr: Arc::new(Column::new("b1", 1)),
+                options: Default::default(),
+            }],
+            None,
+            true,
+        )?);
+        let stream = window.execute(0, task_ctx)?;
+        let batches = datafusion::physical_plan::common::collect(stream).await?;
+        assert!(batches.len() > 1);
+        let output = arrow::compute::concat_batches(&window.schema(), &batches)?;
+        assert_eq!(output.num_rows(), num_rows as usize);
+
+        // expected values of spark's ROWS BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW
+        let (mut row_number, mut rank, mut dense_rank) = (0, 0, 0);
+        let (mut sum, mut min, mut max) = (0i64, 0, 0);
+        for i in 0..num_rows as usize {
+            if i == 0 || a[i] != a[i - 1] {
+                (row_number, rank, dense_rank) = (0, 0, 0);
+                (sum, min, max) = (0, i32::MAX, i32::MIN);
+            }
+            row_number += 1;
+            if row_number == 1 || b[i] != b[i - 1] {
+                rank = row_number;
+                dense_rank += 1;
+            }
+            sum += c[i] as i64;
+            min = min.min(c[i]);
+            max = max.max(c[i]);
+
+            let col = |name: &str| output.column_by_name(name).unwrap();
+            assert_eq!(col("a1").as_primitive::<Int32Type>().value(i), a[i]);
+            assert_eq!(col("b1").as_primitive::<Int32Type>().value(i), b[i]);
+            assert_eq!(col("row_number").as_primitive::<Int32Type>().value(i), row_number);
+            assert_eq!(col("rank").as_primitive::<Int32Type>().value(i), rank);
+            assert_eq!(col("dense_rank").as_primitive::<Int32Type>().value(i), dense_rank);
+            assert_eq!(col("sum").as_primitive::<Int64Type>().value(i), sum);
+            assert_eq!(col("min").as_primitive::<Int32Type>().value(i), min);
+            assert_eq!(col("max").as_primitive::<Int32Type>().value(i), max);
+        }
+        Ok(())
+    }
 }
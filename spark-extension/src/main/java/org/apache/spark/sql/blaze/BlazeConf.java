@@ -23,10 +23,20 @@ public enum BlazeConf {
     /// suggested batch size for arrow batches.
     BATCH_SIZE("spark.blaze.batchSize", 10000),
 
+    /// number of batches buffered between native operators and their consumers.
+    OUTPUT_CHANNEL_CAPACITY("spark.blaze.outputChannel.capacity", 1),
+
+    /// milliseconds a native operator waits for its consumer to accept a batch before failing
+    /// with "output stalled". 0 for waiting forever.
+    OUTPUT_SEND_TIMEOUT_MS("spark.blaze.outputChannel.sendTimeoutMs", 0),
+
     /// suggested fraction of off-heap memory used in native execution.
     /// actual off-heap memory usage is expected to be spark.executor.memoryOverhead * fraction.
     MEMORY_FRACTION("spark.blaze.memoryFraction", 0.6),
 
+    /// fraction of native managed memory at which spillable operators start spilling.
+    MEMORY_SPILL_WATERMARK("spark.blaze.memorySpillWatermark", 1.0),
+
     /// enable converting upper/lower functions to native, special cases may provide different
     /// outputs from spark due to different unicode versions.
     CASE_CONVERT_FUNCTIONS_ENABLE("spark.blaze.enable.caseconvert.functions", true),
@@ -83,6 +93,86 @@ public enum BlazeConf {
     // spark spill compression codec
     SPILL_COMPRESSION_CODEC("spark.blaze.spill.compression.codec", "lz4"),
 
+    // spills larger than this size are written directly to disk files, bypassing on-heap spill
+    SPILL_DIRECT_TO_DISK_THRESHOLD("spark.blaze.spill.directToDisk.threshold", 268435456),
+
+    // enable per-column encodings (like delta-rle for sorted integers) when writing spills
+    SPILL_COLUMN_ENCODING_ENABLE("spark.blaze.spill.columnEncoding.enable", false),
+
+    // small shuffle spills are kept in native memory until they grow larger than this size
+    SPILL_RESIDENT_THRESHOLD("spark.blaze.spill.resident.threshold", 1048576),
+
+    // encrypt spill data written to disk or on-heap spills with AES-256-GCM
+    SPILL_ENCRYPTION_ENABLE("spark.blaze.spill.encryption.enable", false),
+
+    // seed for choosing spill directories, making spill placement reproducible. -1 to use a time-based seed
+    SPILL_PLACEMENT_SEED("spark.blaze.spill.placement.seed", -1L),
+
+    // max bytes of disk used by spills of a shuffle writer, the task fails once exceeded instead of filling up the
+    // disk. -1 for no limit
+    SPILL_MAX_DISK_BYTES("spark.blaze.spill.maxDiskBytes", -1L),
+
+    // keep original row order within each shuffle partition, making shuffle output reproducible
+    SHUFFLE_STABLE_ORDER_ENABLE("spark.blaze.shuffle.stableOrder.enable", false),
+
+    // format of shuffle spills and data files: ipc or parquet. both are private to blaze, a parquet partition
+    // segment is a concatenation of parquet files which generic parquet readers cannot read
+    SHUFFLE_SPILL_FORMAT("spark.blaze.shuffle.spillFormat", "ipc"),
+
+    // hash of hash partitioning rows whose keys are all null, spark hashes these rows to the seed 42
+    SHUFFLE_NULL_KEYS_HASH_SEED("spark.blaze.shuffle.nullKeys.hashSeed", 42),
+
+    // route hash partitioning rows whose keys are all null to this partition, isolating null key skew. -1 to disable
+    SHUFFLE_NULL_KEYS_PARTITION("spark.blaze.shuffle.nullKeys.partition", -1),
+
+    // append a checksummed trailer to each shuffle spill/data segment, older readers cannot read segments with trailers
+    SHUFFLE_SEGMENT_TRAILER_ENABLE("spark.blaze.shuffle.segmentTrailer.enable", false),
+
+    // cap estimated bytes of each sub-batch written into shuffle spills/data files, in addition to the row cap. 0 to disable
+    SHUFFLE_SUB_BATCH_MEM_SIZE("spark.blaze.shuffle.subBatch.memSize", 0),
+
+    // warn about skewed shuffle output when the largest partition exceeds this multiple of the median size of
+    // non-empty partitions. 0 to disable
+    SHUFFLE_SKEW_WARNING_RATIO("spark.blaze.shuffle.skewWarning.ratio", 100.0),
+
+    // max number of batches buffered by a shuffle writer before it spills, regardless of memory usage. 0 to disable
+    SHUFFLE_MAX_BUFFERED_BATCHES("spark.blaze.shuffle.maxBufferedBatches", 100000),
+
+    // write min/max of integer hash partitioning keys before each shuffle sub-batch, so that readers can skip
+    // sub-batches with runtime filters. requires all executors to support reading batch stats
+    SHUFFLE_BATCH_STATS_ENABLE("spark.blaze.shuffle.batchStats.enable", false),
+
+    // shuffle writer implementation: sort, auto or bypass. auto uses bypass merge writer if the number of output
+    // partitions is not greater than spark.blaze.shuffle.bypassMergeThreshold
+    SHUFFLE_WRITER("spark.blaze.shuffle.writer", "sort"),
+
+    // max number of output partitions to use bypass merge shuffle writer in auto mode
+    SHUFFLE_BYPASS_MERGE_THRESHOLD("spark.blaze.shuffle.bypassMergeThreshold", 200),
+
+    // append a bitmap of non-empty partitions to native shuffle index files, so that readers can skip empty
+    // partitions. spark rewrites the index file without the bitmap when committing map output
+    SHUFFLE_INDEX_BITMAP_ENABLE("spark.blaze.shuffle.indexBitmap.enable", false),
+
+    // write native shuffle index files in a sparse format which only stores offsets of non-empty partitions,
+    // for shuffles with a huge number of mostly empty partitions. takes precedence over the bitmap
+    SHUFFLE_INDEX_SPARSE_ENABLE("spark.blaze.shuffle.indexSparse.enable", false),
+
+    // mapping of hash partitioning hashes to partition ids: pmod or fibonacci. fibonacci mixes all hash bits to reduce
+    // clustering of structured hashes, but is not compatible with spark's partitioning of non-native shuffles
+    SHUFFLE_PARTITION_ID_ASSIGNMENT("spark.blaze.shuffle.partitionIdAssignment", "pmod"),
+
+    // decode merged shuffle output and check row counts of each partition against the merged spills, for debugging
+    SHUFFLE_MERGE_VALIDATION_ENABLE("spark.blaze.shuffle.mergeValidation.enable", false),
+
+    // max serialized bytes of a single batch in shuffle/spill data, larger batches are split into multiple batches
+    SHUFFLE_MAX_MESSAGE_SIZE("spark.blaze.shuffle.maxMessageSize", 2147483647L),
+
+    // fsync shuffle data and index files when closing native shuffle writers, trading write latency for durability
+    SHUFFLE_SYNC_ON_CLOSE_ENABLE("spark.blaze.shuffle.syncOnClose.enable", false),
+
+    // max number of spills merged at the same time in external sorting, more spills are merged in multiple passes
+    SORT_MAX_MERGE_FANIN("spark.blaze.sort.maxMergeFanIn", 256),
+
     // enable hash join falling back to sort merge join when hash table is too big
     SMJ_FALLBACK_ENABLE("spark.blaze.smjfallback.enable", false),
 
@@ -92,6 +182,9 @@ public enum BlazeConf {
     // smj fallback threshold
     SMJ_FALLBACK_MEM_SIZE_THRESHOLD("spark.blaze.smjfallback.mem.threshold", 134217728),
 
+    // spill a key group of sort merge join if its buffered data exceeds this size
+    SMJ_GROUP_SPILL_MEM_SIZE_THRESHOLD("spark.blaze.smj.groupSpill.mem.threshold", 67108864),
+
     // max memory fraction of on-heap spills
     ON_HEAP_SPILL_MEM_FRACTION("spark.blaze.onHeapSpill.memoryFraction", 0.9),
 
@@ -103,7 +196,14 @@ public enum BlazeConf {
     // batches in memory at the same time
     SUGGESTED_BATCH_MEM_SIZE_KWAY_MERGE("spark.blaze.suggested.batch.memSize.multiwayMerging", 1048576),
 
-    ORC_FORCE_POSITIONAL_EVOLUTION("spark.blaze.orc.force.positional.evolution", false);
+    ORC_FORCE_POSITIONAL_EVOLUTION("spark.blaze.orc.force.positional.evolution", false),
+
+    // comma separated debug ids of native DebugExec operators to instrument, empty to instrument all of them
+    DEBUG_EXEC_TAGS("spark.blaze.debugExec.tags", ""),
+
+    // cap of bytes of native output batches exported to the JVM but not yet consumed, the native side is blocked
+    // when exceeding. 0 for no limit
+    EXPORT_IN_FLIGHT_MAX_MEM_SIZE("spark.blaze.export.inFlight.maxMemSize", 0L);
 
     public final String key;
     private final Object defaultValue;
@@ -71,19 +71,9 @@ abstract class BlazeShuffleWriterBase[K, V](metrics: ShuffleWriteMetricsReporter
     assert(iterator.toArray.isEmpty)
 
     // get partition lengths from shuffle write output index file
-    var offset = 0L
-    partitionLengths = Files
-      .readAllBytes(tempIndexFilePath)
-      .grouped(8)
-      .drop(1) // first partition offset is always 0
-      .map(indexBytes => {
-        val partitionOffset =
-          ByteBuffer.wrap(indexBytes).order(ByteOrder.LITTLE_ENDIAN).getLong
-        val partitionLength = partitionOffset - offset
-        offset = partitionOffset
-        partitionLength
-      })
-      .toArray
+    val numPartitions = dep.partitioner.numPartitions
+    val offsets = readIndexOffsets(Files.readAllBytes(tempIndexFilePath), numPartitions)
+    partitionLengths = (1 to numPartitions).map(i => offsets(i) - offsets(i - 1)).toArray
 
     // update metrics
     val dataSize = Files.size(tempDataFilePath)
@@ -100,6 +90,29 @@ abstract class BlazeShuffleWriterBase[K, V](metrics: ShuffleWriteMetricsReporter
         context))
   }
 
+  // decodes offsets of a native shuffle index file. a sparse index file ends with
+  // version byte 2 and only stores (partition_id, offset) pairs of non-empty
+  // partitions, otherwise offsets may be followed by the non-empty partition bitmap.
+  private def readIndexOffsets(indexBytes: Array[Byte], numPartitions: Int): Array[Long] = {
+    val buf = ByteBuffer.wrap(indexBytes).order(ByteOrder.LITTLE_ENDIAN)
+    if (indexBytes.length % 16 == 1 && indexBytes.last == 2) {
+      // empty partitions have the same offset as the next non-empty partition
+      val offsets = new Array[Long](numPartitions + 1)
+      var numFilled = 0
+      for (_ <- 0 until indexBytes.length / 16) {
+        val partitionId = buf.getLong.toInt
+        val offset = buf.getLong
+        while (numFilled <= partitionId) {
+          offsets(numFilled) = offset
+          numFilled += 1
+        }
+      }
+      offsets
+    } else {
+      Array.fill(numPartitions + 1)(buf.getLong)
+    }
+  }
+
   override def stop(success: Boolean): Option[MapStatus] = {
     mapStatus.filter(_ => success)
   }